@@ -0,0 +1,1280 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::alert::AlertRule;
+use crate::clienttimeout::TimeoutPolicy;
+use crate::database::ClientRow;
+use crate::dependency;
+use crate::maintenance;
+use crate::notifier::ClientInfo;
+use crate::storage::{Storage, ROLLUP_1H, ROLLUP_5M};
+use crate::structs::{
+    AcknowledgeRequest, AdminResult, AlertRuleRequest, ClientFilter, ClientKey, ClientPatch,
+    ErrorCodes, HistoryQuery, IncidentQuery, MaintenanceRequest, Response, TimeoutOverride,
+    UptimeReport,
+};
+use crate::{get_current_timestamp, ExtraData};
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound};
+use actix_web::{web, HttpResponse};
+use std::sync::Arc;
+
+pub async fn resolve_client(storage: &dyn Storage, key: &ClientKey) -> actix_web::Result<ClientRow> {
+    let client = match key {
+        ClientKey::Id(id) => storage.get_client(*id).await,
+        ClientKey::Uuid(uuid) => storage.get_client_by_uuid(uuid).await,
+    }
+    .map_err(ErrorInternalServerError)?;
+    client.ok_or_else(|| ErrorNotFound(Response::from(ErrorCodes::ClientNotFound)))
+}
+
+/// Resolution of aggregated history asked by query, `None` asks for raw samples.
+fn get_resolution(query: &HistoryQuery) -> actix_web::Result<Option<i64>> {
+    match query.get_resolution() {
+        Some(resolution) if resolution != ROLLUP_5M && resolution != ROLLUP_1H => {
+            Err(ErrorBadRequest(Response::from_error_with_message(
+                ErrorCodes::InvalidParameter,
+                format!("Resolution should be {} or {}", ROLLUP_5M, ROLLUP_1H),
+            )))
+        }
+        resolution => Ok(resolution),
+    }
+}
+
+const DEFAULT_UPTIME_WINDOW: i64 = 30 * 86400;
+
+/// Compute uptime of client from incidents overlapping the window, overlapped incidents
+/// are merged so downtime is not counted twice.
+pub async fn compute_uptime(
+    storage: &dyn Storage,
+    id: i32,
+    query: &HistoryQuery,
+) -> anyhow::Result<UptimeReport> {
+    let current_time = get_current_timestamp() as i64;
+    let until = query.get_until().min(current_time);
+    let since = query
+        .get_since_opt()
+        .unwrap_or(until - DEFAULT_UPTIME_WINDOW)
+        .min(until);
+    let incidents = storage.list_incidents_between(id, since, until).await?;
+    let mut downtime = 0;
+    // End of merged interval counted so far
+    let mut counted_until = since;
+    for incident in incidents.iter() {
+        let start = incident.get_started_at().max(counted_until);
+        let end = incident.get_resolved_at().unwrap_or(current_time).min(until);
+        if end > start {
+            downtime += end - start;
+            counted_until = end;
+        }
+    }
+    Ok(UptimeReport::new(id, since, until, downtime, incidents.len()))
+}
+
+/// Query historical data of client, `kind` is one of `raw_data`, `metrics`, `disks`, `network`,
+/// `incidents`, `uptime` and `alerts`.
+pub async fn query_history(
+    storage: &dyn Storage,
+    key: &ClientKey,
+    kind: &str,
+    query: &HistoryQuery,
+) -> actix_web::Result<AdminResult> {
+    let id = resolve_client(storage, key).await?.get_id();
+    let result = match kind {
+        "raw_data" => AdminResult::new_ok(
+            storage
+                .query_raw_data(id, query)
+                .await
+                .map_err(ErrorInternalServerError)?,
+        ),
+        "metrics" => match get_resolution(query)? {
+            None => AdminResult::new_ok(
+                storage
+                    .query_metrics(id, query)
+                    .await
+                    .map_err(ErrorInternalServerError)?,
+            ),
+            Some(resolution) => AdminResult::new_ok(
+                storage
+                    .query_metrics_rollup(id, resolution, query)
+                    .await
+                    .map_err(ErrorInternalServerError)?,
+            ),
+        },
+        "disks" => match get_resolution(query)? {
+            None => AdminResult::new_ok(
+                storage
+                    .query_disk_metrics(id, query)
+                    .await
+                    .map_err(ErrorInternalServerError)?,
+            ),
+            Some(resolution) => AdminResult::new_ok(
+                storage
+                    .query_disk_metrics_rollup(id, resolution, query)
+                    .await
+                    .map_err(ErrorInternalServerError)?,
+            ),
+        },
+        "network" => match get_resolution(query)? {
+            None => AdminResult::new_ok(
+                storage
+                    .query_network_metrics(id, query)
+                    .await
+                    .map_err(ErrorInternalServerError)?,
+            ),
+            Some(resolution) => AdminResult::new_ok(
+                storage
+                    .query_network_metrics_rollup(id, resolution, query)
+                    .await
+                    .map_err(ErrorInternalServerError)?,
+            ),
+        },
+        "incidents" => AdminResult::new_ok(
+            storage
+                .query_incidents(Some(id), &IncidentQuery::from(query))
+                .await
+                .map_err(ErrorInternalServerError)?,
+        ),
+        "uptime" => AdminResult::new_ok(
+            compute_uptime(storage, id, query)
+                .await
+                .map_err(ErrorInternalServerError)?,
+        ),
+        "alerts" => AdminResult::new_ok(
+            storage
+                .query_alerts(id, query)
+                .await
+                .map_err(ErrorInternalServerError)?,
+        ),
+        _ => return Err(ErrorNotFound(Response::from(ErrorCodes::UnsupportedMethod))),
+    };
+    result.map_err(ErrorInternalServerError)
+}
+
+/// `GET /admin/incidents`
+pub async fn route_list_incidents(
+    query: web::Query<IncidentQuery>,
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    let client_id = match query.get_client() {
+        Some(key) => Some(resolve_client(data.storage.as_ref(), &key).await?.get_id()),
+        None => None,
+    };
+    to_response(AdminResult::new_ok(
+        data.storage
+            .query_incidents(client_id, &query)
+            .await
+            .map_err(ErrorInternalServerError)?,
+    ))
+}
+
+/// `POST /admin/incidents/{id}/acknowledge`, stop escalation of open incident.
+pub async fn route_acknowledge_incident(
+    path: web::Path<i64>,
+    request: Option<web::Json<AcknowledgeRequest>>,
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    let id = path.into_inner();
+    let storage = data.storage.as_ref();
+    if storage
+        .get_incident(id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .is_none()
+    {
+        return Err(ErrorNotFound(Response::from(ErrorCodes::InvalidParameter)));
+    }
+    let request = request.map(|request| request.into_inner()).unwrap_or_default();
+    if !storage
+        .acknowledge_incident(id, request.get_by(), get_current_timestamp() as i64)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorBadRequest(Response::from_error_with_message(
+            ErrorCodes::InvalidParameter,
+            "Incident is resolved or acknowledged already".to_string(),
+        )));
+    }
+    to_response(AdminResult::new_ok(
+        storage
+            .get_incident(id)
+            .await
+            .map_err(ErrorInternalServerError)?,
+    ))
+}
+
+/// `GET /admin/clients/{client}/{kind}`
+pub async fn route_client_history(
+    path: web::Path<(String, String)>,
+    query: web::Query<HistoryQuery>,
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    let (client, kind) = path.into_inner();
+    let result = query_history(
+        data.storage.as_ref(),
+        &ClientKey::from(client.as_str()),
+        &kind,
+        &query,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+fn to_response(result: anyhow::Result<AdminResult>) -> actix_web::Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(result.map_err(ErrorInternalServerError)?))
+}
+
+/// Keep clients matched by every pattern of filter.
+fn filter_clients(clients: Vec<ClientRow>, filter: &ClientFilter) -> Vec<ClientRow> {
+    let patterns = filter.get_patterns();
+    clients
+        .into_iter()
+        .filter(|client| {
+            let info = ClientInfo::from(client);
+            patterns.iter().all(|pattern| info.matches(pattern))
+        })
+        .collect()
+}
+
+/// `GET /admin/clients`
+pub async fn route_list_clients(
+    filter: web::Query<ClientFilter>,
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    to_response(AdminResult::new_ok(filter_clients(
+        data.storage
+            .list_clients()
+            .await
+            .map_err(ErrorInternalServerError)?,
+        &filter,
+    )))
+}
+
+/// List clients sent heartbeat within their timeout.
+pub async fn list_online(
+    storage: &dyn Storage,
+    policy: &TimeoutPolicy,
+) -> anyhow::Result<Vec<ClientRow>> {
+    let current_time = get_current_timestamp() as i64;
+    Ok(storage
+        .list_clients()
+        .await?
+        .into_iter()
+        .filter(|client| policy.is_online(client, current_time))
+        .collect())
+}
+
+/// `GET /admin/clients/online`
+pub async fn route_list_online(
+    filter: web::Query<ClientFilter>,
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    to_response(AdminResult::new_ok(filter_clients(
+        list_online(data.storage.as_ref(), &data.timeout_policy)
+            .await
+            .map_err(ErrorInternalServerError)?,
+        &filter,
+    )))
+}
+
+/// `GET /admin/clients/{client}`
+pub async fn route_get_client(
+    path: web::Path<String>,
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    let client = resolve_client(data.storage.as_ref(), &ClientKey::from(path.as_str())).await?;
+    to_response(AdminResult::new_ok(client))
+}
+
+/// `DELETE /admin/clients/{client}`
+pub async fn route_delete_client(
+    path: web::Path<String>,
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    let client = resolve_client(data.storage.as_ref(), &ClientKey::from(path.as_str())).await?;
+    if data
+        .storage
+        .delete_client(client.get_id())
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ErrorNotFound(Response::from(ErrorCodes::ClientNotFound)))
+    }
+}
+
+/// Apply patch to client, return the updated row.
+pub async fn patch_client(
+    storage: &dyn Storage,
+    key: &ClientKey,
+    patch: &ClientPatch,
+) -> actix_web::Result<ClientRow> {
+    let client = resolve_client(storage, key).await?;
+    if let Some(display_name) = patch.get_display_name() {
+        let display_name = display_name.trim();
+        storage
+            .set_display_name(
+                client.get_id(),
+                if display_name.is_empty() {
+                    None
+                } else {
+                    Some(display_name)
+                },
+            )
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    if let Some(timeout) = patch.get_timeout() {
+        if timeout < 0 {
+            return Err(ErrorBadRequest(Response::from_error_with_message(
+                ErrorCodes::InvalidParameter,
+                "Timeout should not be negative".to_string(),
+            )));
+        }
+        storage
+            .set_timeout(
+                client.get_id(),
+                if timeout == 0 { None } else { Some(timeout) },
+            )
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    if let Some(tags) = patch.get_tags() {
+        let mut normalized: Vec<String> = Vec::new();
+        for tag in tags.iter().map(|tag| tag.trim()).filter(|tag| !tag.is_empty()) {
+            if !normalized.iter().any(|t| t == tag) {
+                normalized.push(tag.to_string());
+            }
+        }
+        storage
+            .set_tags(client.get_id(), &normalized)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    if let Some(patch_labels) = patch.get_labels() {
+        let mut labels = client.get_labels().clone();
+        for (key, value) in patch_labels {
+            let key = key.trim();
+            if key.is_empty() || key.contains('=') {
+                return Err(ErrorBadRequest(Response::from_error_with_message(
+                    ErrorCodes::InvalidParameter,
+                    format!("Invalid label key: {:?}", key),
+                )));
+            }
+            match value {
+                Some(value) => labels.insert(key.to_string(), value.clone()),
+                None => labels.remove(key),
+            };
+        }
+        storage
+            .set_labels(client.get_id(), &labels)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    if let Some(parent) = patch.get_parent() {
+        let parent = parent.trim();
+        let parent_id = if parent.is_empty() {
+            None
+        } else {
+            let parent = resolve_client(storage, &ClientKey::from(parent)).await?;
+            let clients = storage
+                .list_clients()
+                .await
+                .map_err(ErrorInternalServerError)?;
+            if dependency::creates_cycle(
+                &dependency::get_parents(&clients),
+                client.get_id(),
+                parent.get_id(),
+            ) {
+                return Err(ErrorBadRequest(Response::from_error_with_message(
+                    ErrorCodes::InvalidParameter,
+                    format!("Depending on {} makes a dependency loop", parent.get_name()),
+                )));
+            }
+            Some(parent.get_id())
+        };
+        storage
+            .set_parent(client.get_id(), parent_id)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    if let Some(retired) = patch.get_retired() {
+        storage
+            .set_retired(client.get_id(), retired)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    resolve_client(storage, &ClientKey::Id(client.get_id())).await
+}
+
+/// `PATCH /admin/clients/{client}`
+pub async fn route_patch_client(
+    path: web::Path<String>,
+    patch: web::Json<ClientPatch>,
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    let client = patch_client(
+        data.storage.as_ref(),
+        &ClientKey::from(path.as_str()),
+        &patch,
+    )
+    .await?;
+    to_response(AdminResult::new_ok(client))
+}
+
+/// `GET /admin/timeouts`
+pub async fn route_list_timeouts(data: web::Data<Arc<ExtraData>>) -> actix_web::Result<HttpResponse> {
+    to_response(AdminResult::new_ok(
+        data.storage
+            .list_timeout_overrides()
+            .await
+            .map_err(ErrorInternalServerError)?,
+    ))
+}
+
+/// `PUT /admin/timeouts/{pattern}`, pattern is matched against clients like `[[watchdog.group]]`.
+pub async fn route_put_timeout(
+    path: web::Path<String>,
+    body: web::Json<TimeoutOverride>,
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    if body.get_timeout() <= 0 {
+        return Err(ErrorBadRequest(Response::from_error_with_message(
+            ErrorCodes::InvalidParameter,
+            "Timeout should be positive".to_string(),
+        )));
+    }
+    data.storage
+        .set_timeout_override(path.as_str(), body.get_timeout())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    data.timeout_policy
+        .load_overrides(data.storage.as_ref())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    route_list_timeouts(data).await
+}
+
+/// `DELETE /admin/timeouts/{pattern}`
+pub async fn route_delete_timeout(
+    path: web::Path<String>,
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    if !data
+        .storage
+        .delete_timeout_override(path.as_str())
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorNotFound(Response::from(ErrorCodes::InvalidParameter)));
+    }
+    data.timeout_policy
+        .load_overrides(data.storage.as_ref())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// `GET /admin/maintenance`
+pub async fn route_list_maintenance(
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    to_response(AdminResult::new_ok(
+        data.storage
+            .list_maintenance()
+            .await
+            .map_err(ErrorInternalServerError)?,
+    ))
+}
+
+/// `POST /admin/maintenance`
+pub async fn route_create_maintenance(
+    window: web::Json<MaintenanceRequest>,
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    let current_time = get_current_timestamp() as i64;
+    if let Err(reason) = maintenance::validate(&window, current_time) {
+        return Err(ErrorBadRequest(Response::from_error_with_message(
+            ErrorCodes::InvalidParameter,
+            reason,
+        )));
+    }
+    to_response(AdminResult::new_ok(
+        data.storage
+            .insert_maintenance(&window, current_time)
+            .await
+            .map_err(ErrorInternalServerError)?,
+    ))
+}
+
+/// `DELETE /admin/maintenance/{id}`, active window is ended and its summary is sent.
+pub async fn route_delete_maintenance(
+    path: web::Path<i64>,
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    if maintenance::remove_window(
+        data.storage.as_ref(),
+        path.into_inner(),
+        get_current_timestamp() as i64,
+    )
+    .await
+    .map_err(ErrorInternalServerError)?
+    {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ErrorNotFound(Response::from(ErrorCodes::InvalidParameter)))
+    }
+}
+
+/// `GET /admin/alerts`, pending and firing alerts of all clients.
+pub async fn route_list_alerts(data: web::Data<Arc<ExtraData>>) -> actix_web::Result<HttpResponse> {
+    to_response(AdminResult::new_ok(
+        data.storage
+            .list_active_alerts(None)
+            .await
+            .map_err(ErrorInternalServerError)?,
+    ))
+}
+
+/// `GET /admin/alerts/rules`
+pub async fn route_list_alert_rules(
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    to_response(AdminResult::new_ok(data.alert_engine.get_rules().await))
+}
+
+/// `POST /admin/alerts/rules`
+pub async fn route_create_alert_rule(
+    rule: web::Json<AlertRuleRequest>,
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    let bad_request = |message: String| {
+        ErrorBadRequest(Response::from_error_with_message(
+            ErrorCodes::InvalidParameter,
+            message,
+        ))
+    };
+    if let Err(e) = AlertRule::new(
+        rule.get_name(),
+        rule.get_expression(),
+        rule.get_clients(),
+        false,
+    ) {
+        return Err(bad_request(e.to_string()));
+    }
+    if data
+        .alert_engine
+        .get_rules()
+        .await
+        .iter()
+        .any(|exist| exist.get_name() == rule.get_name())
+    {
+        return Err(bad_request(format!(
+            "Alert rule {} already exists",
+            rule.get_name()
+        )));
+    }
+    let id = data
+        .storage
+        .insert_alert_rule(
+            rule.get_name(),
+            rule.get_expression(),
+            rule.get_clients(),
+            get_current_timestamp() as i64,
+        )
+        .await
+        .map_err(ErrorInternalServerError)?;
+    data.alert_engine
+        .reload(data.storage.as_ref())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    to_response(AdminResult::new_ok(id))
+}
+
+/// `DELETE /admin/alerts/rules/{name}`, alerts of the rule are dropped without notification.
+pub async fn route_delete_alert_rule(
+    path: web::Path<String>,
+    data: web::Data<Arc<ExtraData>>,
+) -> actix_web::Result<HttpResponse> {
+    let name = path.into_inner();
+    if data
+        .alert_engine
+        .get_rules()
+        .await
+        .iter()
+        .any(|rule| rule.get_name() == &name && rule.is_from_config())
+    {
+        return Err(ErrorBadRequest(Response::from_error_with_message(
+            ErrorCodes::InvalidParameter,
+            format!("Alert rule {} is defined in configure file", name),
+        )));
+    }
+    if !data
+        .storage
+        .delete_alert_rule(&name)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorNotFound(Response::from(ErrorCodes::InvalidParameter)));
+    }
+    data.alert_engine
+        .reload(data.storage.as_ref())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Statistics;
+    use crate::storage::IncidentCause;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    const BASE: i64 = 1_699_999_200;
+
+    async fn setup() -> Arc<ExtraData> {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        let id = storage
+            .register_client("client-a", BASE, Some("host-a"), BASE)
+            .await
+            .unwrap()
+            .get_id();
+        for i in 0..3 {
+            storage
+                .insert_raw_data(id, &format!("data {}", i), BASE + i)
+                .await
+                .unwrap();
+        }
+        for (offset, cpu) in [(0, 10.0), (60, 30.0), (300, 50.0)] {
+            let statistics = Statistics::from_body(
+                &serde_json::json!({
+                    "cpu_percent": cpu,
+                    "disk_usage": {"/": {"total": 100, "used": cpu as i64}},
+                    "net_io_counters": {"eth0": {"bytes_recv": offset, "bytes_sent": offset}},
+                })
+                .to_string(),
+            )
+            .unwrap();
+            storage
+                .insert_metrics(id, &statistics, BASE + offset)
+                .await
+                .unwrap();
+        }
+        // First bucket is aggregated, the last sample stays raw
+        storage.rollup_metrics(ROLLUP_5M, BASE + 300).await.unwrap();
+        crate::tests::extra_data(storage)
+    }
+
+    /// Send request to admin routes as they are mounted by server, body is `Null` if empty.
+    async fn call(extra_data: &Arc<ExtraData>, request: test::TestRequest) -> (StatusCode, Value) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(extra_data.clone()))
+                .service(web::resource("/admin/clients").route(web::get().to(route_list_clients)))
+                .service(
+                    web::resource("/admin/clients/online").route(web::get().to(route_list_online)),
+                )
+                .service(
+                    web::resource("/admin/clients/{client}")
+                        .route(web::get().to(route_get_client))
+                        .route(web::delete().to(route_delete_client))
+                        .route(web::patch().to(route_patch_client)),
+                )
+                .service(
+                    web::resource("/admin/clients/{client}/{kind}")
+                        .route(web::get().to(route_client_history)),
+                )
+                .service(
+                    web::resource("/admin/incidents").route(web::get().to(route_list_incidents)),
+                )
+                .service(
+                    web::resource("/admin/incidents/{id}/acknowledge")
+                        .route(web::post().to(route_acknowledge_incident)),
+                )
+                .service(web::resource("/admin/timeouts").route(web::get().to(route_list_timeouts)))
+                .service(
+                    web::resource("/admin/timeouts/{pattern}")
+                        .route(web::put().to(route_put_timeout))
+                        .route(web::delete().to(route_delete_timeout)),
+                )
+                .service(
+                    web::resource("/admin/maintenance")
+                        .route(web::get().to(route_list_maintenance))
+                        .route(web::post().to(route_create_maintenance)),
+                )
+                .service(
+                    web::resource("/admin/maintenance/{id}")
+                        .route(web::delete().to(route_delete_maintenance)),
+                ),
+        )
+        .await;
+        let response = test::call_service(&app, request.to_request()).await;
+        let status = response.status();
+        let body = test::read_body(response).await;
+        if body.is_empty() {
+            (status, Value::Null)
+        } else {
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+    }
+
+    async fn get(extra_data: &Arc<ExtraData>, uri: &str) -> (StatusCode, Value) {
+        call(extra_data, test::TestRequest::get().uri(uri)).await
+    }
+
+    #[actix_rt::test]
+    async fn raw_data() {
+        let extra_data = setup().await;
+        let (status, body) = get(&extra_data, "/admin/clients/1/raw_data?limit=2").await;
+        assert_eq!(status, StatusCode::OK);
+        let rows = body["result"].as_array().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["data"], "data 2");
+        assert_eq!(rows[1]["data"], "data 1");
+
+        // Client is resolved by uuid as well
+        let (_, body) = get(
+            &extra_data,
+            "/admin/clients/client-a/raw_data?order=asc&since=1699999201",
+        )
+        .await;
+        let rows = body["result"].as_array().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["data"], "data 1");
+    }
+
+    #[actix_rt::test]
+    async fn metrics_history() {
+        let extra_data = setup().await;
+        let (status, body) = get(&extra_data, "/admin/clients/1/metrics").await;
+        assert_eq!(status, StatusCode::OK);
+        let rows = body["result"].as_array().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["cpu_usage"], 50.0);
+
+        let (status, body) = get(&extra_data, "/admin/clients/1/metrics?resolution=300").await;
+        assert_eq!(status, StatusCode::OK);
+        let rows = body["result"].as_array().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["samples"], 2);
+        assert_eq!(rows[0]["cpu_usage_avg"], 20.0);
+
+        let (_, body) = get(&extra_data, "/admin/clients/1/disks?resolution=300").await;
+        assert_eq!(body["result"][0]["used_max"], 30);
+        let (_, body) = get(&extra_data, "/admin/clients/1/network?resolution=300").await;
+        assert_eq!(body["result"][0]["rx_bytes_max"], 60);
+        let (_, body) = get(&extra_data, "/admin/clients/1/network").await;
+        assert_eq!(body["result"][0]["rx_bytes"], 300);
+        // Nothing is aggregated hourly yet
+        let (_, body) = get(&extra_data, "/admin/clients/1/disks?resolution=3600").await;
+        assert!(body["result"].as_array().unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn invalid_requests() {
+        let extra_data = setup().await;
+        let (status, body) = get(&extra_data, "/admin/clients/1/metrics?resolution=60").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["status"], i64::from(&ErrorCodes::InvalidParameter));
+
+        let (status, body) = get(&extra_data, "/admin/clients/42/metrics").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["status"], i64::from(&ErrorCodes::ClientNotFound));
+        let (status, body) = get(&extra_data, "/admin/clients/missing/raw_data").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["status"], i64::from(&ErrorCodes::ClientNotFound));
+
+        let (status, body) = get(&extra_data, "/admin/clients/1/unknown").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["status"], i64::from(&ErrorCodes::UnsupportedMethod));
+    }
+
+    #[actix_rt::test]
+    async fn list_clients() {
+        let extra_data = setup().await;
+        extra_data
+            .storage
+            .register_client(
+                "client-b",
+                BASE,
+                None,
+                crate::get_current_timestamp() as i64,
+            )
+            .await
+            .unwrap();
+        let (status, body) = get(&extra_data, "/admin/clients").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"].as_array().unwrap().len(), 2);
+
+        // Only client-b reported recently
+        let (status, body) = get(&extra_data, "/admin/clients/online").await;
+        assert_eq!(status, StatusCode::OK);
+        let online = body["result"].as_array().unwrap();
+        assert_eq!(online.len(), 1);
+        assert_eq!(online[0]["uuid"], "client-b");
+
+        let (status, body) = get(&extra_data, "/admin/clients/client-a").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"]["id"], 1);
+        assert_eq!(body["result"]["hostname"], "host-a");
+    }
+
+    #[actix_rt::test]
+    async fn rename_client() {
+        let extra_data = setup().await;
+        let rename = |display_name: &str| {
+            test::TestRequest::patch()
+                .uri("/admin/clients/1")
+                .set_json(serde_json::json!({ "display_name": display_name }))
+        };
+        let (status, body) = call(&extra_data, rename("  web  ")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"]["display_name"], "web");
+        assert_eq!(
+            extra_data
+                .storage
+                .get_client(1)
+                .await
+                .unwrap()
+                .unwrap()
+                .get_name(),
+            "web"
+        );
+
+        // Empty name restores reported hostname
+        let (status, body) = call(&extra_data, rename("")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"]["display_name"], Value::Null);
+
+        let (status, body) = call(
+            &extra_data,
+            test::TestRequest::patch()
+                .uri("/admin/clients/1")
+                .set_json(serde_json::json!({ "retired": true })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"]["retired"], true);
+        // Fields left out are not touched
+        assert_eq!(body["result"]["display_name"], Value::Null);
+
+        let (status, body) = call(
+            &extra_data,
+            test::TestRequest::patch()
+                .uri("/admin/clients/missing")
+                .set_json(serde_json::json!({ "display_name": "web" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["status"], i64::from(&ErrorCodes::ClientNotFound));
+    }
+
+    #[actix_rt::test]
+    async fn client_tags_and_labels() {
+        let extra_data = setup().await;
+        let patch = |client: &str, body: Value| {
+            test::TestRequest::patch()
+                .uri(&format!("/admin/clients/{}", client))
+                .set_json(body)
+        };
+        let (status, body) = call(
+            &extra_data,
+            patch(
+                "client-a",
+                serde_json::json!({
+                    "tags": [" prod ", "db", "", "prod"],
+                    "labels": {"site": "tokyo", " role ": "primary"},
+                }),
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"]["tags"], serde_json::json!(["prod", "db"]));
+        assert_eq!(
+            body["result"]["labels"],
+            serde_json::json!({"role": "primary", "site": "tokyo"})
+        );
+        // Labels are merged, `null` removes one, tags are replaced
+        let (_, body) = call(
+            &extra_data,
+            patch(
+                "client-a",
+                serde_json::json!({"tags": ["prod"], "labels": {"role": null, "owner": "alice"}}),
+            ),
+        )
+        .await;
+        assert_eq!(body["result"]["tags"], serde_json::json!(["prod"]));
+        assert_eq!(
+            body["result"]["labels"],
+            serde_json::json!({"owner": "alice", "site": "tokyo"})
+        );
+        for key in ["", "a=b"] {
+            let (status, body) = call(
+                &extra_data,
+                patch("client-a", serde_json::json!({ "labels": { key: "x" } })),
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["status"], i64::from(&ErrorCodes::InvalidParameter));
+        }
+
+        let id = extra_data
+            .storage
+            .register_client("client-b", BASE, None, BASE)
+            .await
+            .unwrap()
+            .get_id();
+        extra_data
+            .storage
+            .set_tags(id, &["staging".to_string()])
+            .await
+            .unwrap();
+        extra_data
+            .storage
+            .set_labels(id, &[("site".to_string(), "tokyo".to_string())].into())
+            .await
+            .unwrap();
+        // Patterns of filter have to match all, unknown label key matches nothing
+        let cases = [
+            ("", vec!["client-a", "client-b"]),
+            ("tag:prod", vec!["client-a"]),
+            ("site=tokyo", vec!["client-a", "client-b"]),
+            ("tag:prod,site=tokyo", vec!["client-a"]),
+            ("tag:prod,tag:staging", vec![]),
+            ("site=tok*,owner=alice", vec!["client-a"]),
+            ("rack=*", vec![]),
+            ("client-*, ,tag:staging", vec!["client-b"]),
+        ];
+        for (filter, expected) in cases {
+            let (status, body) = call(
+                &extra_data,
+                test::TestRequest::get().uri(&format!(
+                    "/admin/clients?filter={}",
+                    filter.replace(',', "%2C").replace(' ', "%20")
+                )),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+            let uuids: Vec<&str> = body["result"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|client| client["uuid"].as_str().unwrap())
+                .collect();
+            assert_eq!(uuids, expected, "{:?}", filter);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn delete_client() {
+        let extra_data = setup().await;
+        let delete = || test::TestRequest::delete().uri("/admin/clients/client-a");
+        let (status, body) = call(&extra_data, delete()).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(body, Value::Null);
+        assert!(extra_data.storage.get_client(1).await.unwrap().is_none());
+
+        let (status, body) = call(&extra_data, delete()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["status"], i64::from(&ErrorCodes::ClientNotFound));
+        let (status, _) = get(&extra_data, "/admin/clients/1/raw_data").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn uptime() {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        // Window of 1000s well before now, so open incidents are clipped at its end
+        let since = get_current_timestamp() as i64 - 100_000;
+        let until = since + 1000;
+        let cases = vec![
+            ("no incident", vec![], 0, 0),
+            ("inside window", vec![(100, Some(300))], 200, 1),
+            (
+                "overlapping",
+                vec![(100, Some(300)), (200, Some(400))],
+                300,
+                2,
+            ),
+            ("nested", vec![(100, Some(500)), (200, Some(300))], 400, 2),
+            ("started before window", vec![(-500, Some(50))], 50, 1),
+            ("open clipped at end", vec![(900, None)], 100, 1),
+            ("open before window", vec![(-500, None)], 1000, 1),
+            (
+                "outside window",
+                vec![(-500, Some(-100)), (1100, Some(1200))],
+                0,
+                0,
+            ),
+        ];
+        for (name, incidents, downtime, count) in cases {
+            let id = storage
+                .register_client(name, 0, None, until)
+                .await
+                .unwrap()
+                .get_id();
+            for (start, end) in incidents {
+                let incident = storage
+                    .open_incident(id, since + start, since + start, IncidentCause::Timeout)
+                    .await
+                    .unwrap();
+                if let Some(end) = end {
+                    storage
+                        .resolve_incident(incident, since + end, None)
+                        .await
+                        .unwrap();
+                }
+            }
+            let query: HistoryQuery =
+                serde_json::from_value(serde_json::json!({"since": since, "until": until}))
+                    .unwrap();
+            let report =
+                serde_json::to_value(compute_uptime(storage.as_ref(), id, &query).await.unwrap())
+                    .unwrap();
+            assert_eq!(report["downtime"], downtime, "{}", name);
+            assert_eq!(report["incidents"], count, "{}", name);
+            assert_eq!(
+                report["uptime_percent"].as_f64().unwrap(),
+                (1000 - downtime) as f64 / 10.0,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn uptime_window() {
+        let extra_data = setup().await;
+        let current_time = get_current_timestamp() as i64;
+        extra_data
+            .storage
+            .open_incident(1, current_time - 3600, current_time, IncidentCause::Timeout)
+            .await
+            .unwrap();
+        // Window defaults to last 30 days, and never reaches into the future
+        let (status, body) = get(
+            &extra_data,
+            &format!(
+                "/admin/clients/client-a/uptime?until={}",
+                current_time + 86400
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let until = body["result"]["until"].as_i64().unwrap();
+        assert!(until >= current_time && until < current_time + 60);
+        assert_eq!(
+            until - body["result"]["since"].as_i64().unwrap(),
+            DEFAULT_UPTIME_WINDOW
+        );
+        let downtime = body["result"]["downtime"].as_i64().unwrap();
+        assert!((3600..3660).contains(&downtime), "{}", downtime);
+    }
+
+    #[actix_rt::test]
+    async fn list_incidents() {
+        let extra_data = setup().await;
+        let storage = &extra_data.storage;
+        let other = storage
+            .register_client("client-b", BASE, Some("host-b"), BASE)
+            .await
+            .unwrap()
+            .get_id();
+        storage
+            .open_incident(1, BASE, BASE + 60, IncidentCause::Timeout)
+            .await
+            .unwrap();
+        let resolved = storage
+            .open_incident(other, BASE + 100, BASE + 160, IncidentCause::Timeout)
+            .await
+            .unwrap();
+        storage
+            .resolve_incident(resolved, BASE + 200, Some(IncidentCause::Reboot))
+            .await
+            .unwrap();
+
+        let client_ids = |body: &Value| -> Vec<i64> {
+            body["result"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|row| row["client_id"].as_i64().unwrap())
+                .collect()
+        };
+        let (status, body) = get(&extra_data, "/admin/incidents").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(client_ids(&body), vec![i64::from(other), 1]);
+        let (_, body) = get(&extra_data, "/admin/incidents?client=client-b").await;
+        assert_eq!(client_ids(&body), vec![i64::from(other)]);
+        assert_eq!(body["result"][0]["cause"], "reboot");
+        let (_, body) = get(&extra_data, "/admin/incidents?open=true").await;
+        assert_eq!(client_ids(&body), vec![1]);
+        let (_, body) = get(&extra_data, "/admin/clients/client-a/incidents").await;
+        assert_eq!(client_ids(&body), vec![1]);
+
+        let (status, body) = get(&extra_data, "/admin/incidents?client=missing").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["status"], i64::from(&ErrorCodes::ClientNotFound));
+    }
+
+    #[actix_rt::test]
+    async fn acknowledge_incident() {
+        let extra_data = setup().await;
+        let storage = &extra_data.storage;
+        let open = storage
+            .open_incident(1, BASE, BASE + 60, IncidentCause::Timeout)
+            .await
+            .unwrap();
+        let acknowledge = |id: i64| {
+            test::TestRequest::post().uri(&format!("/admin/incidents/{}/acknowledge", id))
+        };
+
+        let (status, body) = call(
+            &extra_data,
+            acknowledge(open).set_json(serde_json::json!({"by": "alice"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"]["id"], open);
+        assert_eq!(body["result"]["acknowledged_by"], "alice");
+        assert!(body["result"]["acknowledged_at"].is_i64());
+        // Acknowledged once only
+        let (status, body) = call(&extra_data, acknowledge(open)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["status"], i64::from(&ErrorCodes::InvalidParameter));
+
+        // Body is optional, resolved incident can not be acknowledged
+        storage
+            .resolve_incident(open, BASE + 90, None)
+            .await
+            .unwrap();
+        let other = storage
+            .open_incident(1, BASE + 100, BASE + 160, IncidentCause::Timeout)
+            .await
+            .unwrap();
+        let (status, body) = call(&extra_data, acknowledge(other)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"]["acknowledged_by"], "admin");
+        storage
+            .resolve_incident(other, BASE + 200, None)
+            .await
+            .unwrap();
+        let resolved = storage
+            .open_incident(1, BASE + 300, BASE + 360, IncidentCause::Timeout)
+            .await
+            .unwrap();
+        storage
+            .resolve_incident(resolved, BASE + 400, None)
+            .await
+            .unwrap();
+        let (status, body) = call(&extra_data, acknowledge(resolved)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["status"], i64::from(&ErrorCodes::InvalidParameter));
+
+        let (status, body) = call(&extra_data, acknowledge(resolved + 100)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["status"], i64::from(&ErrorCodes::InvalidParameter));
+    }
+
+    #[actix_rt::test]
+    async fn timeout_overrides() {
+        let extra_data = setup().await;
+        let put = |pattern: &str, body: Value| {
+            test::TestRequest::put()
+                .uri(&format!("/admin/timeouts/{}", pattern))
+                .set_json(body)
+        };
+
+        let (status, body) = call(
+            &extra_data,
+            put("client-*", serde_json::json!({"timeout": 60})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"][0]["tag"], "client-*");
+        assert_eq!(body["result"][0]["timeout"], 60);
+        // Overwritten, and applied by watchdog without restart
+        call(
+            &extra_data,
+            put("client-*", serde_json::json!({"timeout": 120})),
+        )
+        .await;
+        let (_, body) = get(&extra_data, "/admin/timeouts").await;
+        assert_eq!(body["result"].as_array().unwrap().len(), 1);
+        assert_eq!(body["result"][0]["timeout"], 120);
+        let client = extra_data.storage.get_client(1).await.unwrap().unwrap();
+        assert_eq!(extra_data.timeout_policy.get_timeout(&client), 120);
+
+        for timeout in [0, -1] {
+            let (status, body) = call(
+                &extra_data,
+                put("client-*", serde_json::json!({"timeout": timeout})),
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["status"], i64::from(&ErrorCodes::InvalidParameter));
+        }
+
+        let delete = || test::TestRequest::delete().uri("/admin/timeouts/client-*");
+        let (status, _) = call(&extra_data, delete()).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(extra_data.timeout_policy.get_timeout(&client), 420);
+        let (status, body) = call(&extra_data, delete()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["status"], i64::from(&ErrorCodes::InvalidParameter));
+    }
+
+    #[actix_rt::test]
+    async fn maintenance_windows() {
+        let extra_data = setup().await;
+        let create = |body: Value| {
+            test::TestRequest::post()
+                .uri("/admin/maintenance")
+                .set_json(body)
+        };
+        let (status, body) = call(
+            &extra_data,
+            create(serde_json::json!({"schedule": "0 3 * * *", "duration": 3600})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"], 1);
+        let (status, body) = call(
+            &extra_data,
+            create(serde_json::json!({"schedule": "every night", "duration": 3600})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["status"], i64::from(&ErrorCodes::InvalidParameter));
+
+        let (_, body) = get(&extra_data, "/admin/maintenance").await;
+        assert_eq!(body["result"].as_array().unwrap().len(), 1);
+        assert_eq!(body["result"][0]["schedule"], "0 3 * * *");
+
+        let delete = || test::TestRequest::delete().uri("/admin/maintenance/1");
+        let (status, _) = call(&extra_data, delete()).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, body) = call(&extra_data, delete()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["status"], i64::from(&ErrorCodes::InvalidParameter));
+    }
+}
@@ -0,0 +1,887 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::configparser::Config;
+use crate::database::AlertRow;
+use crate::metrics::Statistics;
+use crate::notifier::{ClientInfo, Event};
+use crate::storage::Storage;
+use crate::utils::parse_duration;
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use serde_derive::Serialize;
+use std::str::FromStr;
+use tokio::sync::RwLock;
+
+/// Metric of heartbeat statistics rules can watch, `*_usage` are percentages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Metric {
+    Cpu,
+    Load1,
+    Load5,
+    Load15,
+    MemoryUsage,
+    MemoryUsed,
+    SwapUsage,
+    SwapUsed,
+    DiskUsage,
+    DiskUsed,
+    DiskFree,
+    Uptime,
+}
+
+impl Metric {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "cpu" => Metric::Cpu,
+            "load1" => Metric::Load1,
+            "load5" => Metric::Load5,
+            "load15" => Metric::Load15,
+            "memory_usage" => Metric::MemoryUsage,
+            "memory_used" => Metric::MemoryUsed,
+            "swap_usage" => Metric::SwapUsage,
+            "swap_used" => Metric::SwapUsed,
+            "disk_usage" => Metric::DiskUsage,
+            "disk_used" => Metric::DiskUsed,
+            "disk_free" => Metric::DiskFree,
+            "uptime" => Metric::Uptime,
+            _ => return None,
+        })
+    }
+
+    /// Label names metric has, one series per disk for disk metrics.
+    fn get_labels(&self) -> &'static [&'static str] {
+        match self {
+            Metric::DiskUsage | Metric::DiskUsed | Metric::DiskFree => &["mount"],
+            _ => &[],
+        }
+    }
+}
+
+fn percent(used: i64, total: i64) -> Option<f64> {
+    if total > 0 {
+        Some(used as f64 * 100.0 / total as f64)
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Operator {
+    /// Longer operators go first so `>=` is not taken as `>`.
+    const ALL: &'static [(&'static str, Operator)] = &[
+        (">=", Operator::GreaterEqual),
+        ("<=", Operator::LessEqual),
+        ("==", Operator::Equal),
+        ("!=", Operator::NotEqual),
+        (">", Operator::Greater),
+        ("<", Operator::Less),
+    ];
+
+    fn compare(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Operator::Greater => value > threshold,
+            Operator::GreaterEqual => value >= threshold,
+            Operator::Less => value < threshold,
+            Operator::LessEqual => value <= threshold,
+            Operator::Equal => approx_eq(value, threshold),
+            Operator::NotEqual => !approx_eq(value, threshold),
+        }
+    }
+}
+
+/// Relative tolerance of `==` and `!=`, reported values are rounded by clients.
+const EQUAL_TOLERANCE: f64 = 1e-9;
+
+fn approx_eq(a: f64, b: f64) -> bool {
+    (a - b).abs() <= EQUAL_TOLERANCE * a.abs().max(b.abs()).max(1.0)
+}
+
+#[derive(Clone, Debug)]
+struct LabelMatcher {
+    value: String,
+    negate: bool,
+}
+
+impl LabelMatcher {
+    fn matches(&self, value: &str) -> bool {
+        (self.value == value) != self.negate
+    }
+}
+
+/// Condition of alert rule, e.g. `disk_usage{mount="/"} > 90% for 10m` or `load1 > 8`.
+///
+/// Series of metric without label matchers are all checked, e.g. `disk_usage > 90` alerts
+/// on each disk separately. Condition must hold for `duration` seconds before alert fires.
+#[derive(Clone, Debug)]
+pub struct Expression {
+    metric: Metric,
+    labels: Vec<LabelMatcher>,
+    operator: Operator,
+    threshold: f64,
+    duration: i64,
+}
+
+fn parse_labels(metric: Metric, s: &str) -> anyhow::Result<Vec<LabelMatcher>> {
+    let mut labels = Vec::new();
+    for matcher in s.split(',').map(|matcher| matcher.trim()) {
+        if matcher.is_empty() {
+            continue;
+        }
+        let (name, value, negate) = match matcher.find("!=") {
+            Some(pos) => (&matcher[..pos], &matcher[pos + 2..], true),
+            None => {
+                let pos = matcher
+                    .find('=')
+                    .ok_or_else(|| anyhow!("Invalid label matcher {:?}", matcher))?;
+                (&matcher[..pos], &matcher[pos + 1..], false)
+            }
+        };
+        let name = name.trim();
+        if !metric.get_labels().contains(&name) {
+            return Err(anyhow!("Unknown label {:?} of metric", name));
+        }
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .ok_or_else(|| anyhow!("Label value should be quoted: {}", value))?;
+        labels.push(LabelMatcher {
+            value: value.to_string(),
+            negate,
+        });
+    }
+    Ok(labels)
+}
+
+impl FromStr for Expression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let name_end = s
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(s.len());
+        let metric = Metric::from_name(&s[..name_end])
+            .ok_or_else(|| anyhow!("Unknown metric {:?}", &s[..name_end]))?;
+        let mut rest = s[name_end..].trim_start();
+        let labels = match rest.strip_prefix('{') {
+            Some(inner) => {
+                let end = inner
+                    .find('}')
+                    .ok_or_else(|| anyhow!("Unclosed label matchers"))?;
+                rest = inner[end + 1..].trim_start();
+                parse_labels(metric, &inner[..end])?
+            }
+            None => Vec::new(),
+        };
+        let (operator, rest) = Operator::ALL
+            .iter()
+            .find_map(|(token, operator)| rest.strip_prefix(token).map(|rest| (*operator, rest)))
+            .ok_or_else(|| anyhow!("Comparison operator is required"))?;
+        let tokens: Vec<&str> = rest.split_whitespace().collect();
+        let duration = match tokens.as_slice() {
+            [_] => 0,
+            [_, "for", duration] => parse_duration(duration)
+                .ok_or_else(|| anyhow!("Invalid duration {:?}", duration))?
+                as i64,
+            _ => return Err(anyhow!("Expect threshold and optional `for` duration")),
+        };
+        let threshold = tokens[0].trim_end_matches('%');
+        let threshold = threshold
+            .parse::<f64>()
+            .map_err(|_| anyhow!("Invalid threshold {:?}", threshold))?;
+        Ok(Self {
+            metric,
+            labels,
+            operator,
+            threshold,
+            duration,
+        })
+    }
+}
+
+impl Expression {
+    /// Values of series in statistics keyed by labels, `None` if metric is not reported.
+    fn get_samples(&self, statistics: &Statistics) -> Option<Vec<(String, f64)>> {
+        let single = |value: Option<f64>| value.map(|value| vec![(String::new(), value)]);
+        match self.metric {
+            Metric::Cpu => single(statistics.get_cpu()),
+            Metric::Load1 => single(
+                statistics
+                    .get_load_average()
+                    .as_ref()
+                    .map(|load| load.get_one()),
+            ),
+            Metric::Load5 => single(
+                statistics
+                    .get_load_average()
+                    .as_ref()
+                    .map(|load| load.get_five()),
+            ),
+            Metric::Load15 => single(
+                statistics
+                    .get_load_average()
+                    .as_ref()
+                    .map(|load| load.get_fifteen()),
+            ),
+            Metric::MemoryUsage => single(
+                statistics
+                    .get_memory()
+                    .as_ref()
+                    .and_then(|memory| percent(memory.get_used(), memory.get_total())),
+            ),
+            Metric::MemoryUsed => single(
+                statistics
+                    .get_memory()
+                    .as_ref()
+                    .map(|memory| memory.get_used() as f64),
+            ),
+            Metric::SwapUsage => single(
+                statistics
+                    .get_swap()
+                    .as_ref()
+                    .and_then(|swap| percent(swap.get_used(), swap.get_total())),
+            ),
+            Metric::SwapUsed => single(
+                statistics
+                    .get_swap()
+                    .as_ref()
+                    .map(|swap| swap.get_used() as f64),
+            ),
+            Metric::Uptime => single(statistics.get_uptime().map(|uptime| uptime as f64)),
+            Metric::DiskUsage | Metric::DiskUsed | Metric::DiskFree => {
+                if statistics.get_disks().is_empty() {
+                    return None;
+                }
+                Some(
+                    statistics
+                        .get_disks()
+                        .iter()
+                        .filter(|disk| {
+                            self.labels
+                                .iter()
+                                .all(|label| label.matches(disk.get_mount()))
+                        })
+                        .filter_map(|disk| {
+                            let value = match self.metric {
+                                Metric::DiskUsage => percent(disk.get_used(), disk.get_total())?,
+                                Metric::DiskUsed => disk.get_used() as f64,
+                                _ => (disk.get_total() - disk.get_used()) as f64,
+                            };
+                            Some((format!("mount=\"{}\"", disk.get_mount()), value))
+                        })
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    fn matches(&self, value: f64) -> bool {
+        self.operator.compare(value, self.threshold)
+    }
+}
+
+/// Alert rule from configure file (`[[alert]]`) or admin API.
+#[derive(Clone, Debug, Serialize)]
+pub struct AlertRule {
+    name: String,
+    expression: String,
+    /// Client id, uuid or name, `*` matches any characters, empty means all clients
+    clients: Vec<String>,
+    /// Rules in configure file can not be deleted by admin API
+    from_config: bool,
+    #[serde(skip)]
+    parsed: Expression,
+}
+
+impl AlertRule {
+    pub fn new(
+        name: &str,
+        expression: &str,
+        clients: &[String],
+        from_config: bool,
+    ) -> anyhow::Result<Self> {
+        let parsed = expression
+            .parse()
+            .map_err(|e| anyhow!("Invalid expression of alert rule {}: {}", name, e))?;
+        Ok(Self {
+            name: name.to_string(),
+            expression: expression.to_string(),
+            clients: clients.to_vec(),
+            from_config,
+            parsed,
+        })
+    }
+
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn is_from_config(&self) -> bool {
+        self.from_config
+    }
+
+    fn covers(&self, client: &ClientInfo) -> bool {
+        self.clients.is_empty() || self.clients.iter().any(|pattern| client.matches(pattern))
+    }
+
+    fn to_event(&self, client: &ClientInfo, series: &str, value: f64, firing: bool) -> Event {
+        Event::Alert {
+            client: client.clone(),
+            rule: self.name.clone(),
+            expression: self.expression.clone(),
+            series: series.to_string(),
+            value,
+            firing,
+        }
+    }
+}
+
+/// Evaluate alert rules against statistics of heartbeat, state of alerts is kept in
+/// database so pending and firing alerts survive restart.
+pub struct AlertEngine {
+    config_rules: Vec<AlertRule>,
+    rules: RwLock<Vec<AlertRule>>,
+}
+
+impl AlertEngine {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        let mut config_rules = Vec::new();
+        for rule in config.get_alert_rules() {
+            config_rules.push(AlertRule::new(
+                rule.get_name(),
+                rule.get_expression(),
+                rule.get_clients(),
+                true,
+            )?);
+        }
+        Ok(Self {
+            rules: RwLock::new(config_rules.clone()),
+            config_rules,
+        })
+    }
+
+    /// Load rules defined by admin API, alerts of removed rules are dropped.
+    pub async fn reload(&self, storage: &dyn Storage) -> anyhow::Result<()> {
+        let mut rules = self.config_rules.clone();
+        for row in storage.list_alert_rules().await? {
+            if rules.iter().any(|rule| rule.get_name() == row.get_name()) {
+                warn!(
+                    "Alert rule {} is already defined in configure file",
+                    row.get_name()
+                );
+                continue;
+            }
+            match AlertRule::new(
+                row.get_name(),
+                row.get_expression(),
+                row.get_clients(),
+                false,
+            ) {
+                Ok(rule) => rules.push(rule),
+                Err(e) => warn!("Skip alert rule: {}", e),
+            }
+        }
+        for alert in storage.list_active_alerts(None).await? {
+            if !rules.iter().any(|rule| rule.get_name() == alert.get_rule()) {
+                info!(
+                    "Drop alert {} of removed rule {}",
+                    alert.get_id(),
+                    alert.get_rule()
+                );
+                storage.delete_alert(alert.get_id()).await?;
+            }
+        }
+        *self.rules.write().await = rules;
+        Ok(())
+    }
+
+    pub async fn get_rules(&self) -> Vec<AlertRule> {
+        self.rules.read().await.clone()
+    }
+
+    /// Update alerts of client with statistics of heartbeat, return events of alerts fired or
+    /// resolved. Alerts of muted clients are tracked but not notified.
+    pub async fn evaluate(
+        &self,
+        storage: &dyn Storage,
+        id: i32,
+        statistics: &Statistics,
+        timestamp: i64,
+    ) -> anyhow::Result<Vec<Event>> {
+        let rules = self.rules.read().await;
+        if rules.is_empty() {
+            return Ok(Vec::new());
+        }
+        let client = match storage.get_client(id).await? {
+            Some(client) => client,
+            None => return Ok(Vec::new()),
+        };
+        let info = ClientInfo::from(&client);
+        let active = storage.list_active_alerts(Some(id)).await?;
+        let mut events = Vec::new();
+        for rule in rules.iter() {
+            let samples = if rule.covers(&info) {
+                match rule.parsed.get_samples(statistics) {
+                    Some(samples) => samples,
+                    // Keep state until metric is reported again
+                    None => continue,
+                }
+            } else {
+                Vec::new()
+            };
+            let alerts: Vec<&AlertRow> = active
+                .iter()
+                .filter(|alert| alert.get_rule() == rule.get_name())
+                .collect();
+            for (series, value) in samples.iter() {
+                let alert = alerts.iter().find(|alert| alert.get_series() == series);
+                if !rule.parsed.matches(*value) {
+                    if let Some(alert) = alert {
+                        if close_alert(storage, alert, *value, timestamp).await? {
+                            events.push(rule.to_event(&info, series, *value, false));
+                        }
+                    }
+                    continue;
+                }
+                match alert {
+                    None => {
+                        let fired_at = if rule.parsed.duration > 0 {
+                            None
+                        } else {
+                            Some(timestamp)
+                        };
+                        storage
+                            .insert_alert(rule.get_name(), id, series, *value, timestamp, fired_at)
+                            .await?;
+                        if fired_at.is_some() {
+                            events.push(rule.to_event(&info, series, *value, true));
+                        }
+                    }
+                    Some(alert)
+                        if alert.get_fired_at().is_none()
+                            && timestamp - alert.get_pending_since() >= rule.parsed.duration =>
+                    {
+                        storage
+                            .update_alert(alert.get_id(), *value, Some(timestamp))
+                            .await?;
+                        events.push(rule.to_event(&info, series, *value, true));
+                    }
+                    Some(alert) => storage.update_alert(alert.get_id(), *value, None).await?,
+                }
+            }
+            // Series disappeared, e.g. disk unmounted, or client is no longer covered by rule
+            for alert in alerts.iter().filter(|alert| {
+                !samples
+                    .iter()
+                    .any(|(series, _)| series == alert.get_series())
+            }) {
+                if close_alert(storage, alert, alert.get_value(), timestamp).await? {
+                    events.push(rule.to_event(&info, alert.get_series(), alert.get_value(), false));
+                }
+            }
+        }
+        if client.is_muted(timestamp) && !events.is_empty() {
+            debug!(
+                "Client {} is muted, skip {} alert notification(s)",
+                id,
+                events.len()
+            );
+            return Ok(Vec::new());
+        }
+        Ok(events)
+    }
+}
+
+/// Resolve firing alert or drop pending one, return `true` if alert was firing.
+async fn close_alert(
+    storage: &dyn Storage,
+    alert: &AlertRow,
+    value: f64,
+    timestamp: i64,
+) -> anyhow::Result<bool> {
+    if alert.get_fired_at().is_some() {
+        storage
+            .resolve_alert(alert.get_id(), value, timestamp)
+            .await?;
+        Ok(true)
+    } else {
+        storage.delete_alert(alert.get_id()).await?;
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configparser::tests::config_with;
+    use std::sync::Arc;
+
+    const BASE: i64 = 1_700_000_000;
+
+    #[test]
+    fn parse_expression() {
+        // (expression, metric, label matchers as (value, negate), operator, threshold, duration)
+        let cases = [
+            (
+                r#"disk_usage{mount="/"} > 90% for 10m"#,
+                Metric::DiskUsage,
+                vec![("/", false)],
+                Operator::Greater,
+                90.0,
+                600,
+            ),
+            (
+                "load1 > 8",
+                Metric::Load1,
+                vec![],
+                Operator::Greater,
+                8.0,
+                0,
+            ),
+            (
+                r#"disk_free { mount != "/boot" , } <= 1024 for 30s"#,
+                Metric::DiskFree,
+                vec![("/boot", true)],
+                Operator::LessEqual,
+                1024.0,
+                30,
+            ),
+            (
+                "cpu>=99.5",
+                Metric::Cpu,
+                vec![],
+                Operator::GreaterEqual,
+                99.5,
+                0,
+            ),
+            (
+                "memory_usage < 5%",
+                Metric::MemoryUsage,
+                vec![],
+                Operator::Less,
+                5.0,
+                0,
+            ),
+            (
+                "swap_used == 0 for 1h",
+                Metric::SwapUsed,
+                vec![],
+                Operator::Equal,
+                0.0,
+                3600,
+            ),
+            (
+                "uptime != 0",
+                Metric::Uptime,
+                vec![],
+                Operator::NotEqual,
+                0.0,
+                0,
+            ),
+        ];
+        for (s, metric, labels, operator, threshold, duration) in cases {
+            let expression: Expression = s.parse().unwrap();
+            assert_eq!(expression.metric, metric, "{}", s);
+            assert_eq!(
+                expression
+                    .labels
+                    .iter()
+                    .map(|label| (label.value.as_str(), label.negate))
+                    .collect::<Vec<_>>(),
+                labels,
+                "{}",
+                s
+            );
+            assert_eq!(expression.operator, operator, "{}", s);
+            assert_eq!(expression.threshold, threshold, "{}", s);
+            assert_eq!(expression.duration, duration, "{}", s);
+        }
+    }
+
+    #[test]
+    fn parse_invalid_expression() {
+        for s in [
+            "",
+            "temperature > 80",
+            "load1 8",
+            "load1 >",
+            "load1 > high",
+            "load1 > 8 for",
+            "load1 > 8 for ever",
+            "load1 > 8 during 10m",
+            r#"load1{mount="/"} > 8"#,
+            r#"disk_usage{device="sda"} > 90"#,
+            "disk_usage{mount=/} > 90",
+            r#"disk_usage{mount="/" > 90"#,
+            r#"disk_usage{mount} > 90"#,
+        ] {
+            assert!(s.parse::<Expression>().is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn compare() {
+        let cases = [
+            (Operator::Greater, 8.5, 8.0, true),
+            (Operator::Greater, 8.0, 8.0, false),
+            (Operator::GreaterEqual, 8.0, 8.0, true),
+            (Operator::Less, 7.9, 8.0, true),
+            (Operator::LessEqual, 8.1, 8.0, false),
+            // Equality tolerates rounding relative to magnitude of values
+            (Operator::Equal, 0.1 + 0.2, 0.3, true),
+            (Operator::Equal, 1e12 + 100.0, 1e12, true),
+            (Operator::Equal, 1e12 + 1e4, 1e12, false),
+            (Operator::Equal, 1.001, 1.0, false),
+            (Operator::Equal, 0.0, 0.0, true),
+            (Operator::NotEqual, 0.1 + 0.2, 0.3, false),
+            (Operator::NotEqual, 1.001, 1.0, true),
+        ];
+        for (operator, value, threshold, expected) in cases {
+            assert_eq!(
+                operator.compare(value, threshold),
+                expected,
+                "{:?} {} {}",
+                operator,
+                value,
+                threshold
+            );
+        }
+    }
+
+    fn statistics(value: serde_json::Value) -> Statistics {
+        Statistics::from_body(&value.to_string()).unwrap()
+    }
+
+    fn disks(usage: &[(&str, i64)]) -> Statistics {
+        let disks: serde_json::Map<String, serde_json::Value> = usage
+            .iter()
+            .map(|(mount, used)| {
+                (
+                    mount.to_string(),
+                    serde_json::json!({"total": 100, "used": used}),
+                )
+            })
+            .collect();
+        statistics(serde_json::json!({ "disk_usage": disks }))
+    }
+
+    /// Series and state of alert events, `true` for firing.
+    fn summary(events: &[Event]) -> Vec<(String, String, bool)> {
+        events
+            .iter()
+            .map(|event| match event {
+                Event::Alert {
+                    rule,
+                    series,
+                    firing,
+                    ..
+                } => (rule.clone(), series.clone(), *firing),
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect()
+    }
+
+    fn event(rule: &str, series: &str, firing: bool) -> (String, String, bool) {
+        (rule.to_string(), series.to_string(), firing)
+    }
+
+    async fn setup(rules: &str) -> (AlertEngine, Arc<dyn Storage>, i32) {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        let id = storage
+            .register_client("web-1", 0, Some("web-1"), BASE)
+            .await
+            .unwrap()
+            .get_id();
+        let engine = AlertEngine::new(&config_with(rules)).unwrap();
+        engine.reload(storage.as_ref()).await.unwrap();
+        (engine, storage, id)
+    }
+
+    const RULES: &str = r#"
+[[alert]]
+name = "root-disk"
+expr = 'disk_usage{mount="/"} > 90% for 10m'
+
+[[alert]]
+name = "high-load"
+expr = "load1 > 8"
+"#;
+
+    #[actix_rt::test]
+    async fn pending_firing_and_resolved() {
+        let (engine, storage, id) = setup(RULES).await;
+        let evaluate = |statistics: Statistics, timestamp: i64| {
+            let engine = &engine;
+            let storage = storage.clone();
+            async move {
+                summary(
+                    &engine
+                        .evaluate(storage.as_ref(), id, &statistics, timestamp)
+                        .await
+                        .unwrap(),
+                )
+            }
+        };
+        let root = r#"mount="/""#;
+
+        // Rule without `for` fires at once, the other one is pending
+        let full = statistics(serde_json::json!({
+            "load_avg": [9.0, 4.0, 2.0],
+            "disk_usage": {"/": {"total": 100, "used": 95}, "/data": {"total": 100, "used": 99}},
+        }));
+        assert_eq!(
+            evaluate(full.clone(), BASE).await,
+            vec![event("high-load", "", true)]
+        );
+        let alerts = storage.list_active_alerts(Some(id)).await.unwrap();
+        assert_eq!(alerts.len(), 2);
+        assert!(alerts
+            .iter()
+            .any(|alert| alert.get_series() == root && alert.get_fired_at().is_none()));
+        assert!(evaluate(full.clone(), BASE + 599).await.is_empty());
+        assert_eq!(
+            evaluate(full.clone(), BASE + 600).await,
+            vec![event("root-disk", root, true)]
+        );
+        // Firing alerts are not notified again
+        assert!(evaluate(full, BASE + 660).await.is_empty());
+
+        // Metric left out of heartbeat keeps state, disk below threshold resolves
+        assert_eq!(
+            evaluate(disks(&[("/", 50)]), BASE + 720).await,
+            vec![event("root-disk", root, false)]
+        );
+        assert_eq!(
+            evaluate(
+                statistics(serde_json::json!({"load_avg": [1.0, 4.0, 2.0]})),
+                BASE + 780
+            )
+            .await,
+            vec![event("high-load", "", false)]
+        );
+        assert!(storage
+            .list_active_alerts(Some(id))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Pending alert is dropped silently once condition no longer holds
+        assert!(evaluate(disks(&[("/", 95)]), BASE + 800).await.is_empty());
+        assert_eq!(storage.list_active_alerts(None).await.unwrap().len(), 1);
+        assert!(evaluate(disks(&[("/", 80)]), BASE + 900).await.is_empty());
+        assert!(storage.list_active_alerts(None).await.unwrap().is_empty());
+        let history = storage
+            .query_alerts(id, &crate::structs::HistoryQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn series_disappearance() {
+        let (engine, storage, id) = setup(
+            r#"
+[[alert]]
+name = "disk-full"
+expr = "disk_usage > 90"
+"#,
+        )
+        .await;
+        let events = engine
+            .evaluate(
+                storage.as_ref(),
+                id,
+                &disks(&[("/", 95), ("/data", 95), ("/tmp", 10)]),
+                BASE,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            summary(&events),
+            vec![
+                event("disk-full", r#"mount="/""#, true),
+                event("disk-full", r#"mount="/data""#, true)
+            ]
+        );
+        // Unmounted disk resolves with its last value
+        let events = engine
+            .evaluate(storage.as_ref(), id, &disks(&[("/", 95)]), BASE + 60)
+            .await
+            .unwrap();
+        assert_eq!(
+            summary(&events),
+            vec![event("disk-full", r#"mount="/data""#, false)]
+        );
+        match &events[0] {
+            Event::Alert { value, .. } => assert_eq!(*value, 95.0),
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        // Client muted meanwhile still gets its alerts tracked
+        storage
+            .set_muted_until(id, Some(BASE + 3600))
+            .await
+            .unwrap();
+        assert!(engine
+            .evaluate(storage.as_ref(), id, &disks(&[("/", 10)]), BASE + 120)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage.list_active_alerts(None).await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn state_survives_restart() {
+        let (engine, storage, id) = setup(RULES).await;
+        engine
+            .evaluate(storage.as_ref(), id, &disks(&[("/", 95)]), BASE)
+            .await
+            .unwrap();
+        drop(engine);
+
+        // Pending since is kept in database, so `for` is not restarted
+        let engine = AlertEngine::new(&config_with(RULES)).unwrap();
+        engine.reload(storage.as_ref()).await.unwrap();
+        assert_eq!(
+            summary(
+                &engine
+                    .evaluate(storage.as_ref(), id, &disks(&[("/", 95)]), BASE + 600)
+                    .await
+                    .unwrap()
+            ),
+            vec![event("root-disk", r#"mount="/""#, true)]
+        );
+
+        // Alerts of rules removed from configure file are dropped on reload
+        let engine = AlertEngine::new(&config_with("")).unwrap();
+        engine.reload(storage.as_ref()).await.unwrap();
+        assert!(storage.list_active_alerts(None).await.unwrap().is_empty());
+    }
+}
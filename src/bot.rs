@@ -0,0 +1,699 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::clienttimeout::TimeoutPolicy;
+use crate::database::ClientRow;
+use crate::get_current_timestamp;
+use crate::maintenance::{self, MaintenanceSet};
+use crate::notifier::ACKNOWLEDGE_CALLBACK_PREFIX;
+use crate::storage::Storage;
+use crate::structs::{HistoryQuery, MaintenanceRequest};
+use crate::utils::{format_duration, format_timestamp, html_escape, parse_duration};
+use chrono_tz::Tz;
+use log::{debug, error, info};
+use std::sync::Arc;
+use teloxide::adaptors::DefaultParseMode;
+use teloxide::prelude::*;
+use teloxide::types::{CallbackQuery, ParseMode};
+use teloxide::utils::command::BotCommands as _;
+
+pub type BotType = DefaultParseMode<Bot>;
+
+pub fn create_bot(bot_token: &str, api_server: &Option<String>) -> anyhow::Result<BotType> {
+    let bot = Bot::new(bot_token);
+    let bot = match api_server {
+        Some(api) => bot.set_api_url(api.parse()?),
+        None => bot,
+    };
+    Ok(bot.parse_mode(ParseMode::Html))
+}
+
+#[derive(teloxide::macros::BotCommands, Clone, Debug)]
+#[command(rename_rule = "lowercase", description = "Supported commands:")]
+enum BotCommands {
+    #[command(description = "show this message.")]
+    Help,
+    #[command(description = "list online clients.")]
+    Online,
+    #[command(description = "list offline clients.")]
+    Offline,
+    #[command(description = "show status of client, e.g. /status web-1")]
+    Status(String),
+    #[command(
+        description = "mute notifications of client, e.g. /mute web-1 2h",
+        parse_with = "split"
+    )]
+    Mute(String, String),
+    #[command(description = "unmute notifications of client.")]
+    Unmute(String),
+    #[command(description = "show last reported data of client.")]
+    Last(String),
+    #[command(description = "list maintenance windows.")]
+    Maintenance,
+    #[command(
+        description = "start maintenance of clients now, e.g. /maintain web-*,db-1 2h or /maintain * 30m",
+        parse_with = "split"
+    )]
+    Maintain(String, String),
+    #[command(description = "end maintenance window, e.g. /endmaintenance 3")]
+    EndMaintenance(String),
+}
+
+/// Find client by id, uuid, display name or hostname.
+async fn find_client(storage: &dyn Storage, keyword: &str) -> anyhow::Result<Option<ClientRow>> {
+    let keyword = keyword.trim();
+    if let Ok(id) = keyword.parse() {
+        if let Some(client) = storage.get_client(id).await? {
+            return Ok(Some(client));
+        }
+    }
+    Ok(storage.list_clients().await?.into_iter().find(|client| {
+        client.get_uuid().eq(keyword)
+            || client.get_display_name().as_deref() == Some(keyword)
+            || client.get_hostname().as_deref() == Some(keyword)
+    }))
+}
+
+fn format_client_line(client: &ClientRow, current_time: i64) -> String {
+    format!(
+        "<b>{}</b> ({}) last seen {} ago{}",
+        html_escape(&client.get_name()),
+        client.get_id(),
+        format_duration((current_time - client.get_last_seen()).max(0) as u64),
+        if client.is_muted(current_time) {
+            " [muted]"
+        } else {
+            ""
+        }
+    )
+}
+
+fn format_client_metadata(client: &ClientRow, parent: Option<&ClientRow>) -> String {
+    let mut text = String::new();
+    if let Some(parent) = parent {
+        text.push_str(&format!("\nDepends on: {}", html_escape(&parent.get_name())));
+    }
+    if !client.get_tags().is_empty() {
+        text.push_str(&format!("\nTags: {}", html_escape(&client.get_tags().join(", "))));
+    }
+    for (key, value) in client.get_labels() {
+        text.push_str(&format!("\n{}: {}", html_escape(key), html_escape(value)));
+    }
+    text
+}
+
+fn format_maintenance_line(
+    maintenance: &MaintenanceSet,
+    window: &maintenance::Maintenance,
+    timezone: &Tz,
+    current_time: i64,
+) -> String {
+    let row = window.get_row();
+    let period = match (row.get_schedule(), row.get_duration()) {
+        (Some(schedule), Some(duration)) => format!(
+            "<code>{}</code> for {}",
+            html_escape(schedule),
+            format_duration(duration as u64)
+        ),
+        _ => format!(
+            "{} - {}",
+            format_timestamp(row.get_starts_at().unwrap_or_default(), timezone),
+            format_timestamp(row.get_ends_at().unwrap_or_default(), timezone)
+        ),
+    };
+    format!(
+        "#{} {}{}: {}{}",
+        row.get_id(),
+        if row.get_clients().is_empty() {
+            "all clients".to_string()
+        } else {
+            html_escape(&row.get_clients().join(", "))
+        },
+        if row.get_description().is_empty() {
+            "".to_string()
+        } else {
+            format!(" ({})", html_escape(row.get_description()))
+        },
+        period,
+        if maintenance.is_active(window, current_time) {
+            " [active]"
+        } else {
+            ""
+        }
+    )
+}
+
+async fn answer_command(
+    storage: &dyn Storage,
+    policy: &TimeoutPolicy,
+    timezone: &Tz,
+    command: BotCommands,
+) -> anyhow::Result<String> {
+    let current_time = get_current_timestamp() as i64;
+    let not_found = |keyword: &str| format!("Client <code>{}</code> not found", html_escape(keyword));
+    Ok(match command {
+        BotCommands::Help => BotCommands::descriptions().to_string(),
+        BotCommands::Online => {
+            let lines: Vec<String> = storage
+                .list_clients()
+                .await?
+                .into_iter()
+                .filter(|client| !client.get_retired() && policy.is_online(client, current_time))
+                .map(|client| format_client_line(&client, current_time))
+                .collect();
+            format!("Online clients ({}):\n{}", lines.len(), lines.join("\n"))
+        }
+        BotCommands::Offline => {
+            let lines: Vec<String> = storage
+                .list_clients()
+                .await?
+                .into_iter()
+                .filter(|client| !client.get_retired() && !policy.is_online(client, current_time))
+                .map(|client| format_client_line(&client, current_time))
+                .collect();
+            format!("Offline clients ({}):\n{}", lines.len(), lines.join("\n"))
+        }
+        BotCommands::Status(keyword) => match find_client(storage, &keyword).await? {
+            Some(client) => {
+                let parent = match client.get_parent_id() {
+                    Some(parent_id) => storage.get_client(parent_id).await?,
+                    None => None,
+                };
+                format!(
+                    "<b>{}</b>\nID: {}\nUUID: <code>{}</code>\nHostname: {}\nStatus: {}\nLast seen: {} ago\nTimeout: {}\nUp for: {}{}{}",
+                    html_escape(&client.get_name()),
+                    client.get_id(),
+                    client.get_uuid(),
+                    html_escape(client.get_hostname().as_deref().unwrap_or_default()),
+                    if client.get_retired() {
+                        "retired"
+                    } else if policy.is_online(&client, current_time) {
+                        "online"
+                    } else {
+                        "offline"
+                    },
+                    format_duration((current_time - client.get_last_seen()).max(0) as u64),
+                    format_duration(policy.get_timeout(&client) as u64),
+                    format_duration((current_time - client.get_boot_time()).max(0) as u64),
+                    match client.get_muted_until() {
+                        Some(until) if until > current_time => format!(
+                            "\nMuted for: {}",
+                            format_duration((until - current_time) as u64)
+                        ),
+                        _ => "".to_string(),
+                    },
+                    format_client_metadata(&client, parent.as_ref())
+                )
+            }
+            None => not_found(&keyword),
+        },
+        BotCommands::Mute(keyword, duration) => {
+            let duration = match parse_duration(&duration) {
+                Some(duration) if duration > 0 => duration,
+                _ => return Ok(format!("Invalid duration: <code>{}</code>", html_escape(&duration))),
+            };
+            match find_client(storage, &keyword).await? {
+                Some(client) => {
+                    storage
+                        .set_muted_until(client.get_id(), Some(current_time + duration as i64))
+                        .await?;
+                    format!(
+                        "<b>{}</b> muted for {}",
+                        html_escape(&client.get_name()),
+                        format_duration(duration)
+                    )
+                }
+                None => not_found(&keyword),
+            }
+        }
+        BotCommands::Unmute(keyword) => match find_client(storage, &keyword).await? {
+            Some(client) => {
+                storage.set_muted_until(client.get_id(), None).await?;
+                format!("<b>{}</b> unmuted", html_escape(&client.get_name()))
+            }
+            None => not_found(&keyword),
+        },
+        BotCommands::Last(keyword) => match find_client(storage, &keyword).await? {
+            Some(client) => {
+                let query = HistoryQuery::new_latest(1);
+                let mut text = format!(
+                    "<b>{}</b> last seen {} ago",
+                    html_escape(&client.get_name()),
+                    format_duration((current_time - client.get_last_seen()).max(0) as u64)
+                );
+                if let Some(metrics) = storage
+                    .query_metrics(client.get_id(), &query)
+                    .await?
+                    .pop()
+                {
+                    text.push_str(&format!(
+                        "\nMetrics:\n<pre>{}</pre>",
+                        html_escape(&serde_json::to_string_pretty(&metrics)?)
+                    ));
+                }
+                if let Some(raw_data) = storage
+                    .query_raw_data(client.get_id(), &query)
+                    .await?
+                    .pop()
+                {
+                    text.push_str(&format!(
+                        "\nRaw data:\n<pre>{}</pre>",
+                        html_escape(&serde_json::to_string_pretty(&raw_data)?)
+                    ));
+                }
+                text
+            }
+            None => not_found(&keyword),
+        },
+        BotCommands::Maintenance => {
+            let maintenance = MaintenanceSet::load(storage, *timezone).await?;
+            let lines: Vec<String> = maintenance
+                .get_windows()
+                .iter()
+                .map(|window| format_maintenance_line(&maintenance, window, timezone, current_time))
+                .collect();
+            format!("Maintenance windows ({}):\n{}", lines.len(), lines.join("\n"))
+        }
+        BotCommands::Maintain(clients, duration) => {
+            let duration = match parse_duration(&duration) {
+                Some(duration) if duration > 0 => duration,
+                _ => return Ok(format!("Invalid duration: <code>{}</code>", html_escape(&duration))),
+            };
+            let clients: Vec<String> = clients
+                .split(',')
+                .map(|pattern| pattern.trim())
+                .filter(|pattern| !pattern.is_empty() && *pattern != "*" && *pattern != "all")
+                .map(|pattern| pattern.to_string())
+                .collect();
+            let id = storage
+                .insert_maintenance(
+                    &MaintenanceRequest::new_once(
+                        "started from Telegram".to_string(),
+                        clients,
+                        current_time,
+                        current_time + duration as i64,
+                    ),
+                    current_time,
+                )
+                .await?;
+            format!(
+                "Maintenance window #{} started, ends in {}",
+                id,
+                format_duration(duration)
+            )
+        }
+        BotCommands::EndMaintenance(id) => match id.trim().parse() {
+            Ok(id) => {
+                if maintenance::remove_window(storage, id, current_time).await? {
+                    format!("Maintenance window #{} ended", id)
+                } else {
+                    format!("Maintenance window #{} not found", id)
+                }
+            }
+            Err(_) => format!("Invalid id: <code>{}</code>", html_escape(&id)),
+        },
+    })
+}
+
+/// Acknowledge incident by data of inline button, return whether it is acknowledged now and
+/// answer to user.
+async fn answer_acknowledge(
+    storage: &dyn Storage,
+    data: &str,
+    by: &str,
+) -> anyhow::Result<(bool, String)> {
+    let id: i64 = match data
+        .strip_prefix(ACKNOWLEDGE_CALLBACK_PREFIX)
+        .and_then(|id| id.parse().ok())
+    {
+        Some(id) => id,
+        None => return Ok((false, "Unknown button".to_string())),
+    };
+    let incident = match storage.get_incident(id).await? {
+        Some(incident) => incident,
+        None => return Ok((false, format!("Incident #{} not found", id))),
+    };
+    if incident.get_resolved_at().is_some() {
+        return Ok((false, "Client is back online already".to_string()));
+    }
+    if let Some(by) = incident.get_acknowledged_by() {
+        return Ok((false, format!("Acknowledged by {} already", by)));
+    }
+    storage
+        .acknowledge_incident(id, by, get_current_timestamp() as i64)
+        .await?;
+    let name = storage
+        .get_client(incident.get_client_id())
+        .await?
+        .map(|client| client.get_name())
+        .unwrap_or_default();
+    Ok((true, format!("Incident of {} acknowledged", name)))
+}
+
+/// Shared state of bot handlers.
+struct BotContext {
+    owner: i64,
+    /// Chats acknowledge buttons are accepted from
+    chats: Vec<i64>,
+    storage: Arc<dyn Storage>,
+    policy: TimeoutPolicy,
+    timezone: Tz,
+}
+
+async fn handle_command(
+    bot: BotType,
+    message: Message,
+    command: BotCommands,
+    context: Arc<BotContext>,
+) -> ResponseResult<()> {
+    if message.chat.id.0 != context.owner {
+        debug!("Ignore command from chat {}", message.chat.id);
+        return Ok(());
+    }
+    let text = match answer_command(
+        context.storage.as_ref(),
+        &context.policy,
+        &context.timezone,
+        command,
+    )
+    .await
+    {
+        Ok(text) => text,
+        Err(e) => {
+            error!("Got error while answering command: {:?}", e);
+            format!("Error: {}", html_escape(&e.to_string()))
+        }
+    };
+    bot.send_message(message.chat.id, text).send().await?;
+    Ok(())
+}
+
+async fn handle_callback_query(
+    bot: BotType,
+    query: CallbackQuery,
+    context: Arc<BotContext>,
+) -> ResponseResult<()> {
+    let chat_id = match query.message.as_ref().map(|message| message.chat.id) {
+        Some(chat_id) if chat_id.0 == context.owner || context.chats.contains(&chat_id.0) => {
+            chat_id
+        }
+        _ => {
+            debug!("Ignore callback query from user {}", query.from.id);
+            return Ok(());
+        }
+    };
+    let by = match &query.from.username {
+        Some(username) => format!("@{}", username),
+        None => query.from.first_name.clone(),
+    };
+    let (acknowledged, answer) = match answer_acknowledge(
+        context.storage.as_ref(),
+        query.data.as_deref().unwrap_or_default(),
+        &by,
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(e) => {
+            error!("Got error while acknowledging incident: {:?}", e);
+            (false, format!("Error: {}", e))
+        }
+    };
+    bot.answer_callback_query(query.id)
+        .text(answer.clone())
+        .send()
+        .await?;
+    if acknowledged {
+        bot.send_message(
+            chat_id,
+            format!("{} by {}", html_escape(&answer), html_escape(&by)),
+        )
+        .send()
+        .await?;
+    }
+    Ok(())
+}
+
+/// Answer commands sent by owner, messages from other chats are ignored. Acknowledge buttons
+/// are accepted from owner and every telegram recipient in `chats`.
+pub async fn command_daemon(
+    bot: BotType,
+    owner: i64,
+    chats: Vec<i64>,
+    storage: Arc<dyn Storage>,
+    policy: TimeoutPolicy,
+    timezone: Tz,
+) -> anyhow::Result<()> {
+    let me = bot.get_me().send().await?;
+    info!(
+        "Bot command daemon started as @{}",
+        me.user.username.clone().unwrap_or_default()
+    );
+    let context = Arc::new(BotContext {
+        owner,
+        chats,
+        storage,
+        policy,
+        timezone,
+    });
+    let handler = dptree::entry()
+        .branch(
+            Update::filter_message()
+                .filter_command::<BotCommands>()
+                .endpoint(handle_command),
+        )
+        .branch(Update::filter_callback_query().endpoint(handle_callback_query));
+    Dispatcher::builder(bot, handler)
+        .dependencies(dptree::deps![context])
+        .default_handler(|_| async {})
+        .build()
+        .dispatch()
+        .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configparser::tests::config_with;
+    use crate::metrics::Statistics;
+
+    /// Storage with online client `web-1` and client `db-1` which went offline an hour ago.
+    async fn setup() -> (Arc<dyn Storage>, i64) {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        let current_time = get_current_timestamp() as i64;
+        let id = storage
+            .register_client("client-a", current_time - 7200, Some("web-1"), current_time)
+            .await
+            .unwrap()
+            .get_id();
+        storage
+            .register_client(
+                "client-b",
+                current_time - 7200,
+                Some("db-1"),
+                current_time - 3600,
+            )
+            .await
+            .unwrap();
+        storage
+            .insert_raw_data(id, "<raw>", current_time)
+            .await
+            .unwrap();
+        let statistics = Statistics::from_body(r#"{"cpu_percent": 12.5}"#).unwrap();
+        storage
+            .insert_metrics(id, &statistics, current_time)
+            .await
+            .unwrap();
+        (storage, current_time)
+    }
+
+    async fn answer(storage: &Arc<dyn Storage>, command: &str) -> String {
+        let command = BotCommands::parse(command, "probe_bot").unwrap();
+        let policy = TimeoutPolicy::from(&config_with(""));
+        answer_command(storage.as_ref(), &policy, &Tz::UTC, command)
+            .await
+            .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn online_and_offline() {
+        let (storage, _) = setup().await;
+        let text = answer(&storage, "/online").await;
+        assert!(
+            text.starts_with("Online clients (1):\n<b>web-1</b> (1)"),
+            "{}",
+            text
+        );
+        let text = answer(&storage, "/offline").await;
+        assert!(
+            text.starts_with("Offline clients (1):\n<b>db-1</b> (2) last seen 1h"),
+            "{}",
+            text
+        );
+        // Retired clients are left out of both lists
+        storage.set_retired(2, true).await.unwrap();
+        assert_eq!(answer(&storage, "/offline").await, "Offline clients (0):\n");
+    }
+
+    #[actix_rt::test]
+    async fn status() {
+        let (storage, _) = setup().await;
+        // Clients are found by id, uuid and hostname
+        for keyword in ["1", "client-a", "web-1"] {
+            let text = answer(&storage, &format!("/status {}", keyword)).await;
+            assert!(text.starts_with("<b>web-1</b>\nID: 1\n"), "{}", text);
+            assert!(text.contains("Status: online\n"), "{}", text);
+            assert!(text.contains("Up for: 2h"), "{}", text);
+        }
+        let text = answer(&storage, "/status db-1").await;
+        assert!(text.contains("Status: offline\n"), "{}", text);
+        assert_eq!(
+            answer(&storage, "/status <nope>").await,
+            "Client <code>&lt;nope&gt;</code> not found"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn mute() {
+        let (storage, current_time) = setup().await;
+        for duration in ["soon", "0m"] {
+            assert_eq!(
+                answer(&storage, &format!("/mute web-1 {}", duration)).await,
+                format!("Invalid duration: <code>{}</code>", duration)
+            );
+        }
+        assert!(!storage
+            .get_client(1)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_muted(current_time));
+
+        assert_eq!(
+            answer(&storage, "/mute web-1 2h").await,
+            "<b>web-1</b> muted for 2h"
+        );
+        let client = storage.get_client(1).await.unwrap().unwrap();
+        assert!(client.is_muted(current_time + 7000));
+        assert!(!client.is_muted(current_time + 7300));
+        assert!(answer(&storage, "/online").await.ends_with(" [muted]"));
+        assert!(answer(&storage, "/status 1")
+            .await
+            .contains("\nMuted for: "));
+
+        assert_eq!(
+            answer(&storage, "/unmute web-1").await,
+            "<b>web-1</b> unmuted"
+        );
+        assert_eq!(
+            storage
+                .get_client(1)
+                .await
+                .unwrap()
+                .unwrap()
+                .get_muted_until(),
+            None
+        );
+    }
+
+    #[actix_rt::test]
+    async fn last() {
+        let (storage, _) = setup().await;
+        let text = answer(&storage, "/last web-1").await;
+        assert!(text.starts_with("<b>web-1</b> last seen "), "{}", text);
+        assert!(text.contains("\nMetrics:\n<pre>"), "{}", text);
+        assert!(text.contains("12.5"), "{}", text);
+        assert!(text.contains("\nRaw data:\n<pre>"), "{}", text);
+        assert!(text.contains("&lt;raw&gt;"), "{}", text);
+        // Client without reports only shows when it was last seen
+        let text = answer(&storage, "/last db-1").await;
+        assert!(text.starts_with("<b>db-1</b> last seen 1h"), "{}", text);
+        assert!(!text.contains("<pre>"), "{}", text);
+    }
+
+    #[actix_rt::test]
+    async fn maintenance() {
+        let (storage, current_time) = setup().await;
+        assert_eq!(
+            answer(&storage, "/maintain web-*,db-1 2h").await,
+            "Maintenance window #1 started, ends in 2h"
+        );
+        assert_eq!(
+            answer(&storage, "/maintain * 30m").await,
+            "Maintenance window #2 started, ends in 30m"
+        );
+        assert_eq!(
+            answer(&storage, "/maintain web-1 soon").await,
+            "Invalid duration: <code>soon</code>"
+        );
+        let rows = storage.list_maintenance().await.unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get_clients(), &vec!["web-*", "db-1"]);
+        assert_eq!(
+            (rows[0].get_starts_at(), rows[0].get_ends_at()),
+            (Some(current_time), Some(current_time + 7200))
+        );
+        // `*` applies to all clients
+        assert!(rows[1].get_clients().is_empty());
+
+        storage
+            .insert_maintenance(
+                &serde_json::from_value(serde_json::json!({
+                    "description": "<backup>",
+                    "schedule": "0 3 * * *",
+                    "duration": 3600,
+                }))
+                .unwrap(),
+                current_time,
+            )
+            .await
+            .unwrap();
+        let text = answer(&storage, "/maintenance").await;
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", text);
+        assert_eq!(lines[0], "Maintenance windows (3):");
+        assert!(
+            lines[1].starts_with("#1 web-*, db-1 (started from Telegram): ")
+                && lines[1].ends_with(" [active]"),
+            "{}",
+            lines[1]
+        );
+        assert!(lines[2].starts_with("#2 all clients ("), "{}", lines[2]);
+        assert!(
+            lines[3].starts_with("#3 all clients (&lt;backup&gt;): <code>0 3 * * *</code> for 1h"),
+            "{}",
+            lines[3]
+        );
+
+        assert_eq!(
+            answer(&storage, "/endmaintenance 2").await,
+            "Maintenance window #2 ended"
+        );
+        assert_eq!(storage.list_maintenance().await.unwrap().len(), 2);
+        assert_eq!(
+            answer(&storage, "/endmaintenance 2").await,
+            "Maintenance window #2 not found"
+        );
+        assert_eq!(
+            answer(&storage, "/endmaintenance two").await,
+            "Invalid id: <code>two</code>"
+        );
+    }
+}
@@ -0,0 +1,183 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::configparser::{Config, TimeoutGroup};
+use crate::database::{ClientRow, TimeoutOverrideRow};
+use crate::notifier::ClientInfo;
+use crate::storage::Storage;
+use std::sync::{Arc, RwLock};
+
+/// Heartbeat timeout of clients, built from `[watchdog]` section of configure file.
+///
+/// Timeout of client is resolved in order: timeout of client set by admin API, first matched
+/// override set by admin API, first matched group, reported heartbeat interval multiplied by
+/// `interval_multiplier`, default timeout.
+///
+/// Overrides are shared between clones, so reloading them is seen by watchdog and bot.
+#[derive(Clone, Debug)]
+pub struct TimeoutPolicy {
+    timeout: i64,
+    interval_multiplier: i64,
+    groups: Vec<TimeoutGroup>,
+    overrides: Arc<RwLock<Vec<TimeoutOverrideRow>>>,
+}
+
+impl TimeoutPolicy {
+    /// Reload overrides after they are changed by admin API.
+    pub async fn load_overrides(&self, storage: &dyn Storage) -> anyhow::Result<()> {
+        let overrides = storage.list_timeout_overrides().await?;
+        *self.overrides.write().unwrap() = overrides;
+        Ok(())
+    }
+
+    pub fn get_timeout(&self, client: &ClientRow) -> i64 {
+        if let Some(timeout) = client.get_timeout() {
+            return timeout;
+        }
+        let info = ClientInfo::from(client);
+        if let Some(row) = self
+            .overrides
+            .read()
+            .unwrap()
+            .iter()
+            .find(|row| info.matches(row.get_tag()))
+        {
+            return row.get_timeout();
+        }
+        if !self.groups.is_empty() {
+            if let Some(group) = self.groups.iter().find(|group| {
+                group
+                    .get_clients()
+                    .iter()
+                    .any(|pattern| info.matches(pattern))
+            }) {
+                return group.get_timeout();
+            }
+        }
+        match client.get_heartbeat_interval() {
+            Some(interval) if interval > 0 => interval * self.interval_multiplier,
+            _ => self.timeout,
+        }
+    }
+
+    /// Whether client has sent heartbeat within its timeout.
+    pub fn is_online(&self, client: &ClientRow, timestamp: i64) -> bool {
+        timestamp - client.get_last_seen() <= self.get_timeout(client)
+    }
+}
+
+impl From<&Config> for TimeoutPolicy {
+    fn from(cfg: &Config) -> Self {
+        let watchdog = cfg.get_watchdog();
+        Self {
+            timeout: watchdog.get_timeout(),
+            interval_multiplier: watchdog.get_interval_multiplier(),
+            groups: watchdog.get_groups().to_vec(),
+            overrides: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configparser::tests::config_with;
+
+    const CONFIG: &str = r#"
+[watchdog]
+timeout = 600
+interval_multiplier = 4
+
+[[watchdog.group]]
+clients = ["db-*"]
+timeout = 900
+"#;
+
+    #[actix_rt::test]
+    async fn resolution_order() {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        let policy = TimeoutPolicy::from(&config_with(CONFIG));
+        storage.set_timeout_override("db-2", 1200).await.unwrap();
+        storage.set_timeout_override("web-3", 1500).await.unwrap();
+        policy.load_overrides(storage.as_ref()).await.unwrap();
+
+        // (uuid, client timeout, advertised interval, expected)
+        let cases = [
+            ("web-1", None, None, 600),
+            ("web-1", None, Some(30), 120),
+            ("web-1", None, Some(0), 600),
+            ("db-1", None, Some(30), 900),
+            ("db-2", None, Some(30), 1200),
+            ("web-3", None, None, 1500),
+            ("db-2", Some(60), Some(30), 60),
+            ("web-1", Some(60), None, 60),
+        ];
+        for (uuid, timeout, interval, expected) in cases {
+            let id = match storage.get_client_by_uuid(uuid).await.unwrap() {
+                Some(client) => client.get_id(),
+                None => storage
+                    .register_client(uuid, 0, None, 0)
+                    .await
+                    .unwrap()
+                    .get_id(),
+            };
+            storage.set_timeout(id, timeout).await.unwrap();
+            storage.set_heartbeat_interval(id, interval).await.unwrap();
+            let client = storage.get_client(id).await.unwrap().unwrap();
+            assert_eq!(
+                policy.get_timeout(&client),
+                expected,
+                "{} {:?} {:?}",
+                uuid,
+                timeout,
+                interval
+            );
+            assert!(policy.is_online(&client, expected));
+            assert!(!policy.is_online(&client, expected + 1));
+        }
+
+        // Removed override falls back to group, clones see the reload
+        let cloned = policy.clone();
+        storage.delete_timeout_override("db-2").await.unwrap();
+        policy.load_overrides(storage.as_ref()).await.unwrap();
+        let id = storage
+            .get_client_by_uuid("db-2")
+            .await
+            .unwrap()
+            .unwrap()
+            .get_id();
+        storage.set_timeout(id, None).await.unwrap();
+        let client = storage.get_client(id).await.unwrap().unwrap();
+        assert_eq!(cloned.get_timeout(&client), 900);
+
+        // Override of tag applies to every client carrying it
+        storage
+            .set_timeout_override("tag:slow*", 1800)
+            .await
+            .unwrap();
+        policy.load_overrides(storage.as_ref()).await.unwrap();
+        assert_eq!(policy.get_timeout(&client), 900);
+        storage
+            .set_tags(id, &["slow-link".to_string()])
+            .await
+            .unwrap();
+        let client = storage.get_client(id).await.unwrap().unwrap();
+        assert_eq!(policy.get_timeout(&client), 1800);
+    }
+}
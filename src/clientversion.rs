@@ -0,0 +1,214 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::configparser::Config;
+use semver::{BuildMetadata, Version};
+
+pub const MINIMUM_CLIENT_VERSION: &str = "1.6.1";
+
+/// Accepted client versions, built from `[client_version]` section of configure file.
+#[derive(Clone, Debug)]
+pub struct VersionPolicy {
+    minimum: Version,
+    maximum: Option<Version>,
+    deny: Vec<Version>,
+}
+
+// Build metadata should not affect precedence, so it is dropped before compare
+fn parse_version(s: &str) -> Result<Version, semver::Error> {
+    let mut version = Version::parse(s.trim().trim_start_matches('v'))?;
+    version.build = BuildMetadata::EMPTY;
+    Ok(version)
+}
+
+impl VersionPolicy {
+    pub fn new(
+        minimum: Option<&String>,
+        maximum: Option<&String>,
+        deny: Option<&Vec<String>>,
+    ) -> anyhow::Result<Self> {
+        let minimum = parse_version(
+            minimum
+                .map(|s| s.as_str())
+                .unwrap_or(MINIMUM_CLIENT_VERSION),
+        )?;
+        let maximum = match maximum {
+            Some(s) => Some(parse_version(s)?),
+            None => None,
+        };
+        let mut deny_list = Vec::new();
+        for s in deny.into_iter().flatten() {
+            deny_list.push(parse_version(s)?);
+        }
+        Ok(Self {
+            minimum,
+            maximum,
+            deny: deny_list,
+        })
+    }
+
+    /// Check client version, return reason if the version is not accepted.
+    pub fn check(&self, version: &str) -> Result<(), String> {
+        let version = match parse_version(version) {
+            Ok(version) => version,
+            Err(e) => {
+                return Err(format!(
+                    "Unable to parse client version {:?} ({}), require version >= {}",
+                    version, e, self.minimum
+                ))
+            }
+        };
+        if version < self.minimum {
+            return Err(format!(
+                "Client version {} is smaller than required version {}",
+                version, self.minimum
+            ));
+        }
+        if let Some(ref maximum) = self.maximum {
+            if &version > maximum {
+                return Err(format!(
+                    "Client version {} is greater than maximum supported version {}",
+                    version, maximum
+                ));
+            }
+        }
+        if self.deny.contains(&version) {
+            return Err(format!(
+                "Client version {} is known bad, please upgrade to another version (require version >= {})",
+                version, self.minimum
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl std::convert::TryFrom<&Config> for VersionPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: &Config) -> Result<Self, Self::Error> {
+        Self::new(
+            cfg.get_minimum_client_version(),
+            cfg.get_maximum_client_version(),
+            cfg.get_denied_client_versions(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configparser::tests::config_with;
+    use std::convert::TryFrom;
+
+    const SMALLER: &str = "smaller than required";
+    const GREATER: &str = "greater than maximum";
+    const DENIED: &str = "known bad";
+    const MALFORMED: &str = "Unable to parse";
+
+    fn policy() -> VersionPolicy {
+        VersionPolicy::new(
+            Some(&"1.6.1".to_string()),
+            Some(&"v2.0.0".to_string()),
+            Some(&vec!["1.7.0".to_string(), "v1.8.1+linux".to_string()]),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn check() {
+        let policy = policy();
+        // (client version, `None` if accepted or part of the rejection reason)
+        let cases: &[(&str, Option<&str>)] = &[
+            // Minimum is inclusive
+            ("1.6.1", None),
+            ("1.6.0", Some(SMALLER)),
+            ("1.6.1-rc.1", Some(SMALLER)),
+            ("0.9.0", Some(SMALLER)),
+            // Maximum is inclusive
+            ("2.0.0", None),
+            ("2.0.0-alpha.1", None),
+            ("2.0.1", Some(GREATER)),
+            ("10.0.0", Some(GREATER)),
+            // Build metadata does not affect precedence
+            ("1.6.1+build.5", None),
+            ("2.0.0+20211017", None),
+            ("1.6.0+build.5", Some(SMALLER)),
+            ("2.0.1+build.5", Some(GREATER)),
+            // Leading `v` and whitespace
+            ("v1.6.1", None),
+            ("v1.6.0", Some(SMALLER)),
+            (" 1.7.1\n", None),
+            // Deny list, its entries are normalized as well
+            ("1.7.0", Some(DENIED)),
+            ("v1.7.0", Some(DENIED)),
+            ("1.7.0+musl", Some(DENIED)),
+            ("1.8.1", Some(DENIED)),
+            ("1.8.1+windows", Some(DENIED)),
+            ("1.7.0-rc.1", None),
+            ("1.8.2", None),
+            // Malformed
+            ("", Some(MALFORMED)),
+            ("1.7", Some(MALFORMED)),
+            ("1.7.0.1", Some(MALFORMED)),
+            ("latest", Some(MALFORMED)),
+            ("1.07.0", Some(MALFORMED)),
+            ("V1.7.0", Some(MALFORMED)),
+        ];
+        for (version, expected) in cases {
+            match (policy.check(version), expected) {
+                (Ok(()), None) => {}
+                (Err(reason), Some(expected)) => assert!(
+                    reason.contains(expected),
+                    "{:?}: {:?} does not contain {:?}",
+                    version,
+                    reason,
+                    expected
+                ),
+                (result, expected) => {
+                    panic!("{:?}: got {:?}, expected {:?}", version, result, expected)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn default_policy() {
+        let policy = VersionPolicy::new(None, None, None).unwrap();
+        assert!(policy.check(MINIMUM_CLIENT_VERSION).is_ok());
+        assert!(policy.check("99.0.0").is_ok());
+        assert!(policy.check("1.6.0").unwrap_err().contains(SMALLER));
+    }
+
+    #[test]
+    fn malformed_policy() {
+        let bad = "1.x".to_string();
+        assert!(VersionPolicy::new(Some(&bad), None, None).is_err());
+        assert!(VersionPolicy::new(None, Some(&bad), None).is_err());
+        assert!(VersionPolicy::new(None, None, Some(&vec!["1.7.0".to_string(), bad])).is_err());
+    }
+
+    #[test]
+    fn from_config() {
+        let config = config_with("[client_version]\nminimum = \"1.7.0\"\ndeny = [\"1.7.2\"]\n");
+        let policy = VersionPolicy::try_from(&config).unwrap();
+        assert!(policy.check("1.6.9").unwrap_err().contains(SMALLER));
+        assert!(policy.check("1.7.2").unwrap_err().contains(DENIED));
+        assert!(policy.check("3.0.0").is_ok());
+    }
+}
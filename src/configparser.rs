@@ -20,12 +20,20 @@
 #![allow(dead_code)]
 use anyhow::Result;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::Path;
 
 #[derive(Deserialize, Serialize)]
 pub struct Config {
     pub(crate) server: Server,
     telegram: Telegram,
+    client_version: Option<ClientVersion>,
+    retention: Option<Retention>,
+    notifier: Option<Vec<NotifierConfig>>,
+    templates: Option<Templates>,
+    watchdog: Option<Watchdog>,
+    alert: Option<Vec<AlertRuleConfig>>,
+    escalation: Option<Vec<EscalationStep>>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -35,6 +43,8 @@ pub struct Server {
     pub(crate) token: String,
     database: String,
     admin_token: Option<String>,
+    /// IANA name of timezone times in notification are shown in, e.g. `Asia/Tokyo`
+    timezone: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -42,6 +52,456 @@ pub struct Telegram {
     bot_token: String,
     api_server: Option<String>,
     owner: i64,
+    recipient: Option<Vec<TelegramRecipient>>,
+}
+
+/// Select which events are sent to a recipient, empty filter accepts everything.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct EventFilter {
+    /// Event types, e.g. online, register, offline, reboot
+    events: Option<Vec<String>>,
+    /// Client id, uuid or name, `*` matches any characters
+    clients: Option<Vec<String>>,
+}
+
+impl EventFilter {
+    pub fn get_events(&self) -> &Option<Vec<String>> {
+        &self.events
+    }
+
+    pub fn get_clients(&self) -> &Option<Vec<String>> {
+        &self.clients
+    }
+}
+
+/// Handlebars templates of notification message by event type, unset ones use built-in text.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Templates {
+    online: Option<String>,
+    register: Option<String>,
+    offline: Option<String>,
+    reboot: Option<String>,
+    flapping: Option<String>,
+    stable: Option<String>,
+    maintenance: Option<String>,
+    alert: Option<String>,
+    resolved: Option<String>,
+    escalation: Option<String>,
+}
+
+impl Templates {
+    pub fn get(&self, kind: &str) -> Option<&String> {
+        match kind {
+            "online" => self.online.as_ref(),
+            "register" => self.register.as_ref(),
+            "offline" => self.offline.as_ref(),
+            "reboot" => self.reboot.as_ref(),
+            "flapping" => self.flapping.as_ref(),
+            "stable" => self.stable.as_ref(),
+            "maintenance" => self.maintenance.as_ref(),
+            "alert" => self.alert.as_ref(),
+            "resolved" => self.resolved.as_ref(),
+            "escalation" => self.escalation.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Templates set in `overrides` take precedence over `self`.
+    pub fn merge(&self, overrides: &Option<Templates>) -> Templates {
+        match overrides {
+            Some(overrides) => Templates {
+                online: overrides.online.clone().or_else(|| self.online.clone()),
+                register: overrides.register.clone().or_else(|| self.register.clone()),
+                offline: overrides.offline.clone().or_else(|| self.offline.clone()),
+                reboot: overrides.reboot.clone().or_else(|| self.reboot.clone()),
+                flapping: overrides.flapping.clone().or_else(|| self.flapping.clone()),
+                stable: overrides.stable.clone().or_else(|| self.stable.clone()),
+                maintenance: overrides
+                    .maintenance
+                    .clone()
+                    .or_else(|| self.maintenance.clone()),
+                alert: overrides.alert.clone().or_else(|| self.alert.clone()),
+                resolved: overrides.resolved.clone().or_else(|| self.resolved.clone()),
+                escalation: overrides
+                    .escalation
+                    .clone()
+                    .or_else(|| self.escalation.clone()),
+            },
+            None => self.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TelegramRecipient {
+    chat_id: i64,
+    /// Topic id in forum supergroup
+    thread_id: Option<i64>,
+    #[serde(flatten)]
+    filter: EventFilter,
+    templates: Option<Templates>,
+}
+
+impl TelegramRecipient {
+    pub fn new(chat_id: i64) -> Self {
+        Self {
+            chat_id,
+            thread_id: None,
+            filter: Default::default(),
+            templates: None,
+        }
+    }
+
+    pub fn get_chat_id(&self) -> i64 {
+        self.chat_id
+    }
+
+    pub fn get_thread_id(&self) -> Option<i64> {
+        self.thread_id
+    }
+
+    pub fn get_filter(&self) -> &EventFilter {
+        &self.filter
+    }
+
+    pub fn get_templates(&self) -> &Option<Templates> {
+        &self.templates
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ClientVersion {
+    minimum: Option<String>,
+    maximum: Option<String>,
+    deny: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct TableRetention {
+    max_age: Option<u64>,
+    max_rows: Option<u64>,
+}
+
+impl TableRetention {
+    const fn new(max_age: u64, max_rows: Option<u64>) -> Self {
+        Self {
+            max_age: Some(max_age),
+            max_rows,
+        }
+    }
+
+    pub fn get_max_age(&self) -> Option<u64> {
+        self.max_age
+    }
+
+    pub fn get_max_rows(&self) -> Option<u64> {
+        self.max_rows
+    }
+}
+
+// Tables not set in configure use these, empty table keeps rows forever
+const DEFAULT_RAW_DATA_RETENTION: TableRetention = TableRetention::new(30 * 86400, Some(1_000_000));
+const DEFAULT_METRICS_RETENTION: TableRetention = TableRetention::new(86400, None);
+const DEFAULT_METRICS_5M_RETENTION: TableRetention = TableRetention::new(7 * 86400, None);
+const DEFAULT_METRICS_1H_RETENTION: TableRetention = TableRetention::new(365 * 86400, None);
+
+/// Retention policy of heartbeat data, all durations are in seconds.
+///
+/// `max_age` of `metrics` and `metrics_5m` means samples older than it are rolled up
+/// into the next resolution, instead of being dropped.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct Retention {
+    interval: Option<u64>,
+    vacuum_interval: Option<u64>,
+    incremental_vacuum: Option<bool>,
+    raw_data: Option<TableRetention>,
+    metrics: Option<TableRetention>,
+    metrics_5m: Option<TableRetention>,
+    metrics_1h: Option<TableRetention>,
+}
+
+impl Retention {
+    pub fn get_interval(&self) -> u64 {
+        self.interval.unwrap_or(600)
+    }
+
+    /// `0` disables vacuum.
+    pub fn get_vacuum_interval(&self) -> u64 {
+        self.vacuum_interval.unwrap_or(86400)
+    }
+
+    pub fn get_incremental_vacuum(&self) -> bool {
+        self.incremental_vacuum.unwrap_or(false)
+    }
+
+    pub fn get_raw_data(&self) -> TableRetention {
+        self.raw_data.clone().unwrap_or(DEFAULT_RAW_DATA_RETENTION)
+    }
+
+    pub fn get_metrics(&self) -> TableRetention {
+        self.metrics.clone().unwrap_or(DEFAULT_METRICS_RETENTION)
+    }
+
+    pub fn get_metrics_5m(&self) -> TableRetention {
+        self.metrics_5m
+            .clone()
+            .unwrap_or(DEFAULT_METRICS_5M_RETENTION)
+    }
+
+    pub fn get_metrics_1h(&self) -> TableRetention {
+        self.metrics_1h
+            .clone()
+            .unwrap_or(DEFAULT_METRICS_1H_RETENTION)
+    }
+}
+
+/// Timeout of clients matching `clients`, applied when no timeout is set by admin API.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TimeoutGroup {
+    /// Client id, uuid or name, `*` matches any characters
+    clients: Vec<String>,
+    timeout: i64,
+}
+
+impl TimeoutGroup {
+    pub fn get_clients(&self) -> &Vec<String> {
+        &self.clients
+    }
+
+    pub fn get_timeout(&self) -> i64 {
+        self.timeout
+    }
+}
+
+/// Client changing state `transitions` times within `window` seconds is flapping, its
+/// notifications are suppressed until no state changes for `stable` seconds.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct Flapping {
+    transitions: Option<i64>,
+    window: Option<i64>,
+    stable: Option<i64>,
+}
+
+impl Flapping {
+    /// `0` disables flapping detection.
+    pub fn get_transitions(&self) -> i64 {
+        self.transitions.unwrap_or(5)
+    }
+
+    pub fn get_window(&self) -> i64 {
+        self.window.unwrap_or(1800)
+    }
+
+    pub fn get_stable(&self) -> i64 {
+        self.stable.unwrap_or(900)
+    }
+}
+
+/// Heartbeat timeout of clients, all durations are in seconds.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct Watchdog {
+    /// Timeout of clients which do not report heartbeat interval
+    timeout: Option<i64>,
+    /// Timeout of clients reporting interval is `interval * interval_multiplier`
+    interval_multiplier: Option<i64>,
+    group: Option<Vec<TimeoutGroup>>,
+    flapping: Option<Flapping>,
+}
+
+impl Watchdog {
+    pub fn get_timeout(&self) -> i64 {
+        self.timeout.unwrap_or(7 * 60)
+    }
+
+    pub fn get_interval_multiplier(&self) -> i64 {
+        self.interval_multiplier.unwrap_or(3)
+    }
+
+    pub fn get_groups(&self) -> &[TimeoutGroup] {
+        self.group.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_flapping(&self) -> Flapping {
+        self.flapping.clone().unwrap_or_default()
+    }
+}
+
+/// Alert rule on heartbeat statistics, e.g. `disk_usage{mount="/"} > 90% for 10m`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AlertRuleConfig {
+    name: String,
+    expr: String,
+    /// Client id, uuid or name, `*` matches any characters
+    clients: Option<Vec<String>>,
+}
+
+impl AlertRuleConfig {
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn get_expression(&self) -> &String {
+        &self.expr
+    }
+
+    pub fn get_clients(&self) -> &[String] {
+        self.clients.as_deref().unwrap_or_default()
+    }
+}
+
+/// Step of escalation chain, taken when offline incident is not acknowledged `after` seconds
+/// since it was detected.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct EscalationStep {
+    after: i64,
+    /// Names of notifiers to send to, every notifier accepting `escalation` events if absent
+    notifiers: Option<Vec<String>>,
+}
+
+impl EscalationStep {
+    pub fn get_after(&self) -> i64 {
+        self.after
+    }
+
+    pub fn get_notifiers(&self) -> &[String] {
+        self.notifiers.as_deref().unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NotifierKind {
+    /// POST event as JSON to url
+    Webhook {
+        url: String,
+        headers: Option<HashMap<String, String>>,
+    },
+    /// Slack incoming webhook
+    Slack { url: String },
+    /// Discord channel webhook
+    Discord { url: String },
+    Matrix {
+        homeserver: String,
+        access_token: String,
+        room_id: String,
+    },
+    /// ntfy topic url, e.g. https://ntfy.sh/topic
+    Ntfy {
+        url: String,
+        token: Option<String>,
+        priority: Option<u8>,
+    },
+    Gotify {
+        url: String,
+        token: String,
+        priority: Option<i64>,
+    },
+    /// Send mail through SMTP relay
+    Email(SmtpConfig),
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Plain text connection, only for local relay or testing
+    None,
+    #[default]
+    StartTls,
+    /// Implicit TLS (SMTPS)
+    Tls,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SmtpConfig {
+    host: String,
+    port: Option<u16>,
+    #[serde(default)]
+    tls: SmtpTls,
+    username: Option<String>,
+    password: Option<String>,
+    from: String,
+    to: Vec<String>,
+    /// Seconds to collect events before sending a single mail
+    batch_interval: Option<u64>,
+}
+
+impl SmtpConfig {
+    pub fn get_host(&self) -> &String {
+        &self.host
+    }
+
+    pub fn get_port(&self) -> u16 {
+        self.port.unwrap_or(match self.tls {
+            SmtpTls::None => 25,
+            SmtpTls::StartTls => 587,
+            SmtpTls::Tls => 465,
+        })
+    }
+
+    pub fn get_tls(&self) -> SmtpTls {
+        self.tls
+    }
+
+    pub fn get_credentials(&self) -> Option<(&String, &String)> {
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => Some((username, password)),
+            _ => None,
+        }
+    }
+
+    pub fn get_from(&self) -> &String {
+        &self.from
+    }
+
+    pub fn get_to(&self) -> &Vec<String> {
+        &self.to
+    }
+
+    pub fn get_batch_interval(&self) -> u64 {
+        self.batch_interval.unwrap_or(30)
+    }
+}
+
+impl NotifierKind {
+    pub fn get_type_name(&self) -> &'static str {
+        match self {
+            NotifierKind::Webhook { .. } => "webhook",
+            NotifierKind::Slack { .. } => "slack",
+            NotifierKind::Discord { .. } => "discord",
+            NotifierKind::Matrix { .. } => "matrix",
+            NotifierKind::Ntfy { .. } => "ntfy",
+            NotifierKind::Gotify { .. } => "gotify",
+            NotifierKind::Email(_) => "email",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct NotifierConfig {
+    name: Option<String>,
+    #[serde(flatten)]
+    filter: EventFilter,
+    templates: Option<Templates>,
+    #[serde(flatten)]
+    kind: NotifierKind,
+}
+
+impl NotifierConfig {
+    pub fn get_name(&self) -> &Option<String> {
+        &self.name
+    }
+
+    pub fn get_filter(&self) -> &EventFilter {
+        &self.filter
+    }
+
+    pub fn get_templates(&self) -> &Option<Templates> {
+        &self.templates
+    }
+
+    pub fn get_kind(&self) -> &NotifierKind {
+        &self.kind
+    }
 }
 
 impl Config {
@@ -56,6 +516,15 @@ impl Config {
         format!("{}:{}", self.server.bind, self.server.port)
     }
 
+    pub fn get_timezone(&self) -> Result<chrono_tz::Tz> {
+        match self.server.timezone {
+            Some(ref timezone) => timezone
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid timezone {:?}: {}", timezone, e)),
+            None => Ok(chrono_tz::UTC),
+        }
+    }
+
     pub fn get_bot_token(&self) -> &String {
         &self.telegram.bot_token
     }
@@ -68,6 +537,19 @@ impl Config {
         self.telegram.owner
     }
 
+    /// Chats receive notification, owner receives all events unless listed explicitly.
+    pub fn get_telegram_recipients(&self) -> Vec<TelegramRecipient> {
+        let mut recipients = self.telegram.recipient.clone().unwrap_or_default();
+        if self.telegram.owner != 0
+            && !recipients
+                .iter()
+                .any(|recipient| recipient.chat_id == self.telegram.owner)
+        {
+            recipients.insert(0, TelegramRecipient::new(self.telegram.owner));
+        }
+        recipients
+    }
+
     pub fn get_database_location(&self) -> &String {
         &self.server.database
     }
@@ -82,6 +564,46 @@ impl Config {
     pub fn get_admin_token(&self) -> Option<String> {
         self.server.admin_token.clone()
     }
+
+    pub fn get_minimum_client_version(&self) -> Option<&String> {
+        self.client_version
+            .as_ref()
+            .and_then(|v| v.minimum.as_ref())
+    }
+
+    pub fn get_maximum_client_version(&self) -> Option<&String> {
+        self.client_version
+            .as_ref()
+            .and_then(|v| v.maximum.as_ref())
+    }
+
+    pub fn get_denied_client_versions(&self) -> Option<&Vec<String>> {
+        self.client_version.as_ref().and_then(|v| v.deny.as_ref())
+    }
+
+    pub fn get_retention(&self) -> Retention {
+        self.retention.clone().unwrap_or_default()
+    }
+
+    pub fn get_notifiers(&self) -> &[NotifierConfig] {
+        self.notifier.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_templates(&self) -> Templates {
+        self.templates.clone().unwrap_or_default()
+    }
+
+    pub fn get_watchdog(&self) -> Watchdog {
+        self.watchdog.clone().unwrap_or_default()
+    }
+
+    pub fn get_alert_rules(&self) -> &[AlertRuleConfig] {
+        self.alert.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_escalation(&self) -> &[EscalationStep] {
+        self.escalation.as_deref().unwrap_or_default()
+    }
 }
 
 pub mod client {
@@ -121,3 +643,86 @@ pub mod client {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const MINIMAL: &str = "[server]\nbind = \"\"\nport = 0\ntoken = \"\"\ndatabase = \"\"\n\n[telegram]\nbot_token = \"\"\nowner = 0\n";
+
+    /// Minimal valid configuration followed by `extra` sections, shared by tests of other modules.
+    pub fn config_with(extra: &str) -> Config {
+        toml::from_str(&format!("{}\n{}", MINIMAL, extra)).unwrap()
+    }
+
+    #[test]
+    fn minimal() {
+        let config = config_with("");
+        assert!(config.get_minimum_client_version().is_none());
+        assert!(config.get_denied_client_versions().is_none());
+    }
+
+    fn assert_default_retention(retention: &Retention) {
+        assert_eq!(retention.get_raw_data().get_max_age(), Some(30 * 86400));
+        assert_eq!(retention.get_raw_data().get_max_rows(), Some(1_000_000));
+        assert_eq!(retention.get_metrics().get_max_age(), Some(86400));
+        assert_eq!(retention.get_metrics_5m().get_max_age(), Some(7 * 86400));
+        assert_eq!(retention.get_metrics_1h().get_max_age(), Some(365 * 86400));
+    }
+
+    #[test]
+    fn default_config_retention() {
+        let config: Config = toml::from_str(include_str!("../data/config.toml.default")).unwrap();
+        assert_default_retention(&config.get_retention());
+    }
+
+    #[test]
+    fn missing_retention_uses_defaults() {
+        let retention = config_with("[retention]\nmetrics_1h = {}\n").get_retention();
+        assert_eq!(retention.get_raw_data().get_max_age(), Some(30 * 86400));
+        assert_eq!(retention.get_metrics().get_max_age(), Some(86400));
+        // Empty table keeps rows forever
+        assert_eq!(retention.get_metrics_1h().get_max_age(), None);
+
+        assert_default_retention(&config_with("").get_retention());
+    }
+
+    #[test]
+    fn telegram_recipients() {
+        let recipients = config_with("").get_telegram_recipients();
+        assert!(recipients.is_empty());
+
+        let owner = |extra: &str| -> Config {
+            toml::from_str(&format!(
+                "{}\n{}",
+                MINIMAL.replace("owner = 0", "owner = 42"),
+                extra
+            ))
+            .unwrap()
+        };
+        let recipients = owner(
+            "[[telegram.recipient]]\nchat_id = -100\nthread_id = 2\nevents = [\"offline\"]\nclients = [\"router-*\"]\n",
+        )
+        .get_telegram_recipients();
+        // Owner receives everything in front of listed chats
+        assert_eq!(recipients.len(), 2);
+        assert_eq!(recipients[0].get_chat_id(), 42);
+        assert!(recipients[0].get_filter().get_events().is_none());
+        assert!(recipients[0].get_filter().get_clients().is_none());
+        assert_eq!(recipients[1].get_chat_id(), -100);
+        assert_eq!(recipients[1].get_thread_id(), Some(2));
+        assert_eq!(
+            recipients[1].get_filter().get_clients(),
+            &Some(vec!["router-*".to_string()])
+        );
+
+        // Owner listed explicitly keeps its filter
+        let recipients = owner("[[telegram.recipient]]\nchat_id = 42\nevents = [\"offline\"]\n")
+            .get_telegram_recipients();
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(
+            recipients[0].get_filter().get_events(),
+            &Some(vec!["offline".to_string()])
+        );
+    }
+}
@@ -17,6 +17,27 @@
  ** You should have received a copy of the GNU Affero General Public License
  ** along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
+#[allow(dead_code)]
+pub mod v1 {
+    pub const CREATE_TABLES: &str = r#"
+    CREATE TABLE "clients" (
+        "id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        "uuid"	TEXT NOT NULL UNIQUE,
+        "boot_time"	INTEGER NOT NULL,
+        "last_seen"	INTEGER NOT NULL
+    );
+
+    CREATE TABLE "raw_data" (
+        "id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        "from"	INTEGER NOT NULL,
+        "data"	TEXT NOT NULL,
+        "timestamp"	INTEGER NOT NULL
+    );
+    "#;
+
+    pub const VERSION: &str = "1";
+}
+
 #[allow(dead_code)]
 pub mod v2 {
     pub const CREATE_TABLES: &str = r#"
@@ -49,6 +70,21 @@ pub mod v2 {
     INSERT INTO "pbs_meta" VALUES ('version', '2');
     "#;
 
+    // v1 databases have no meta table, version row is written by migrate()
+    pub const UPGRADE: &str = r#"
+    CREATE TABLE "pbs_meta" (
+        "key"	TEXT NOT NULL,
+        "value"	TEXT NOT NULL,
+        PRIMARY KEY("key")
+    );
+
+    CREATE TABLE "hostname" (
+        "id"	INTEGER NOT NULL,
+        "name"	TEXT,
+        PRIMARY KEY("id")
+    );
+    "#;
+
     pub const VERSION: &str = "2";
 }
 
@@ -79,21 +115,832 @@ pub mod v3 {
     INSERT INTO "pbs_meta" VALUES ('version', '3');
     "#;
 
+    pub const UPGRADE: &str = r#"
+    ALTER TABLE "clients" ADD COLUMN "hostname" TEXT;
+
+    UPDATE "clients" SET "hostname" = (
+        SELECT "name" FROM "hostname" WHERE "hostname"."id" = "clients"."id"
+    );
+
+    DROP TABLE "hostname";
+    "#;
+
     pub const VERSION: &str = "3";
 
 }
-pub use v3::VERSION;
-pub use v3 as current;
 
+#[allow(dead_code)]
+pub mod v4 {
+    pub const UPGRADE: &str = r#"
+    CREATE TABLE "metrics" (
+        "id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        "client_id"	INTEGER NOT NULL,
+        "timestamp"	INTEGER NOT NULL,
+        "cpu_usage"	REAL,
+        "load1"	REAL,
+        "load5"	REAL,
+        "load15"	REAL,
+        "memory_total"	INTEGER,
+        "memory_used"	INTEGER,
+        "swap_total"	INTEGER,
+        "swap_used"	INTEGER,
+        "uptime"	INTEGER
+    );
+
+    CREATE TABLE "disk_metrics" (
+        "id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        "client_id"	INTEGER NOT NULL,
+        "timestamp"	INTEGER NOT NULL,
+        "mount"	TEXT NOT NULL,
+        "total"	INTEGER NOT NULL,
+        "used"	INTEGER NOT NULL
+    );
+
+    CREATE TABLE "network_metrics" (
+        "id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        "client_id"	INTEGER NOT NULL,
+        "timestamp"	INTEGER NOT NULL,
+        "interface"	TEXT NOT NULL,
+        "rx_bytes"	INTEGER NOT NULL,
+        "tx_bytes"	INTEGER NOT NULL
+    );
+
+    CREATE INDEX "metrics_client_timestamp" ON "metrics" ("client_id", "timestamp");
+    CREATE INDEX "disk_metrics_client_timestamp" ON "disk_metrics" ("client_id", "timestamp");
+    CREATE INDEX "network_metrics_client_timestamp" ON "network_metrics" ("client_id", "timestamp");
+    CREATE INDEX "raw_data_from_timestamp" ON "raw_data" ("from", "timestamp");
+    "#;
+
+    pub const VERSION: &str = "4";
+}
+
+#[allow(dead_code)]
+pub mod v5 {
+    pub const UPGRADE: &str = r#"
+    CREATE TABLE "metrics_rollup" (
+        "client_id"	INTEGER NOT NULL,
+        "resolution"	INTEGER NOT NULL,
+        "timestamp"	INTEGER NOT NULL,
+        "samples"	INTEGER NOT NULL,
+        "cpu_usage_min"	REAL,
+        "cpu_usage_avg"	REAL,
+        "cpu_usage_max"	REAL,
+        "load1_min"	REAL,
+        "load1_avg"	REAL,
+        "load1_max"	REAL,
+        "memory_used_min"	INTEGER,
+        "memory_used_avg"	REAL,
+        "memory_used_max"	INTEGER,
+        "swap_used_min"	INTEGER,
+        "swap_used_avg"	REAL,
+        "swap_used_max"	INTEGER,
+        PRIMARY KEY("client_id", "resolution", "timestamp")
+    );
+
+    CREATE TABLE "disk_metrics_rollup" (
+        "client_id"	INTEGER NOT NULL,
+        "resolution"	INTEGER NOT NULL,
+        "timestamp"	INTEGER NOT NULL,
+        "mount"	TEXT NOT NULL,
+        "samples"	INTEGER NOT NULL,
+        "total"	INTEGER NOT NULL,
+        "used_min"	INTEGER NOT NULL,
+        "used_avg"	REAL NOT NULL,
+        "used_max"	INTEGER NOT NULL,
+        PRIMARY KEY("client_id", "resolution", "timestamp", "mount")
+    );
+
+    CREATE TABLE "network_metrics_rollup" (
+        "client_id"	INTEGER NOT NULL,
+        "resolution"	INTEGER NOT NULL,
+        "timestamp"	INTEGER NOT NULL,
+        "interface"	TEXT NOT NULL,
+        "samples"	INTEGER NOT NULL,
+        "rx_bytes_min"	INTEGER NOT NULL,
+        "rx_bytes_max"	INTEGER NOT NULL,
+        "tx_bytes_min"	INTEGER NOT NULL,
+        "tx_bytes_max"	INTEGER NOT NULL,
+        PRIMARY KEY("client_id", "resolution", "timestamp", "interface")
+    );
+    "#;
+
+    pub const VERSION: &str = "5";
+}
+
+#[allow(dead_code)]
+pub mod v6 {
+    pub const UPGRADE: &str = r#"
+    ALTER TABLE "clients" ADD COLUMN "display_name" TEXT;
+    "#;
+
+    pub const VERSION: &str = "6";
+}
+
+#[allow(dead_code)]
+pub mod v7 {
+    pub const UPGRADE: &str = r#"
+    ALTER TABLE "clients" ADD COLUMN "retired" INTEGER NOT NULL DEFAULT 0;
+    "#;
+
+    pub const VERSION: &str = "7";
+}
+
+#[allow(dead_code)]
+pub mod v8 {
+    pub const UPGRADE: &str = r#"
+    ALTER TABLE "clients" ADD COLUMN "muted_until" INTEGER;
+    "#;
+
+    pub const VERSION: &str = "8";
+}
+
+#[allow(dead_code)]
+pub mod v9 {
+    pub const UPGRADE: &str = r#"
+    CREATE TABLE "notification_queue" (
+        "id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        "notifier"	TEXT NOT NULL,
+        "payload"	TEXT NOT NULL,
+        "attempts"	INTEGER NOT NULL DEFAULT 0,
+        "next_attempt"	INTEGER NOT NULL,
+        "created_at"	INTEGER NOT NULL,
+        "last_error"	TEXT,
+        "failed_at"	INTEGER
+    );
+
+    CREATE INDEX "notification_queue_notifier_next_attempt" ON "notification_queue" ("notifier", "next_attempt");
+    "#;
+
+    pub const VERSION: &str = "9";
+}
+
+#[allow(dead_code)]
+pub mod v10 {
+    pub const UPGRADE: &str = r#"
+    CREATE TABLE "incidents" (
+        "id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        "client_id"	INTEGER NOT NULL,
+        "started_at"	INTEGER NOT NULL,
+        "detected_at"	INTEGER NOT NULL,
+        "resolved_at"	INTEGER
+    );
+
+    CREATE INDEX "incidents_client_started" ON "incidents" ("client_id", "started_at");
+    "#;
+
+    pub const VERSION: &str = "10";
+}
+
+#[allow(dead_code)]
+pub mod v11 {
+    pub const UPGRADE: &str = r#"
+    ALTER TABLE "incidents" ADD COLUMN "cause" TEXT NOT NULL DEFAULT 'timeout';
+    "#;
+
+    pub const VERSION: &str = "11";
+}
+
+#[allow(dead_code)]
+pub mod v12 {
+    pub const UPGRADE: &str = r#"
+    ALTER TABLE "clients" ADD COLUMN "timeout" INTEGER;
+    ALTER TABLE "clients" ADD COLUMN "heartbeat_interval" INTEGER;
+
+    CREATE TABLE "timeout_overrides" (
+        "tag"	TEXT NOT NULL PRIMARY KEY,
+        "timeout"	INTEGER NOT NULL
+    );
+    "#;
+
+    pub const VERSION: &str = "12";
+}
+
+#[allow(dead_code)]
+pub mod v13 {
+    pub const UPGRADE: &str = r#"
+    ALTER TABLE "clients" ADD COLUMN "flapping_since" INTEGER;
+    "#;
+
+    pub const VERSION: &str = "13";
+}
+
+#[allow(dead_code)]
+pub mod v14 {
+    pub const UPGRADE: &str = r#"
+    CREATE TABLE "maintenance" (
+        "id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        "description"	TEXT NOT NULL DEFAULT '',
+        "clients"	TEXT NOT NULL DEFAULT '[]',
+        "starts_at"	INTEGER,
+        "ends_at"	INTEGER,
+        "schedule"	TEXT,
+        "duration"	INTEGER,
+        "active_since"	INTEGER,
+        "created_at"	INTEGER NOT NULL
+    );
+    "#;
+
+    pub const VERSION: &str = "14";
+}
+
+#[allow(dead_code)]
+pub mod v15 {
+    pub const UPGRADE: &str = r#"
+    CREATE TABLE "alert_rules" (
+        "id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        "name"	TEXT NOT NULL UNIQUE,
+        "expression"	TEXT NOT NULL,
+        "clients"	TEXT NOT NULL DEFAULT '[]',
+        "created_at"	INTEGER NOT NULL
+    );
+
+    CREATE TABLE "alerts" (
+        "id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        "rule"	TEXT NOT NULL,
+        "client_id"	INTEGER NOT NULL,
+        "series"	TEXT NOT NULL DEFAULT '',
+        "value"	REAL NOT NULL,
+        "pending_since"	INTEGER NOT NULL,
+        "fired_at"	INTEGER,
+        "resolved_at"	INTEGER
+    );
+
+    CREATE INDEX "alerts_client_pending" ON "alerts" ("client_id", "pending_since");
+    "#;
+
+    pub const VERSION: &str = "15";
+}
+
+#[allow(dead_code)]
+pub mod v16 {
+    pub const UPGRADE: &str = r#"
+    ALTER TABLE "clients" ADD COLUMN "tags" TEXT NOT NULL DEFAULT '[]';
+    ALTER TABLE "clients" ADD COLUMN "labels" TEXT NOT NULL DEFAULT '{}';
+    "#;
+
+    pub const VERSION: &str = "16";
+}
+
+#[allow(dead_code)]
+pub mod v17 {
+    pub const UPGRADE: &str = r#"
+    ALTER TABLE "clients" ADD COLUMN "parent_id" INTEGER;
+    "#;
+
+    pub const VERSION: &str = "17";
+}
+
+#[allow(dead_code)]
+pub mod v18 {
+    pub const UPGRADE: &str = r#"
+    ALTER TABLE "incidents" ADD COLUMN "acknowledged_at" INTEGER;
+    ALTER TABLE "incidents" ADD COLUMN "acknowledged_by" TEXT;
+    ALTER TABLE "incidents" ADD COLUMN "escalation_level" INTEGER NOT NULL DEFAULT 0;
+    "#;
+
+    pub const VERSION: &str = "18";
+}
+
+pub use v18::VERSION;
+// Schema fresh databases are created with, newer versions are reached through MIGRATIONS
+use v3 as base;
+
+use anyhow::anyhow;
+use log::info;
 use serde_derive::{Deserialize, Serialize};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::types::Json;
+use sqlx::{Connection, SqliteConnection, SqlitePool};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_POOL_SIZE: u32 = 8;
+pub const DEFAULT_HOSTNAME: &str = "(no hostname)";
+const DEFAULT_BUSY_TIMEOUT: u64 = 5;
+
+/// Open connection pool to database, database file will be created if not exists.
+pub async fn connect(location: &str) -> anyhow::Result<SqlitePool> {
+    let options = SqliteConnectOptions::from_str(location)?
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(Duration::from_secs(DEFAULT_BUSY_TIMEOUT));
+    // Every connection to memory database opens a new empty database,
+    // so keep exactly one connection alive
+    let pool_options = if location.contains(":memory:") {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+    } else {
+        SqlitePoolOptions::new().max_connections(DEFAULT_POOL_SIZE)
+    };
+    Ok(pool_options.connect_with(options).await?)
+}
+
+/// Ordered upgrade steps, each entry is (version it upgrades from, version it upgrades to, statements).
+const MIGRATIONS: &[(&str, &str, &str)] = &[
+    (v1::VERSION, v2::VERSION, v2::UPGRADE),
+    (v2::VERSION, v3::VERSION, v3::UPGRADE),
+    (v3::VERSION, v4::VERSION, v4::UPGRADE),
+    (v4::VERSION, v5::VERSION, v5::UPGRADE),
+    (v5::VERSION, v6::VERSION, v6::UPGRADE),
+    (v6::VERSION, v7::VERSION, v7::UPGRADE),
+    (v7::VERSION, v8::VERSION, v8::UPGRADE),
+    (v8::VERSION, v9::VERSION, v9::UPGRADE),
+    (v9::VERSION, v10::VERSION, v10::UPGRADE),
+    (v10::VERSION, v11::VERSION, v11::UPGRADE),
+    (v11::VERSION, v12::VERSION, v12::UPGRADE),
+    (v12::VERSION, v13::VERSION, v13::UPGRADE),
+    (v13::VERSION, v14::VERSION, v14::UPGRADE),
+    (v14::VERSION, v15::VERSION, v15::UPGRADE),
+    (v15::VERSION, v16::VERSION, v16::UPGRADE),
+    (v16::VERSION, v17::VERSION, v17::UPGRADE),
+    (v17::VERSION, v18::VERSION, v18::UPGRADE),
+];
+
+async fn table_exists(conn: &mut SqliteConnection, name: &str) -> anyhow::Result<bool> {
+    let rows = sqlx::query(r#"SELECT "name" FROM "sqlite_master" WHERE "type" = 'table' AND "name" = ?"#)
+        .bind(name)
+        .fetch_all(conn)
+        .await?;
+    Ok(!rows.is_empty())
+}
+
+/// Read schema version of database, `None` means the database is empty.
+pub async fn get_schema_version(conn: &mut SqliteConnection) -> anyhow::Result<Option<u32>> {
+    if !table_exists(conn, "pbs_meta").await? {
+        return Ok(if table_exists(conn, "clients").await? {
+            Some(v1::VERSION.parse()?)
+        } else {
+            None
+        });
+    }
+    let r: (String,) = sqlx::query_as(r#"SELECT "value" FROM "pbs_meta" WHERE "key" = 'version'"#)
+        .fetch_one(conn)
+        .await?;
+    Ok(Some(r.0.parse()?))
+}
+
+/// Create tables on empty database, or upgrade database to [`VERSION`] step by step.
+///
+/// Every step runs in its own transaction, so a failed step leaves database in previous version.
+pub async fn migrate(conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    let target: u32 = VERSION.parse()?;
+    let mut version = match get_schema_version(conn).await? {
+        Some(version) => version,
+        None => {
+            info!("Database is empty, create tables (version {})", base::VERSION);
+            let mut tx = conn.begin().await?;
+            sqlx::query(base::CREATE_TABLES).execute(&mut tx).await?;
+            tx.commit().await?;
+            base::VERSION.parse()?
+        }
+    };
+
+    if version > target {
+        return Err(anyhow!(
+            "Database version {} is newer than supported version {}, please upgrade probe-server",
+            version,
+            target
+        ));
+    }
+
+    while version < target {
+        let (_, to, statements) = MIGRATIONS
+            .iter()
+            .find(|(from, _, _)| from.parse::<u32>().is_ok_and(|from| from == version))
+            .ok_or_else(|| anyhow!("No migration found for database version {}", version))?;
+        info!("Upgrade database from version {} to {}", version, to);
+        let mut tx = conn.begin().await?;
+        sqlx::query(statements).execute(&mut tx).await?;
+        sqlx::query(r#"INSERT OR REPLACE INTO "pbs_meta" VALUES ('version', ?)"#)
+            .bind(*to)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        version = to.parse()?;
+    }
+    Ok(())
+}
+
+/// Schema and migrations of PostgreSQL backend, which starts from schema version 3.
+pub mod postgres {
+    use anyhow::anyhow;
+    use log::info;
+    use sqlx::postgres::PgPoolOptions;
+    use sqlx::{Connection, Executor, PgConnection, PgPool};
+
+    const DEFAULT_POOL_SIZE: u32 = 16;
+
+    #[allow(dead_code)]
+    pub mod v3 {
+        pub const CREATE_TABLES: &str = r#"
+        CREATE TABLE "clients" (
+            "id"	SERIAL PRIMARY KEY,
+            "uuid"	TEXT NOT NULL UNIQUE,
+            "boot_time"	BIGINT NOT NULL,
+            "last_seen"	BIGINT NOT NULL,
+            "hostname"	TEXT
+        );
+
+        CREATE TABLE "raw_data" (
+            "id"	BIGSERIAL PRIMARY KEY,
+            "from"	INTEGER NOT NULL,
+            "data"	TEXT NOT NULL,
+            "timestamp"	BIGINT NOT NULL
+        );
+
+        CREATE TABLE "pbs_meta" (
+            "key"	TEXT NOT NULL PRIMARY KEY,
+            "value"	TEXT NOT NULL
+        );
+
+        INSERT INTO "pbs_meta" VALUES ('version', '3');
+        "#;
+
+        pub const VERSION: &str = "3";
+    }
+
+    #[allow(dead_code)]
+    pub mod v4 {
+        pub const UPGRADE: &str = r#"
+        CREATE TABLE "metrics" (
+            "id"	BIGSERIAL PRIMARY KEY,
+            "client_id"	INTEGER NOT NULL,
+            "timestamp"	BIGINT NOT NULL,
+            "cpu_usage"	DOUBLE PRECISION,
+            "load1"	DOUBLE PRECISION,
+            "load5"	DOUBLE PRECISION,
+            "load15"	DOUBLE PRECISION,
+            "memory_total"	BIGINT,
+            "memory_used"	BIGINT,
+            "swap_total"	BIGINT,
+            "swap_used"	BIGINT,
+            "uptime"	BIGINT
+        );
+
+        CREATE TABLE "disk_metrics" (
+            "id"	BIGSERIAL PRIMARY KEY,
+            "client_id"	INTEGER NOT NULL,
+            "timestamp"	BIGINT NOT NULL,
+            "mount"	TEXT NOT NULL,
+            "total"	BIGINT NOT NULL,
+            "used"	BIGINT NOT NULL
+        );
+
+        CREATE TABLE "network_metrics" (
+            "id"	BIGSERIAL PRIMARY KEY,
+            "client_id"	INTEGER NOT NULL,
+            "timestamp"	BIGINT NOT NULL,
+            "interface"	TEXT NOT NULL,
+            "rx_bytes"	BIGINT NOT NULL,
+            "tx_bytes"	BIGINT NOT NULL
+        );
+
+        CREATE INDEX "metrics_client_timestamp" ON "metrics" ("client_id", "timestamp");
+        CREATE INDEX "disk_metrics_client_timestamp" ON "disk_metrics" ("client_id", "timestamp");
+        CREATE INDEX "network_metrics_client_timestamp" ON "network_metrics" ("client_id", "timestamp");
+        CREATE INDEX "raw_data_from_timestamp" ON "raw_data" ("from", "timestamp");
+        "#;
+
+        pub const VERSION: &str = "4";
+    }
+
+    #[allow(dead_code)]
+    pub mod v5 {
+        pub const UPGRADE: &str = r#"
+        CREATE TABLE "metrics_rollup" (
+            "client_id"	INTEGER NOT NULL,
+            "resolution"	BIGINT NOT NULL,
+            "timestamp"	BIGINT NOT NULL,
+            "samples"	BIGINT NOT NULL,
+            "cpu_usage_min"	DOUBLE PRECISION,
+            "cpu_usage_avg"	DOUBLE PRECISION,
+            "cpu_usage_max"	DOUBLE PRECISION,
+            "load1_min"	DOUBLE PRECISION,
+            "load1_avg"	DOUBLE PRECISION,
+            "load1_max"	DOUBLE PRECISION,
+            "memory_used_min"	BIGINT,
+            "memory_used_avg"	DOUBLE PRECISION,
+            "memory_used_max"	BIGINT,
+            "swap_used_min"	BIGINT,
+            "swap_used_avg"	DOUBLE PRECISION,
+            "swap_used_max"	BIGINT,
+            PRIMARY KEY("client_id", "resolution", "timestamp")
+        );
+
+        CREATE TABLE "disk_metrics_rollup" (
+            "client_id"	INTEGER NOT NULL,
+            "resolution"	BIGINT NOT NULL,
+            "timestamp"	BIGINT NOT NULL,
+            "mount"	TEXT NOT NULL,
+            "samples"	BIGINT NOT NULL,
+            "total"	BIGINT NOT NULL,
+            "used_min"	BIGINT NOT NULL,
+            "used_avg"	DOUBLE PRECISION NOT NULL,
+            "used_max"	BIGINT NOT NULL,
+            PRIMARY KEY("client_id", "resolution", "timestamp", "mount")
+        );
+
+        CREATE TABLE "network_metrics_rollup" (
+            "client_id"	INTEGER NOT NULL,
+            "resolution"	BIGINT NOT NULL,
+            "timestamp"	BIGINT NOT NULL,
+            "interface"	TEXT NOT NULL,
+            "samples"	BIGINT NOT NULL,
+            "rx_bytes_min"	BIGINT NOT NULL,
+            "rx_bytes_max"	BIGINT NOT NULL,
+            "tx_bytes_min"	BIGINT NOT NULL,
+            "tx_bytes_max"	BIGINT NOT NULL,
+            PRIMARY KEY("client_id", "resolution", "timestamp", "interface")
+        );
+        "#;
+
+        pub const VERSION: &str = "5";
+    }
+
+    #[allow(dead_code)]
+    pub mod v6 {
+        pub const UPGRADE: &str = r#"
+        ALTER TABLE "clients" ADD COLUMN "display_name" TEXT;
+        "#;
+
+        pub const VERSION: &str = "6";
+    }
+
+    #[allow(dead_code)]
+    pub mod v7 {
+        pub const UPGRADE: &str = r#"
+        ALTER TABLE "clients" ADD COLUMN "retired" BOOLEAN NOT NULL DEFAULT FALSE;
+        "#;
+
+        pub const VERSION: &str = "7";
+    }
+
+    #[allow(dead_code)]
+    pub mod v8 {
+        pub const UPGRADE: &str = r#"
+        ALTER TABLE "clients" ADD COLUMN "muted_until" BIGINT;
+        "#;
+
+        pub const VERSION: &str = "8";
+    }
+
+    #[allow(dead_code)]
+    pub mod v9 {
+        pub const UPGRADE: &str = r#"
+        CREATE TABLE "notification_queue" (
+            "id"	BIGSERIAL PRIMARY KEY,
+            "notifier"	TEXT NOT NULL,
+            "payload"	TEXT NOT NULL,
+            "attempts"	INTEGER NOT NULL DEFAULT 0,
+            "next_attempt"	BIGINT NOT NULL,
+            "created_at"	BIGINT NOT NULL,
+            "last_error"	TEXT,
+            "failed_at"	BIGINT
+        );
+
+        CREATE INDEX "notification_queue_notifier_next_attempt" ON "notification_queue" ("notifier", "next_attempt");
+        "#;
+
+        pub const VERSION: &str = "9";
+    }
+
+    #[allow(dead_code)]
+    pub mod v10 {
+        pub const UPGRADE: &str = r#"
+        CREATE TABLE "incidents" (
+            "id"	BIGSERIAL PRIMARY KEY,
+            "client_id"	INTEGER NOT NULL,
+            "started_at"	BIGINT NOT NULL,
+            "detected_at"	BIGINT NOT NULL,
+            "resolved_at"	BIGINT
+        );
+
+        CREATE INDEX "incidents_client_started" ON "incidents" ("client_id", "started_at");
+        "#;
+
+        pub const VERSION: &str = "10";
+    }
+
+    #[allow(dead_code)]
+    pub mod v11 {
+        pub const UPGRADE: &str = r#"
+        ALTER TABLE "incidents" ADD COLUMN "cause" TEXT NOT NULL DEFAULT 'timeout';
+        "#;
+
+        pub const VERSION: &str = "11";
+    }
+
+    #[allow(dead_code)]
+    pub mod v12 {
+        pub const UPGRADE: &str = r#"
+        ALTER TABLE "clients" ADD COLUMN "timeout" BIGINT;
+        ALTER TABLE "clients" ADD COLUMN "heartbeat_interval" BIGINT;
+
+        CREATE TABLE "timeout_overrides" (
+            "tag"	TEXT NOT NULL PRIMARY KEY,
+            "timeout"	BIGINT NOT NULL
+        );
+        "#;
+
+        pub const VERSION: &str = "12";
+    }
+
+    #[allow(dead_code)]
+    pub mod v13 {
+        pub const UPGRADE: &str = r#"
+        ALTER TABLE "clients" ADD COLUMN "flapping_since" BIGINT;
+        "#;
+
+        pub const VERSION: &str = "13";
+    }
+
+    #[allow(dead_code)]
+    pub mod v14 {
+        pub const UPGRADE: &str = r#"
+        CREATE TABLE "maintenance" (
+            "id"	BIGSERIAL PRIMARY KEY,
+            "description"	TEXT NOT NULL DEFAULT '',
+            "clients"	JSONB NOT NULL DEFAULT '[]',
+            "starts_at"	BIGINT,
+            "ends_at"	BIGINT,
+            "schedule"	TEXT,
+            "duration"	BIGINT,
+            "active_since"	BIGINT,
+            "created_at"	BIGINT NOT NULL
+        );
+        "#;
+
+        pub const VERSION: &str = "14";
+    }
+
+    #[allow(dead_code)]
+    pub mod v15 {
+        pub const UPGRADE: &str = r#"
+        CREATE TABLE "alert_rules" (
+            "id"	BIGSERIAL PRIMARY KEY,
+            "name"	TEXT NOT NULL UNIQUE,
+            "expression"	TEXT NOT NULL,
+            "clients"	JSONB NOT NULL DEFAULT '[]',
+            "created_at"	BIGINT NOT NULL
+        );
+
+        CREATE TABLE "alerts" (
+            "id"	BIGSERIAL PRIMARY KEY,
+            "rule"	TEXT NOT NULL,
+            "client_id"	INTEGER NOT NULL,
+            "series"	TEXT NOT NULL DEFAULT '',
+            "value"	DOUBLE PRECISION NOT NULL,
+            "pending_since"	BIGINT NOT NULL,
+            "fired_at"	BIGINT,
+            "resolved_at"	BIGINT
+        );
+
+        CREATE INDEX "alerts_client_pending" ON "alerts" ("client_id", "pending_since");
+        "#;
+
+        pub const VERSION: &str = "15";
+    }
+
+    #[allow(dead_code)]
+    pub mod v16 {
+        pub const UPGRADE: &str = r#"
+        ALTER TABLE "clients" ADD COLUMN "tags" JSONB NOT NULL DEFAULT '[]';
+        ALTER TABLE "clients" ADD COLUMN "labels" JSONB NOT NULL DEFAULT '{}';
+        "#;
+
+        pub const VERSION: &str = "16";
+    }
+
+    #[allow(dead_code)]
+    pub mod v17 {
+        pub const UPGRADE: &str = r#"
+        ALTER TABLE "clients" ADD COLUMN "parent_id" INTEGER;
+        "#;
+
+        pub const VERSION: &str = "17";
+    }
+
+    #[allow(dead_code)]
+    pub mod v18 {
+        pub const UPGRADE: &str = r#"
+        ALTER TABLE "incidents" ADD COLUMN "acknowledged_at" BIGINT;
+        ALTER TABLE "incidents" ADD COLUMN "acknowledged_by" TEXT;
+        ALTER TABLE "incidents" ADD COLUMN "escalation_level" INTEGER NOT NULL DEFAULT 0;
+        "#;
+
+        pub const VERSION: &str = "18";
+    }
+
+    pub use super::VERSION;
+    use v3 as base;
+
+    /// Same as [`super::MIGRATIONS`], but in PostgreSQL dialect.
+    const MIGRATIONS: &[(&str, &str, &str)] = &[
+        (v3::VERSION, v4::VERSION, v4::UPGRADE),
+        (v4::VERSION, v5::VERSION, v5::UPGRADE),
+        (v5::VERSION, v6::VERSION, v6::UPGRADE),
+        (v6::VERSION, v7::VERSION, v7::UPGRADE),
+        (v7::VERSION, v8::VERSION, v8::UPGRADE),
+        (v8::VERSION, v9::VERSION, v9::UPGRADE),
+        (v9::VERSION, v10::VERSION, v10::UPGRADE),
+        (v10::VERSION, v11::VERSION, v11::UPGRADE),
+        (v11::VERSION, v12::VERSION, v12::UPGRADE),
+        (v12::VERSION, v13::VERSION, v13::UPGRADE),
+        (v13::VERSION, v14::VERSION, v14::UPGRADE),
+        (v14::VERSION, v15::VERSION, v15::UPGRADE),
+        (v15::VERSION, v16::VERSION, v16::UPGRADE),
+        (v16::VERSION, v17::VERSION, v17::UPGRADE),
+        (v17::VERSION, v18::VERSION, v18::UPGRADE),
+    ];
+
+    pub async fn connect(location: &str) -> anyhow::Result<PgPool> {
+        Ok(PgPoolOptions::new()
+            .max_connections(DEFAULT_POOL_SIZE)
+            .connect(location)
+            .await?)
+    }
+
+    pub async fn get_schema_version(conn: &mut PgConnection) -> anyhow::Result<Option<u32>> {
+        let r: (i64,) = sqlx::query_as(
+            r#"SELECT COUNT(*) FROM "information_schema"."tables" WHERE "table_schema" = current_schema() AND "table_name" = 'pbs_meta'"#,
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        if r.0 == 0 {
+            return Ok(None);
+        }
+        let r: (String,) = sqlx::query_as(r#"SELECT "value" FROM "pbs_meta" WHERE "key" = 'version'"#)
+            .fetch_one(conn)
+            .await?;
+        Ok(Some(r.0.parse()?))
+    }
+
+    /// See [`super::migrate`].
+    pub async fn migrate(conn: &mut PgConnection) -> anyhow::Result<()> {
+        let target: u32 = VERSION.parse()?;
+        let mut version = match get_schema_version(conn).await? {
+            Some(version) => version,
+            None => {
+                info!("Database is empty, create tables (version {})", base::VERSION);
+                let mut tx = conn.begin().await?;
+                // Multiple statements are only allowed in simple query protocol
+                (&mut tx).execute(base::CREATE_TABLES).await?;
+                tx.commit().await?;
+                base::VERSION.parse()?
+            }
+        };
+
+        if version > target {
+            return Err(anyhow!(
+                "Database version {} is newer than supported version {}, please upgrade probe-server",
+                version,
+                target
+            ));
+        }
+
+        while version < target {
+            let (_, to, statements) = MIGRATIONS
+                .iter()
+                .find(|(from, _, _)| from.parse::<u32>().is_ok_and(|from| from == version))
+                .ok_or_else(|| anyhow!("No migration found for database version {}", version))?;
+            info!("Upgrade database from version {} to {}", version, to);
+            let mut tx = conn.begin().await?;
+            (&mut tx).execute(*statements).await?;
+            sqlx::query(r#"UPDATE "pbs_meta" SET "value" = $1 WHERE "key" = 'version'"#)
+                .bind(*to)
+                .execute(&mut tx)
+                .await?;
+            tx.commit().await?;
+            version = to.parse()?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
 pub struct ClientRow {
     id: i32,
     uuid: String,
-    boot_time: u32,
-    last_seen: u32,
-    hostname: Option<String>
+    boot_time: i64,
+    last_seen: i64,
+    hostname: Option<String>,
+    display_name: Option<String>,
+    retired: bool,
+    muted_until: Option<i64>,
+    /// Heartbeat timeout set by admin, overrides configured timeout
+    timeout: Option<i64>,
+    /// Heartbeat interval reported by client on register
+    heartbeat_interval: Option<i64>,
+    /// Since when client is flapping, notifications are suppressed meanwhile
+    flapping_since: Option<i64>,
+    tags: Json<Vec<String>>,
+    /// Key/value metadata, e.g. site, role and owner
+    labels: Json<BTreeMap<String, String>>,
+    /// Client this one depends on, e.g. gateway of its site
+    parent_id: Option<i32>,
 }
 
 #[allow(dead_code)]
@@ -106,15 +953,467 @@ impl ClientRow {
         &self.uuid
     }
 
-    pub fn get_boot_time(&self) -> u32 {
+    pub fn get_boot_time(&self) -> i64 {
         self.boot_time
     }
 
-    pub fn get_last_seen(&self) -> u32 {
+    pub fn get_last_seen(&self) -> i64 {
         self.last_seen
     }
 
     pub fn get_hostname(&self) -> &Option<String> {
         &self.hostname
     }
+
+    pub fn get_display_name(&self) -> &Option<String> {
+        &self.display_name
+    }
+
+    pub fn get_retired(&self) -> bool {
+        self.retired
+    }
+
+    pub fn get_muted_until(&self) -> Option<i64> {
+        self.muted_until
+    }
+
+    pub fn get_timeout(&self) -> Option<i64> {
+        self.timeout
+    }
+
+    pub fn get_heartbeat_interval(&self) -> Option<i64> {
+        self.heartbeat_interval
+    }
+
+    pub fn get_flapping_since(&self) -> Option<i64> {
+        self.flapping_since
+    }
+
+    pub fn get_tags(&self) -> &Vec<String> {
+        &self.tags.0
+    }
+
+    pub fn get_labels(&self) -> &BTreeMap<String, String> {
+        &self.labels.0
+    }
+
+    pub fn get_parent_id(&self) -> Option<i32> {
+        self.parent_id
+    }
+
+    pub fn is_muted(&self, timestamp: i64) -> bool {
+        self.muted_until.is_some_and(|until| until > timestamp)
+    }
+
+    /// Name shown in notifications, display name set by admin overrides reported hostname.
+    pub fn get_name(&self) -> String {
+        self.display_name
+            .clone()
+            .or_else(|| self.hostname.clone())
+            .unwrap_or_else(|| DEFAULT_HOSTNAME.to_string())
+    }
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct RawDataRow {
+    id: i64,
+    #[sqlx(rename = "from")]
+    client_id: i32,
+    data: String,
+    timestamp: i64,
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct MetricsRow {
+    id: i64,
+    client_id: i32,
+    timestamp: i64,
+    cpu_usage: Option<f64>,
+    load1: Option<f64>,
+    load5: Option<f64>,
+    load15: Option<f64>,
+    memory_total: Option<i64>,
+    memory_used: Option<i64>,
+    swap_total: Option<i64>,
+    swap_used: Option<i64>,
+    uptime: Option<i64>,
+}
+
+impl MetricsRow {
+    pub fn get_uptime(&self) -> Option<i64> {
+        self.uptime
+    }
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct DiskMetricsRow {
+    id: i64,
+    client_id: i32,
+    timestamp: i64,
+    mount: String,
+    total: i64,
+    used: i64,
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct NetworkMetricsRow {
+    id: i64,
+    client_id: i32,
+    timestamp: i64,
+    interface: String,
+    rx_bytes: i64,
+    tx_bytes: i64,
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct MetricsRollupRow {
+    client_id: i32,
+    resolution: i64,
+    timestamp: i64,
+    samples: i64,
+    cpu_usage_min: Option<f64>,
+    cpu_usage_avg: Option<f64>,
+    cpu_usage_max: Option<f64>,
+    load1_min: Option<f64>,
+    load1_avg: Option<f64>,
+    load1_max: Option<f64>,
+    memory_used_min: Option<i64>,
+    memory_used_avg: Option<f64>,
+    memory_used_max: Option<i64>,
+    swap_used_min: Option<i64>,
+    swap_used_avg: Option<f64>,
+    swap_used_max: Option<i64>,
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct DiskMetricsRollupRow {
+    client_id: i32,
+    resolution: i64,
+    timestamp: i64,
+    mount: String,
+    samples: i64,
+    total: i64,
+    used_min: i64,
+    used_avg: f64,
+    used_max: i64,
+}
+
+/// Counters are cumulative, so traffic within bucket is `max - min` unless counter was reset.
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct NetworkMetricsRollupRow {
+    client_id: i32,
+    resolution: i64,
+    timestamp: i64,
+    interface: String,
+    samples: i64,
+    rx_bytes_min: i64,
+    rx_bytes_max: i64,
+    tx_bytes_min: i64,
+    tx_bytes_max: i64,
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct NotificationRow {
+    id: i64,
+    notifier: String,
+    payload: String,
+    attempts: i32,
+    next_attempt: i64,
+    created_at: i64,
+    last_error: Option<String>,
+}
+
+impl NotificationRow {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn get_payload(&self) -> &String {
+        &self.payload
+    }
+
+    pub fn get_attempts(&self) -> i32 {
+        self.attempts
+    }
+
+    pub fn get_created_at(&self) -> i64 {
+        self.created_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &[(&str, &str)] = &[
+        (v1::VERSION, include_str!("../tests/fixtures/v1.sql")),
+        (v2::VERSION, include_str!("../tests/fixtures/v2.sql")),
+        (v3::VERSION, include_str!("../tests/fixtures/v3.sql")),
+    ];
+
+    async fn load_fixture(fixture: &str) -> SqliteConnection {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::query(fixture).execute(&mut conn).await.unwrap();
+        conn
+    }
+
+    #[actix_rt::test]
+    async fn migrate_fixtures() {
+        for (version, fixture) in FIXTURES {
+            let mut conn = load_fixture(fixture).await;
+            assert_eq!(
+                get_schema_version(&mut conn).await.unwrap(),
+                Some(version.parse().unwrap())
+            );
+
+            migrate(&mut conn).await.unwrap();
+            assert_eq!(
+                get_schema_version(&mut conn).await.unwrap(),
+                Some(VERSION.parse().unwrap()),
+                "fixture v{}",
+                version
+            );
+
+            let clients: Vec<ClientRow> =
+                sqlx::query_as(r#"SELECT * FROM "clients" ORDER BY "id""#)
+                    .fetch_all(&mut conn)
+                    .await
+                    .unwrap();
+            assert_eq!(clients.len(), 2, "fixture v{}", version);
+            assert_eq!(
+                clients[0].get_uuid(),
+                "2f1c5c4e-52a6-4e3b-9f0a-0c6a2b6c1d01"
+            );
+            assert_eq!(clients[0].get_boot_time(), 1634000000);
+            assert_eq!(clients[0].get_last_seen(), 1634086400);
+            // v1 has no hostname at all, v2 keeps it in the dropped "hostname" table
+            let hostname = (*version != v1::VERSION).then(|| "web-1".to_string());
+            assert_eq!(clients[0].get_hostname(), &hostname, "fixture v{}", version);
+            assert_eq!(clients[1].get_hostname(), &None);
+            assert!(!table_exists(&mut conn, "hostname").await.unwrap());
+
+            let (raw_data,): (i64,) = sqlx::query_as(r#"SELECT COUNT(*) FROM "raw_data""#)
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            assert_eq!(raw_data, 3, "fixture v{}", version);
+
+            // Running again is a no-op
+            migrate(&mut conn).await.unwrap();
+        }
+    }
+
+    #[actix_rt::test]
+    async fn create_empty() {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        assert_eq!(get_schema_version(&mut conn).await.unwrap(), None);
+        migrate(&mut conn).await.unwrap();
+        assert_eq!(
+            get_schema_version(&mut conn).await.unwrap(),
+            Some(VERSION.parse().unwrap())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn refuse_newer_schema() {
+        let mut conn = load_fixture(FIXTURES[2].1).await;
+        let newer = VERSION.parse::<u32>().unwrap() + 1;
+        sqlx::query(r#"UPDATE "pbs_meta" SET "value" = ? WHERE "key" = 'version'"#)
+            .bind(newer.to_string())
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        let err = migrate(&mut conn).await.unwrap_err();
+        assert!(err.to_string().contains("newer than supported"), "{}", err);
+
+        // Database is left untouched
+        assert_eq!(get_schema_version(&mut conn).await.unwrap(), Some(newer));
+    }
+}
+
+/// Period a client is offline, `started_at` is last seen time before outage.
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct IncidentRow {
+    id: i64,
+    client_id: i32,
+    started_at: i64,
+    detected_at: i64,
+    resolved_at: Option<i64>,
+    /// One of `timeout`, `unreachable`, `reboot` and `register`
+    cause: String,
+    acknowledged_at: Option<i64>,
+    /// Who acknowledged incident, e.g. telegram user or name given to admin API
+    acknowledged_by: Option<String>,
+    /// Count of escalation steps taken
+    escalation_level: i32,
+}
+
+impl IncidentRow {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn get_client_id(&self) -> i32 {
+        self.client_id
+    }
+
+    pub fn get_started_at(&self) -> i64 {
+        self.started_at
+    }
+
+    pub fn get_detected_at(&self) -> i64 {
+        self.detected_at
+    }
+
+    pub fn get_resolved_at(&self) -> Option<i64> {
+        self.resolved_at
+    }
+
+    pub fn get_cause(&self) -> &String {
+        &self.cause
+    }
+
+    pub fn get_acknowledged_at(&self) -> Option<i64> {
+        self.acknowledged_at
+    }
+
+    pub fn get_acknowledged_by(&self) -> &Option<String> {
+        &self.acknowledged_by
+    }
+
+    pub fn get_escalation_level(&self) -> i32 {
+        self.escalation_level
+    }
+}
+
+/// Heartbeat timeout set by admin for clients matching `tag`.
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct TimeoutOverrideRow {
+    tag: String,
+    timeout: i64,
+}
+
+impl TimeoutOverrideRow {
+    pub fn get_tag(&self) -> &String {
+        &self.tag
+    }
+
+    pub fn get_timeout(&self) -> i64 {
+        self.timeout
+    }
+}
+
+/// One-off window has `starts_at` and `ends_at`, recurring window has cron `schedule` and
+/// `duration`. Empty `clients` applies to all clients.
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct MaintenanceRow {
+    id: i64,
+    description: String,
+    clients: Json<Vec<String>>,
+    starts_at: Option<i64>,
+    ends_at: Option<i64>,
+    schedule: Option<String>,
+    duration: Option<i64>,
+    /// Start of current window, set by watchdog until summary of window is sent
+    active_since: Option<i64>,
+    created_at: i64,
+}
+
+impl MaintenanceRow {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn get_description(&self) -> &String {
+        &self.description
+    }
+
+    pub fn get_clients(&self) -> &Vec<String> {
+        &self.clients.0
+    }
+
+    pub fn get_starts_at(&self) -> Option<i64> {
+        self.starts_at
+    }
+
+    pub fn get_ends_at(&self) -> Option<i64> {
+        self.ends_at
+    }
+
+    pub fn get_schedule(&self) -> &Option<String> {
+        &self.schedule
+    }
+
+    pub fn get_duration(&self) -> Option<i64> {
+        self.duration
+    }
+
+    pub fn get_active_since(&self) -> Option<i64> {
+        self.active_since
+    }
+}
+
+/// Alert rule defined by admin API, rules in configure file are not stored.
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct AlertRuleRow {
+    id: i64,
+    name: String,
+    expression: String,
+    clients: Json<Vec<String>>,
+    created_at: i64,
+}
+
+impl AlertRuleRow {
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn get_expression(&self) -> &String {
+        &self.expression
+    }
+
+    pub fn get_clients(&self) -> &Vec<String> {
+        &self.clients.0
+    }
+}
+
+/// Alert is pending until condition holds for duration of rule, then it fires until resolved.
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct AlertRow {
+    id: i64,
+    rule: String,
+    client_id: i32,
+    /// Labels of series, e.g. `mount="/"`, empty for metrics without labels
+    series: String,
+    value: f64,
+    pending_since: i64,
+    fired_at: Option<i64>,
+    resolved_at: Option<i64>,
+}
+
+impl AlertRow {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn get_rule(&self) -> &String {
+        &self.rule
+    }
+
+    pub fn get_series(&self) -> &String {
+        &self.series
+    }
+
+    pub fn get_value(&self) -> f64 {
+        self.value
+    }
+
+    pub fn get_pending_since(&self) -> i64 {
+        self.pending_since
+    }
+
+    pub fn get_fired_at(&self) -> Option<i64> {
+        self.fired_at
+    }
 }
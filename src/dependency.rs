@@ -0,0 +1,219 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::clienttimeout::TimeoutPolicy;
+use crate::database::ClientRow;
+use std::collections::{HashMap, HashSet};
+
+/// Parent/child relations of clients with their online state at one point of time.
+///
+/// Child of an offline parent cannot be told apart from down, so its outage is folded into
+/// the parent's one and recorded as unreachable.
+pub struct Dependencies {
+    parents: HashMap<i32, i32>,
+    offline: HashSet<i32>,
+}
+
+/// Map of client id to id of its parent.
+pub fn get_parents(clients: &[ClientRow]) -> HashMap<i32, i32> {
+    clients
+        .iter()
+        .filter_map(|client| {
+            client
+                .get_parent_id()
+                .map(|parent| (client.get_id(), parent))
+        })
+        .collect()
+}
+
+impl Dependencies {
+    pub fn new(clients: &[ClientRow], policy: &TimeoutPolicy, timestamp: i64) -> Self {
+        Self::from_parts(
+            get_parents(clients),
+            clients
+                .iter()
+                .filter(|client| !client.get_retired() && !policy.is_online(client, timestamp))
+                .map(|client| client.get_id())
+                .collect(),
+        )
+    }
+
+    /// Build from map of client id to parent id and ids of offline clients.
+    pub fn from_parts(parents: HashMap<i32, i32>, offline: HashSet<i32>) -> Self {
+        Self { parents, offline }
+    }
+
+    /// Top-most offline ancestor of client, which outage of client is folded into.
+    pub fn get_offline_ancestor(&self, id: i32) -> Option<i32> {
+        let mut visited = HashSet::new();
+        let mut current = id;
+        let mut ancestor = None;
+        visited.insert(id);
+        while let Some(&parent) = self.parents.get(&current) {
+            if !visited.insert(parent) {
+                break;
+            }
+            if self.offline.contains(&parent) {
+                ancestor = Some(parent);
+            }
+            current = parent;
+        }
+        ancestor
+    }
+}
+
+/// Whether setting `parent_id` as parent of client `id` makes a dependency loop, `parents`
+/// is built by [`get_parents`].
+pub fn creates_cycle(parents: &HashMap<i32, i32>, id: i32, parent_id: i32) -> bool {
+    let mut visited = HashSet::new();
+    let mut current = parent_id;
+    loop {
+        if current == id {
+            return true;
+        }
+        if !visited.insert(current) {
+            return false;
+        }
+        match parents.get(&current) {
+            Some(&parent) => current = parent,
+            None => return false,
+        }
+    }
+}
+
+/// Track unreachable clients whose ancestors are back, a client still offline after its
+/// timeout since then is down by itself.
+#[derive(Default)]
+pub struct UnreachableTracker {
+    reachable_since: HashMap<i32, i64>,
+}
+
+impl UnreachableTracker {
+    /// Called for every offline client with unreachable incident, return true when the client
+    /// should be reported as down.
+    pub fn is_down(
+        &mut self,
+        dependencies: &Dependencies,
+        id: i32,
+        timeout: i64,
+        timestamp: i64,
+    ) -> bool {
+        if dependencies.get_offline_ancestor(id).is_some() {
+            self.reachable_since.remove(&id);
+            return false;
+        }
+        let since = *self.reachable_since.entry(id).or_insert(timestamp);
+        if timestamp - since > timeout {
+            self.reachable_since.remove(&id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Client is back or no longer unreachable.
+    pub fn forget(&mut self, id: i32) {
+        self.reachable_since.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parents(pairs: &[(i32, i32)]) -> HashMap<i32, i32> {
+        pairs.iter().copied().collect()
+    }
+
+    fn build(pairs: &[(i32, i32)], offline: &[i32]) -> Dependencies {
+        Dependencies::from_parts(parents(pairs), offline.iter().copied().collect())
+    }
+
+    #[test]
+    fn cycle() {
+        // 3 -> 2 -> 1
+        let chain = parents(&[(3, 2), (2, 1)]);
+        assert!(creates_cycle(&chain, 1, 1));
+        assert!(creates_cycle(&chain, 1, 3));
+        assert!(creates_cycle(&chain, 1, 2));
+        assert!(creates_cycle(&chain, 2, 3));
+        assert!(!creates_cycle(&chain, 3, 1));
+        assert!(!creates_cycle(&chain, 4, 3));
+        assert!(!creates_cycle(&chain, 1, 4));
+        assert!(!creates_cycle(&HashMap::new(), 1, 2));
+
+        // Existing loop not involving the client does not hang
+        let looped = parents(&[(5, 6), (6, 5)]);
+        assert!(!creates_cycle(&looped, 7, 5));
+        assert!(creates_cycle(&looped, 5, 6));
+    }
+
+    #[test]
+    fn offline_ancestor() {
+        // 4 -> 3 -> 2 -> 1
+        let chain = [(4, 3), (3, 2), (2, 1)];
+        let dependencies = build(&chain, &[1, 3]);
+        // Top-most offline ancestor takes the outage
+        assert_eq!(dependencies.get_offline_ancestor(4), Some(1));
+        assert_eq!(dependencies.get_offline_ancestor(3), Some(1));
+        assert_eq!(dependencies.get_offline_ancestor(2), Some(1));
+        assert_eq!(dependencies.get_offline_ancestor(1), None);
+        assert_eq!(dependencies.get_offline_ancestor(5), None);
+
+        let dependencies = build(&chain, &[3, 4]);
+        assert_eq!(dependencies.get_offline_ancestor(4), Some(3));
+        assert_eq!(dependencies.get_offline_ancestor(3), None);
+        assert_eq!(dependencies.get_offline_ancestor(2), None);
+
+        let dependencies = build(&chain, &[]);
+        assert_eq!(dependencies.get_offline_ancestor(4), None);
+
+        // Loop left in database stops at the first repeated client
+        let dependencies = build(&[(1, 2), (2, 1)], &[2]);
+        assert_eq!(dependencies.get_offline_ancestor(1), Some(2));
+        assert_eq!(dependencies.get_offline_ancestor(2), None);
+    }
+
+    #[test]
+    fn unreachable_tracker() {
+        let behind_offline = build(&[(2, 1)], &[1, 2]);
+        let parent_back = build(&[(2, 1)], &[2]);
+        let mut tracker = UnreachableTracker::default();
+
+        assert!(!tracker.is_down(&behind_offline, 2, 60, 0));
+        // Timeout counts from the first check after parent is back
+        assert!(!tracker.is_down(&parent_back, 2, 60, 100));
+        assert!(!tracker.is_down(&parent_back, 2, 60, 160));
+        assert!(tracker.is_down(&parent_back, 2, 60, 161));
+        // Reported once, tracking starts over afterwards
+        assert!(!tracker.is_down(&parent_back, 2, 60, 170));
+
+        // Parent going offline again resets the timer
+        assert!(!tracker.is_down(&behind_offline, 2, 60, 200));
+        assert!(!tracker.is_down(&parent_back, 2, 60, 300));
+        assert!(!tracker.is_down(&parent_back, 2, 60, 350));
+        assert!(tracker.is_down(&parent_back, 2, 60, 361));
+
+        // Forgotten client starts over
+        assert!(!tracker.is_down(&parent_back, 2, 60, 400));
+        tracker.forget(2);
+        assert!(!tracker.is_down(&parent_back, 2, 60, 470));
+        assert!(tracker.is_down(&parent_back, 2, 60, 531));
+    }
+}
@@ -0,0 +1,286 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::configparser::{Config, EscalationStep};
+use crate::maintenance::MaintenanceSet;
+use crate::notifier::{ClientInfo, Event};
+use crate::storage::{IncidentCause, Storage};
+use log::{debug, warn};
+
+/// Escalation chain of offline incidents, built from `[[escalation]]` sections of configure
+/// file. Steps are taken in order of their delay until incident is acknowledged or resolved.
+#[derive(Clone, Debug)]
+pub struct EscalationPolicy {
+    steps: Vec<EscalationStep>,
+}
+
+impl EscalationPolicy {
+    /// Warn about steps naming notifiers which do not exist.
+    pub fn check_notifiers(&self, names: &[&str]) {
+        for step in self.steps.iter() {
+            for notifier in step.get_notifiers() {
+                if !names.contains(&notifier.as_str()) {
+                    warn!("Escalation step names unknown notifier {}", notifier);
+                }
+            }
+        }
+    }
+
+    /// Take due steps of open incidents, return escalation events to send.
+    ///
+    /// Unreachable clients are covered by their parent, muted, flapping and maintained
+    /// clients are not escalated until they are notified as usual again.
+    pub async fn check(
+        &self,
+        storage: &dyn Storage,
+        maintenance: &MaintenanceSet,
+        timestamp: i64,
+    ) -> anyhow::Result<Vec<Event>> {
+        let mut events = Vec::new();
+        if self.steps.is_empty() {
+            return Ok(events);
+        }
+        for incident in storage.list_open_incidents().await? {
+            if incident.get_acknowledged_at().is_some()
+                || incident.get_cause() != IncidentCause::Timeout.get_name()
+            {
+                continue;
+            }
+            let level = incident.get_escalation_level();
+            let step = match self.steps.get(level as usize) {
+                Some(step) => step,
+                None => continue,
+            };
+            if timestamp - incident.get_detected_at() < step.get_after() {
+                continue;
+            }
+            let client = match storage.get_client(incident.get_client_id()).await? {
+                Some(client) if !client.get_retired() => client,
+                _ => continue,
+            };
+            if client.is_muted(timestamp)
+                || client.get_flapping_since().is_some()
+                || maintenance.covers(&client, timestamp)
+            {
+                debug!(
+                    "Client {} is not notified now, skip escalation",
+                    client.get_id()
+                );
+                continue;
+            }
+            storage
+                .set_escalation_level(incident.get_id(), level + 1)
+                .await?;
+            events.push(Event::Escalation {
+                client: ClientInfo::from(&client)
+                    .with_downtime(Some(timestamp - incident.get_started_at()))
+                    .with_incident(incident.get_id()),
+                level: level + 1,
+                notifiers: step.get_notifiers().to_vec(),
+            });
+        }
+        Ok(events)
+    }
+}
+
+impl From<&Config> for EscalationPolicy {
+    fn from(cfg: &Config) -> Self {
+        let mut steps = cfg.get_escalation().to_vec();
+        steps.sort_by_key(|step| step.get_after());
+        Self { steps }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configparser::tests::config_with;
+    use std::sync::Arc;
+
+    const DETECTED_AT: i64 = 10_000;
+
+    fn policy() -> EscalationPolicy {
+        // Steps are listed out of order on purpose
+        EscalationPolicy::from(&config_with(
+            "[[escalation]]\nafter = 900\nnotifiers = [\"ops\"]\n\n[[escalation]]\nafter = 300\n",
+        ))
+    }
+
+    async fn open_incident(storage: &dyn Storage, uuid: &str, cause: IncidentCause) -> (i32, i64) {
+        let id = storage
+            .register_client(uuid, 0, None, 0)
+            .await
+            .unwrap()
+            .get_id();
+        let incident = storage
+            .open_incident(id, DETECTED_AT - 120, DETECTED_AT, cause)
+            .await
+            .unwrap();
+        (id, incident)
+    }
+
+    /// Return (client id, level, notifiers) of escalation events.
+    async fn check_with(
+        policy: &EscalationPolicy,
+        storage: &Arc<dyn Storage>,
+        maintenance: &MaintenanceSet,
+        after: i64,
+    ) -> Vec<(i32, i32, Vec<String>)> {
+        policy
+            .check(storage.as_ref(), maintenance, DETECTED_AT + after)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| match event {
+                Event::Escalation {
+                    client,
+                    level,
+                    notifiers,
+                } => (client.get_id(), level, notifiers),
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect()
+    }
+
+    async fn check(
+        policy: &EscalationPolicy,
+        storage: &Arc<dyn Storage>,
+        after: i64,
+    ) -> Vec<(i32, i32, Vec<String>)> {
+        check_with(
+            policy,
+            storage,
+            &MaintenanceSet::empty(chrono_tz::Tz::UTC),
+            after,
+        )
+        .await
+    }
+
+    #[actix_rt::test]
+    async fn steps_in_order() {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        let (id, incident) =
+            open_incident(storage.as_ref(), "client", IncidentCause::Timeout).await;
+        let policy = policy();
+
+        assert!(check(&policy, &storage, 299).await.is_empty());
+        assert_eq!(check(&policy, &storage, 300).await, vec![(id, 1, vec![])]);
+        // Each step is taken once
+        assert!(check(&policy, &storage, 301).await.is_empty());
+        assert!(check(&policy, &storage, 899).await.is_empty());
+        assert_eq!(
+            check(&policy, &storage, 900).await,
+            vec![(id, 2, vec!["ops".to_string()])]
+        );
+        assert!(check(&policy, &storage, 100_000).await.is_empty());
+        assert_eq!(
+            storage
+                .get_incident(incident)
+                .await
+                .unwrap()
+                .unwrap()
+                .get_escalation_level(),
+            2
+        );
+    }
+
+    #[actix_rt::test]
+    async fn late_check_takes_one_step() {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        let (id, _) = open_incident(storage.as_ref(), "client", IncidentCause::Timeout).await;
+        let policy = policy();
+
+        // Both steps are due, they still go out one per check
+        assert_eq!(check(&policy, &storage, 1000).await, vec![(id, 1, vec![])]);
+        assert_eq!(
+            check(&policy, &storage, 1000).await,
+            vec![(id, 2, vec!["ops".to_string()])]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn skipped_incidents() {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        let (_, acknowledged) =
+            open_incident(storage.as_ref(), "acknowledged", IncidentCause::Timeout).await;
+        storage
+            .acknowledge_incident(acknowledged, "admin", DETECTED_AT + 10)
+            .await
+            .unwrap();
+        open_incident(storage.as_ref(), "unreachable", IncidentCause::Unreachable).await;
+        let (muted, _) = open_incident(storage.as_ref(), "muted", IncidentCause::Timeout).await;
+        storage
+            .set_muted_until(muted, Some(DETECTED_AT + 10_000))
+            .await
+            .unwrap();
+        let (retired, _) = open_incident(storage.as_ref(), "retired", IncidentCause::Timeout).await;
+        storage.set_retired(retired, true).await.unwrap();
+        let (flapping, _) =
+            open_incident(storage.as_ref(), "flapping", IncidentCause::Timeout).await;
+        storage
+            .set_flapping_since(flapping, Some(DETECTED_AT))
+            .await
+            .unwrap();
+        let policy = policy();
+
+        assert!(check(&policy, &storage, 1000).await.is_empty());
+
+        // Muted client is escalated once mute expires
+        assert_eq!(
+            check(&policy, &storage, 10_000).await,
+            vec![(muted, 1, vec![])]
+        );
+
+        // No steps configured
+        let (id, _) = open_incident(storage.as_ref(), "client", IncidentCause::Timeout).await;
+        let empty = EscalationPolicy::from(&config_with(""));
+        assert!(check(&empty, &storage, 1000).await.is_empty());
+        assert_eq!(check(&policy, &storage, 1000).await, vec![(id, 1, vec![])]);
+    }
+
+    #[actix_rt::test]
+    async fn maintenance() {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        let (id, _) = open_incident(storage.as_ref(), "client", IncidentCause::Timeout).await;
+        storage
+            .insert_maintenance(
+                &serde_json::from_value(serde_json::json!({
+                    "clients": ["client"],
+                    "ends_at": DETECTED_AT + 2000,
+                }))
+                .unwrap(),
+                DETECTED_AT,
+            )
+            .await
+            .unwrap();
+        let maintenance = MaintenanceSet::load(storage.as_ref(), chrono_tz::Tz::UTC)
+            .await
+            .unwrap();
+        let policy = policy();
+
+        // Steps wait until window ends
+        assert!(check_with(&policy, &storage, &maintenance, 1000)
+            .await
+            .is_empty());
+        assert_eq!(
+            check_with(&policy, &storage, &maintenance, 2000).await,
+            vec![(id, 1, vec![])]
+        );
+    }
+}
@@ -0,0 +1,276 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::configparser::Config;
+use crate::database::ClientRow;
+use crate::notifier::{ClientInfo, Event};
+use crate::storage::Storage;
+
+/// What to do with notification of client state change.
+pub enum FlapState {
+    /// Client is not flapping, notify as usual
+    Normal,
+    /// Client starts flapping, send this event instead
+    Started(Box<Event>),
+    /// Client is flapping, drop notification
+    Suppressed,
+}
+
+/// Detect clients flapping between online and offline, built from `[watchdog.flapping]`
+/// section of configure file.
+///
+/// State changes are counted from incidents, every incident is one change to offline and
+/// one change back to online once it is resolved.
+#[derive(Clone, Debug)]
+pub struct FlapDetector {
+    transitions: i64,
+    window: i64,
+    stable: i64,
+}
+
+impl FlapDetector {
+    /// Count state changes of client after `since`, return count and time of the last change.
+    async fn count_transitions(
+        storage: &dyn Storage,
+        client_id: i32,
+        since: i64,
+        until: i64,
+    ) -> anyhow::Result<(i64, i64)> {
+        let mut count = 0;
+        let mut last = since;
+        for incident in storage
+            .list_incidents_between(client_id, since, until)
+            .await?
+        {
+            if incident.get_detected_at() >= since {
+                count += 1;
+                last = last.max(incident.get_detected_at());
+            }
+            if let Some(resolved_at) = incident.get_resolved_at() {
+                if resolved_at >= since {
+                    count += 1;
+                    last = last.max(resolved_at);
+                }
+            }
+        }
+        Ok((count, last))
+    }
+
+    /// Called after state change of client is recorded as incident.
+    pub async fn on_transition(
+        &self,
+        storage: &dyn Storage,
+        client: &ClientRow,
+        timestamp: i64,
+    ) -> anyhow::Result<FlapState> {
+        if client.get_flapping_since().is_some() {
+            return Ok(FlapState::Suppressed);
+        }
+        if self.transitions <= 0 {
+            return Ok(FlapState::Normal);
+        }
+        let (transitions, _) =
+            Self::count_transitions(storage, client.get_id(), timestamp - self.window, timestamp)
+                .await?;
+        if transitions < self.transitions {
+            return Ok(FlapState::Normal);
+        }
+        storage
+            .set_flapping_since(client.get_id(), Some(timestamp))
+            .await?;
+        Ok(FlapState::Started(Box::new(Event::Flapping {
+            client: ClientInfo::from(client),
+            transitions,
+            window: self.window,
+        })))
+    }
+
+    /// Clear flapping mark of client if its state has not changed for `stable` seconds,
+    /// return the recovery event to send.
+    pub async fn check_stable(
+        &self,
+        storage: &dyn Storage,
+        client: &ClientRow,
+        online: bool,
+        timestamp: i64,
+    ) -> anyhow::Result<Option<Event>> {
+        let since = match client.get_flapping_since() {
+            Some(since) => since,
+            None => return Ok(None),
+        };
+        let (transitions, last) =
+            Self::count_transitions(storage, client.get_id(), since, timestamp).await?;
+        if timestamp - last < self.stable {
+            return Ok(None);
+        }
+        storage.set_flapping_since(client.get_id(), None).await?;
+        Ok(Some(Event::Stable {
+            client: ClientInfo::from(client),
+            online,
+            transitions,
+            duration: timestamp - since,
+        }))
+    }
+}
+
+impl From<&Config> for FlapDetector {
+    fn from(cfg: &Config) -> Self {
+        let flapping = cfg.get_watchdog().get_flapping();
+        Self {
+            transitions: flapping.get_transitions(),
+            window: flapping.get_window(),
+            stable: flapping.get_stable(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configparser::tests::config_with;
+    use crate::storage::IncidentCause;
+
+    fn detector(transitions: i64) -> FlapDetector {
+        FlapDetector::from(&config_with(&format!(
+            "[watchdog.flapping]\ntransitions = {}\nwindow = 1000\nstable = 300\n",
+            transitions
+        )))
+    }
+
+    /// Incidents of one client around window `[1000, 2000]`, as (started, detected, resolved).
+    async fn storage_with_incidents() -> (std::sync::Arc<dyn Storage>, i32) {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        let id = storage
+            .register_client("client", 0, None, 0)
+            .await
+            .unwrap()
+            .get_id();
+        for (started, detected, resolved) in [
+            // Over before window, not counted
+            (100, 150, Some(900)),
+            // Straddles start of window, only coming back is counted
+            (500, 600, Some(1100)),
+            // Last seen before window but detected in it, both counted
+            (990, 1150, Some(1300)),
+            // Still offline, going offline is counted
+            (1900, 1950, None),
+        ] {
+            let incident = storage
+                .open_incident(id, started, detected, IncidentCause::Timeout)
+                .await
+                .unwrap();
+            if let Some(resolved) = resolved {
+                storage
+                    .resolve_incident(incident, resolved, None)
+                    .await
+                    .unwrap();
+            }
+        }
+        (storage, id)
+    }
+
+    #[actix_rt::test]
+    async fn count_transitions() {
+        let (storage, id) = storage_with_incidents().await;
+        assert_eq!(
+            FlapDetector::count_transitions(storage.as_ref(), id, 1000, 2000)
+                .await
+                .unwrap(),
+            (4, 1950)
+        );
+        assert_eq!(
+            FlapDetector::count_transitions(storage.as_ref(), id, 1200, 2000)
+                .await
+                .unwrap(),
+            (2, 1950)
+        );
+        // No change since, last change defaults to start of window
+        assert_eq!(
+            FlapDetector::count_transitions(storage.as_ref(), id, 1960, 2000)
+                .await
+                .unwrap(),
+            (0, 1960)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn start_and_stop_flapping() {
+        let (storage, id) = storage_with_incidents().await;
+        let client = storage.get_client(id).await.unwrap().unwrap();
+        assert!(matches!(
+            detector(5)
+                .on_transition(storage.as_ref(), &client, 2000)
+                .await
+                .unwrap(),
+            FlapState::Normal
+        ));
+        assert!(matches!(
+            detector(0)
+                .on_transition(storage.as_ref(), &client, 2000)
+                .await
+                .unwrap(),
+            FlapState::Normal
+        ));
+
+        let detector = detector(4);
+        match detector
+            .on_transition(storage.as_ref(), &client, 2000)
+            .await
+            .unwrap()
+        {
+            FlapState::Started(event) => match *event {
+                Event::Flapping {
+                    transitions,
+                    window,
+                    ..
+                } => assert_eq!((transitions, window), (4, 1000)),
+                event => panic!("unexpected event {:?}", event),
+            },
+            _ => panic!("client should start flapping"),
+        }
+        let client = storage.get_client(id).await.unwrap().unwrap();
+        assert_eq!(client.get_flapping_since(), Some(2000));
+        assert!(matches!(
+            detector
+                .on_transition(storage.as_ref(), &client, 2010)
+                .await
+                .unwrap(),
+            FlapState::Suppressed
+        ));
+
+        // Stable once no state change for 300 seconds since flapping started
+        assert!(detector
+            .check_stable(storage.as_ref(), &client, false, 2299)
+            .await
+            .unwrap()
+            .is_none());
+        match detector
+            .check_stable(storage.as_ref(), &client, false, 2300)
+            .await
+            .unwrap()
+        {
+            Some(Event::Stable {
+                online, duration, ..
+            }) => assert_eq!((online, duration), (false, 300)),
+            event => panic!("unexpected event {:?}", event),
+        }
+        let client = storage.get_client(id).await.unwrap().unwrap();
+        assert_eq!(client.get_flapping_since(), None);
+    }
+}
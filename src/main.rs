@@ -18,29 +18,47 @@
  ** along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
+mod admin;
+mod alert;
+mod bot;
+mod clienttimeout;
+mod clientversion;
 mod configparser;
 mod database;
+mod dependency;
+mod escalation;
+mod flapping;
+mod maintenance;
+mod metrics;
+mod notifier;
+mod retention;
+mod storage;
 mod structs;
+mod utils;
 
+use crate::alert::AlertEngine;
+use crate::clienttimeout::TimeoutPolicy;
+use crate::clientversion::VersionPolicy;
 use crate::configparser::Config;
+use crate::dependency::{Dependencies, UnreachableTracker};
+use crate::escalation::EscalationPolicy;
+use crate::flapping::{FlapDetector, FlapState};
+use crate::maintenance::MaintenanceSet;
+use crate::storage::{IncidentCause, Storage};
 use crate::structs::{AdditionalInfo, AdminResult, Response};
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
 use log::{debug, error, info};
-use sqlx::{Connection, Row, SqliteConnection};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::sync::Arc;
 use std::time::Duration;
-use teloxide::requests::{Request, Requester, RequesterExt};
-use teloxide::types::ParseMode;
-use teloxide::Bot;
-use tokio::sync::{mpsc, Mutex};
-use tokio_stream::StreamExt as _;
-
-const CLIENT_TIMEOUT: u32 = 7 * 60;
-const CLIENT_TIMEOUT_U64: u64 = CLIENT_TIMEOUT as u64;
-const DEFAULT_COMMAND_CHANNEL_TIMEOUT: u64 = 10;
+use tokio::sync::mpsc;
+
+const WATCHDOG_SWEEP_INTERVAL: u64 = 10;
+const NOTIFICATION_RETRY_INTERVAL: u64 = 5;
+/// Boot time differs less than this is considered clock jitter rather than reboot
+const REBOOT_TOLERANCE: i64 = 120;
 use structs::SERVER_VERSION;
-const MINIMUM_CLIENT_VERSION: &str = "1.6.1";
-const DEFAULT_HOSTNAME: &str = "(no hostname)";
 
 fn get_current_timestamp() -> u64 {
     let start = std::time::SystemTime::now();
@@ -51,56 +69,63 @@ fn get_current_timestamp() -> u64 {
 }
 
 struct ExtraData {
-    conn: SqliteConnection,
-    bot_tx: mpsc::Sender<Command>,
+    storage: Arc<dyn Storage>,
+    timeout_policy: TimeoutPolicy,
+    /// Timezone of notifications and maintenance schedules
+    timezone: chrono_tz::Tz,
+    alert_engine: AlertEngine,
+}
+
+/// Senders to background tasks, given to request handlers apart from [`ExtraData`].
+#[derive(Clone)]
+struct Channels {
     watchdog_tx: mpsc::Sender<Command>,
+    bot_tx: mpsc::Sender<Command>,
+}
+
+/// How client checked in, tells watchdog which notification to send.
+#[derive(Debug, Clone, Copy)]
+enum CheckIn {
+    Heartbeat,
+    /// Register command without reboot, `new` is true for first register of client
+    Register { new: bool },
+    /// Boot time changed, detected by register command or uptime in heartbeat
+    Reboot {
+        previous_boot_time: i64,
+        previous_last_seen: i64,
+    },
 }
 
 #[derive(Debug)]
 enum Command {
-    StringData(String),
-    MachineID((i32, bool)),
+    Notify(Box<notifier::Event>),
+    MachineID((i32, CheckIn)),
     Terminate,
 }
 
-/*impl Command {
-    fn new<T: Into<String>>(s: T) -> Command
-    {
-        Command::StringData(s.into())
-    }
-}*/
-
 async fn process_send_message(
-    bot_token: String,
-    api_server: Option<String>,
-    owner: i64,
+    storage: Arc<dyn Storage>,
+    notifiers: Vec<Box<dyn notifier::Notifier>>,
     mut rx: mpsc::Receiver<Command>,
 ) -> anyhow::Result<()> {
-    if bot_token.is_empty() {
-        info!("Token is empty, skipped all send message request.");
-        while let Some(cmd) = rx.recv().await {
-            match cmd {
-                Command::Terminate => break,
-                _ => {}
-            }
-        }
-        return Ok(())
+    let mut queue = notifier::NotificationQueue::new(storage, notifiers);
+    if queue.is_empty() {
+        info!("No notifier configured, skipped all send message request.");
     }
-    let bot = Bot::new(bot_token);
-    let bot = match api_server {
-        Some(api) => bot.set_api_url(api.parse()?),
-        None => bot,
-    };
-    let bot = bot.parse_mode(ParseMode::Html);
-    while let Some(cmd) = rx.recv().await {
-        match cmd {
-            Command::StringData(text) => {
-                if let Err(e) = bot.send_message(owner, text).send().await {
-                    error!("Got error in send message {:?}", e);
-                }
-            }
-            Command::Terminate => break,
-            _ => {}
+    if let Err(e) = queue.purge_unknown().await {
+        error!("Got error in clean up notification queue: {:?}", e);
+    }
+    loop {
+        // Wake up periodically to retry queued notifications
+        match tokio::time::timeout(Duration::from_secs(NOTIFICATION_RETRY_INTERVAL), rx.recv())
+            .await
+        {
+            Ok(Some(Command::Notify(event))) => queue.enqueue(&event).await,
+            Ok(Some(Command::Terminate)) | Ok(None) => break,
+            Ok(Some(_)) | Err(_) => {}
+        }
+        if let Err(e) = queue.deliver().await {
+            error!("Got error in deliver queued notification: {:?}", e);
         }
     }
     debug!("Send message daemon exiting...");
@@ -110,62 +135,49 @@ async fn process_send_message(
 async fn route_post(
     _req: HttpRequest,
     payload: web::Json<structs::Request>,
-    data: web::Data<Arc<Mutex<ExtraData>>>,
+    data: web::Data<Arc<ExtraData>>,
+    channels: web::Data<Channels>,
+    version_policy: web::Data<VersionPolicy>,
 ) -> actix_web::Result<HttpResponse> {
     let additional_info: AdditionalInfo = match payload.get_body() {
         None => Default::default(),
         Some(s) => {
-            if let Ok(info) = serde_json::from_str::<structs::AdditionalInfo>(s) {
-                info
-            } else {
-                Default::default()
-            }
+            serde_json::from_str::<structs::AdditionalInfo>(s).unwrap_or_default()
         }
     };
-    if payload
-        .get_version()
-        .lt(&MINIMUM_CLIENT_VERSION.to_string())
-    {
-        return Err(actix_web::error::ErrorBadRequest(Response::from(
-            structs::ErrorCodes::ClientVersionMismatch,
-        )));
+    if let Err(reason) = version_policy.check(payload.get_version()) {
+        return Err(actix_web::error::ErrorBadRequest(
+            Response::from_error_with_message(structs::ErrorCodes::ClientVersionMismatch, reason),
+        ));
     }
     {
-        let mut extra_data = data.lock().await;
+        let extra_data = data.get_ref();
         let mut new_machine = false;
-        let r = sqlx::query(r#"SELECT "id", "boot_time" FROM "clients" WHERE "uuid" = ?"#)
-            .bind(payload.get_uuid())
-            .fetch_one(&mut extra_data.conn)
-            .await;
-        let (id, boot_time) = if let Ok(row) = r {
-            (row.get(0), row.get(1))
-        } else if payload.get_action().eq("register") {
-            sqlx::query(
-                r#"INSERT INTO "clients" ("uuid", "boot_time", "last_seen", "hostname") VALUES (?, ?, ?, ?)"#,
-            )
-            .bind(payload.get_uuid())
-            .bind(additional_info.get_boot_time())
-            .bind(get_current_timestamp() as u32)
-            .bind({
-                let s: Option<String> =
-                    if additional_info.get_host_name().is_empty() {
-                        None
-                    } else {
-                        Some(additional_info.get_host_name().clone())
-                    };
-                s
-            })
-            .execute(&mut extra_data.conn)
+        let client = extra_data
+            .storage
+            .get_client_by_uuid(payload.get_uuid())
             .await
-            .unwrap();
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+        let (id, boot_time, last_seen) = if let Some(client) = client {
+            (client.get_id(), client.get_boot_time(), client.get_last_seen())
+        } else if payload.get_action().eq("register") {
+            let hostname = if additional_info.get_host_name().is_empty() {
+                None
+            } else {
+                Some(additional_info.get_host_name().as_str())
+            };
+            let client = extra_data
+                .storage
+                .register_client(
+                    payload.get_uuid(),
+                    additional_info.get_boot_time(),
+                    hostname,
+                    get_current_timestamp() as i64,
+                )
+                .await
+                .map_err(actix_web::error::ErrorInternalServerError)?;
             new_machine = true;
-            let r: (i32, i64) =
-                sqlx::query_as(r#"SELECT "id", "boot_time" FROM "clients" WHERE "uuid" = ?"#)
-                    .bind(payload.get_uuid())
-                    .fetch_one(&mut extra_data.conn)
-                    .await
-                    .unwrap();
-            r
+            (client.get_id(), client.get_boot_time(), client.get_last_seen())
         } else {
             return Err(actix_web::error::ErrorBadRequest(Response::from(
                 structs::ErrorCodes::NotRegister,
@@ -178,48 +190,112 @@ async fn route_post(
                     additional_info.get_host_name(),
                     payload.get_uuid()
                 );
-                if boot_time != additional_info.get_boot_time() || new_machine {
-                    if !new_machine {
-                        sqlx::query(r#"UPDATE "clients" SET "boot_time" = ?, "last_seen" = ? WHERE "id" = ?"#)
-                            .bind(additional_info.get_boot_time())
-                            .bind(get_current_timestamp() as i64)
-                            .bind(id)
-                            .execute(&mut extra_data.conn)
-                            .await
-                            .unwrap();
-                    }
+                let timestamp = get_current_timestamp() as i64;
+                if additional_info.get_interval().is_some() {
                     extra_data
-                        .watchdog_tx
-                        .send(Command::MachineID((id, true)))
+                        .storage
+                        .set_heartbeat_interval(id, additional_info.get_interval())
+                        .await
+                        .map_err(actix_web::error::ErrorInternalServerError)?;
+                }
+                if !additional_info.get_tags().is_empty() || !additional_info.get_labels().is_empty() {
+                    update_client_metadata(extra_data.storage.as_ref(), id, &additional_info)
                         .await
-                        .unwrap();
+                        .map_err(actix_web::error::ErrorInternalServerError)?;
                 }
+                let check_in = if new_machine {
+                    CheckIn::Register { new: true }
+                } else if (boot_time - additional_info.get_boot_time()).abs() > REBOOT_TOLERANCE {
+                    extra_data
+                        .storage
+                        .update_boot_time(id, additional_info.get_boot_time(), timestamp)
+                        .await
+                        .map_err(actix_web::error::ErrorInternalServerError)?;
+                    CheckIn::Reboot {
+                        previous_boot_time: boot_time,
+                        previous_last_seen: last_seen,
+                    }
+                } else {
+                    extra_data
+                        .storage
+                        .update_last_seen(id, timestamp)
+                        .await
+                        .map_err(actix_web::error::ErrorInternalServerError)?;
+                    CheckIn::Register { new: false }
+                };
+                channels
+                    .watchdog_tx
+                    .send(Command::MachineID((id, check_in)))
+                    .await
+                    .map_err(actix_web::error::ErrorInternalServerError)?;
             }
             "heartbeat" => {
                 debug!("Got heartbeat command from {}({})", id, payload.get_uuid());
+                let timestamp = get_current_timestamp() as i64;
                 // Update last seen
-                sqlx::query(r#"UPDATE "clients" SET "last_seen" = ? WHERE "id" = ? "#)
-                    .bind(get_current_timestamp() as u32)
-                    .bind(id)
-                    .execute(&mut extra_data.conn)
-                    .await
-                    .unwrap();
                 extra_data
-                    .watchdog_tx
-                    .send(Command::MachineID((id, false)))
+                    .storage
+                    .update_last_seen(id, timestamp)
                     .await
-                    .unwrap();
-
-                if payload.get_body().is_some() {
-                    sqlx::query(
-                        r#"INSERT INTO "raw_data" ("from", "data", "timestamp") VALUES (?, ?, ?)"#,
+                    .map_err(actix_web::error::ErrorInternalServerError)?;
+                let statistics = payload
+                    .get_body()
+                    .as_ref()
+                    .and_then(|body| metrics::Statistics::from_body(body));
+                let check_in = match statistics.as_ref().and_then(|s| s.get_uptime()) {
+                    Some(uptime) => detect_reboot(
+                        extra_data.storage.as_ref(),
+                        id,
+                        (boot_time, last_seen),
+                        uptime,
+                        timestamp,
                     )
-                    .bind(id)
-                    .bind(payload.get_body().clone().unwrap())
-                    .bind(get_current_timestamp() as u32)
-                    .execute(&mut extra_data.conn)
                     .await
-                    .unwrap();
+                    .map_err(actix_web::error::ErrorInternalServerError)?,
+                    None => CheckIn::Heartbeat,
+                };
+                channels
+                    .watchdog_tx
+                    .send(Command::MachineID((id, check_in)))
+                    .await
+                    .map_err(actix_web::error::ErrorInternalServerError)?;
+
+                if let Some(body) = payload.get_body() {
+                    // Known statistics go to metrics tables, only the rest is kept as raw data
+                    let raw_data = match statistics {
+                        Some(statistics) => {
+                            extra_data
+                                .storage
+                                .insert_metrics(id, &statistics, timestamp)
+                                .await
+                                .map_err(actix_web::error::ErrorInternalServerError)?;
+                            match extra_data
+                                .alert_engine
+                                .evaluate(extra_data.storage.as_ref(), id, &statistics, timestamp)
+                                .await
+                            {
+                                Ok(events) => {
+                                    for event in events {
+                                        channels
+                                            .bot_tx
+                                            .send(Command::Notify(Box::new(event)))
+                                            .await
+                                            .map_err(actix_web::error::ErrorInternalServerError)?;
+                                    }
+                                }
+                                Err(e) => error!("Got error while evaluating alert rules: {:?}", e),
+                            }
+                            statistics.get_unknown_fields()
+                        }
+                        None => Some(body.clone()),
+                    };
+                    if let Some(raw_data) = raw_data {
+                        extra_data
+                            .storage
+                            .insert_raw_data(id, &raw_data, timestamp)
+                            .await
+                            .map_err(actix_web::error::ErrorInternalServerError)?;
+                    }
                 }
             }
             _ => return Err(actix_web::error::ErrorBadRequest("Method not allowed")),
@@ -228,42 +304,130 @@ async fn route_post(
     Ok(HttpResponse::Ok().json(Response::new_ok()))
 }
 
+/// Reboot between heartbeats without register, uptime goes backwards and boot time moves.
+async fn detect_reboot(
+    storage: &dyn Storage,
+    id: i32,
+    (boot_time, last_seen): (i64, i64),
+    uptime: i64,
+    timestamp: i64,
+) -> anyhow::Result<CheckIn> {
+    let new_boot_time = timestamp - uptime;
+    // Client registered without boot time, learn it from the first heartbeat
+    if boot_time == 0 {
+        storage.update_boot_time(id, new_boot_time, timestamp).await?;
+        return Ok(CheckIn::Heartbeat);
+    }
+    if (new_boot_time - boot_time).abs() <= REBOOT_TOLERANCE {
+        return Ok(CheckIn::Heartbeat);
+    }
+    let previous = storage
+        .query_metrics(id, &structs::HistoryQuery::new_latest(1))
+        .await?;
+    match previous.first().and_then(|row| row.get_uptime()) {
+        Some(previous_uptime) if uptime < previous_uptime => {
+            storage.update_boot_time(id, new_boot_time, timestamp).await?;
+            Ok(CheckIn::Reboot {
+                previous_boot_time: boot_time,
+                previous_last_seen: last_seen,
+            })
+        }
+        _ => Ok(CheckIn::Heartbeat),
+    }
+}
+
+/// Merge tags and labels reported on register, labels already set (e.g. by admin) are kept.
+async fn update_client_metadata(
+    storage: &dyn Storage,
+    id: i32,
+    additional_info: &AdditionalInfo,
+) -> anyhow::Result<()> {
+    let client = match storage.get_client(id).await? {
+        Some(client) => client,
+        None => return Ok(()),
+    };
+    let mut tags = client.get_tags().clone();
+    for tag in additional_info.get_tags() {
+        if !tag.is_empty() && !tags.contains(tag) {
+            tags.push(tag.clone());
+        }
+    }
+    if tags.len() != client.get_tags().len() {
+        storage.set_tags(id, &tags).await?;
+    }
+    let mut labels = client.get_labels().clone();
+    for (key, value) in additional_info.get_labels() {
+        labels.entry(key.clone()).or_insert_with(|| value.clone());
+    }
+    if labels.len() != client.get_labels().len() {
+        storage.set_labels(id, &labels).await?;
+    }
+    Ok(())
+}
+
+fn require_client(payload: &structs::AdminRequest) -> actix_web::Result<&structs::ClientKey> {
+    payload.get_client().as_ref().ok_or_else(|| {
+        actix_web::error::ErrorBadRequest(Response::from(structs::ErrorCodes::InvalidParameter))
+    })
+}
+
 async fn route_admin_query(
     _req: HttpRequest,
     payload: web::Json<structs::AdminRequest>,
-    data: web::Data<Arc<Mutex<ExtraData>>>,
+    data: web::Data<Arc<ExtraData>>,
 ) -> actix_web::Result<HttpResponse> {
-    let mut ext = data.lock().await;
-    let timeout_timestamp = (get_current_timestamp() - CLIENT_TIMEOUT_U64) as i64;
+    let ext = data.get_ref();
     let resp = match payload.get_action().as_str() {
-        "query_online" => {
-            let r: Vec<database::ClientRow> =
-                sqlx::query_as(r#"SELECT * FROM "clients" WHERE "last_seen" > ?"#)
-                    .bind(timeout_timestamp)
-                    .fetch_all(&mut ext.conn)
-                    .await
-                    .unwrap();
-            let mut output = Vec::new();
-            for row in r {
-                output.push(row)
-            }
-            AdminResult::new_ok(output)
-        }
-        "query_online_num" => {
-            let r: (i64,) = sqlx::query_as(r#"SELECT COUNT(*) FROM "clients" WHERE "last_seen" > ?"#)
-                .bind(timeout_timestamp)
-                .fetch_one(&mut ext.conn)
+        "query_online" => AdminResult::new_ok(
+            admin::list_online(ext.storage.as_ref(), &ext.timeout_policy)
                 .await
-                .unwrap();
-           AdminResult::new_ok(r.0)
-        },
-        "query" => {
-            let r: Vec<database::ClientRow> =
-                sqlx::query_as(r#"SELECT * FROM "clients""#)
-                    .fetch_all(&mut ext.conn)
+                .map_err(actix_web::error::ErrorInternalServerError)?,
+        ),
+        "query_online_num" => AdminResult::new_ok(
+            admin::list_online(ext.storage.as_ref(), &ext.timeout_policy)
+                .await
+                .map_err(actix_web::error::ErrorInternalServerError)?
+                .len(),
+        ),
+        "query" => AdminResult::new_ok(
+            ext.storage
+                .list_clients()
+                .await
+                .map_err(actix_web::error::ErrorInternalServerError)?,
+        ),
+        "delete" => {
+            let client = admin::resolve_client(ext.storage.as_ref(), require_client(&payload)?).await?;
+            AdminResult::new_ok(
+                ext.storage
+                    .delete_client(client.get_id())
                     .await
-                    .unwrap();
-            AdminResult::new_ok(r)
+                    .map_err(actix_web::error::ErrorInternalServerError)?,
+            )
+        }
+        "rename" | "retire" | "unretire" | "set_timeout" => {
+            let patch = match payload.get_action().as_str() {
+                "retire" => structs::ClientPatch::new_retired(true),
+                "unretire" => structs::ClientPatch::new_retired(false),
+                _ => payload.get_patch().clone(),
+            };
+            AdminResult::new_ok(
+                admin::patch_client(ext.storage.as_ref(), require_client(&payload)?, &patch).await?,
+            )
+        }
+        action @ "query_raw_data"
+        | action @ "query_metrics"
+        | action @ "query_disks"
+        | action @ "query_network"
+        | action @ "query_incidents"
+        | action @ "query_alerts"
+        | action @ "query_uptime" => {
+            Ok(admin::query_history(
+                ext.storage.as_ref(),
+                require_client(&payload)?,
+                action.trim_start_matches("query_"),
+                payload.get_query(),
+            )
+            .await?)
         }
         _ => return Err(actix_web::error::ErrorBadRequest(Response::from(
             structs::ErrorCodes::UnsupportedMethod,
@@ -272,110 +436,357 @@ async fn route_admin_query(
     Ok(HttpResponse::Ok().json(resp.unwrap()))
 }
 
-async fn client_watchdog(
-    mut rx: mpsc::Receiver<Command>,
-    extra_data: Arc<Mutex<ExtraData>>,
-) -> anyhow::Result<()> {
-    use Command::*;
-    let mut conn = {
-        let mut extra = extra_data.lock().await;
-        let mut conn_ = SqliteConnection::connect("sqlite::memory:").await?;
-        sqlx::query(structs::CREATE_TABLES_WATCHDOG)
-            .execute(&mut conn_)
-            .await?;
-        let r: Vec<(i32,)> = sqlx::query_as(r#"SELECT "id" FROM "clients" WHERE "last_seen" > ?"#)
-            .bind((get_current_timestamp() - CLIENT_TIMEOUT_U64) as u32)
-            .fetch_all(&mut extra.conn)
-            .await?;
-        for item in r {
-            sqlx::query(r#"INSERT INTO "list" VALUES (?)"#)
-                .bind(item.0)
-                .execute(&mut conn_)
-                .await?;
+/// State of client watchdog, kept between check-ins and sweeps.
+struct Watchdog {
+    extra_data: Arc<ExtraData>,
+    bot_tx: mpsc::Sender<Command>,
+    flap_detector: FlapDetector,
+    escalation_policy: EscalationPolicy,
+    unreachable_tracker: UnreachableTracker,
+    maintenance: MaintenanceSet,
+}
+
+impl Watchdog {
+    /// Reload maintenance windows, previous windows are kept if database is unavailable.
+    async fn reload_maintenance(&mut self) -> bool {
+        match MaintenanceSet::load(self.extra_data.storage.as_ref(), self.extra_data.timezone)
+            .await
+        {
+            Ok(set) => {
+                self.maintenance = set;
+                true
+            }
+            Err(e) => {
+                error!("Unable to load maintenance windows, use previous ones: {:?}", e);
+                false
+            }
         }
-        conn_
-    };
-    debug!("Starting watchdog");
-    loop {
-        if let Ok(Some(cmd)) = tokio::time::timeout(Duration::from_secs(DEFAULT_COMMAND_CHANNEL_TIMEOUT), rx.recv()).await {
-            match cmd {
-                MachineID((id, from_register)) => {
-                    let items = sqlx::query(r#"SELECT * FROM "list" WHERE "id" = ?"#)
-                        .bind(id)
-                        .fetch_all(&mut conn)
+    }
+
+    /// Resolve incident of client checked in and notify its state change.
+    async fn on_check_in(&mut self, id: i32, check_in: CheckIn) -> anyhow::Result<()> {
+        let storage = &self.extra_data.storage;
+        let client = match storage.get_client(id).await? {
+            Some(client) if !client.get_retired() => client,
+            // Retired clients are not watched
+            _ => return Ok(()),
+        };
+        let current_time = get_current_timestamp() as i64;
+        let cause = match check_in {
+            CheckIn::Heartbeat => None,
+            CheckIn::Register { .. } => Some(IncidentCause::Register),
+            CheckIn::Reboot { .. } => Some(IncidentCause::Reboot),
+        };
+        self.unreachable_tracker.forget(id);
+        let mut was_unreachable = false;
+        let downtime = match storage.get_open_incident(id).await? {
+            Some(incident) => {
+                was_unreachable = incident.get_cause() == IncidentCause::Unreachable.get_name();
+                storage
+                    .resolve_incident(incident.get_id(), current_time, cause)
+                    .await?;
+                Some(current_time - incident.get_started_at())
+            }
+            None => match check_in {
+                // Reboot without offline period still goes to history
+                CheckIn::Reboot {
+                    previous_last_seen, ..
+                } => {
+                    let incident = storage
+                        .open_incident(id, previous_last_seen, current_time, IncidentCause::Reboot)
                         .await?;
-                    if items.is_empty() || from_register {
-                        if items.is_empty() {
-                            sqlx::query(r#"INSERT INTO "list" VALUES (?)"#)
-                                .bind(id)
-                                .execute(&mut conn)
-                                .await?;
-                        }
-                        let extra_data = extra_data.clone();
-                        let mut ext = extra_data.lock().await;
-                        let r: (String, Option<String>,) =
-                            sqlx::query_as(r#"SELECT "uuid", "hostname" FROM "clients" WHERE "id" = ?"#)
-                                .bind(id)
-                                .fetch_one(&mut ext.conn)
-                                .await?;
-                        ext.bot_tx
-                            .send(Command::StringData(format!(
-                                "<b>{}</b> ({}: <code>{}</code>) {}",
-                                r.1.unwrap_or_else(|| DEFAULT_HOSTNAME.to_string()),
-                                id,
-                                r.0,
-                                if from_register {"comes online with register command"} else {
-                                    "back online"
-                                }
-                            )))
+                    storage
+                        .resolve_incident(incident, current_time, None)
+                        .await?;
+                    Some(current_time - previous_last_seen)
+                }
+                _ => None,
+            },
+        };
+        let event = match check_in {
+            CheckIn::Reboot {
+                previous_boot_time,
+                previous_last_seen,
+            } => notifier::Event::Reboot {
+                client: notifier::ClientInfo::from(&client).with_downtime(downtime),
+                uptime: if previous_boot_time > 0 {
+                    Some(previous_last_seen - previous_boot_time)
+                } else {
+                    None
+                },
+            },
+            CheckIn::Register { new } if new || downtime.is_some() => notifier::Event::Online {
+                client: notifier::ClientInfo::from(&client).with_downtime(downtime),
+                from_register: true,
+            },
+            CheckIn::Heartbeat if downtime.is_some() => notifier::Event::Online {
+                client: notifier::ClientInfo::from(&client).with_downtime(downtime),
+                from_register: false,
+            },
+            _ => return Ok(()),
+        };
+        // Events other than first register are state changes
+        let event = if downtime.is_some() {
+            match self
+                .flap_detector
+                .on_transition(storage.as_ref(), &client, current_time)
+                .await?
+            {
+                FlapState::Normal => event,
+                FlapState::Started(flapping) => *flapping,
+                FlapState::Suppressed => {
+                    debug!(
+                        "Client {} is flapping, skip {} notification",
+                        id,
+                        event.get_kind()
+                    );
+                    return Ok(());
+                }
+            }
+        } else {
+            event
+        };
+        // Outage was folded into the parent's one, which tells when it is back
+        if was_unreachable && matches!(event, notifier::Event::Online { .. }) {
+            debug!("Client {} was unreachable, skip online notification", id);
+            return Ok(());
+        }
+        if client.is_muted(current_time) {
+            debug!(
+                "Client {} is muted, skip {} notification",
+                id,
+                event.get_kind()
+            );
+            return Ok(());
+        }
+        // Check-ins are frequent, windows are only loaded when there is something to send
+        self.reload_maintenance().await;
+        if self.maintenance.covers(&client, current_time) {
+            debug!(
+                "Client {} is in maintenance, skip {} notification",
+                id,
+                event.get_kind()
+            );
+            return Ok(());
+        }
+        self.bot_tx.send(Command::Notify(Box::new(event))).await?;
+        Ok(())
+    }
+
+    /// Open incidents of clients timed out and notify them as offline, escalate incidents not
+    /// acknowledged in time.
+    async fn sweep(&mut self) -> anyhow::Result<()> {
+        let loaded = self.reload_maintenance().await;
+        let storage = &self.extra_data.storage;
+        let policy = &self.extra_data.timeout_policy;
+        let maintenance = &self.maintenance;
+        let current_time = get_current_timestamp() as i64;
+        let incidents = storage.list_open_incidents().await?;
+        let down: HashSet<i32> = incidents
+            .iter()
+            .map(|incident| incident.get_client_id())
+            .collect();
+        let unreachable: HashMap<i32, i64> = incidents
+            .iter()
+            .filter(|incident| incident.get_cause() == IncidentCause::Unreachable.get_name())
+            .map(|incident| (incident.get_client_id(), incident.get_id()))
+            .collect();
+        // Windows not loaded this time may have changed, update them next time
+        let mut events = if loaded {
+            maintenance
+                .update(storage.as_ref(), current_time)
+                .await
+                .unwrap_or_else(|e| {
+                    error!("Got error while updating maintenance windows: {:?}", e);
+                    Vec::new()
+                })
+        } else {
+            Vec::new()
+        };
+        let mut offline_clients: Vec<notifier::ClientInfo> = Default::default();
+        // Offline clients folded into outage of their top-most offline ancestor
+        let mut folded: HashMap<i32, Vec<notifier::ClientInfo>> = Default::default();
+        let clients = storage.list_clients().await?;
+        let dependencies = Dependencies::new(&clients, policy, current_time);
+        for client in clients {
+            if client.get_retired() {
+                continue;
+            }
+            let online = policy.is_online(&client, current_time);
+            if !online {
+                if let Some(&incident) = unreachable.get(&client.get_id()) {
+                    if self.unreachable_tracker.is_down(
+                        &dependencies,
+                        client.get_id(),
+                        policy.get_timeout(&client),
+                        current_time,
+                    ) {
+                        // Ancestors are back but client is not, it is down by itself
+                        storage
+                            .set_incident_cause(incident, IncidentCause::Timeout)
                             .await?;
+                        if !client.is_muted(current_time)
+                            && !maintenance.covers(&client, current_time)
+                        {
+                            offline_clients
+                                .push(notifier::ClientInfo::from(&client).with_incident(incident));
+                        }
+                        continue;
                     }
                 }
-                Terminate => break,
-                _ => {}
             }
-        }
-        let current_time = get_current_timestamp() as u32;
-        let mut offline_clients: Vec<(i32, String, String)> = Default::default();
-        {
-            let mut extras = extra_data.lock().await;
-            let mut q = sqlx::query(r#"SELECT * FROM "list""#).fetch(&mut conn);
-            while let Some(Ok(row)) = q.next().await {
-                let row = sqlx::query_as::<_, database::ClientRow>(
-                    r#"SELECT * FROM "clients" WHERE "id" = ?"#,
+            if online || down.contains(&client.get_id()) {
+                if let Some(event) = self
+                    .flap_detector
+                    .check_stable(storage.as_ref(), &client, online, current_time)
+                    .await?
+                {
+                    if !client.is_muted(current_time)
+                        && !maintenance.covers(&client, current_time)
+                    {
+                        events.push(event);
+                    }
+                }
+                continue;
+            }
+            let ancestor = dependencies.get_offline_ancestor(client.get_id());
+            let incident = storage
+                .open_incident(
+                    client.get_id(),
+                    client.get_last_seen(),
+                    current_time,
+                    if ancestor.is_some() {
+                        IncidentCause::Unreachable
+                    } else {
+                        IncidentCause::Timeout
+                    },
                 )
-                .bind(row.get::<i32, usize>(0))
-                .fetch_one(&mut extras.conn)
                 .await?;
-                if current_time - row.get_last_seen() > CLIENT_TIMEOUT {
-                    offline_clients.push((
-                        row.get_id(),
-                        row.get_uuid().clone(),
-                        row.get_hostname().clone().unwrap_or_else(|| DEFAULT_HOSTNAME.to_string())
-                    ));
-                }
+            let state = self
+                .flap_detector
+                .on_transition(storage.as_ref(), &client, current_time)
+                .await?;
+            // Muted clients still get incident, so they are reported again when back
+            if client.is_muted(current_time) || maintenance.covers(&client, current_time) {
+                continue;
             }
-            if !offline_clients.is_empty() {
-                let uuids: Vec<String> = offline_clients
-                    .clone()
-                    .into_iter()
-                    .map(|x| format!("<b>{}</b>: <code>{}</code>", x.2, x.1))
-                    .collect();
-                extras
-                    .bot_tx
-                    .send(Command::StringData(format!(
-                        "Clients offline:\n{}",
-                        uuids.join("\n")
-                    )))
-                    .await?;
+            match state {
+                FlapState::Normal => match ancestor {
+                    Some(ancestor) => folded
+                        .entry(ancestor)
+                        .or_default()
+                        .push(notifier::ClientInfo::from(&client)),
+                    None => offline_clients
+                        .push(notifier::ClientInfo::from(&client).with_incident(incident)),
+                },
+                FlapState::Started(event) => events.push(*event),
+                FlapState::Suppressed => {}
             }
         }
-        for client in &offline_clients {
-            sqlx::query(r#"DELETE FROM "list" WHERE "id" = ?"#)
-                .bind(client.0)
-                .execute(&mut conn)
+        events.extend(
+            self.escalation_policy
+                .check(storage.as_ref(), maintenance, current_time)
+                .await?,
+        );
+        for event in events {
+            self.bot_tx.send(Command::Notify(Box::new(event))).await?;
+        }
+        let offline_clients: Vec<notifier::ClientInfo> = offline_clients
+            .into_iter()
+            .map(|client| match folded.remove(&client.get_id()) {
+                Some(children) => client.with_unreachable(children),
+                None => client,
+            })
+            .collect();
+        // Ancestor was reported before, or is muted or in maintenance
+        for (ancestor, children) in folded {
+            debug!(
+                "{} client(s) unreachable behind offline client {}, skip notification",
+                children.len(),
+                ancestor
+            );
+        }
+        if !offline_clients.is_empty() {
+            self.bot_tx
+                .send(Command::Notify(Box::new(notifier::Event::Offline {
+                    clients: offline_clients,
+                })))
                 .await?;
         }
+        Ok(())
+    }
+}
+
+async fn client_watchdog(
+    mut rx: mpsc::Receiver<Command>,
+    extra_data: Arc<ExtraData>,
+    bot_tx: mpsc::Sender<Command>,
+    flap_detector: FlapDetector,
+    escalation_policy: EscalationPolicy,
+) -> anyhow::Result<()> {
+    use Command::*;
+    let storage = extra_data.storage.clone();
+    let policy = &extra_data.timeout_policy;
+    // Clients went offline while server was down are recorded without notification
+    {
+        let current_time = get_current_timestamp() as i64;
+        let down: HashSet<i32> = storage
+            .list_open_incidents()
+            .await?
+            .iter()
+            .map(|incident| incident.get_client_id())
+            .collect();
+        let clients = storage.list_clients().await?;
+        let dependencies = Dependencies::new(&clients, policy, current_time);
+        for client in clients {
+            if !client.get_retired()
+                && !down.contains(&client.get_id())
+                && !policy.is_online(&client, current_time)
+            {
+                storage
+                    .open_incident(
+                        client.get_id(),
+                        client.get_last_seen(),
+                        current_time,
+                        if dependencies.get_offline_ancestor(client.get_id()).is_some() {
+                            IncidentCause::Unreachable
+                        } else {
+                            IncidentCause::Timeout
+                        },
+                    )
+                    .await?;
+            }
+        }
+    }
+    debug!("Starting watchdog");
+    let mut watchdog = Watchdog {
+        maintenance: MaintenanceSet::empty(extra_data.timezone),
+        extra_data,
+        bot_tx,
+        flap_detector,
+        escalation_policy,
+        unreachable_tracker: UnreachableTracker::default(),
+    };
+    // Sweep runs on its own timer, check-ins are handled as they arrive
+    let mut sweep = tokio::time::interval(Duration::from_secs(WATCHDOG_SWEEP_INTERVAL));
+    sweep.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            cmd = rx.recv() => match cmd {
+                Some(MachineID((id, check_in))) => {
+                    if let Err(e) = watchdog.on_check_in(id, check_in).await {
+                        error!("Got error while handling check-in of client {}: {:?}", id, e);
+                    }
+                }
+                Some(Terminate) | None => break,
+                Some(_) => {}
+            },
+            _ = sweep.tick() => {
+                if let Err(e) = watchdog.sweep().await {
+                    error!("Got error while checking clients: {:?}", e);
+                }
+            }
+        }
     }
     debug!("Client watchdog exiting...");
     Ok(())
@@ -384,44 +795,78 @@ async fn client_watchdog(
 async fn async_main() -> anyhow::Result<()> {
     let config = Config::new("data/config.toml")?;
 
-    if !config.get_database_location().eq("sqlite::memory:") {
-        let file = std::path::Path::new(config.get_database_location());
-        if !file.exists() {
-            std::fs::File::create(file)?;
-        }
-    }
-
-    let mut conn = SqliteConnection::connect(config.get_database_location()).await?;
-
-    let rows = sqlx::query(r#"SELECT name FROM sqlite_master WHERE type='table' AND name=?"#)
-        .bind("pbs_meta")
-        .fetch_all(&mut conn)
-        .await?;
-
-    if rows.is_empty() {
-        sqlx::query(database::current::CREATE_TABLES)
-            .execute(&mut conn)
-            .await?;
-    }
+    let storage = storage::connect(config.get_database_location()).await?;
 
     let (bot_tx, bot_rx) = mpsc::channel(1024);
     let (watchdog_tx, watchdog_rx) = mpsc::channel(1024);
+    let (retention_tx, retention_rx) = mpsc::channel(1);
 
     let authorization_guard = crate::structs::AuthorizationGuard::from(&config);
     let admin_authorization_guard =
         crate::structs::AuthorizationGuard::from(config.get_admin_token());
     let bind_addr = config.get_bind_params();
+    let version_policy = VersionPolicy::try_from(&config)?;
 
-    let extra_data = Arc::new(Mutex::new(ExtraData {
-        conn,
-        bot_tx: bot_tx.clone(),
+    let extra_data = Arc::new(ExtraData {
+        storage,
+        timeout_policy: TimeoutPolicy::from(&config),
+        timezone: config.get_timezone()?,
+        alert_engine: AlertEngine::new(&config)?,
+    });
+    extra_data
+        .timeout_policy
+        .load_overrides(extra_data.storage.as_ref())
+        .await?;
+    extra_data
+        .alert_engine
+        .reload(extra_data.storage.as_ref())
+        .await?;
+    let channels = Channels {
         watchdog_tx: watchdog_tx.clone(),
-    }));
-    let guard_task = tokio::spawn(client_watchdog(watchdog_rx, extra_data.clone()));
+        bot_tx: bot_tx.clone(),
+    };
+    let escalation_policy = EscalationPolicy::from(&config);
+    let guard_task = tokio::spawn(client_watchdog(
+        watchdog_rx,
+        extra_data.clone(),
+        bot_tx.clone(),
+        FlapDetector::from(&config),
+        escalation_policy.clone(),
+    ));
+    let retention_task = tokio::spawn(retention::retention_daemon(
+        extra_data.storage.clone(),
+        config.get_retention(),
+        retention_rx,
+    ));
+    let bot = if config.get_bot_token().is_empty() {
+        None
+    } else {
+        Some(bot::create_bot(config.get_bot_token(), config.get_api_server())?)
+    };
+    let command_task = bot.map(|bot| {
+        tokio::spawn(bot::command_daemon(
+            bot,
+            config.get_owner(),
+            config
+                .get_telegram_recipients()
+                .iter()
+                .map(|recipient| recipient.get_chat_id())
+                .collect(),
+            extra_data.storage.clone(),
+            extra_data.timeout_policy.clone(),
+            extra_data.timezone,
+        ))
+    });
+    let notifiers = notifier::build_notifiers(&config)?;
+    escalation_policy.check_notifiers(
+        &notifiers
+            .iter()
+            .map(|notifier| notifier.get_name())
+            .collect::<Vec<&str>>(),
+    );
     let msg_sender = tokio::spawn(process_send_message(
-        config.get_bot_token().clone(),
-        config.get_api_server().clone(),
-        config.get_owner(),
+        extra_data.storage.clone(),
+        notifiers,
         bot_rx,
     ));
 
@@ -434,19 +879,76 @@ async fn async_main() -> anyhow::Result<()> {
                 .service(
                     web::scope("/admin")
                         .guard(admin_authorization_guard.to_owned())
-                        .data(extra_data.clone())
+                        .app_data(web::Data::new(extra_data.clone()))
                         .service(web::resource("").route(web::post().to(route_admin_query)))
+                        .service(
+                            web::resource("/clients").route(web::get().to(admin::route_list_clients)),
+                        )
+                        .service(
+                            web::resource("/clients/online")
+                                .route(web::get().to(admin::route_list_online)),
+                        )
+                        .service(
+                            web::resource("/clients/{client}")
+                                .route(web::get().to(admin::route_get_client))
+                                .route(web::delete().to(admin::route_delete_client))
+                                .route(web::patch().to(admin::route_patch_client)),
+                        )
+                        .service(
+                            web::resource("/incidents")
+                                .route(web::get().to(admin::route_list_incidents)),
+                        )
+                        .service(
+                            web::resource("/incidents/{id}/acknowledge")
+                                .route(web::post().to(admin::route_acknowledge_incident)),
+                        )
+                        .service(
+                            web::resource("/alerts").route(web::get().to(admin::route_list_alerts)),
+                        )
+                        .service(
+                            web::resource("/alerts/rules")
+                                .route(web::get().to(admin::route_list_alert_rules))
+                                .route(web::post().to(admin::route_create_alert_rule)),
+                        )
+                        .service(
+                            web::resource("/alerts/rules/{name}")
+                                .route(web::delete().to(admin::route_delete_alert_rule)),
+                        )
+                        .service(
+                            web::resource("/maintenance")
+                                .route(web::get().to(admin::route_list_maintenance))
+                                .route(web::post().to(admin::route_create_maintenance)),
+                        )
+                        .service(
+                            web::resource("/maintenance/{id}")
+                                .route(web::delete().to(admin::route_delete_maintenance)),
+                        )
+                        .service(
+                            web::resource("/timeouts")
+                                .route(web::get().to(admin::route_list_timeouts)),
+                        )
+                        .service(
+                            web::resource("/timeouts/{pattern}")
+                                .route(web::put().to(admin::route_put_timeout))
+                                .route(web::delete().to(admin::route_delete_timeout)),
+                        )
+                        .service(
+                            web::resource("/clients/{client}/{kind}")
+                                .route(web::get().to(admin::route_client_history)),
+                        )
                         .route("", web::to(HttpResponse::Forbidden)),
                 )
                 .service(
                     web::scope("/")
                         .guard(authorization_guard.to_owned())
-                        .data(extra_data.clone())
+                        .app_data(web::Data::new(extra_data.clone()))
+                        .app_data(web::Data::new(channels.clone()))
+                        .app_data(web::Data::new(version_policy.clone()))
                         .route("", web::post().to(route_post)),
                 )
                 .service(web::scope("/").route(
                     "",
-                    web::get().to(|| HttpResponse::Ok().json(Response::new_ok())),
+                    web::get().to(|| async { HttpResponse::Ok().json(Response::new_ok()) }),
                 ))
                 .route("/", web::to(HttpResponse::Forbidden))
         })
@@ -455,16 +957,21 @@ async fn async_main() -> anyhow::Result<()> {
     );
 
     server.await??;
+    if let Some(command_task) = command_task {
+        command_task.abort();
+    }
     bot_tx.send(Command::Terminate).await?;
     watchdog_tx.send(Command::Terminate).await?;
+    retention_tx.send(Command::Terminate).await?;
     guard_task.await??;
+    retention_task.await??;
     msg_sender.await??;
 
     Ok(())
 }
 
 async fn distribution_configure(data: web::Data<String>) -> actix_web::Result<HttpResponse> {
-    Ok(HttpResponse::Ok().body(data.get_ref()))
+    Ok(HttpResponse::Ok().body(data.get_ref().clone()))
 }
 
 async fn distribution_server(server_address: &str) -> anyhow::Result<()> {
@@ -514,3 +1021,586 @@ fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    const CLIENTS: usize = 300;
+    const HEARTBEATS: usize = 5;
+    /// Far below what a debug build reaches, only catches requests serializing on a lock again
+    const MINIMUM_REQUESTS_PER_SECOND: f64 = 50.0;
+
+    /// Shared state of handlers with default configure, also used by tests of other modules.
+    pub(crate) fn extra_data(storage: Arc<dyn Storage>) -> Arc<ExtraData> {
+        let config = configparser::tests::config_with("");
+        Arc::new(ExtraData {
+            storage,
+            timeout_policy: TimeoutPolicy::from(&config),
+            timezone: config.get_timezone().unwrap(),
+            alert_engine: AlertEngine::new(&config).unwrap(),
+        })
+    }
+
+    fn request(uuid: &str, action: &str, body: serde_json::Value) -> structs::Request {
+        serde_json::from_value(serde_json::json!({
+            "version": clientversion::MINIMUM_CLIENT_VERSION,
+            "action": action,
+            "uuid": uuid,
+            "body": body.to_string(),
+        }))
+        .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn heartbeat_throughput() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = configparser::tests::config_with("");
+        let storage = storage::connect(&format!(
+            "sqlite://{}",
+            dir.path().join("probe.db").display()
+        ))
+        .await
+        .unwrap();
+        let extra_data = extra_data(storage);
+        // Channel holds every command, so handlers never wait on watchdog
+        let (watchdog_tx, mut watchdog_rx) = mpsc::channel(CLIENTS * (HEARTBEATS + 1));
+        let (bot_tx, _bot_rx) = mpsc::channel(1);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(extra_data.clone()))
+                .app_data(web::Data::new(Channels {
+                    watchdog_tx,
+                    bot_tx,
+                }))
+                .app_data(web::Data::new(VersionPolicy::try_from(&config).unwrap()))
+                .route("/", web::post().to(route_post)),
+        )
+        .await;
+
+        let uuids: Vec<String> = (0..CLIENTS).map(|i| format!("load-test-{}", i)).collect();
+        let started = std::time::Instant::now();
+        let responses = futures::future::join_all(uuids.iter().map(|uuid| {
+            let request = test::TestRequest::post()
+                .uri("/")
+                .set_json(request(
+                    uuid,
+                    "register",
+                    serde_json::json!({"hostname": uuid, "boot_time": 0}),
+                ))
+                .to_request();
+            test::call_service(&app, request)
+        }))
+        .await;
+        assert!(responses
+            .iter()
+            .all(|response| response.status().is_success()));
+        for round in 0..HEARTBEATS {
+            let responses = futures::future::join_all(uuids.iter().map(|uuid| {
+                let request = test::TestRequest::post()
+                    .uri("/")
+                    .set_json(request(
+                        uuid,
+                        "heartbeat",
+                        serde_json::json!({
+                            "cpu_percent": round as f64,
+                            "load_avg": [0.5, 0.4, 0.3],
+                            "virtual_memory": {"total": 8254660608_i64, "used": 2466156544_i64},
+                            "disk_usage": {"/": {"total": 62725623808_i64, "used": 19312578560_i64}},
+                            "uptime": 3600 + round,
+                        }),
+                    ))
+                    .to_request();
+                test::call_service(&app, request)
+            }))
+            .await;
+            assert!(responses
+                .iter()
+                .all(|response| response.status().is_success()));
+        }
+        let requests_per_second =
+            (CLIENTS * (HEARTBEATS + 1)) as f64 / started.elapsed().as_secs_f64();
+        assert!(
+            requests_per_second > MINIMUM_REQUESTS_PER_SECOND,
+            "{:.0} requests/s",
+            requests_per_second
+        );
+
+        let mut check_ins = 0;
+        while let Ok(Command::MachineID(_)) = watchdog_rx.try_recv() {
+            check_ins += 1;
+        }
+        assert_eq!(check_ins, CLIENTS * (HEARTBEATS + 1));
+        assert_eq!(
+            extra_data.storage.list_clients().await.unwrap().len(),
+            CLIENTS
+        );
+    }
+
+    async fn admin_query(
+        extra_data: &Arc<ExtraData>,
+        payload: serde_json::Value,
+    ) -> (actix_web::http::StatusCode, serde_json::Value) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(extra_data.clone()))
+                .route("/admin", web::post().to(route_admin_query)),
+        )
+        .await;
+        let request = test::TestRequest::post()
+            .uri("/admin")
+            .set_json(payload)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        let status = response.status();
+        (status, test::read_body_json(response).await)
+    }
+
+    #[actix_rt::test]
+    async fn admin_actions() {
+        use actix_web::http::StatusCode;
+        let storage = storage::connect("sqlite::memory:").await.unwrap();
+        for uuid in ["client-a", "client-b"] {
+            storage
+                .register_client(uuid, 0, Some(uuid), get_current_timestamp() as i64)
+                .await
+                .unwrap();
+        }
+        let extra_data = extra_data(storage);
+
+        let (status, body) = admin_query(
+            &extra_data,
+            serde_json::json!({"action": "rename", "client": "client-a", "display_name": "web"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"]["display_name"], "web");
+
+        let (_, body) = admin_query(
+            &extra_data,
+            serde_json::json!({"action": "retire", "client": 1}),
+        )
+        .await;
+        assert_eq!(body["result"]["retired"], true);
+        assert_eq!(body["result"]["display_name"], "web");
+        let (_, body) = admin_query(
+            &extra_data,
+            serde_json::json!({"action": "unretire", "client": 1}),
+        )
+        .await;
+        assert_eq!(body["result"]["retired"], false);
+
+        let (_, body) = admin_query(
+            &extra_data,
+            serde_json::json!({"action": "query_online_num"}),
+        )
+        .await;
+        assert_eq!(body["result"], 2);
+        let (status, body) = admin_query(
+            &extra_data,
+            serde_json::json!({"action": "delete", "client": "client-b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"], true);
+        let (_, body) = admin_query(&extra_data, serde_json::json!({"action": "query"})).await;
+        assert_eq!(body["result"].as_array().unwrap().len(), 1);
+
+        let (status, body) = admin_query(
+            &extra_data,
+            serde_json::json!({"action": "delete", "client": "client-b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            body["status"],
+            i64::from(&structs::ErrorCodes::ClientNotFound)
+        );
+        // Actions on a client need to name it
+        let (status, body) =
+            admin_query(&extra_data, serde_json::json!({"action": "retire"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["status"],
+            i64::from(&structs::ErrorCodes::InvalidParameter)
+        );
+        let (status, body) =
+            admin_query(&extra_data, serde_json::json!({"action": "reboot"})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["status"],
+            i64::from(&structs::ErrorCodes::UnsupportedMethod)
+        );
+    }
+
+    fn notified(rx: &mut mpsc::Receiver<Command>) -> Vec<notifier::Event> {
+        let mut events = Vec::new();
+        while let Ok(cmd) = rx.try_recv() {
+            match cmd {
+                Command::Notify(event) => events.push(*event),
+                cmd => panic!("unexpected command {:?}", cmd),
+            }
+        }
+        events
+    }
+
+    #[actix_rt::test]
+    async fn watchdog_sweep() {
+        let storage = storage::connect("sqlite::memory:").await.unwrap();
+        let current_time = get_current_timestamp() as i64;
+        let register = |uuid: &'static str, last_seen: i64| {
+            let storage = storage.clone();
+            async move {
+                storage
+                    .register_client(uuid, 0, Some(uuid), last_seen)
+                    .await
+                    .unwrap()
+                    .get_id()
+            }
+        };
+        let web = register("web-1", current_time).await;
+        let db = register("db-1", current_time - 3600).await;
+        let cache = register("cache-1", current_time - 3600).await;
+        let retired = register("old-1", current_time - 3600).await;
+        storage
+            .set_muted_until(cache, Some(current_time + 3600))
+            .await
+            .unwrap();
+        storage.set_retired(retired, true).await.unwrap();
+        let (bot_tx, mut bot_rx) = mpsc::channel(16);
+        let mut watchdog = Watchdog {
+            extra_data: extra_data(storage.clone()),
+            bot_tx,
+            flap_detector: FlapDetector::from(&configparser::tests::config_with("")),
+            escalation_policy: EscalationPolicy::from(&configparser::tests::config_with("")),
+            unreachable_tracker: UnreachableTracker::default(),
+            maintenance: MaintenanceSet::empty(chrono_tz::Tz::UTC),
+        };
+
+        watchdog.sweep().await.unwrap();
+        // Muted client gets incident without being reported, retired one is ignored
+        match notified(&mut bot_rx).as_slice() {
+            [notifier::Event::Offline { clients }] => {
+                assert_eq!(clients.len(), 1);
+                assert_eq!(clients[0].get_name(), "db-1");
+            }
+            events => panic!("unexpected events {:?}", events),
+        }
+        let mut incidents = storage.list_open_incidents().await.unwrap();
+        incidents.sort_by_key(|incident| incident.get_client_id());
+        assert_eq!(
+            incidents
+                .iter()
+                .map(|incident| (incident.get_client_id(), incident.get_started_at()))
+                .collect::<Vec<_>>(),
+            vec![(db, current_time - 3600), (cache, current_time - 3600)]
+        );
+
+        // Clients already down are not reported twice
+        watchdog.sweep().await.unwrap();
+        assert!(notified(&mut bot_rx).is_empty());
+        assert_eq!(storage.list_open_incidents().await.unwrap().len(), 2);
+
+        // Heartbeat of online client is not a state change
+        watchdog.on_check_in(web, CheckIn::Heartbeat).await.unwrap();
+        assert!(notified(&mut bot_rx).is_empty());
+
+        storage.update_last_seen(db, current_time).await.unwrap();
+        watchdog.on_check_in(db, CheckIn::Heartbeat).await.unwrap();
+        match notified(&mut bot_rx).as_slice() {
+            [notifier::Event::Online {
+                client,
+                from_register: false,
+            }] => {
+                assert_eq!(client.get_name(), "db-1");
+                assert!(client.get_downtime().unwrap() >= 3600);
+            }
+            events => panic!("unexpected events {:?}", events),
+        }
+        assert!(storage.get_open_incident(db).await.unwrap().is_none());
+
+        // Muted client is resolved silently
+        watchdog
+            .on_check_in(cache, CheckIn::Heartbeat)
+            .await
+            .unwrap();
+        assert!(notified(&mut bot_rx).is_empty());
+        assert!(storage.list_open_incidents().await.unwrap().is_empty());
+
+        // Register is always reported, without downtime when client was online
+        watchdog
+            .on_check_in(web, CheckIn::Register { new: true })
+            .await
+            .unwrap();
+        match notified(&mut bot_rx).as_slice() {
+            [notifier::Event::Online {
+                client,
+                from_register: true,
+            }] => assert_eq!(client.get_downtime(), None),
+            events => panic!("unexpected events {:?}", events),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn maintenance_suppression() {
+        let storage = storage::connect("sqlite::memory:").await.unwrap();
+        let current_time = get_current_timestamp() as i64;
+        let mut ids = Vec::new();
+        for uuid in ["web-1", "db-1"] {
+            ids.push(
+                storage
+                    .register_client(uuid, 0, Some(uuid), current_time - 3600)
+                    .await
+                    .unwrap()
+                    .get_id(),
+            );
+        }
+        storage
+            .insert_maintenance(
+                &structs::MaintenanceRequest::new_once(
+                    "upgrade".to_string(),
+                    vec!["web-*".to_string()],
+                    current_time - 60,
+                    current_time + 3600,
+                ),
+                current_time,
+            )
+            .await
+            .unwrap();
+        let (bot_tx, mut bot_rx) = mpsc::channel(16);
+        let mut watchdog = Watchdog {
+            extra_data: extra_data(storage.clone()),
+            bot_tx,
+            flap_detector: FlapDetector::from(&configparser::tests::config_with("")),
+            escalation_policy: EscalationPolicy::from(&configparser::tests::config_with("")),
+            unreachable_tracker: UnreachableTracker::default(),
+            maintenance: MaintenanceSet::empty(chrono_tz::Tz::UTC),
+        };
+
+        // Client in maintenance gets incident without being reported
+        watchdog.sweep().await.unwrap();
+        match notified(&mut bot_rx).as_slice() {
+            [notifier::Event::Offline { clients }] => {
+                assert_eq!(clients.len(), 1);
+                assert_eq!(clients[0].get_name(), "db-1");
+            }
+            events => panic!("unexpected events {:?}", events),
+        }
+        assert_eq!(storage.list_open_incidents().await.unwrap().len(), 2);
+        storage
+            .update_last_seen(ids[0], current_time)
+            .await
+            .unwrap();
+        watchdog
+            .on_check_in(ids[0], CheckIn::Heartbeat)
+            .await
+            .unwrap();
+        assert!(notified(&mut bot_rx).is_empty());
+
+        // Summary is sent once window is ended
+        assert!(
+            maintenance::remove_window(storage.as_ref(), 1, current_time)
+                .await
+                .unwrap()
+        );
+        watchdog.sweep().await.unwrap();
+        match notified(&mut bot_rx).as_slice() {
+            [notifier::Event::Maintenance {
+                offline, recovered, ..
+            }] => {
+                assert!(offline.is_empty());
+                assert_eq!(recovered.len(), 1);
+                assert_eq!(recovered[0].get_name(), "web-1");
+            }
+            events => panic!("unexpected events {:?}", events),
+        }
+        assert!(storage.list_maintenance().await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn client_metadata_on_register() {
+        let storage = storage::connect("sqlite::memory:").await.unwrap();
+        let id = storage
+            .register_client("web-1", 0, None, 0)
+            .await
+            .unwrap()
+            .get_id();
+        storage
+            .set_labels(id, &[("site".to_string(), "tokyo".to_string())].into())
+            .await
+            .unwrap();
+        let info: AdditionalInfo = serde_json::from_value(serde_json::json!({
+            "hostname": "web-1",
+            "boot_time": 0,
+            "tags": ["web", "", "prod", "web"],
+            "labels": {"site": "osaka", "role": "frontend"},
+        }))
+        .unwrap();
+        update_client_metadata(storage.as_ref(), id, &info)
+            .await
+            .unwrap();
+        // Reported tags are added, labels already set are kept
+        let client = storage.get_client(id).await.unwrap().unwrap();
+        assert_eq!(client.get_tags(), &vec!["web", "prod"]);
+        assert_eq!(
+            client.get_labels(),
+            &[("role", "frontend"), ("site", "tokyo")]
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        );
+        // Tags replaced by admin are added back when client registers with them again
+        storage.set_tags(id, &["db".to_string()]).await.unwrap();
+        update_client_metadata(storage.as_ref(), id, &info)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_client(id).await.unwrap().unwrap().get_tags(),
+            &vec!["db", "web", "prod"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn reboot_detection() {
+        let storage = storage::connect("sqlite::memory:").await.unwrap();
+        let current_time = get_current_timestamp() as i64;
+        let last_seen = current_time - 60;
+        let boot_time = current_time - 10_000;
+        // (name, recorded boot time, uptime of previous heartbeat, uptime reported now)
+        let cases = vec![
+            ("reboot", boot_time, Some(9_940), 30),
+            (
+                "jitter within tolerance",
+                boot_time,
+                Some(9_940),
+                10_000 - 90,
+            ),
+            ("clock step without reboot", boot_time, Some(9_940), 10_500),
+            ("reboot without previous metrics", boot_time, None, 30),
+            ("no previous boot time", 0, None, 30),
+        ];
+        for (name, recorded, previous_uptime, uptime) in cases {
+            let id = storage
+                .register_client(name, recorded, None, last_seen)
+                .await
+                .unwrap()
+                .get_id();
+            if let Some(previous_uptime) = previous_uptime {
+                let statistics = metrics::Statistics::from_body(
+                    &serde_json::json!({ "uptime": previous_uptime }).to_string(),
+                )
+                .unwrap();
+                storage
+                    .insert_metrics(id, &statistics, last_seen)
+                    .await
+                    .unwrap();
+            }
+            let check_in = detect_reboot(
+                storage.as_ref(),
+                id,
+                (recorded, last_seen),
+                uptime,
+                current_time,
+            )
+            .await
+            .unwrap();
+            let client = storage.get_client(id).await.unwrap().unwrap();
+            match (name, check_in) {
+                (
+                    "reboot",
+                    CheckIn::Reboot {
+                        previous_boot_time,
+                        previous_last_seen,
+                    },
+                ) => {
+                    assert_eq!(previous_boot_time, boot_time);
+                    assert_eq!(previous_last_seen, last_seen);
+                    assert_eq!(client.get_boot_time(), current_time - uptime);
+                }
+                ("no previous boot time", CheckIn::Heartbeat) => {
+                    assert_eq!(client.get_boot_time(), current_time - uptime, "{}", name);
+                }
+                (_, CheckIn::Heartbeat) if name != "reboot" => {
+                    assert_eq!(client.get_boot_time(), recorded, "{}", name);
+                }
+                (name, check_in) => panic!("{}: unexpected {:?}", name, check_in),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn reboot_notification() {
+        let storage = storage::connect("sqlite::memory:").await.unwrap();
+        let current_time = get_current_timestamp() as i64;
+        let id = storage
+            .register_client("web-1", current_time - 30, Some("web-1"), current_time)
+            .await
+            .unwrap()
+            .get_id();
+        let (bot_tx, mut bot_rx) = mpsc::channel(16);
+        let mut watchdog = Watchdog {
+            extra_data: extra_data(storage.clone()),
+            bot_tx,
+            flap_detector: FlapDetector::from(&configparser::tests::config_with("")),
+            escalation_policy: EscalationPolicy::from(&configparser::tests::config_with("")),
+            unreachable_tracker: UnreachableTracker::default(),
+            maintenance: MaintenanceSet::empty(chrono_tz::Tz::UTC),
+        };
+        watchdog
+            .on_check_in(
+                id,
+                CheckIn::Reboot {
+                    previous_boot_time: current_time - 10_000,
+                    previous_last_seen: current_time - 60,
+                },
+            )
+            .await
+            .unwrap();
+        match notified(&mut bot_rx).as_slice() {
+            [notifier::Event::Reboot { client, uptime }] => {
+                assert_eq!(*uptime, Some(9_940));
+                let downtime = client.get_downtime().unwrap();
+                assert!((60..120).contains(&downtime), "{}", downtime);
+            }
+            events => panic!("unexpected events {:?}", events),
+        }
+        // Reboot without outage is recorded as a resolved incident
+        let incidents = storage
+            .query_incidents(Some(id), &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].get_started_at(), current_time - 60);
+        let incident = serde_json::to_value(&incidents[0]).unwrap();
+        assert_eq!(incident["cause"], "reboot");
+        assert!(incident["resolved_at"].is_i64());
+
+        // Register during outage resolves it with the register cause
+        storage
+            .open_incident(id, current_time - 600, current_time, IncidentCause::Timeout)
+            .await
+            .unwrap();
+        watchdog
+            .on_check_in(id, CheckIn::Register { new: false })
+            .await
+            .unwrap();
+        match notified(&mut bot_rx).as_slice() {
+            [notifier::Event::Online {
+                from_register: true,
+                ..
+            }] => {}
+            events => panic!("unexpected events {:?}", events),
+        }
+        // Newest first, the outage started before the reboot
+        let incidents = storage
+            .query_incidents(Some(id), &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(incidents[1].get_started_at(), current_time - 600);
+        assert_eq!(
+            serde_json::to_value(&incidents[1]).unwrap()["cause"],
+            "register"
+        );
+    }
+}
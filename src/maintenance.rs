@@ -0,0 +1,546 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::database::{ClientRow, MaintenanceRow};
+use crate::notifier::{ClientInfo, Event};
+use crate::storage::Storage;
+use crate::structs::MaintenanceRequest;
+use anyhow::anyhow;
+use chrono::TimeZone;
+use chrono_tz::Tz;
+use cron::Schedule;
+use log::{info, warn};
+use std::str::FromStr;
+
+/// Parse cron expression, expressions of 5 fields (without seconds) are accepted.
+pub fn parse_schedule(expression: &str) -> anyhow::Result<Schedule> {
+    let expression = expression.trim();
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+    Schedule::from_str(&expression).map_err(|e| anyhow!("Invalid schedule {:?}: {}", expression, e))
+}
+
+/// Check window before it is inserted, return reason if it is not acceptable.
+pub fn validate(request: &MaintenanceRequest, timestamp: i64) -> Result<(), String> {
+    match request.get_schedule() {
+        Some(schedule) => {
+            parse_schedule(schedule).map_err(|e| e.to_string())?;
+            if request
+                .get_duration()
+                .is_none_or(|duration| duration <= 0)
+            {
+                return Err("Recurring window requires positive duration".to_string());
+            }
+            if request.get_ends_at().is_some() {
+                return Err("Recurring window should not have ends_at".to_string());
+            }
+        }
+        None => match request.get_ends_at() {
+            Some(ends_at) if ends_at > request.get_starts_at().unwrap_or(timestamp) => {}
+            Some(_) => return Err("ends_at should be later than starts_at".to_string()),
+            None => return Err("Either ends_at or schedule is required".to_string()),
+        },
+    }
+    Ok(())
+}
+
+/// Maintenance window loaded from database, notifications of clients it covers are
+/// suppressed while it is active.
+pub struct Maintenance {
+    row: MaintenanceRow,
+    schedule: Option<Schedule>,
+}
+
+impl Maintenance {
+    pub fn new(row: MaintenanceRow) -> anyhow::Result<Self> {
+        let schedule = match row.get_schedule() {
+            Some(expression) => Some(parse_schedule(expression)?),
+            None => None,
+        };
+        Ok(Self { row, schedule })
+    }
+
+    pub fn get_row(&self) -> &MaintenanceRow {
+        &self.row
+    }
+
+    /// Start and end of window containing `timestamp`, cron schedule is evaluated in `timezone`.
+    pub fn get_window(&self, timestamp: i64, timezone: &Tz) -> Option<(i64, i64)> {
+        match (&self.schedule, self.row.get_duration()) {
+            (Some(schedule), Some(duration)) => {
+                let since = timezone.timestamp_opt(timestamp - duration, 0).single()?;
+                let start = schedule.after(&since).next()?.timestamp();
+                if start <= timestamp {
+                    Some((start, start + duration))
+                } else {
+                    None
+                }
+            }
+            (Some(_), None) => None,
+            (None, _) => {
+                let (start, end) = (self.row.get_starts_at()?, self.row.get_ends_at()?);
+                if start <= timestamp && timestamp < end {
+                    Some((start, end))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// One-off window which has ended.
+    pub fn is_expired(&self, timestamp: i64) -> bool {
+        self.schedule.is_none() && self.row.get_ends_at().is_none_or(|end| end <= timestamp)
+    }
+
+    pub fn covers(&self, client: &ClientInfo) -> bool {
+        let patterns = self.row.get_clients();
+        patterns.is_empty() || patterns.iter().any(|pattern| client.matches(pattern))
+    }
+}
+
+/// All maintenance windows, reloaded by watchdog each time it checks clients.
+pub struct MaintenanceSet {
+    windows: Vec<Maintenance>,
+    timezone: Tz,
+}
+
+impl MaintenanceSet {
+    /// Set without windows, used before windows are loaded.
+    pub fn empty(timezone: Tz) -> Self {
+        Self {
+            windows: Vec::new(),
+            timezone,
+        }
+    }
+
+    pub async fn load(storage: &dyn Storage, timezone: Tz) -> anyhow::Result<Self> {
+        let mut windows = Vec::new();
+        for row in storage.list_maintenance().await? {
+            let id = row.get_id();
+            match Maintenance::new(row) {
+                Ok(window) => windows.push(window),
+                Err(e) => warn!("Skip maintenance window {}: {}", id, e),
+            }
+        }
+        Ok(Self { windows, timezone })
+    }
+
+    pub fn get_windows(&self) -> &[Maintenance] {
+        &self.windows
+    }
+
+    pub fn is_active(&self, window: &Maintenance, timestamp: i64) -> bool {
+        window.get_window(timestamp, &self.timezone).is_some()
+    }
+
+    /// Whether client is in any active window.
+    pub fn covers(&self, client: &ClientRow, timestamp: i64) -> bool {
+        let info = ClientInfo::from(client);
+        self.windows
+            .iter()
+            .any(|window| window.covers(&info) && self.is_active(window, timestamp))
+    }
+
+    /// Record start of windows, return summaries of windows which have ended.
+    /// Ended one-off windows are removed.
+    pub async fn update(
+        &self,
+        storage: &dyn Storage,
+        timestamp: i64,
+    ) -> anyhow::Result<Vec<Event>> {
+        let mut events = Vec::new();
+        for window in self.windows.iter() {
+            let row = window.get_row();
+            match (
+                window.get_window(timestamp, &self.timezone),
+                row.get_active_since(),
+            ) {
+                (Some((start, end)), None) => {
+                    info!(
+                        "Maintenance window {} started, ends at {}",
+                        row.get_id(),
+                        end
+                    );
+                    storage
+                        .set_maintenance_active(row.get_id(), Some(start))
+                        .await?;
+                }
+                (None, Some(since)) => {
+                    info!("Maintenance window {} ended", row.get_id());
+                    events.push(summarize(storage, window, since, timestamp).await?);
+                    storage.set_maintenance_active(row.get_id(), None).await?;
+                }
+                _ => {}
+            }
+            if window.is_expired(timestamp) {
+                storage.delete_maintenance(row.get_id()).await?;
+            }
+        }
+        Ok(events)
+    }
+}
+
+/// Clients covered by window which went offline during it, split by whether they are back.
+async fn summarize(
+    storage: &dyn Storage,
+    window: &Maintenance,
+    since: i64,
+    until: i64,
+) -> anyhow::Result<Event> {
+    let mut offline = Vec::new();
+    let mut recovered = Vec::new();
+    for client in storage.list_clients().await? {
+        let info = ClientInfo::from(&client);
+        if client.get_retired() || !window.covers(&info) {
+            continue;
+        }
+        let incidents = storage
+            .list_incidents_between(client.get_id(), since, until)
+            .await?;
+        if incidents
+            .iter()
+            .any(|incident| incident.get_resolved_at().is_none())
+        {
+            offline.push(info);
+        } else if !incidents.is_empty() {
+            recovered.push(info);
+        }
+    }
+    Ok(Event::Maintenance {
+        description: window.get_row().get_description().clone(),
+        started_at: since,
+        ended_at: until,
+        offline,
+        recovered,
+    })
+}
+
+/// Remove window, active window is ended instead so its summary is still sent by watchdog.
+/// Return `false` if window not exists.
+pub async fn remove_window(storage: &dyn Storage, id: i64, timestamp: i64) -> anyhow::Result<bool> {
+    match storage
+        .list_maintenance()
+        .await?
+        .into_iter()
+        .find(|row| row.get_id() == id)
+    {
+        Some(row) if row.get_active_since().is_some() => {
+            storage.end_maintenance(id, timestamp).await?;
+            Ok(true)
+        }
+        Some(_) => storage.delete_maintenance(id).await,
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::IncidentCause;
+    use chrono::Utc;
+    use std::sync::Arc;
+
+    fn request(value: serde_json::Value) -> MaintenanceRequest {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn at(hour: u32, minute: u32) -> i64 {
+        Utc.with_ymd_and_hms(2023, 11, 15, hour, minute, 0)
+            .unwrap()
+            .timestamp()
+    }
+
+    async fn load(storage: &Arc<dyn Storage>, timezone: Tz) -> MaintenanceSet {
+        MaintenanceSet::load(storage.as_ref(), timezone)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn validate_request() {
+        let cases = [
+            (serde_json::json!({"ends_at": 2000}), true),
+            (
+                serde_json::json!({"starts_at": 1500, "ends_at": 2000}),
+                true,
+            ),
+            (
+                serde_json::json!({"starts_at": 2000, "ends_at": 2000}),
+                false,
+            ),
+            // One-off window without starts_at starts now
+            (serde_json::json!({"ends_at": 900}), false),
+            (
+                serde_json::json!({"schedule": "0 3 * * *", "duration": 3600}),
+                true,
+            ),
+            (
+                serde_json::json!({"schedule": "0 0 3 * * *", "duration": 3600}),
+                true,
+            ),
+            (serde_json::json!({"schedule": "0 3 * * *"}), false),
+            (
+                serde_json::json!({"schedule": "0 3 * * *", "duration": 0}),
+                false,
+            ),
+            (
+                serde_json::json!({"schedule": "0 3 * *", "duration": 3600}),
+                false,
+            ),
+            (
+                serde_json::json!({"schedule": "0 3 * * *", "duration": 3600, "ends_at": 2000}),
+                false,
+            ),
+            (serde_json::json!({}), false),
+        ];
+        for (value, valid) in cases {
+            assert_eq!(
+                validate(&request(value.clone()), 1000).is_ok(),
+                valid,
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn window_activity() {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        for value in [
+            serde_json::json!({"starts_at": at(1, 0), "ends_at": at(2, 0)}),
+            serde_json::json!({"schedule": "0 3 * * *", "duration": 3600}),
+        ] {
+            storage
+                .insert_maintenance(&request(value), at(0, 0))
+                .await
+                .unwrap();
+        }
+        let windows = |set: &MaintenanceSet, timestamp: i64| -> Vec<Option<(i64, i64)>> {
+            set.get_windows()
+                .iter()
+                .map(|window| window.get_window(timestamp, &set.timezone))
+                .collect()
+        };
+
+        let set = load(&storage, Tz::UTC).await;
+        let one_off = Some((at(1, 0), at(2, 0)));
+        let daily = Some((at(3, 0), at(4, 0)));
+        let cases = [
+            (at(0, 59), vec![None, None]),
+            (at(1, 0), vec![one_off, None]),
+            (at(1, 59), vec![one_off, None]),
+            (at(2, 0), vec![None, None]),
+            (at(3, 0), vec![None, daily]),
+            (at(3, 59), vec![None, daily]),
+            (at(4, 0), vec![None, None]),
+            // Schedule recurs the next day
+            (
+                at(3, 30) + 86400,
+                vec![None, Some((at(3, 0) + 86400, at(4, 0) + 86400))],
+            ),
+        ];
+        for (timestamp, expected) in cases {
+            assert_eq!(windows(&set, timestamp), expected, "{}", timestamp);
+        }
+        assert!(!set.get_windows()[0].is_expired(at(1, 59)));
+        assert!(set.get_windows()[0].is_expired(at(2, 0)));
+        assert!(!set.get_windows()[1].is_expired(at(4, 0)));
+
+        // Schedule is evaluated in configured timezone, 03:00 in Tokyo is 18:00 UTC
+        let set = load(&storage, chrono_tz::Asia::Tokyo).await;
+        assert_eq!(windows(&set, at(3, 30))[1], None);
+        assert_eq!(windows(&set, at(18, 30))[1], Some((at(18, 0), at(19, 0))));
+    }
+
+    #[actix_rt::test]
+    async fn client_matching() {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        let mut clients = Vec::new();
+        for (uuid, hostname) in [("uuid-1", "web-1"), ("uuid-2", "web-2"), ("uuid-3", "db-1")] {
+            clients.push(
+                storage
+                    .register_client(uuid, 0, Some(hostname), 0)
+                    .await
+                    .unwrap(),
+            );
+        }
+        storage
+            .insert_maintenance(
+                &request(serde_json::json!({"clients": ["web-1"], "ends_at": 2000})),
+                1000,
+            )
+            .await
+            .unwrap();
+        let set = load(&storage, Tz::UTC).await;
+        let covered = |set: &MaintenanceSet, timestamp: i64| -> Vec<bool> {
+            clients
+                .iter()
+                .map(|client| set.covers(client, timestamp))
+                .collect()
+        };
+        assert_eq!(covered(&set, 1500), vec![true, false, false]);
+        // Inactive window covers nobody
+        assert_eq!(covered(&set, 2000), vec![false, false, false]);
+
+        let cases = [
+            (serde_json::json!(["web-*"]), vec![true, true, false]),
+            (serde_json::json!(["uuid-3", "2"]), vec![false, true, true]),
+            (serde_json::json!([]), vec![true, true, true]),
+        ];
+        for (patterns, expected) in cases {
+            let row = storage.list_maintenance().await.unwrap().remove(0);
+            storage.delete_maintenance(row.get_id()).await.unwrap();
+            storage
+                .insert_maintenance(
+                    &request(serde_json::json!({"clients": patterns, "ends_at": 2000})),
+                    1000,
+                )
+                .await
+                .unwrap();
+            let set = load(&storage, Tz::UTC).await;
+            assert_eq!(covered(&set, 1500), expected, "{}", patterns);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn end_of_window_summary() {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        let mut ids = Vec::new();
+        for uuid in ["down", "back", "fine", "before"] {
+            ids.push(
+                storage
+                    .register_client(uuid, 0, None, 0)
+                    .await
+                    .unwrap()
+                    .get_id(),
+            );
+        }
+        storage
+            .insert_maintenance(
+                &request(serde_json::json!({
+                    "description": "upgrade",
+                    "starts_at": 1000,
+                    "ends_at": 2000,
+                })),
+                500,
+            )
+            .await
+            .unwrap();
+        // Window is recorded as started, nothing to summarize yet
+        let set = load(&storage, Tz::UTC).await;
+        assert!(set.update(storage.as_ref(), 1100).await.unwrap().is_empty());
+        assert_eq!(
+            storage.list_maintenance().await.unwrap()[0].get_active_since(),
+            Some(1000)
+        );
+
+        storage
+            .open_incident(ids[0], 1200, 1300, IncidentCause::Timeout)
+            .await
+            .unwrap();
+        let incident = storage
+            .open_incident(ids[1], 1400, 1500, IncidentCause::Timeout)
+            .await
+            .unwrap();
+        storage
+            .resolve_incident(incident, 1600, None)
+            .await
+            .unwrap();
+        let incident = storage
+            .open_incident(ids[3], 100, 200, IncidentCause::Timeout)
+            .await
+            .unwrap();
+        storage.resolve_incident(incident, 300, None).await.unwrap();
+
+        let set = load(&storage, Tz::UTC).await;
+        assert!(set.update(storage.as_ref(), 1900).await.unwrap().is_empty());
+        let events = set.update(storage.as_ref(), 2000).await.unwrap();
+        match events.as_slice() {
+            [Event::Maintenance {
+                description,
+                started_at,
+                ended_at,
+                offline,
+                recovered,
+            }] => {
+                assert_eq!(description, "upgrade");
+                assert_eq!((*started_at, *ended_at), (1000, 2000));
+                let uuids = |clients: &Vec<ClientInfo>| -> Vec<String> {
+                    clients
+                        .iter()
+                        .map(|client| client.get_uuid().clone())
+                        .collect()
+                };
+                assert_eq!(uuids(offline), vec!["down"]);
+                assert_eq!(uuids(recovered), vec!["back"]);
+            }
+            events => panic!("unexpected events {:?}", events),
+        }
+        // Ended one-off window is removed
+        assert!(storage.list_maintenance().await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn remove_active_window() {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        for value in [
+            serde_json::json!({"schedule": "0 3 * * *", "duration": 3600}),
+            serde_json::json!({"starts_at": at(5, 0), "ends_at": at(6, 0)}),
+        ] {
+            storage
+                .insert_maintenance(&request(value), at(0, 0))
+                .await
+                .unwrap();
+        }
+        let set = load(&storage, Tz::UTC).await;
+        set.update(storage.as_ref(), at(3, 0)).await.unwrap();
+
+        // Active window is ended, so watchdog still sends its summary
+        assert!(remove_window(storage.as_ref(), 1, at(3, 30)).await.unwrap());
+        let set = load(&storage, Tz::UTC).await;
+        let row = set.get_windows()[0].get_row();
+        assert_eq!(row.get_schedule(), &None);
+        assert_eq!(
+            (row.get_starts_at(), row.get_ends_at()),
+            (Some(at(3, 0)), Some(at(3, 30)))
+        );
+        match set
+            .update(storage.as_ref(), at(3, 30))
+            .await
+            .unwrap()
+            .as_slice()
+        {
+            [Event::Maintenance {
+                started_at,
+                ended_at,
+                ..
+            }] => assert_eq!((*started_at, *ended_at), (at(3, 0), at(3, 30))),
+            events => panic!("unexpected events {:?}", events),
+        }
+
+        // Inactive window is deleted right away
+        assert!(remove_window(storage.as_ref(), 2, at(3, 30)).await.unwrap());
+        assert!(storage.list_maintenance().await.unwrap().is_empty());
+        assert!(!remove_window(storage.as_ref(), 2, at(3, 30)).await.unwrap());
+    }
+}
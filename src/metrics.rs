@@ -0,0 +1,378 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+#![allow(dead_code)]
+use serde::{Deserialize as _, Deserializer};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Load average is reported as `[1m, 5m, 15m]` tuple by psutil, object form is accepted as well.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LoadAverageRepr {
+    Tuple(f64, f64, f64),
+    Object { one: f64, five: f64, fifteen: f64 },
+}
+
+impl From<LoadAverageRepr> for LoadAverage {
+    fn from(repr: LoadAverageRepr) -> Self {
+        match repr {
+            LoadAverageRepr::Tuple(one, five, fifteen)
+            | LoadAverageRepr::Object { one, five, fifteen } => Self { one, five, fifteen },
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(from = "LoadAverageRepr")]
+pub struct LoadAverage {
+    one: f64,
+    five: f64,
+    fifteen: f64,
+}
+
+impl LoadAverage {
+    pub fn get_one(&self) -> f64 {
+        self.one
+    }
+
+    pub fn get_five(&self) -> f64 {
+        self.five
+    }
+
+    pub fn get_fifteen(&self) -> f64 {
+        self.fifteen
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct MemoryUsage {
+    total: i64,
+    used: i64,
+}
+
+impl MemoryUsage {
+    pub fn get_total(&self) -> i64 {
+        self.total
+    }
+
+    pub fn get_used(&self) -> i64 {
+        self.used
+    }
+}
+
+/// Value of `disk_usage` map, keyed by mount point.
+#[derive(Deserialize)]
+struct MountUsage {
+    total: i64,
+    used: i64,
+}
+
+/// Disks are reported as `{mount: usage}` map, list of `DiskUsage` is accepted as well.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DisksRepr {
+    List(Vec<DiskUsage>),
+    Map(BTreeMap<String, MountUsage>),
+}
+
+fn deserialize_disks<'de, D>(deserializer: D) -> Result<Option<Vec<DiskUsage>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(
+        Option::<DisksRepr>::deserialize(deserializer)?.map(|repr| match repr {
+            DisksRepr::List(disks) => disks,
+            DisksRepr::Map(disks) => disks
+                .into_iter()
+                .map(|(mount, usage)| DiskUsage {
+                    mount,
+                    total: usage.total,
+                    used: usage.used,
+                })
+                .collect(),
+        }),
+    )
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct DiskUsage {
+    mount: String,
+    total: i64,
+    used: i64,
+}
+
+impl DiskUsage {
+    pub fn get_mount(&self) -> &String {
+        &self.mount
+    }
+
+    pub fn get_total(&self) -> i64 {
+        self.total
+    }
+
+    pub fn get_used(&self) -> i64 {
+        self.used
+    }
+}
+
+/// Value of psutil `net_io_counters(pernic=True)` map, keyed by interface.
+#[derive(Deserialize)]
+struct InterfaceCounter {
+    #[serde(alias = "bytes_recv")]
+    rx_bytes: i64,
+    #[serde(alias = "bytes_sent")]
+    tx_bytes: i64,
+}
+
+/// Network is reported as `{interface: counters}` map, list of `NetworkCounter` is accepted as well.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NetworkRepr {
+    List(Vec<NetworkCounter>),
+    Map(BTreeMap<String, InterfaceCounter>),
+}
+
+fn deserialize_network<'de, D>(deserializer: D) -> Result<Option<Vec<NetworkCounter>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(
+        Option::<NetworkRepr>::deserialize(deserializer)?.map(|repr| match repr {
+            NetworkRepr::List(network) => network,
+            NetworkRepr::Map(network) => network
+                .into_iter()
+                .map(|(interface, counter)| NetworkCounter {
+                    interface,
+                    rx_bytes: counter.rx_bytes,
+                    tx_bytes: counter.tx_bytes,
+                })
+                .collect(),
+        }),
+    )
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct NetworkCounter {
+    interface: String,
+    #[serde(alias = "bytes_recv")]
+    rx_bytes: i64,
+    #[serde(alias = "bytes_sent")]
+    tx_bytes: i64,
+}
+
+impl NetworkCounter {
+    pub fn get_interface(&self) -> &String {
+        &self.interface
+    }
+
+    pub fn get_rx_bytes(&self) -> i64 {
+        self.rx_bytes
+    }
+
+    pub fn get_tx_bytes(&self) -> i64 {
+        self.tx_bytes
+    }
+}
+
+/// Statistics reported in heartbeat body, fields server does not know are kept in `extra`.
+///
+/// Keys follow the psutil calls probe-client reports (`cpu_percent`, `virtual_memory`, ...),
+/// see README for the payload format.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Statistics {
+    #[serde(alias = "cpu_percent")]
+    cpu: Option<f64>,
+    #[serde(alias = "load_avg")]
+    load_average: Option<LoadAverage>,
+    #[serde(alias = "virtual_memory")]
+    memory: Option<MemoryUsage>,
+    #[serde(alias = "swap_memory")]
+    swap: Option<MemoryUsage>,
+    #[serde(alias = "disk_usage", default, deserialize_with = "deserialize_disks")]
+    disks: Option<Vec<DiskUsage>>,
+    #[serde(
+        alias = "net_io_counters",
+        default,
+        deserialize_with = "deserialize_network"
+    )]
+    network: Option<Vec<NetworkCounter>>,
+    uptime: Option<i64>,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Statistics {
+    /// Parse heartbeat body, return `None` if body contains no known statistics.
+    pub fn from_body(body: &str) -> Option<Self> {
+        let statistics = serde_json::from_str::<Self>(body).ok()?;
+        if statistics.is_empty() {
+            None
+        } else {
+            Some(statistics)
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.cpu.is_none()
+            && self.load_average.is_none()
+            && self.memory.is_none()
+            && self.swap.is_none()
+            && self.disks.is_none()
+            && self.network.is_none()
+            && self.uptime.is_none()
+    }
+
+    pub fn get_cpu(&self) -> Option<f64> {
+        self.cpu
+    }
+
+    pub fn get_load_average(&self) -> &Option<LoadAverage> {
+        &self.load_average
+    }
+
+    pub fn get_memory(&self) -> &Option<MemoryUsage> {
+        &self.memory
+    }
+
+    pub fn get_swap(&self) -> &Option<MemoryUsage> {
+        &self.swap
+    }
+
+    pub fn get_disks(&self) -> &[DiskUsage] {
+        self.disks.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_network(&self) -> &[NetworkCounter] {
+        self.network.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_uptime(&self) -> Option<i64> {
+        self.uptime
+    }
+
+    /// Serialized unknown fields, should be stored as raw data.
+    pub fn get_unknown_fields(&self) -> Option<String> {
+        if self.extra.is_empty() {
+            None
+        } else {
+            serde_json::to_string(&self.extra).ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Heartbeat body in the shape probe-client builds it from psutil results.
+    const CLIENT_BODY: &str = r#"{
+        "cpu_percent": 7.3,
+        "load_avg": [0.52, 0.38, 0.31],
+        "virtual_memory": {"total": 8254660608, "available": 5431316480, "percent": 34.2, "used": 2466156544, "free": 1092440064, "active": 3562082304, "inactive": 2831781888, "buffers": 313839616, "cached": 4382224384, "shared": 23638016, "slab": 519905280},
+        "swap_memory": {"total": 2147479552, "used": 12582912, "free": 2134896640, "percent": 0.6, "sin": 0, "sout": 12779520},
+        "disk_usage": {"/": {"total": 62725623808, "used": 19312578560, "free": 40190242816, "percent": 32.5}, "/boot": {"total": 535805952, "used": 94052352, "free": 441753600, "percent": 17.6}},
+        "net_io_counters": {"eth0": {"bytes_sent": 1093358405, "bytes_recv": 4386731829, "packets_sent": 5287611, "packets_recv": 7032844, "errin": 0, "errout": 0, "dropin": 0, "dropout": 0}},
+        "uptime": 1284213,
+        "users": [{"name": "root", "terminal": "pts/0", "host": "192.168.1.2", "started": 1697531904.0, "pid": 4012}]
+    }"#;
+
+    #[test]
+    fn parse_client_body() {
+        let statistics = Statistics::from_body(CLIENT_BODY).unwrap();
+        assert_eq!(statistics.get_cpu(), Some(7.3));
+
+        let load = statistics.get_load_average().as_ref().unwrap();
+        assert_eq!(
+            (load.get_one(), load.get_five(), load.get_fifteen()),
+            (0.52, 0.38, 0.31)
+        );
+
+        let memory = statistics.get_memory().as_ref().unwrap();
+        assert_eq!(
+            (memory.get_total(), memory.get_used()),
+            (8254660608, 2466156544)
+        );
+        let swap = statistics.get_swap().as_ref().unwrap();
+        assert_eq!((swap.get_total(), swap.get_used()), (2147479552, 12582912));
+
+        let disks = statistics
+            .get_disks()
+            .iter()
+            .map(|disk| (disk.get_mount().as_str(), disk.get_total(), disk.get_used()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            disks,
+            vec![
+                ("/", 62725623808, 19312578560),
+                ("/boot", 535805952, 94052352)
+            ]
+        );
+
+        let network = statistics.get_network();
+        assert_eq!(network.len(), 1);
+        assert_eq!(network[0].get_interface(), "eth0");
+        assert_eq!(network[0].get_rx_bytes(), 4386731829);
+        assert_eq!(network[0].get_tx_bytes(), 1093358405);
+
+        assert_eq!(statistics.get_uptime(), Some(1284213));
+    }
+
+    #[test]
+    fn unknown_fields_fall_through() {
+        let statistics = Statistics::from_body(CLIENT_BODY).unwrap();
+        let raw: serde_json::Value =
+            serde_json::from_str(&statistics.get_unknown_fields().unwrap()).unwrap();
+        assert_eq!(raw.as_object().unwrap().len(), 1);
+        assert_eq!(raw["users"][0]["name"], "root");
+
+        let statistics = Statistics::from_body(r#"{"cpu_percent": 1.0}"#).unwrap();
+        assert_eq!(statistics.get_unknown_fields(), None);
+    }
+
+    #[test]
+    fn parse_list_form() {
+        let statistics = Statistics::from_body(
+            r#"{"cpu": 50.0, "load_average": {"one": 1.0, "five": 2.0, "fifteen": 3.0},
+                "disks": [{"mount": "/", "total": 100, "used": 40}],
+                "network": [{"interface": "lo", "rx_bytes": 10, "tx_bytes": 20}]}"#,
+        )
+        .unwrap();
+        assert_eq!(statistics.get_cpu(), Some(50.0));
+        assert_eq!(
+            statistics
+                .get_load_average()
+                .as_ref()
+                .unwrap()
+                .get_fifteen(),
+            3.0
+        );
+        assert_eq!(statistics.get_disks()[0].get_used(), 40);
+        assert_eq!(statistics.get_network()[0].get_tx_bytes(), 20);
+    }
+
+    #[test]
+    fn body_without_statistics() {
+        // Whole body is kept as raw data
+        assert!(Statistics::from_body("plain text").is_none());
+        assert!(Statistics::from_body(r#"{"hostname": "node"}"#).is_none());
+        assert!(Statistics::from_body(r#"{"cpu_percent": "high"}"#).is_none());
+        assert!(Statistics::from_body("[1, 2, 3]").is_none());
+    }
+}
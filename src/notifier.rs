@@ -0,0 +1,1515 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+mod smtp;
+mod template;
+
+use crate::configparser::{
+    Config, EventFilter, NotifierConfig, NotifierKind, TelegramRecipient, Templates,
+};
+use crate::database::{ClientRow, NotificationRow, DEFAULT_HOSTNAME};
+use crate::storage::Storage;
+use crate::utils::wildcard_match;
+use async_trait::async_trait;
+use chrono_tz::Tz;
+use log::{error, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub use smtp::SmtpNotifier;
+pub use template::{Format, Renderer};
+
+/// Snapshot of client when event happens.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClientInfo {
+    id: i32,
+    uuid: String,
+    name: String,
+    #[serde(default)]
+    hostname: String,
+    #[serde(default)]
+    last_seen: i64,
+    #[serde(default)]
+    boot_time: i64,
+    /// Seconds since client went offline, known only when client comes back
+    #[serde(default)]
+    downtime: Option<i64>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    /// Offline clients depending on this one, folded into its outage
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    unreachable: Vec<ClientInfo>,
+    /// Open incident of offline client, which can be acknowledged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    incident: Option<i64>,
+}
+
+impl From<&ClientRow> for ClientInfo {
+    fn from(row: &ClientRow) -> Self {
+        Self {
+            id: row.get_id(),
+            uuid: row.get_uuid().clone(),
+            name: row.get_name(),
+            hostname: row
+                .get_hostname()
+                .clone()
+                .unwrap_or_else(|| DEFAULT_HOSTNAME.to_string()),
+            last_seen: row.get_last_seen(),
+            boot_time: row.get_boot_time(),
+            downtime: None,
+            tags: row.get_tags().clone(),
+            labels: row.get_labels().clone(),
+            unreachable: Vec::new(),
+            incident: None,
+        }
+    }
+}
+
+impl ClientInfo {
+    pub fn with_downtime(mut self, downtime: Option<i64>) -> Self {
+        self.downtime = downtime;
+        self
+    }
+
+    pub fn with_incident(mut self, incident: i64) -> Self {
+        self.incident = Some(incident);
+        self
+    }
+
+    pub fn with_unreachable(mut self, unreachable: Vec<ClientInfo>) -> Self {
+        self.unreachable = unreachable;
+        self
+    }
+
+    pub fn get_id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn get_uuid(&self) -> &String {
+        &self.uuid
+    }
+
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn get_hostname(&self) -> &String {
+        &self.hostname
+    }
+
+    pub fn get_last_seen(&self) -> i64 {
+        self.last_seen
+    }
+
+    pub fn get_boot_time(&self) -> i64 {
+        self.boot_time
+    }
+
+    pub fn get_downtime(&self) -> Option<i64> {
+        self.downtime
+    }
+
+    pub fn get_tags(&self) -> &Vec<String> {
+        &self.tags
+    }
+
+    pub fn get_labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
+
+    pub fn get_unreachable(&self) -> &Vec<ClientInfo> {
+        &self.unreachable
+    }
+
+    pub fn get_incident(&self) -> Option<i64> {
+        self.incident
+    }
+
+    /// Whether pattern matches id, uuid or name of client, `tag:<pattern>` matches tags and
+    /// `<key>=<pattern>` matches value of label.
+    pub fn matches(&self, pattern: &str) -> bool {
+        if let Some(tag) = pattern.strip_prefix("tag:") {
+            return self.tags.iter().any(|t| wildcard_match(tag, t));
+        }
+        if let Some((key, value)) = pattern.split_once('=') {
+            return self
+                .labels
+                .get(key.trim())
+                .is_some_and(|label| wildcard_match(value.trim(), label));
+        }
+        wildcard_match(pattern, &self.name)
+            || wildcard_match(pattern, &self.uuid)
+            || pattern == self.id.to_string()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Online {
+        client: ClientInfo,
+        from_register: bool,
+    },
+    Offline {
+        clients: Vec<ClientInfo>,
+    },
+    /// `uptime` is how long client had been up before reboot
+    Reboot {
+        client: ClientInfo,
+        uptime: Option<i64>,
+    },
+    /// Client changed state `transitions` times within `window` seconds, further state
+    /// changes are not notified until it is stable
+    Flapping {
+        client: ClientInfo,
+        transitions: i64,
+        window: i64,
+    },
+    /// Flapping client has kept its state for a while, `transitions` is how many times
+    /// state changed during `duration` seconds of flapping
+    Stable {
+        client: ClientInfo,
+        online: bool,
+        transitions: i64,
+        duration: i64,
+    },
+    /// Maintenance window ended, `offline` clients went offline during it and are still
+    /// offline, `recovered` clients went offline during it but are back
+    Maintenance {
+        description: String,
+        started_at: i64,
+        ended_at: i64,
+        offline: Vec<ClientInfo>,
+        recovered: Vec<ClientInfo>,
+    },
+    /// Alert rule fired or resolved, `series` is labels of checked value, e.g. `mount="/"`
+    Alert {
+        client: ClientInfo,
+        rule: String,
+        expression: String,
+        series: String,
+        value: f64,
+        firing: bool,
+    },
+    /// Offline incident of client is not acknowledged in time, `level` is the number of
+    /// escalation step taken, `notifiers` are names of notifiers it goes to (all if empty)
+    Escalation {
+        client: ClientInfo,
+        level: i32,
+        #[serde(default)]
+        notifiers: Vec<String>,
+    },
+}
+
+impl Event {
+    /// Event type used by routing filters.
+    pub fn get_kind(&self) -> &'static str {
+        match self {
+            Event::Online {
+                from_register: true,
+                ..
+            } => "register",
+            Event::Online { .. } => "online",
+            Event::Offline { .. } => "offline",
+            Event::Reboot { .. } => "reboot",
+            Event::Flapping { .. } => "flapping",
+            Event::Stable { .. } => "stable",
+            Event::Maintenance { .. } => "maintenance",
+            Event::Alert { firing: true, .. } => "alert",
+            Event::Alert { .. } => "resolved",
+            Event::Escalation { .. } => "escalation",
+        }
+    }
+
+    pub fn get_clients(&self) -> Vec<&ClientInfo> {
+        match self {
+            Event::Online { client, .. }
+            | Event::Reboot { client, .. }
+            | Event::Flapping { client, .. }
+            | Event::Stable { client, .. }
+            | Event::Alert { client, .. }
+            | Event::Escalation { client, .. } => vec![client],
+            Event::Offline { clients } => clients.iter().collect(),
+            Event::Maintenance {
+                offline, recovered, ..
+            } => offline.iter().chain(recovered.iter()).collect(),
+        }
+    }
+
+    pub fn get_title(&self) -> String {
+        match self {
+            Event::Online {
+                client,
+                from_register,
+            } => format!(
+                "{} {}",
+                client.name,
+                if *from_register {
+                    "registered"
+                } else {
+                    "back online"
+                }
+            ),
+            Event::Offline { clients } => format!("{} client(s) offline", clients.len()),
+            Event::Reboot { client, .. } => format!("{} rebooted", client.name),
+            Event::Flapping { client, .. } => format!("{} is flapping", client.name),
+            Event::Stable { client, online, .. } => format!(
+                "{} stopped flapping ({})",
+                client.name,
+                if *online { "online" } else { "offline" }
+            ),
+            Event::Alert {
+                client,
+                rule,
+                firing,
+                ..
+            } => format!(
+                "{} alert {} {}",
+                client.name,
+                rule,
+                if *firing { "firing" } else { "resolved" }
+            ),
+            Event::Escalation { client, level, .. } => format!(
+                "{} still offline, escalation level {}",
+                client.name, level
+            ),
+            Event::Maintenance { description, .. } => {
+                if description.is_empty() {
+                    "Maintenance ended".to_string()
+                } else {
+                    format!("Maintenance {} ended", description)
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    fn get_name(&self) -> &str;
+
+    fn get_filter(&self) -> &EventFilter;
+
+    async fn notify(&self, event: &Event) -> anyhow::Result<()>;
+
+    /// Seconds queued events wait for later ones to be sent together, 0 sends each alone.
+    fn get_batch_interval(&self) -> u64 {
+        0
+    }
+
+    /// Send queued events in one message, only called when batch interval is set.
+    async fn notify_batch(&self, events: &[Event]) -> anyhow::Result<()> {
+        for event in events {
+            self.notify(event).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Narrow event down to what filter accepts, `None` if nothing is left.
+///
+/// Escalation naming notifiers goes to them only, regardless of their filters.
+pub fn filter_event(name: &str, filter: &EventFilter, event: &Event) -> Option<Event> {
+    if let Event::Escalation { notifiers, .. } = event {
+        if !notifiers.is_empty() {
+            return if notifiers.iter().any(|notifier| notifier == name) {
+                Some(event.clone())
+            } else {
+                None
+            };
+        }
+    }
+    if let Some(events) = filter.get_events() {
+        if !events.iter().any(|kind| kind.eq(event.get_kind())) {
+            return None;
+        }
+    }
+    let patterns = match filter.get_clients() {
+        Some(patterns) => patterns,
+        None => return Some(event.clone()),
+    };
+    let accept = |client: &ClientInfo| patterns.iter().any(|pattern| client.matches(pattern));
+    match event {
+        Event::Online { client, .. }
+        | Event::Reboot { client, .. }
+        | Event::Flapping { client, .. }
+        | Event::Stable { client, .. }
+        | Event::Alert { client, .. }
+        | Event::Escalation { client, .. } => {
+            if accept(client) {
+                Some(event.clone())
+            } else {
+                None
+            }
+        }
+        Event::Offline { clients } => {
+            let clients: Vec<ClientInfo> = clients
+                .iter()
+                .filter(|client| accept(client))
+                .cloned()
+                .collect();
+            if clients.is_empty() {
+                None
+            } else {
+                Some(Event::Offline { clients })
+            }
+        }
+        Event::Maintenance {
+            description,
+            started_at,
+            ended_at,
+            offline,
+            recovered,
+        } => {
+            let select = |clients: &Vec<ClientInfo>| -> Vec<ClientInfo> {
+                clients
+                    .iter()
+                    .filter(|client| accept(client))
+                    .cloned()
+                    .collect()
+            };
+            let (offline, recovered) = (select(offline), select(recovered));
+            // Summary of window not covering any accepted client is not interesting
+            if offline.is_empty() && recovered.is_empty() {
+                None
+            } else {
+                Some(Event::Maintenance {
+                    description: description.clone(),
+                    started_at: *started_at,
+                    ended_at: *ended_at,
+                    offline,
+                    recovered,
+                })
+            }
+        }
+    }
+}
+
+/// Receiver asks to slow down, retry after given seconds.
+#[derive(Debug)]
+pub struct RetryAfter(pub u64);
+
+impl std::fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rate limited, retry after {}s", self.0)
+    }
+}
+
+impl std::error::Error for RetryAfter {}
+
+/// Receiver refused message for good, e.g. HTTP 4xx, sending it again fails the same way.
+#[derive(Debug)]
+pub struct Rejected(pub String);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rejected: {}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Turn HTTP 429 into [`RetryAfter`] using `Retry-After` header, other 4xx into
+/// [`Rejected`], 5xx as is.
+async fn check_response(response: reqwest::Response) -> anyhow::Result<()> {
+    let status = response.status();
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(60);
+        return Err(RetryAfter(retry_after).into());
+    }
+    if status.is_client_error() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(Rejected(format!("{} {}", status, body)).into());
+    }
+    response.error_for_status()?;
+    Ok(())
+}
+
+const DEFAULT_TELEGRAM_API_SERVER: &str = "https://api.telegram.org";
+/// Callback data of acknowledge button is this prefix followed by incident id
+pub const ACKNOWLEDGE_CALLBACK_PREFIX: &str = "ack:";
+
+/// Inline keyboard with acknowledge button for every open incident in event.
+fn acknowledge_keyboard(event: &Event) -> Option<serde_json::Value> {
+    if !matches!(event, Event::Offline { .. } | Event::Escalation { .. }) {
+        return None;
+    }
+    let buttons: Vec<serde_json::Value> = event
+        .get_clients()
+        .into_iter()
+        .filter_map(|client| {
+            client.incident.map(|incident| {
+                serde_json::json!([{
+                    "text": format!("Acknowledge {}", client.name),
+                    "callback_data": format!("{}{}", ACKNOWLEDGE_CALLBACK_PREFIX, incident),
+                }])
+            })
+        })
+        .collect();
+    if buttons.is_empty() {
+        None
+    } else {
+        Some(serde_json::json!({ "inline_keyboard": buttons }))
+    }
+}
+
+/// Send message to one telegram chat, call Bot API directly so topic (thread) id is supported.
+pub struct TelegramNotifier {
+    name: String,
+    client: reqwest::Client,
+    endpoint: String,
+    recipient: TelegramRecipient,
+    renderer: Renderer,
+}
+
+impl TelegramNotifier {
+    pub fn new(
+        client: reqwest::Client,
+        bot_token: &str,
+        api_server: &Option<String>,
+        recipient: TelegramRecipient,
+        templates: &Templates,
+        timezone: Tz,
+    ) -> anyhow::Result<Self> {
+        let api_server = api_server
+            .as_deref()
+            .unwrap_or(DEFAULT_TELEGRAM_API_SERVER)
+            .trim_end_matches('/');
+        Ok(Self {
+            name: match recipient.get_thread_id() {
+                Some(thread_id) => format!("telegram-{}/{}", recipient.get_chat_id(), thread_id),
+                None => format!("telegram-{}", recipient.get_chat_id()),
+            },
+            client,
+            endpoint: format!("{}/bot{}/sendMessage", api_server, bot_token),
+            renderer: Renderer::new(
+                Format::Html,
+                &templates.merge(recipient.get_templates()),
+                timezone,
+            )?,
+            recipient,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for TelegramNotifier {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_filter(&self) -> &EventFilter {
+        self.recipient.get_filter()
+    }
+
+    async fn notify(&self, event: &Event) -> anyhow::Result<()> {
+        let mut body = serde_json::json!({
+            "chat_id": self.recipient.get_chat_id(),
+            "text": self.renderer.render(event)?,
+            "parse_mode": "HTML",
+        });
+        if let Some(thread_id) = self.recipient.get_thread_id() {
+            body["message_thread_id"] = serde_json::Value::from(thread_id);
+        }
+        if let Some(keyboard) = acknowledge_keyboard(event) {
+            body["reply_markup"] = keyboard;
+        }
+        let response = self.client.post(&self.endpoint).json(&body).send().await?;
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let body: serde_json::Value = response.json().await?;
+            let retry_after = body["parameters"]["retry_after"].as_u64().unwrap_or(30);
+            return Err(RetryAfter(retry_after).into());
+        }
+        check_response(response).await
+    }
+}
+
+/// Notifier posts to HTTP endpoint, configured by `[[notifier]]` section.
+pub struct HttpNotifier {
+    name: String,
+    filter: EventFilter,
+    kind: NotifierKind,
+    renderer: Renderer,
+    /// Plain text fallback of formatted message
+    plain: Renderer,
+    client: reqwest::Client,
+    transaction_id: AtomicU64,
+}
+
+impl HttpNotifier {
+    pub fn new(
+        index: usize,
+        cfg: &NotifierConfig,
+        templates: &Templates,
+        timezone: Tz,
+    ) -> anyhow::Result<Self> {
+        let templates = templates.merge(cfg.get_templates());
+        let format = match cfg.get_kind() {
+            NotifierKind::Slack { .. } => Format::Slack,
+            NotifierKind::Discord { .. } => Format::Markdown,
+            NotifierKind::Matrix { .. } => Format::Html,
+            _ => Format::Plain,
+        };
+        Ok(Self {
+            name: cfg
+                .get_name()
+                .clone()
+                .unwrap_or_else(|| format!("{}-{}", cfg.get_kind().get_type_name(), index)),
+            filter: cfg.get_filter().clone(),
+            kind: cfg.get_kind().clone(),
+            renderer: Renderer::new(format, &templates, timezone)?,
+            plain: Renderer::new(Format::Plain, &templates, timezone)?,
+            client: reqwest::Client::new(),
+            transaction_id: AtomicU64::new(0),
+        })
+    }
+
+    async fn post_json(
+        &self,
+        url: &str,
+        body: &serde_json::Value,
+        headers: Option<&HashMap<String, String>>,
+    ) -> anyhow::Result<()> {
+        let mut request = self.client.post(url).json(body);
+        for (key, value) in headers.into_iter().flatten() {
+            request = request.header(key.as_str(), value.as_str());
+        }
+        check_response(request.send().await?).await
+    }
+}
+
+#[async_trait]
+impl Notifier for HttpNotifier {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_filter(&self) -> &EventFilter {
+        &self.filter
+    }
+
+    async fn notify(&self, event: &Event) -> anyhow::Result<()> {
+        match &self.kind {
+            NotifierKind::Webhook { url, headers } => {
+                let mut body = serde_json::to_value(event)?;
+                body["text"] = serde_json::Value::from(self.renderer.render(event)?);
+                self.post_json(url, &body, headers.as_ref()).await
+            }
+            NotifierKind::Slack { url } => {
+                self.post_json(
+                    url,
+                    &serde_json::json!({ "text": self.renderer.render(event)? }),
+                    None,
+                )
+                .await
+            }
+            NotifierKind::Discord { url } => {
+                self.post_json(
+                    url,
+                    &serde_json::json!({ "content": self.renderer.render(event)? }),
+                    None,
+                )
+                .await
+            }
+            NotifierKind::Matrix {
+                homeserver,
+                access_token,
+                room_id,
+            } => {
+                // Transaction id only needs to be unique in this access token
+                let transaction_id = format!(
+                    "probe-server-{}-{}",
+                    crate::get_current_timestamp(),
+                    self.transaction_id.fetch_add(1, Ordering::Relaxed)
+                );
+                let mut url = reqwest::Url::parse(homeserver)?;
+                url.path_segments_mut()
+                    .map_err(|_| anyhow::anyhow!("Invalid homeserver url: {}", homeserver))?
+                    .pop_if_empty()
+                    .extend(&["_matrix", "client", "r0", "rooms"])
+                    .push(room_id)
+                    .extend(&["send", "m.room.message", &transaction_id]);
+                let response = self
+                    .client
+                    .put(url)
+                    .bearer_auth(access_token)
+                    .json(&serde_json::json!({
+                        "msgtype": "m.text",
+                        "body": self.plain.render(event)?,
+                        "format": "org.matrix.custom.html",
+                        "formatted_body": self.renderer.render(event)?.replace('\n', "<br>"),
+                    }))
+                    .send()
+                    .await?;
+                check_response(response).await
+            }
+            NotifierKind::Ntfy {
+                url,
+                token,
+                priority,
+            } => {
+                let mut request = self
+                    .client
+                    .post(url)
+                    .header("Title", event.get_title())
+                    .body(self.renderer.render(event)?);
+                if let Some(token) = token {
+                    request = request.bearer_auth(token);
+                }
+                if let Some(priority) = priority {
+                    request = request.header("Priority", priority.to_string());
+                }
+                check_response(request.send().await?).await
+            }
+            NotifierKind::Gotify {
+                url,
+                token,
+                priority,
+            } => {
+                let response = self
+                    .client
+                    .post(format!("{}/message", url.trim_end_matches('/')))
+                    .header("X-Gotify-Key", token.as_str())
+                    .json(&serde_json::json!({
+                        "title": event.get_title(),
+                        "message": self.renderer.render(event)?,
+                        "priority": priority.unwrap_or(5),
+                    }))
+                    .send()
+                    .await?;
+                check_response(response).await
+            }
+            // Email configures are built into SmtpNotifier
+            NotifierKind::Email(_) => Err(anyhow::anyhow!(
+                "Notifier {} is not sent over HTTP",
+                self.name
+            )),
+        }
+    }
+}
+
+/// Build notifiers from configure, telegram recipients are added if bot token is set.
+pub fn build_notifiers(config: &Config) -> anyhow::Result<Vec<Box<dyn Notifier>>> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+    let templates = config.get_templates();
+    let timezone = config.get_timezone()?;
+    if !config.get_bot_token().is_empty() {
+        let client = reqwest::Client::new();
+        for recipient in config.get_telegram_recipients() {
+            notifiers.push(Box::new(TelegramNotifier::new(
+                client.clone(),
+                config.get_bot_token(),
+                config.get_api_server(),
+                recipient,
+                &templates,
+                timezone,
+            )?));
+        }
+    }
+    for (index, cfg) in config.get_notifiers().iter().enumerate() {
+        match cfg.get_kind() {
+            NotifierKind::Email(smtp) => {
+                let name = cfg
+                    .get_name()
+                    .clone()
+                    .unwrap_or_else(|| format!("email-{}", index));
+                notifiers.push(Box::new(SmtpNotifier::new(
+                    name,
+                    cfg.get_filter().clone(),
+                    smtp,
+                    &templates.merge(cfg.get_templates()),
+                    timezone,
+                )?));
+            }
+            _ => notifiers.push(Box::new(HttpNotifier::new(
+                index, cfg, &templates, timezone,
+            )?)),
+        }
+    }
+    Ok(notifiers)
+}
+
+const QUEUE_BATCH_SIZE: i64 = 50;
+const RETRY_BASE_DELAY: i64 = 10;
+const RETRY_MAX_DELAY: i64 = 3600;
+/// Queued notification is marked failed after this many failed attempts.
+const MAX_ATTEMPTS: i32 = 30;
+
+/// Schedule next attempt of failed notification, return the delay or `None` if it is
+/// rejected by receiver or failed too many times, which marks it failed.
+async fn reschedule(
+    storage: &dyn Storage,
+    notifier: &str,
+    row: &NotificationRow,
+    e: &anyhow::Error,
+    now: i64,
+) -> anyhow::Result<Option<i64>> {
+    if e.downcast_ref::<Rejected>().is_some() {
+        error!(
+            "Notification {} to {} is rejected, not retrying: {:?}",
+            row.get_id(),
+            notifier,
+            e
+        );
+        storage
+            .fail_notification(row.get_id(), now, &e.to_string())
+            .await?;
+        return Ok(None);
+    }
+    let attempts = row.get_attempts() + 1;
+    if attempts >= MAX_ATTEMPTS {
+        error!(
+            "Give up notification {} to {} after {} attempts: {:?}",
+            row.get_id(),
+            notifier,
+            attempts,
+            e
+        );
+        storage
+            .fail_notification(row.get_id(), now, &e.to_string())
+            .await?;
+        return Ok(None);
+    }
+    let delay = match e.downcast_ref::<RetryAfter>() {
+        Some(retry_after) => retry_after.0 as i64,
+        None => (RETRY_BASE_DELAY << (attempts - 1).min(16)).min(RETRY_MAX_DELAY),
+    };
+    warn!(
+        "Send message to {} failed (attempt {}), retry in {}s: {:?}",
+        notifier, attempts, delay, e
+    );
+    storage
+        .reschedule_notification(row.get_id(), now + delay, &e.to_string())
+        .await?;
+    Ok(Some(delay))
+}
+
+/// Persistent outbound queue, every notification is stored before sending and
+/// removed only after notifier accepted it, rejected one is kept as failed.
+pub struct NotificationQueue {
+    storage: Arc<dyn Storage>,
+    notifiers: Vec<Box<dyn Notifier>>,
+    /// Notifier is skipped until this timestamp after failure, so message order is kept
+    blocked_until: HashMap<String, i64>,
+}
+
+impl NotificationQueue {
+    pub fn new(storage: Arc<dyn Storage>, notifiers: Vec<Box<dyn Notifier>>) -> Self {
+        Self {
+            storage,
+            notifiers,
+            blocked_until: Default::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notifiers.is_empty()
+    }
+
+    /// Drop notifications left by notifiers which are removed from configure.
+    pub async fn purge_unknown(&self) -> anyhow::Result<()> {
+        for name in self.storage.list_queued_notifiers().await? {
+            if !self
+                .notifiers
+                .iter()
+                .any(|notifier| notifier.get_name() == name)
+            {
+                let count = self.storage.delete_notifications_of(&name).await?;
+                warn!(
+                    "Dropped {} queued notification(s) of unknown notifier {}",
+                    count, name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Store event for every notifier accepts it, send directly if database is unavailable.
+    pub async fn enqueue(&self, event: &Event) {
+        let timestamp = crate::get_current_timestamp() as i64;
+        for notifier in self.notifiers.iter() {
+            if let Some(event) = filter_event(notifier.get_name(), notifier.get_filter(), event) {
+                let result = match serde_json::to_string(&event) {
+                    Ok(payload) => self
+                        .storage
+                        .enqueue_notification(notifier.get_name(), &payload, timestamp)
+                        .await
+                        .map(|_| ()),
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    error!("Unable to queue notification, send directly: {:?}", e);
+                    if let Err(e) = notifier.notify(&event).await {
+                        error!(
+                            "Got error in send message to {}: {:?}",
+                            notifier.get_name(),
+                            e
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send all due notifications, failed one is rescheduled with exponential backoff.
+    ///
+    /// Notifier with batch interval gets all due notifications in one message once the
+    /// oldest of them has waited that long, they stay queued until the message is sent.
+    pub async fn deliver(&mut self) -> anyhow::Result<()> {
+        let now = crate::get_current_timestamp() as i64;
+        self.blocked_until.retain(|_, until| *until > now);
+        for notifier in self.notifiers.iter() {
+            if self.blocked_until.contains_key(notifier.get_name()) {
+                continue;
+            }
+            let rows = self
+                .storage
+                .fetch_due_notifications(notifier.get_name(), now, QUEUE_BATCH_SIZE)
+                .await?;
+            let mut events = Vec::new();
+            for row in rows {
+                match serde_json::from_str::<Event>(row.get_payload()) {
+                    Ok(event) => events.push((row, event)),
+                    Err(e) => {
+                        error!("Drop malformed notification {}: {:?}", row.get_id(), e);
+                        self.storage.delete_notification(row.get_id()).await?;
+                    }
+                }
+            }
+            let batch_interval = notifier.get_batch_interval() as i64;
+            if batch_interval > 0 {
+                match events.first() {
+                    Some((row, _)) if now - row.get_created_at() >= batch_interval => {}
+                    _ => continue,
+                }
+                let batch: Vec<Event> = events.iter().map(|(_, event)| event.clone()).collect();
+                let e = match notifier.notify_batch(&batch).await {
+                    Ok(_) => {
+                        for (row, _) in events.iter() {
+                            self.storage.delete_notification(row.get_id()).await?;
+                        }
+                        continue;
+                    }
+                    Err(e) => e,
+                };
+                let mut retry_at = None;
+                for (row, _) in events.iter() {
+                    if let Some(delay) =
+                        reschedule(self.storage.as_ref(), notifier.get_name(), row, &e, now)
+                            .await?
+                    {
+                        retry_at = retry_at.max(Some(now + delay));
+                    }
+                }
+                if let Some(retry_at) = retry_at {
+                    self.blocked_until
+                        .insert(notifier.get_name().to_string(), retry_at);
+                }
+                continue;
+            }
+            for (row, event) in events {
+                let e = match notifier.notify(&event).await {
+                    Ok(_) => {
+                        self.storage.delete_notification(row.get_id()).await?;
+                        continue;
+                    }
+                    Err(e) => e,
+                };
+                if let Some(delay) =
+                    reschedule(self.storage.as_ref(), notifier.get_name(), &row, &e, now).await?
+                {
+                    self.blocked_until
+                        .insert(notifier.get_name().to_string(), now + delay);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::configparser::tests::config_with;
+    use wiremock::matchers::{header, method, path, path_regex};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Build the single notifier of `section`, which is the body of `[[notifier]]`.
+    fn build_notifier(section: &str) -> Box<dyn Notifier> {
+        let config = config_with(&format!("[[notifier]]\n{}", section));
+        let mut notifiers = build_notifiers(&config).unwrap();
+        assert_eq!(notifiers.len(), 1);
+        notifiers.remove(0)
+    }
+
+    fn event() -> Event {
+        serde_json::from_value(serde_json::json!({
+            "event": "online",
+            "client": {"id": 1, "uuid": "2f1c5c4e", "name": "web-1"},
+            "from_register": false,
+        }))
+        .unwrap()
+    }
+
+    async fn received_request(server: &MockServer) -> wiremock::Request {
+        let mut requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        requests.remove(0)
+    }
+
+    #[test]
+    fn routing() {
+        let notifier = build_notifier("type = \"slack\"\nurl = \"\"\nevents = [\"offline\"]\n");
+        assert_eq!(notifier.get_name(), "slack-0");
+        assert!(filter_event(notifier.get_name(), notifier.get_filter(), &event()).is_none());
+        assert!(filter_event(
+            notifier.get_name(),
+            notifier.get_filter(),
+            &Event::Offline { clients: vec![] }
+        )
+        .is_some());
+        let notifier = build_notifier("type = \"slack\"\nname = \"ops\"\nurl = \"\"\n");
+        assert_eq!(notifier.get_name(), "ops");
+        assert!(filter_event(notifier.get_name(), notifier.get_filter(), &event()).is_some());
+    }
+
+    pub(super) fn client_info(id: i32, uuid: &str, name: &str) -> ClientInfo {
+        serde_json::from_value(serde_json::json!({"id": id, "uuid": uuid, "name": name})).unwrap()
+    }
+
+    fn offline(names: &[&str]) -> Event {
+        Event::Offline {
+            clients: names
+                .iter()
+                .enumerate()
+                .map(|(index, name)| {
+                    client_info(index as i32 + 1, &format!("uuid-{}", index + 1), name)
+                })
+                .collect(),
+        }
+    }
+
+    fn tagged(id: i32, name: &str, tags: &[&str], labels: &[(&str, &str)]) -> ClientInfo {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "uuid": format!("uuid-{}", id),
+            "name": name,
+            "tags": tags,
+            "labels": labels.iter().cloned().collect::<BTreeMap<_, _>>(),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn client_matches() {
+        let client = tagged(
+            7,
+            "db-1",
+            &["prod", "db"],
+            &[("site", "tokyo"), ("role", "")],
+        );
+        let cases = [
+            ("db-1", true),
+            ("db-*", true),
+            ("uuid-7", true),
+            ("7", true),
+            ("tag:prod", true),
+            ("tag:d*", true),
+            ("tag:web", false),
+            // Tags are not matched by plain patterns, nor names by tag patterns
+            ("prod", false),
+            ("tag:db-1", false),
+            ("site=tokyo", true),
+            (" site = tok* ", true),
+            ("site=osaka", false),
+            ("role=", true),
+            ("role=*", true),
+            // Unknown label key never matches, even with wildcard value
+            ("owner=*", false),
+            ("owner=", false),
+            ("tokyo", false),
+        ];
+        for (pattern, expected) in cases {
+            assert_eq!(client.matches(pattern), expected, "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn route_by_tags_and_labels() {
+        // Any pattern of `clients` selects client
+        let recipient = recipient("clients = [\"tag:web\", \"site=tokyo\"]\n");
+        let event = Event::Offline {
+            clients: vec![
+                tagged(1, "web-1", &["web"], &[("site", "osaka")]),
+                tagged(2, "db-1", &["db"], &[("site", "tokyo")]),
+                tagged(3, "db-2", &["db"], &[("site", "osaka")]),
+                tagged(4, "cache-1", &[], &[]),
+            ],
+        };
+        match filter_event(RECIPIENT, recipient.get_filter(), &event).unwrap() {
+            Event::Offline { clients } => assert_eq!(
+                clients
+                    .iter()
+                    .map(|client| client.get_name().as_str())
+                    .collect::<Vec<_>>(),
+                vec!["web-1", "db-1"]
+            ),
+            event => panic!("unexpected event {:?}", event),
+        }
+        let event = Event::Offline {
+            clients: vec![tagged(3, "db-2", &["db"], &[("site", "osaka")])],
+        };
+        assert!(filter_event(RECIPIENT, recipient.get_filter(), &event).is_none());
+    }
+
+    /// Name of notifier built from recipient of [`recipient`].
+    const RECIPIENT: &str = "telegram--100";
+
+    fn recipient(section: &str) -> TelegramRecipient {
+        toml::from_str(&format!("chat_id = -100\n{}", section)).unwrap()
+    }
+
+    #[test]
+    fn recipient_filters() {
+        let all = recipient("");
+        assert!(filter_event(RECIPIENT, all.get_filter(), &event()).is_some());
+
+        let routers = recipient("events = [\"offline\"]\nclients = [\"router-*\", \"2\"]\n");
+        assert!(filter_event(RECIPIENT, routers.get_filter(), &event()).is_none());
+        // Offline notice only keeps clients recipient watches, matched by name or id
+        let narrowed = filter_event(
+            RECIPIENT,
+            routers.get_filter(),
+            &offline(&["router-1", "web-1", "db-1"]),
+        )
+        .unwrap();
+        assert_eq!(
+            Renderer::new(Format::Plain, &Templates::default(), Tz::UTC)
+                .unwrap()
+                .render(&narrowed)
+                .unwrap(),
+            "Clients offline:\nrouter-1: uuid-1 (last seen 1970-01-01 00:00:00 UTC)\nweb-1: uuid-2 (last seen 1970-01-01 00:00:00 UTC)"
+        );
+        assert!(filter_event(RECIPIENT, routers.get_filter(), &offline(&["db-1"])).is_none());
+
+        let by_uuid = recipient("clients = [\"2f1c*\"]\n");
+        assert!(filter_event(RECIPIENT, by_uuid.get_filter(), &event()).is_some());
+        let register = recipient("events = [\"register\"]\n");
+        assert!(filter_event(RECIPIENT, register.get_filter(), &event()).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn telegram() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/botTOKEN/sendMessage"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"ok": true})))
+            .expect(2)
+            .mount(&server)
+            .await;
+        let event = Event::Online {
+            client: client_info(1, "<uuid>", "a&b <web>"),
+            from_register: true,
+        };
+        for section in ["", "thread_id = 2\n"] {
+            let notifier = TelegramNotifier::new(
+                reqwest::Client::new(),
+                "TOKEN",
+                &Some(format!("{}/", server.uri())),
+                recipient(section),
+                &Templates::default(),
+                Tz::UTC,
+            )
+            .unwrap();
+            notifier.notify(&event).await.unwrap();
+        }
+
+        let requests = server.received_requests().await.unwrap();
+        let body: serde_json::Value = requests[0].body_json().unwrap();
+        assert_eq!(body["chat_id"], -100);
+        assert_eq!(body["parse_mode"], "HTML");
+        assert!(body.get("message_thread_id").is_none());
+        // Names are escaped for HTML parse mode
+        assert_eq!(
+            body["text"],
+            "<b>a&amp;b &lt;web&gt;</b> (1: <code>&lt;uuid&gt;</code>) comes online with register command"
+        );
+        let body: serde_json::Value = requests[1].body_json().unwrap();
+        assert_eq!(body["message_thread_id"], 2);
+    }
+
+    #[actix_rt::test]
+    async fn webhook() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/hook"))
+            .and(header("X-Token", "secret"))
+            .and(header("content-type", "application/json"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let notifier = build_notifier(&format!(
+            "type = \"webhook\"\nurl = \"{}/hook\"\nheaders = {{ X-Token = \"secret\" }}\n",
+            server.uri()
+        ));
+        notifier.notify(&event()).await.unwrap();
+
+        let body: serde_json::Value = received_request(&server).await.body_json().unwrap();
+        assert_eq!(body["event"], "online");
+        assert_eq!(body["from_register"], false);
+        assert_eq!(body["client"]["name"], "web-1");
+        assert_eq!(body["text"], "web-1 (1: 2f1c5c4e) back online");
+    }
+
+    #[actix_rt::test]
+    async fn slack_and_discord() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("content-type", "application/json"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount(&server)
+            .await;
+        build_notifier(&format!(
+            "type = \"slack\"\nurl = \"{}/slack\"\n",
+            server.uri()
+        ))
+        .notify(&event())
+        .await
+        .unwrap();
+        build_notifier(&format!(
+            "type = \"discord\"\nurl = \"{}/discord\"\n",
+            server.uri()
+        ))
+        .notify(&event())
+        .await
+        .unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests[0].url.path(), "/slack");
+        let body: serde_json::Value = requests[0].body_json().unwrap();
+        // Slack mrkdwn marks bold with single asterisks
+        assert_eq!(
+            body,
+            serde_json::json!({"text": "*web-1* (1: `2f1c5c4e`) back online"})
+        );
+        assert_eq!(requests[1].url.path(), "/discord");
+        let body: serde_json::Value = requests[1].body_json().unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"content": "**web-1** (1: `2f1c5c4e`) back online"})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn matrix() {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path_regex(
+                r"^/synapse/_matrix/client/r0/rooms/!room:example\.org%2Fx/send/m\.room\.message/probe-server-\d+-0$",
+            ))
+            .and(header("Authorization", "Bearer syt_token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"event_id": "$1"})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // Homeserver may be served under a path, room id is kept in a single segment
+        let notifier = build_notifier(&format!(
+            "type = \"matrix\"\nhomeserver = \"{}/synapse/\"\naccess_token = \"syt_token\"\nroom_id = \"!room:example.org/x\"\n",
+            server.uri()
+        ));
+        notifier.notify(&event()).await.unwrap();
+
+        let body: serde_json::Value = received_request(&server).await.body_json().unwrap();
+        assert_eq!(body["msgtype"], "m.text");
+        assert_eq!(body["format"], "org.matrix.custom.html");
+        assert_eq!(body["body"], "web-1 (1: 2f1c5c4e) back online");
+        assert_eq!(
+            body["formatted_body"],
+            "<b>web-1</b> (1: <code>2f1c5c4e</code>) back online"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn ntfy_and_gotify() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/probe-server"))
+            .and(header("Title", "web-1 back online"))
+            .and(header("Priority", "4"))
+            .and(header("Authorization", "Bearer tk_token"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/message"))
+            .and(header("X-Gotify-Key", "app_token"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        build_notifier(&format!(
+            "type = \"ntfy\"\nurl = \"{}/probe-server\"\ntoken = \"tk_token\"\npriority = 4\n",
+            server.uri()
+        ))
+        .notify(&event())
+        .await
+        .unwrap();
+        build_notifier(&format!(
+            "type = \"gotify\"\nurl = \"{}/\"\ntoken = \"app_token\"\n",
+            server.uri()
+        ))
+        .notify(&event())
+        .await
+        .unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&requests[0].body),
+            "web-1 (1: 2f1c5c4e) back online"
+        );
+        let body: serde_json::Value = requests[1].body_json().unwrap();
+        assert_eq!(body["title"], "web-1 back online");
+        assert_eq!(body["priority"], 5);
+    }
+
+    #[test]
+    fn malformed_template_at_startup() {
+        let config = config_with(
+            "[[notifier]]\ntype = \"webhook\"\nurl = \"http://127.0.0.1/hook\"\n[notifier.templates]\noffline = \"{{#each clients}}\"\n",
+        );
+        let e = build_notifiers(&config).err().unwrap();
+        assert!(
+            e.to_string().starts_with("Invalid offline template"),
+            "{}",
+            e
+        );
+    }
+
+    #[actix_rt::test]
+    async fn email_is_not_http() {
+        let config = config_with(
+            "[[notifier]]\ntype = \"email\"\nname = \"mail\"\nhost = \"127.0.0.1\"\nfrom = \"probe@example.com\"\nto = [\"ops@example.com\"]\n",
+        );
+        let notifier = HttpNotifier::new(
+            0,
+            &config.get_notifiers()[0],
+            &Templates::default(),
+            Tz::UTC,
+        )
+        .unwrap();
+        let e = notifier.notify(&event()).await.unwrap_err();
+        assert_eq!(e.to_string(), "Notifier mail is not sent over HTTP");
+    }
+
+    #[actix_rt::test]
+    async fn error_status() {
+        let server = MockServer::start().await;
+        Mock::given(path("/limited"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "30"))
+            .mount(&server)
+            .await;
+        Mock::given(path("/gone"))
+            .respond_with(ResponseTemplate::new(404).set_body_string("no_team"))
+            .mount(&server)
+            .await;
+        Mock::given(path("/broken"))
+            .respond_with(ResponseTemplate::new(502))
+            .mount(&server)
+            .await;
+
+        let notify = |url: &str| {
+            let notifier = build_notifier(&format!(
+                "type = \"slack\"\nurl = \"{}{}\"\n",
+                server.uri(),
+                url
+            ));
+            async move { notifier.notify(&event()).await.unwrap_err() }
+        };
+        let e = notify("/limited").await;
+        assert_eq!(e.downcast_ref::<RetryAfter>().unwrap().0, 30);
+        let e = notify("/gone").await;
+        assert!(e.downcast_ref::<Rejected>().unwrap().0.contains("no_team"));
+        // Server errors are worth retrying
+        let e = notify("/broken").await;
+        assert!(e.downcast_ref::<Rejected>().is_none());
+        assert!(e.downcast_ref::<RetryAfter>().is_none());
+    }
+
+    /// Notifier keeps every message it is asked to send, fails while `failing` is set.
+    struct Recorder {
+        name: String,
+        filter: EventFilter,
+        batch_interval: u64,
+        sent: Sent,
+        failing: Failing,
+    }
+
+    #[async_trait]
+    impl Notifier for Recorder {
+        fn get_name(&self) -> &str {
+            &self.name
+        }
+
+        fn get_filter(&self) -> &EventFilter {
+            &self.filter
+        }
+
+        async fn notify(&self, event: &Event) -> anyhow::Result<()> {
+            self.notify_batch(std::slice::from_ref(event)).await
+        }
+
+        fn get_batch_interval(&self) -> u64 {
+            self.batch_interval
+        }
+
+        async fn notify_batch(&self, events: &[Event]) -> anyhow::Result<()> {
+            if let Some(error) = *self.failing.lock().unwrap() {
+                return Err(error());
+            }
+            self.sent.lock().unwrap().push(events.to_vec());
+            Ok(())
+        }
+    }
+
+    type Sent = Arc<std::sync::Mutex<Vec<Vec<Event>>>>;
+    type Failing = Arc<std::sync::Mutex<Option<fn() -> anyhow::Error>>>;
+
+    async fn queue(batch_interval: u64) -> (NotificationQueue, Arc<dyn Storage>, Sent, Failing) {
+        let storage = crate::storage::connect("sqlite::memory:").await.unwrap();
+        let sent: Sent = Default::default();
+        let failing: Failing = Default::default();
+        let recorder = Recorder {
+            name: "recorder".to_string(),
+            filter: Default::default(),
+            batch_interval,
+            sent: sent.clone(),
+            failing: failing.clone(),
+        };
+        let queue = NotificationQueue::new(storage.clone(), vec![Box::new(recorder)]);
+        (queue, storage, sent, failing)
+    }
+
+    async fn queued(storage: &Arc<dyn Storage>, now: i64) -> Vec<NotificationRow> {
+        storage
+            .fetch_due_notifications("recorder", now, 10)
+            .await
+            .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn queue_retry() {
+        let (mut queue, storage, sent, failing) = queue(0).await;
+        let now = crate::get_current_timestamp() as i64;
+        queue.enqueue(&event()).await;
+        queue.enqueue(&offline(&["db-1"])).await;
+
+        *failing.lock().unwrap() = Some(|| anyhow::anyhow!("502 Bad Gateway"));
+        queue.deliver().await.unwrap();
+        // First failure blocks the notifier so later messages keep their order
+        let rows = queued(&storage, now + 3600).await;
+        assert_eq!(
+            rows.iter()
+                .map(|row| row.get_attempts())
+                .collect::<Vec<_>>(),
+            vec![1, 0]
+        );
+        assert!(queued(&storage, now + RETRY_BASE_DELAY - 1).await.len() < 2);
+        queue.deliver().await.unwrap();
+        assert_eq!(queued(&storage, now + 3600).await[0].get_attempts(), 1);
+
+        *failing.lock().unwrap() = None;
+        queue.blocked_until.clear();
+        storage
+            .reschedule_notification(rows[0].get_id(), now, "")
+            .await
+            .unwrap();
+        queue.deliver().await.unwrap();
+        assert!(queued(&storage, now + 3600).await.is_empty());
+        let sent = sent.lock().unwrap();
+        assert_eq!(
+            sent.iter()
+                .map(|batch| batch[0].get_kind())
+                .collect::<Vec<_>>(),
+            vec!["online", "offline"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn queue_rejected() {
+        let (mut queue, storage, sent, failing) = queue(0).await;
+        let now = crate::get_current_timestamp() as i64;
+        queue.enqueue(&event()).await;
+        *failing.lock().unwrap() = Some(|| Rejected("404 no_team".to_string()).into());
+        queue.deliver().await.unwrap();
+        // Rejected notification is failed at once and does not block the notifier
+        assert!(queued(&storage, now + 86400).await.is_empty());
+        assert!(queue.blocked_until.is_empty());
+
+        *failing.lock().unwrap() = Some(|| RetryAfter(30).into());
+        queue.enqueue(&event()).await;
+        queue.deliver().await.unwrap();
+        assert!(queued(&storage, now + 29).await.is_empty());
+        assert_eq!(queued(&storage, now + 31).await.len(), 1);
+        assert!(sent.lock().unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn queue_batch() {
+        let (mut queue, storage, sent, _) = queue(60).await;
+        let now = crate::get_current_timestamp() as i64;
+        queue.enqueue(&event()).await;
+        queue.deliver().await.unwrap();
+        // Nothing is sent before the oldest notification waited batch interval
+        assert!(sent.lock().unwrap().is_empty());
+        assert_eq!(queued(&storage, now).await.len(), 1);
+
+        storage.delete_notifications_of("recorder").await.unwrap();
+        storage
+            .enqueue_notification(
+                "recorder",
+                &serde_json::to_string(&offline(&["db-1"])).unwrap(),
+                now - 60,
+            )
+            .await
+            .unwrap();
+        queue.enqueue(&event()).await;
+        queue.deliver().await.unwrap();
+        // Later notifications go out together with the oldest one
+        assert!(queued(&storage, now + 1).await.is_empty());
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            sent[0].iter().map(Event::get_kind).collect::<Vec<_>>(),
+            vec!["offline", "online"]
+        );
+    }
+}
@@ -0,0 +1,353 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use super::{Event, Format, Notifier, Rejected, Renderer};
+use crate::configparser::{EventFilter, SmtpConfig, SmtpTls, Templates};
+use async_trait::async_trait;
+use chrono_tz::Tz;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+type Transport = AsyncSmtpTransport<Tokio1Executor>;
+
+/// Notifier sends events by email, events queued within batch interval are merged into
+/// one mail.
+pub struct SmtpNotifier {
+    name: String,
+    filter: EventFilter,
+    mailer: Mailer,
+    batch_interval: u64,
+}
+
+struct Mailer {
+    transport: Transport,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    renderer: Renderer,
+}
+
+impl Mailer {
+    fn new(cfg: &SmtpConfig, templates: &Templates, timezone: Tz) -> anyhow::Result<Self> {
+        let builder = match cfg.get_tls() {
+            SmtpTls::None => Transport::builder_dangerous(cfg.get_host()),
+            SmtpTls::StartTls => Transport::starttls_relay(cfg.get_host())?,
+            SmtpTls::Tls => Transport::relay(cfg.get_host())?,
+        };
+        let mut builder = builder.port(cfg.get_port());
+        if let Some((username, password)) = cfg.get_credentials() {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        let mut to = Vec::new();
+        for address in cfg.get_to() {
+            to.push(address.parse()?);
+        }
+        if to.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Email notifier requires at least one recipient"
+            ));
+        }
+        Ok(Self {
+            transport: builder.build(),
+            from: cfg.get_from().parse()?,
+            to,
+            renderer: Renderer::new(Format::Plain, templates, timezone)?,
+        })
+    }
+
+    async fn send(&self, events: &[Event]) -> anyhow::Result<()> {
+        let subject = match events {
+            [event] => event.get_title(),
+            _ => format!("{} probe events", events.len()),
+        };
+        let body = events
+            .iter()
+            .map(|event| self.renderer.render(event))
+            .collect::<Result<Vec<String>, _>>()?;
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(format!("[probe-server] {}", subject));
+        for to in &self.to {
+            builder = builder.to(to.clone());
+        }
+        match self.transport.send(builder.body(body.join("\n\n"))?).await {
+            Ok(_) => Ok(()),
+            // 5xx reply of SMTP server, e.g. unknown recipient
+            Err(e) if e.is_permanent() => Err(Rejected(e.to_string()).into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Merge all offline notices into one event, other events keep their order.
+fn merge_offline(events: Vec<Event>) -> Vec<Event> {
+    let mut merged: Vec<Event> = Vec::new();
+    let mut offline_index = None;
+    for event in events {
+        match event {
+            Event::Offline { clients } => match offline_index {
+                Some(index) => {
+                    if let Event::Offline {
+                        clients: ref mut all,
+                    } = merged[index]
+                    {
+                        all.extend(clients);
+                    }
+                }
+                None => {
+                    offline_index = Some(merged.len());
+                    merged.push(Event::Offline { clients });
+                }
+            },
+            event => merged.push(event),
+        }
+    }
+    merged
+}
+
+impl SmtpNotifier {
+    pub fn new(
+        name: String,
+        filter: EventFilter,
+        cfg: &SmtpConfig,
+        templates: &Templates,
+        timezone: Tz,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            name,
+            filter,
+            mailer: Mailer::new(cfg, templates, timezone)?,
+            batch_interval: cfg.get_batch_interval(),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for SmtpNotifier {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_filter(&self) -> &EventFilter {
+        &self.filter
+    }
+
+    async fn notify(&self, event: &Event) -> anyhow::Result<()> {
+        self.mailer.send(std::slice::from_ref(event)).await
+    }
+
+    fn get_batch_interval(&self) -> u64 {
+        self.batch_interval
+    }
+
+    async fn notify_batch(&self, events: &[Event]) -> anyhow::Result<()> {
+        self.mailer.send(&merge_offline(events.to_vec())).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    #[derive(Debug)]
+    struct Mail {
+        to: Vec<String>,
+        data: String,
+    }
+
+    /// Minimal SMTP server keeps every accepted mail, recipients of `reject` domain get 550.
+    async fn smtp_sink(reject: &'static str) -> (u16, Arc<Mutex<Vec<Mail>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mails = Arc::new(Mutex::new(Vec::new()));
+        let inbox = mails.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let inbox = inbox.clone();
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    writer.write_all(b"220 sink ESMTP\r\n").await.unwrap();
+                    let mut to = Vec::new();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let command = line.to_ascii_uppercase();
+                        let reply: &[u8] = if command.starts_with("EHLO") {
+                            b"250-sink\r\n250 8BITMIME\r\n"
+                        } else if command.starts_with("RCPT TO") {
+                            if line.contains(reject) {
+                                b"550 5.1.1 mailbox unavailable\r\n"
+                            } else {
+                                to.push(
+                                    line[8..].trim_matches(|c| c == '<' || c == '>').to_string(),
+                                );
+                                b"250 OK\r\n"
+                            }
+                        } else if command == "DATA" {
+                            writer.write_all(b"354 end with .\r\n").await.unwrap();
+                            let mut data = String::new();
+                            while let Ok(Some(line)) = lines.next_line().await {
+                                if line == "." {
+                                    break;
+                                }
+                                data.push_str(&line);
+                                data.push('\n');
+                            }
+                            inbox.lock().unwrap().push(Mail {
+                                to: std::mem::take(&mut to),
+                                data,
+                            });
+                            b"250 queued\r\n"
+                        } else if command == "QUIT" {
+                            writer.write_all(b"221 bye\r\n").await.unwrap();
+                            break;
+                        } else {
+                            // MAIL FROM, RSET, NOOP
+                            b"250 OK\r\n"
+                        };
+                        writer.write_all(reply).await.unwrap();
+                    }
+                });
+            }
+        });
+        (port, mails)
+    }
+
+    fn notifier(port: u16, to: &str) -> SmtpNotifier {
+        let cfg: SmtpConfig = toml::from_str(&format!(
+            "host = \"127.0.0.1\"\nport = {}\ntls = \"none\"\nfrom = \"probe@example.com\"\nto = [\"{}\"]\nbatch_interval = 60\n",
+            port, to
+        ))
+        .unwrap();
+        SmtpNotifier::new(
+            "email".to_string(),
+            EventFilter::default(),
+            &cfg,
+            &Templates::default(),
+            Tz::UTC,
+        )
+        .unwrap()
+    }
+
+    fn offline(id: i32, name: &str) -> Event {
+        serde_json::from_value(serde_json::json!({
+            "event": "offline",
+            "clients": [{"id": id, "uuid": format!("uuid-{}", id), "name": name}],
+        }))
+        .unwrap()
+    }
+
+    fn online(name: &str) -> Event {
+        serde_json::from_value(serde_json::json!({
+            "event": "online",
+            "client": {"id": 9, "uuid": "uuid-9", "name": name},
+            "from_register": false,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn merge_offline_keeps_order() {
+        let merged = merge_offline(vec![
+            online("db-1"),
+            offline(1, "web-1"),
+            online("db-2"),
+            offline(2, "web-2"),
+        ]);
+        assert_eq!(
+            merged.iter().map(Event::get_kind).collect::<Vec<_>>(),
+            vec!["online", "offline", "online"]
+        );
+        assert_eq!(
+            Renderer::new(Format::Plain, &Templates::default(), Tz::UTC)
+                .unwrap()
+                .render(&merged[1])
+                .unwrap(),
+            "Clients offline:\nweb-1: uuid-1 (last seen 1970-01-01 00:00:00 UTC)\nweb-2: uuid-2 (last seen 1970-01-01 00:00:00 UTC)"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn batch_in_one_mail() {
+        let (port, mails) = smtp_sink("@invalid").await;
+        let notifier = notifier(port, "ops@example.com");
+        assert_eq!(notifier.get_batch_interval(), 60);
+        notifier
+            .notify_batch(&[
+                offline(1, "web-1"),
+                offline(2, "web-2"),
+                online("db-1"),
+                offline(3, "web-3"),
+            ])
+            .await
+            .unwrap();
+
+        let mails = mails.lock().unwrap();
+        assert_eq!(mails.len(), 1);
+        let mail = &mails[0];
+        assert_eq!(mail.to, vec!["ops@example.com"]);
+        assert!(
+            mail.data.contains("Subject: [probe-server] 2 probe events"),
+            "{}",
+            mail.data
+        );
+        // Offline clients are listed under a single notice
+        assert_eq!(mail.data.matches("Clients offline:").count(), 1);
+        for name in ["web-1", "web-2", "web-3", "db-1"] {
+            assert!(
+                mail.data.contains(name),
+                "{} missing in {}",
+                name,
+                mail.data
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn single_event_subject() {
+        let (port, mails) = smtp_sink("@invalid").await;
+        let notifier = notifier(port, "ops@example.com");
+        notifier.notify(&online("db-1")).await.unwrap();
+
+        let mails = mails.lock().unwrap();
+        assert_eq!(mails.len(), 1);
+        assert!(mails[0]
+            .data
+            .contains("Subject: [probe-server] db-1 back online"));
+    }
+
+    #[actix_rt::test]
+    async fn rejected_recipient() {
+        let (port, mails) = smtp_sink("@invalid").await;
+        let notifier = notifier(port, "nobody@invalid");
+        let e = notifier.notify(&online("db-1")).await.unwrap_err();
+        assert!(e.downcast_ref::<Rejected>().is_some(), "{:?}", e);
+        assert!(mails.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn requires_recipient() {
+        let cfg: SmtpConfig =
+            toml::from_str("host = \"127.0.0.1\"\nfrom = \"probe@example.com\"\nto = []\n")
+                .unwrap();
+        assert!(Mailer::new(&cfg, &Templates::default(), Tz::UTC).is_err());
+    }
+}
@@ -0,0 +1,410 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use super::{ClientInfo, Event};
+use crate::configparser::Templates;
+use crate::utils::{format_duration, format_timestamp, html_escape};
+use chrono_tz::Tz;
+use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderError};
+use serde_json::{json, Value};
+
+pub const DEFAULT_ONLINE_TEMPLATE: &str = "{{bold client.name}} ({{client.id}}: {{code client.uuid}}) back online{{#if client.downtime}} after {{client.downtime}}{{/if}}";
+pub const DEFAULT_REGISTER_TEMPLATE: &str =
+    "{{bold client.name}} ({{client.id}}: {{code client.uuid}}) comes online with register command";
+pub const DEFAULT_REBOOT_TEMPLATE: &str = "{{bold client.name}} ({{client.id}}: {{code client.uuid}}) rebooted{{#if uptime}} (uptime was {{uptime}}){{/if}}";
+pub const DEFAULT_FLAPPING_TEMPLATE: &str = "{{bold client.name}} ({{client.id}}: {{code client.uuid}}) is flapping, {{transitions}} state changes within {{window}}, notifications are suppressed until it is stable";
+pub const DEFAULT_STABLE_TEMPLATE: &str = "{{bold client.name}} ({{client.id}}: {{code client.uuid}}) stopped flapping and is {{#if online}}online{{else}}offline{{/if}}, {{transitions}} state changes in {{duration}}";
+pub const DEFAULT_MAINTENANCE_TEMPLATE: &str = "Maintenance{{#if description}} {{bold description}}{{/if}} ended after {{duration}}{{#if offline}}\nStill offline:{{#each offline}}\n{{bold name}}: {{code uuid}} (last seen {{last_seen}}){{/each}}{{/if}}{{#if recovered}}\nBack online:{{#each recovered}}\n{{bold name}}: {{code uuid}}{{/each}}{{/if}}{{#unless count}}, no client went offline{{/unless}}";
+pub const DEFAULT_ALERT_TEMPLATE: &str = "{{bold client.name}} ({{client.id}}: {{code client.uuid}}) alert {{bold rule}} firing: {{code expression}}{{#if series}} on {{series}}{{/if}}, value is {{value}}";
+pub const DEFAULT_RESOLVED_TEMPLATE: &str = "{{bold client.name}} ({{client.id}}: {{code client.uuid}}) alert {{bold rule}} resolved{{#if series}} on {{series}}{{/if}}, value is {{value}}";
+pub const DEFAULT_ESCALATION_TEMPLATE: &str = "{{bold client.name}} ({{client.id}}: {{code client.uuid}}) is still offline after {{client.downtime}} and nobody acknowledged it (escalation level {{level}})";
+pub const DEFAULT_OFFLINE_TEMPLATE: &str = "Clients offline:{{#each clients}}\n{{bold name}}: {{code uuid}} (last seen {{last_seen}}){{#if unreachable}}, unreachable behind it:{{#each unreachable}}{{#unless @first}},{{/unless}} {{name}}{{/each}}{{/if}}{{/each}}";
+
+/// Markup of rendered message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Html,
+    /// Discord flavored markdown
+    Markdown,
+    /// Slack mrkdwn
+    Slack,
+    Plain,
+}
+
+impl Format {
+    pub fn escape(&self, s: &str) -> String {
+        match self {
+            Format::Html => html_escape(s),
+            Format::Markdown => {
+                let mut output = String::with_capacity(s.len());
+                for c in s.chars() {
+                    if matches!(c, '\\' | '*' | '_' | '~' | '`' | '|' | '>') {
+                        output.push('\\');
+                    }
+                    output.push(c);
+                }
+                output
+            }
+            Format::Slack => s
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;"),
+            Format::Plain => s.to_string(),
+        }
+    }
+
+    pub fn bold(&self, s: &str) -> String {
+        let s = self.escape(s);
+        match self {
+            Format::Html => format!("<b>{}</b>", s),
+            Format::Markdown => format!("**{}**", s),
+            Format::Slack => format!("*{}*", s),
+            Format::Plain => s,
+        }
+    }
+
+    pub fn code(&self, s: &str) -> String {
+        match self {
+            Format::Html => format!("<code>{}</code>", html_escape(s)),
+            Format::Markdown | Format::Slack => format!("`{}`", s.replace('`', "'")),
+            Format::Plain => s.to_string(),
+        }
+    }
+}
+
+fn param_to_string(h: &Helper) -> String {
+    match h.param(0).map(|param| param.value()) {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(value) => value.to_string(),
+    }
+}
+
+fn client_context(client: &ClientInfo, timezone: &Tz) -> Value {
+    json!({
+        "id": client.get_id(),
+        "uuid": client.get_uuid(),
+        "name": client.get_name(),
+        "hostname": client.get_hostname(),
+        "last_seen": format_timestamp(client.get_last_seen(), timezone),
+        "last_seen_timestamp": client.get_last_seen(),
+        "boot_time": format_timestamp(client.get_boot_time(), timezone),
+        "boot_time_timestamp": client.get_boot_time(),
+        "downtime": client.get_downtime().map(|downtime| format_duration(downtime.max(0) as u64)),
+        "downtime_seconds": client.get_downtime(),
+        "tags": client.get_tags(),
+        "labels": client.get_labels(),
+        "unreachable": client
+            .get_unreachable()
+            .iter()
+            .map(|child| client_context(child, timezone))
+            .collect::<Vec<Value>>(),
+        "incident": client.get_incident(),
+    })
+}
+
+fn event_context(event: &Event, timezone: &Tz) -> Value {
+    let clients: Vec<Value> = event
+        .get_clients()
+        .into_iter()
+        .map(|client| client_context(client, timezone))
+        .collect();
+    let uptime = match event {
+        Event::Reboot { uptime, .. } => *uptime,
+        _ => None,
+    };
+    let mut context = json!({
+        "event": event.get_kind(),
+        "count": clients.len(),
+        "client": clients.first(),
+        "clients": clients,
+        "uptime": uptime.map(|uptime| format_duration(uptime.max(0) as u64)),
+        "uptime_seconds": uptime,
+    });
+    match event {
+        Event::Flapping {
+            transitions,
+            window,
+            ..
+        } => {
+            context["transitions"] = json!(transitions);
+            context["window"] = json!(format_duration(*window as u64));
+        }
+        Event::Stable {
+            online,
+            transitions,
+            duration,
+            ..
+        } => {
+            context["online"] = json!(online);
+            context["transitions"] = json!(transitions);
+            context["duration"] = json!(format_duration((*duration).max(0) as u64));
+        }
+        Event::Alert {
+            rule,
+            expression,
+            series,
+            value,
+            ..
+        } => {
+            context["rule"] = json!(rule);
+            context["expression"] = json!(expression);
+            context["series"] = json!(series);
+            context["value"] = json!((value * 100.0).round() / 100.0);
+        }
+        Event::Escalation { level, .. } => {
+            context["level"] = json!(level);
+        }
+        Event::Maintenance {
+            description,
+            started_at,
+            ended_at,
+            offline,
+            recovered,
+        } => {
+            let clients = |clients: &Vec<ClientInfo>| -> Vec<Value> {
+                clients
+                    .iter()
+                    .map(|client| client_context(client, timezone))
+                    .collect()
+            };
+            context["description"] = json!(description);
+            context["started_at"] = json!(format_timestamp(*started_at, timezone));
+            context["ended_at"] = json!(format_timestamp(*ended_at, timezone));
+            context["duration"] = json!(format_duration((ended_at - started_at).max(0) as u64));
+            context["offline"] = json!(clients(offline));
+            context["recovered"] = json!(clients(recovered));
+        }
+        _ => {}
+    }
+    context
+}
+
+/// Render events with handlebars templates, values are escaped according to [`Format`].
+///
+/// Besides plain `{{name}}`, templates can use `{{bold name}}` and `{{code uuid}}` helpers
+/// to emit markup of the target format.
+pub struct Renderer {
+    registry: Handlebars<'static>,
+    timezone: Tz,
+}
+
+impl Renderer {
+    pub fn new(format: Format, templates: &Templates, timezone: Tz) -> anyhow::Result<Self> {
+        let mut registry = Handlebars::new();
+        registry.register_escape_fn(move |s| format.escape(s));
+        registry.register_helper(
+            "bold",
+            Box::new(
+                move |h: &Helper,
+                      _: &Handlebars,
+                      _: &Context,
+                      _: &mut RenderContext,
+                      out: &mut dyn Output|
+                      -> HelperResult {
+                    out.write(&format.bold(&param_to_string(h)))?;
+                    Ok(())
+                },
+            ),
+        );
+        registry.register_helper(
+            "code",
+            Box::new(
+                move |h: &Helper,
+                      _: &Handlebars,
+                      _: &Context,
+                      _: &mut RenderContext,
+                      out: &mut dyn Output|
+                      -> HelperResult {
+                    out.write(&format.code(&param_to_string(h)))?;
+                    Ok(())
+                },
+            ),
+        );
+        for &(kind, default) in &[
+            ("online", DEFAULT_ONLINE_TEMPLATE),
+            ("register", DEFAULT_REGISTER_TEMPLATE),
+            ("offline", DEFAULT_OFFLINE_TEMPLATE),
+            ("reboot", DEFAULT_REBOOT_TEMPLATE),
+            ("flapping", DEFAULT_FLAPPING_TEMPLATE),
+            ("stable", DEFAULT_STABLE_TEMPLATE),
+            ("maintenance", DEFAULT_MAINTENANCE_TEMPLATE),
+            ("alert", DEFAULT_ALERT_TEMPLATE),
+            ("resolved", DEFAULT_RESOLVED_TEMPLATE),
+            ("escalation", DEFAULT_ESCALATION_TEMPLATE),
+        ] {
+            let template = templates.get(kind).map(|s| s.as_str()).unwrap_or(default);
+            registry
+                .register_template_string(kind, template)
+                .map_err(|e| anyhow::anyhow!("Invalid {} template: {}", kind, e))?;
+        }
+        Ok(Self { registry, timezone })
+    }
+
+    pub fn render(&self, event: &Event) -> Result<String, RenderError> {
+        self.registry
+            .render(event.get_kind(), &event_context(event, &self.timezone))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifier::tests::client_info;
+
+    #[test]
+    fn render() {
+        let event = Event::Offline {
+            clients: vec![client_info(2, "uuid`1", "<db>_1")],
+        };
+        let render = |format| {
+            Renderer::new(format, &Templates::default(), Tz::UTC)
+                .unwrap()
+                .render(&event)
+                .unwrap()
+        };
+        assert_eq!(
+            render(Format::Html),
+            "Clients offline:\n<b>&lt;db&gt;_1</b>: <code>uuid`1</code> (last seen 1970-01-01 00:00:00 UTC)"
+        );
+        assert_eq!(
+            render(Format::Markdown),
+            "Clients offline:\n**<db\\>\\_1**: `uuid'1` (last seen 1970-01-01 00:00:00 UTC)"
+        );
+        assert_eq!(
+            render(Format::Slack),
+            "Clients offline:\n*&lt;db&gt;_1*: `uuid'1` (last seen 1970-01-01 00:00:00 UTC)"
+        );
+        assert_eq!(
+            render(Format::Plain),
+            "Clients offline:\n<db>_1: uuid`1 (last seen 1970-01-01 00:00:00 UTC)"
+        );
+    }
+
+    fn templates(toml: &str) -> Templates {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn default_templates() {
+        let renderer = Renderer::new(Format::Html, &Templates::default(), Tz::UTC).unwrap();
+        let cases =
+            vec![
+            (
+                Event::Online {
+                    client: client_info(1, "2f1c", "web-1").with_downtime(Some(3720)),
+                    from_register: false,
+                },
+                "<b>web-1</b> (1: <code>2f1c</code>) back online after 1h 2m",
+            ),
+            (
+                Event::Online {
+                    client: client_info(1, "2f1c", "web-1"),
+                    from_register: false,
+                },
+                "<b>web-1</b> (1: <code>2f1c</code>) back online",
+            ),
+            (
+                Event::Online {
+                    client: client_info(1, "2f1c", "web-1"),
+                    from_register: true,
+                },
+                "<b>web-1</b> (1: <code>2f1c</code>) comes online with register command",
+            ),
+            (
+                Event::Offline {
+                    clients: vec![client_info(1, "2f1c", "web-1"), client_info(2, "9a0b", "db-1")],
+                },
+                "Clients offline:\n<b>web-1</b>: <code>2f1c</code> (last seen 1970-01-01 00:00:00 UTC)\n<b>db-1</b>: <code>9a0b</code> (last seen 1970-01-01 00:00:00 UTC)",
+            ),
+        ];
+        for (event, expected) in cases {
+            assert_eq!(
+                renderer.render(&event).unwrap(),
+                expected,
+                "{}",
+                event.get_kind()
+            );
+        }
+    }
+
+    #[test]
+    fn custom_templates() {
+        let renderer = Renderer::new(
+            Format::Html,
+            &templates(
+                "online = \"{{client.name}} up, down {{client.downtime_seconds}}s\"\n\
+                 offline = \"{{count}} offline:{{#each clients}} {{name}}@{{last_seen}}{{/each}}\"\n",
+            ),
+            Tz::UTC,
+        )
+        .unwrap();
+        let online = Event::Online {
+            client: client_info(1, "2f1c", "a&b").with_downtime(Some(90)),
+            from_register: false,
+        };
+        // Plain values are escaped for the target format
+        assert_eq!(renderer.render(&online).unwrap(), "a&amp;b up, down 90s");
+        let offline = Event::Offline {
+            clients: vec![serde_json::from_value(serde_json::json!({
+                "id": 2, "uuid": "9a0b", "name": "<db>", "last_seen": 1622548800,
+            }))
+            .unwrap()],
+        };
+        assert_eq!(
+            renderer.render(&offline).unwrap(),
+            "1 offline: &lt;db&gt;@2021-06-01 12:00:00 UTC"
+        );
+        // Unset kinds fall back to the built-in text
+        let register = Event::Online {
+            client: client_info(1, "2f1c", "web-1"),
+            from_register: true,
+        };
+        assert!(renderer
+            .render(&register)
+            .unwrap()
+            .ends_with("comes online with register command"));
+    }
+
+    #[test]
+    fn merge_overrides() {
+        let global = templates("online = \"global\"\noffline = \"global\"\n");
+        let merged = global.merge(&Some(templates("online = \"notifier\"\n")));
+        assert_eq!(merged.get("online").unwrap(), "notifier");
+        assert_eq!(merged.get("offline").unwrap(), "global");
+        assert!(merged.get("register").is_none());
+        assert_eq!(global.merge(&None).get("online").unwrap(), "global");
+    }
+
+    #[test]
+    fn malformed_template() {
+        let e = Renderer::new(
+            Format::Plain,
+            &templates("online = \"{{#if client.name}}\"\n"),
+            Tz::UTC,
+        )
+        .err()
+        .unwrap();
+        assert!(
+            e.to_string().starts_with("Invalid online template"),
+            "{}",
+            e
+        );
+    }
+}
@@ -0,0 +1,120 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::configparser::{Retention, TableRetention};
+use crate::storage::{RetentionTable, Storage, ROLLUP_1H, ROLLUP_5M};
+use crate::{get_current_timestamp, Command};
+use log::{debug, error, info};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// Bucket which contains timestamp should not be aggregated, since it may still receive samples
+fn align(timestamp: i64, resolution: i64) -> i64 {
+    timestamp / resolution * resolution
+}
+
+async fn truncate(
+    storage: &dyn Storage,
+    table: RetentionTable,
+    policy: &TableRetention,
+) -> anyhow::Result<()> {
+    if let Some(max_rows) = policy.get_max_rows() {
+        let rows = storage.truncate_rows(table, max_rows as i64).await?;
+        if rows > 0 {
+            debug!("Truncated {} rows from {:?}", rows, table);
+        }
+    }
+    Ok(())
+}
+
+async fn apply_policy(storage: &dyn Storage, policy: &Retention) -> anyhow::Result<()> {
+    let current_time = get_current_timestamp() as i64;
+
+    let raw_data = policy.get_raw_data();
+    if let Some(max_age) = raw_data.get_max_age() {
+        let rows = storage
+            .delete_expired(RetentionTable::RawData, current_time - max_age as i64)
+            .await?;
+        if rows > 0 {
+            debug!("Deleted {} expired raw data", rows);
+        }
+    }
+    truncate(storage, RetentionTable::RawData, &raw_data).await?;
+
+    let metrics = policy.get_metrics();
+    if let Some(max_age) = metrics.get_max_age() {
+        let before = align(current_time - max_age as i64, ROLLUP_5M);
+        let rows = storage.rollup_metrics(ROLLUP_5M, before).await?;
+        if rows > 0 {
+            debug!("Rolled up {} 5-minute buckets", rows);
+        }
+    }
+    truncate(storage, RetentionTable::Metrics, &metrics).await?;
+
+    let metrics_5m = policy.get_metrics_5m();
+    if let Some(max_age) = metrics_5m.get_max_age() {
+        let before = align(current_time - max_age as i64, ROLLUP_1H);
+        let rows = storage.rollup_metrics(ROLLUP_1H, before).await?;
+        if rows > 0 {
+            debug!("Rolled up {} hourly buckets", rows);
+        }
+    }
+    truncate(storage, RetentionTable::Rollup(ROLLUP_5M), &metrics_5m).await?;
+
+    let metrics_1h = policy.get_metrics_1h();
+    if let Some(max_age) = metrics_1h.get_max_age() {
+        storage
+            .delete_expired(
+                RetentionTable::Rollup(ROLLUP_1H),
+                current_time - max_age as i64,
+            )
+            .await?;
+    }
+    truncate(storage, RetentionTable::Rollup(ROLLUP_1H), &metrics_1h).await?;
+    Ok(())
+}
+
+pub async fn retention_daemon(
+    storage: Arc<dyn Storage>,
+    policy: Retention,
+    mut rx: mpsc::Receiver<Command>,
+) -> anyhow::Result<()> {
+    let mut last_vacuum = get_current_timestamp();
+    debug!("Starting retention daemon");
+    loop {
+        match tokio::time::timeout(Duration::from_secs(policy.get_interval()), rx.recv()).await {
+            Ok(Some(Command::Terminate)) | Ok(None) => break,
+            _ => {}
+        }
+        if let Err(e) = apply_policy(storage.as_ref(), &policy).await {
+            error!("Got error while applying retention policy: {:?}", e);
+        }
+        let vacuum_interval = policy.get_vacuum_interval();
+        if vacuum_interval > 0 && get_current_timestamp() - last_vacuum >= vacuum_interval {
+            info!("Vacuum database");
+            if let Err(e) = storage.vacuum(policy.get_incremental_vacuum()).await {
+                error!("Got error while vacuum database: {:?}", e);
+            }
+            last_vacuum = get_current_timestamp();
+        }
+    }
+    debug!("Retention daemon exiting...");
+    Ok(())
+}
@@ -0,0 +1,1155 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+mod postgres;
+mod sqlite;
+
+use crate::database::{
+    AlertRow, AlertRuleRow, ClientRow, DiskMetricsRollupRow, DiskMetricsRow, IncidentRow,
+    MaintenanceRow, MetricsRollupRow, MetricsRow, NetworkMetricsRollupRow, NetworkMetricsRow,
+    NotificationRow, RawDataRow, TimeoutOverrideRow,
+};
+use crate::metrics::Statistics;
+use crate::structs::{HistoryQuery, IncidentQuery, MaintenanceRequest};
+use async_trait::async_trait;
+use log::info;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+pub use postgres::PostgresStorage;
+pub use sqlite::SqliteStorage;
+
+pub const ROLLUP_5M: i64 = 5 * 60;
+pub const ROLLUP_1H: i64 = 60 * 60;
+
+/// Tables which reference client by `client_id` column, rows are removed with the client.
+pub const CLIENT_TABLES: &[&str] = &[
+    "metrics",
+    "disk_metrics",
+    "network_metrics",
+    "metrics_rollup",
+    "disk_metrics_rollup",
+    "network_metrics_rollup",
+    "incidents",
+    "alerts",
+];
+
+/// Tables of aggregated metrics, all of them have `resolution` and `timestamp` columns.
+pub const ROLLUP_TABLES: &[&str] = &[
+    "metrics_rollup",
+    "disk_metrics_rollup",
+    "network_metrics_rollup",
+];
+
+/// Tables which rows expire by retention policy.
+#[derive(Clone, Copy, Debug)]
+pub enum RetentionTable {
+    RawData,
+    Metrics,
+    /// Aggregated metrics of specified resolution, in every table of [`ROLLUP_TABLES`]
+    Rollup(i64),
+}
+
+impl RetentionTable {
+    pub fn get_table_name(&self) -> &'static str {
+        match self {
+            RetentionTable::RawData => "raw_data",
+            RetentionTable::Metrics => "metrics",
+            RetentionTable::Rollup(_) => "metrics_rollup",
+        }
+    }
+}
+
+/// Why client is considered down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IncidentCause {
+    /// No heartbeat within timeout
+    Timeout,
+    /// Client registered with new boot time
+    Reboot,
+    /// Client registered again without reboot, e.g. probe client restarted
+    Register,
+    /// No heartbeat while a client it depends on is offline too
+    Unreachable,
+}
+
+impl IncidentCause {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            IncidentCause::Timeout => "timeout",
+            IncidentCause::Reboot => "reboot",
+            IncidentCause::Register => "register",
+            IncidentCause::Unreachable => "unreachable",
+        }
+    }
+}
+
+/// Persistent storage of probe-server, every backend should keep the same schema version.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Create tables or upgrade them to current schema version.
+    async fn migrate(&self) -> anyhow::Result<()>;
+
+    async fn get_client(&self, id: i32) -> anyhow::Result<Option<ClientRow>>;
+
+    async fn get_client_by_uuid(&self, uuid: &str) -> anyhow::Result<Option<ClientRow>>;
+
+    /// Insert new client, return the inserted row.
+    async fn register_client(
+        &self,
+        uuid: &str,
+        boot_time: i64,
+        hostname: Option<&str>,
+        timestamp: i64,
+    ) -> anyhow::Result<ClientRow>;
+
+    /// Update boot time of client, also refresh last seen.
+    async fn update_boot_time(&self, id: i32, boot_time: i64, timestamp: i64)
+        -> anyhow::Result<()>;
+
+    async fn update_last_seen(&self, id: i32, timestamp: i64) -> anyhow::Result<()>;
+
+    /// Delete client with all data belongs to it, return `false` if client not exists.
+    async fn delete_client(&self, id: i32) -> anyhow::Result<bool>;
+
+    /// Set display name of client, `None` restores reported hostname.
+    async fn set_display_name(&self, id: i32, display_name: Option<&str>) -> anyhow::Result<()>;
+
+    /// Retired clients are kept in database, but ignored by watchdog.
+    async fn set_retired(&self, id: i32, retired: bool) -> anyhow::Result<()>;
+
+    /// Suppress notifications of client until `until`, `None` unmutes client.
+    async fn set_muted_until(&self, id: i32, until: Option<i64>) -> anyhow::Result<()>;
+
+    /// Set heartbeat timeout of client, `None` restores configured timeout.
+    async fn set_timeout(&self, id: i32, timeout: Option<i64>) -> anyhow::Result<()>;
+
+    async fn set_heartbeat_interval(&self, id: i32, interval: Option<i64>) -> anyhow::Result<()>;
+
+    async fn list_timeout_overrides(&self) -> anyhow::Result<Vec<TimeoutOverrideRow>>;
+
+    /// Set heartbeat timeout of clients matching `tag`, replacing previous one.
+    async fn set_timeout_override(&self, tag: &str, timeout: i64) -> anyhow::Result<()>;
+
+    /// Return `false` if no override exists for `tag`.
+    async fn delete_timeout_override(&self, tag: &str) -> anyhow::Result<bool>;
+
+    /// Mark client as flapping since `since`, `None` clears the mark.
+    async fn set_flapping_since(&self, id: i32, since: Option<i64>) -> anyhow::Result<()>;
+
+    async fn set_tags(&self, id: i32, tags: &[String]) -> anyhow::Result<()>;
+
+    async fn set_labels(&self, id: i32, labels: &BTreeMap<String, String>) -> anyhow::Result<()>;
+
+    async fn set_parent(&self, id: i32, parent_id: Option<i32>) -> anyhow::Result<()>;
+
+    async fn insert_raw_data(&self, id: i32, data: &str, timestamp: i64) -> anyhow::Result<()>;
+
+    /// Insert parsed statistics of heartbeat, all rows share the same timestamp.
+    async fn insert_metrics(
+        &self,
+        id: i32,
+        statistics: &Statistics,
+        timestamp: i64,
+    ) -> anyhow::Result<()>;
+
+    async fn query_raw_data(&self, id: i32, query: &HistoryQuery)
+        -> anyhow::Result<Vec<RawDataRow>>;
+
+    async fn query_metrics(&self, id: i32, query: &HistoryQuery) -> anyhow::Result<Vec<MetricsRow>>;
+
+    /// Query aggregated metrics of `resolution`.
+    async fn query_metrics_rollup(
+        &self,
+        id: i32,
+        resolution: i64,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<MetricsRollupRow>>;
+
+    async fn query_disk_metrics(
+        &self,
+        id: i32,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<DiskMetricsRow>>;
+
+    async fn query_network_metrics(
+        &self,
+        id: i32,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<NetworkMetricsRow>>;
+
+    /// Query aggregated disk usage of `resolution`.
+    async fn query_disk_metrics_rollup(
+        &self,
+        id: i32,
+        resolution: i64,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<DiskMetricsRollupRow>>;
+
+    /// Query aggregated network counters of `resolution`.
+    async fn query_network_metrics_rollup(
+        &self,
+        id: i32,
+        resolution: i64,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<NetworkMetricsRollupRow>>;
+
+    /// Delete rows older than `before`, return number of deleted rows.
+    async fn delete_expired(&self, table: RetentionTable, before: i64) -> anyhow::Result<u64>;
+
+    /// Keep only newest `max_rows` rows, return number of deleted rows.
+    async fn truncate_rows(&self, table: RetentionTable, max_rows: i64) -> anyhow::Result<u64>;
+
+    /// Aggregate metrics older than `before` into buckets of `resolution`, then remove source rows.
+    /// Disk and network metrics are aggregated together with them.
+    ///
+    /// [`ROLLUP_5M`] reads raw samples, [`ROLLUP_1H`] reads 5-minute aggregates.
+    async fn rollup_metrics(&self, resolution: i64, before: i64) -> anyhow::Result<u64>;
+
+    async fn vacuum(&self, incremental: bool) -> anyhow::Result<()>;
+
+    /// Persist notification before sending, return id of queued row.
+    async fn enqueue_notification(
+        &self,
+        notifier: &str,
+        payload: &str,
+        timestamp: i64,
+    ) -> anyhow::Result<i64>;
+
+    /// Pending notifications of `notifier` which next attempt is not later than `now`,
+    /// oldest first.
+    async fn fetch_due_notifications(
+        &self,
+        notifier: &str,
+        now: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<NotificationRow>>;
+
+    /// Name of notifiers that have queued notifications.
+    async fn list_queued_notifiers(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Drop all queued notifications of `notifier`, return number of deleted rows.
+    async fn delete_notifications_of(&self, notifier: &str) -> anyhow::Result<u64>;
+
+    async fn delete_notification(&self, id: i64) -> anyhow::Result<()>;
+
+    /// Record failed attempt and schedule next one.
+    async fn reschedule_notification(
+        &self,
+        id: i64,
+        next_attempt: i64,
+        error: &str,
+    ) -> anyhow::Result<()>;
+
+    /// Record final failed attempt, failed notification is kept but never fetched again.
+    async fn fail_notification(&self, id: i64, timestamp: i64, error: &str) -> anyhow::Result<()>;
+
+    /// Record client went offline, `started_at` is its last seen time.
+    async fn open_incident(
+        &self,
+        client_id: i32,
+        started_at: i64,
+        detected_at: i64,
+        cause: IncidentCause,
+    ) -> anyhow::Result<i64>;
+
+    async fn get_open_incident(&self, client_id: i32) -> anyhow::Result<Option<IncidentRow>>;
+
+    async fn list_open_incidents(&self) -> anyhow::Result<Vec<IncidentRow>>;
+
+    /// Close incident, `cause` replaces the recorded one if the real cause is known now.
+    async fn resolve_incident(
+        &self,
+        id: i64,
+        resolved_at: i64,
+        cause: Option<IncidentCause>,
+    ) -> anyhow::Result<()>;
+
+    async fn get_incident(&self, id: i64) -> anyhow::Result<Option<IncidentRow>>;
+
+    /// Acknowledge open incident, return false if it is not found, resolved or acknowledged.
+    async fn acknowledge_incident(&self, id: i64, by: &str, timestamp: i64) -> anyhow::Result<bool>;
+
+    async fn set_escalation_level(&self, id: i64, level: i32) -> anyhow::Result<()>;
+
+    /// Replace cause of open incident, e.g. unreachable client turns out to be down by itself.
+    async fn set_incident_cause(&self, id: i64, cause: IncidentCause) -> anyhow::Result<()>;
+
+    /// Incidents overlap time range of `query`, newest first by default.
+    async fn query_incidents(
+        &self,
+        client_id: Option<i32>,
+        query: &IncidentQuery,
+    ) -> anyhow::Result<Vec<IncidentRow>>;
+
+    /// All incidents of client overlap `[since, until]`, ordered by start time.
+    async fn list_incidents_between(
+        &self,
+        client_id: i32,
+        since: i64,
+        until: i64,
+    ) -> anyhow::Result<Vec<IncidentRow>>;
+
+    /// Insert maintenance window, one-off window without `starts_at` starts at `timestamp`.
+    async fn insert_maintenance(
+        &self,
+        window: &MaintenanceRequest,
+        timestamp: i64,
+    ) -> anyhow::Result<i64>;
+
+    async fn list_maintenance(&self) -> anyhow::Result<Vec<MaintenanceRow>>;
+
+    /// Return `false` if maintenance window not exists.
+    async fn delete_maintenance(&self, id: i64) -> anyhow::Result<bool>;
+
+    /// Record start of current window, `None` after summary of window is sent.
+    async fn set_maintenance_active(&self, id: i64, since: Option<i64>) -> anyhow::Result<()>;
+
+    /// Turn active window into one-off window ending at `timestamp`.
+    async fn end_maintenance(&self, id: i64, timestamp: i64) -> anyhow::Result<()>;
+
+    async fn list_alert_rules(&self) -> anyhow::Result<Vec<AlertRuleRow>>;
+
+    async fn insert_alert_rule(
+        &self,
+        name: &str,
+        expression: &str,
+        clients: &[String],
+        timestamp: i64,
+    ) -> anyhow::Result<i64>;
+
+    /// Return `false` if rule not exists.
+    async fn delete_alert_rule(&self, name: &str) -> anyhow::Result<bool>;
+
+    /// Pending and firing alerts, of all clients if `client_id` is `None`.
+    async fn list_active_alerts(&self, client_id: Option<i32>) -> anyhow::Result<Vec<AlertRow>>;
+
+    async fn query_alerts(&self, client_id: i32, query: &HistoryQuery)
+        -> anyhow::Result<Vec<AlertRow>>;
+
+    /// Insert pending alert, or firing alert if `fired_at` is set.
+    async fn insert_alert(
+        &self,
+        rule: &str,
+        client_id: i32,
+        series: &str,
+        value: f64,
+        pending_since: i64,
+        fired_at: Option<i64>,
+    ) -> anyhow::Result<i64>;
+
+    /// Update last value of alert, fire it if `fired_at` is set.
+    async fn update_alert(&self, id: i64, value: f64, fired_at: Option<i64>) -> anyhow::Result<()>;
+
+    async fn resolve_alert(&self, id: i64, value: f64, resolved_at: i64) -> anyhow::Result<()>;
+
+    async fn delete_alert(&self, id: i64) -> anyhow::Result<()>;
+
+    async fn list_clients(&self) -> anyhow::Result<Vec<ClientRow>>;
+}
+
+fn is_postgres(location: &str) -> bool {
+    location.starts_with("postgres://") || location.starts_with("postgresql://")
+}
+
+/// Select storage backend by scheme of database location, fallback to SQLite.
+pub async fn connect(location: &str) -> anyhow::Result<Arc<dyn Storage>> {
+    let storage: Arc<dyn Storage> = if is_postgres(location) {
+        info!("Using PostgreSQL storage backend");
+        Arc::new(PostgresStorage::connect(location).await?)
+    } else {
+        info!("Using SQLite storage backend");
+        Arc::new(SqliteStorage::connect(location).await?)
+    };
+    storage.migrate().await?;
+    Ok(storage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{Connection, Executor, PgConnection};
+
+    /// Aligned to an hour, so samples after it fall into predictable buckets.
+    const BASE: i64 = 1_699_999_200;
+
+    fn latest(limit: i64) -> HistoryQuery {
+        serde_json::from_value(serde_json::json!({ "limit": limit })).unwrap()
+    }
+
+    fn to_value<T: serde::Serialize>(rows: &[T]) -> Vec<serde_json::Value> {
+        rows.iter()
+            .map(|row| serde_json::to_value(row).unwrap())
+            .collect()
+    }
+
+    fn statistics(cpu: f64, disk_used: i64, rx_bytes: i64) -> Statistics {
+        Statistics::from_body(
+            &serde_json::json!({
+                "cpu_percent": cpu,
+                "load_avg": [1.0, 0.5, 0.25],
+                "virtual_memory": {"total": 1000, "used": 400},
+                "disk_usage": {"/": {"total": 100, "used": disk_used}},
+                "net_io_counters": {"eth0": {"bytes_recv": rx_bytes, "bytes_sent": rx_bytes / 2}},
+                "uptime": 3600,
+            })
+            .to_string(),
+        )
+        .unwrap()
+    }
+
+    async fn clients(storage: &dyn Storage) -> i32 {
+        let client = storage
+            .register_client("client-a", BASE - 100, Some("host-a"), BASE)
+            .await
+            .unwrap();
+        assert_eq!(client.get_uuid(), "client-a");
+        assert_eq!(client.get_hostname().as_deref(), Some("host-a"));
+        assert_eq!(client.get_boot_time(), BASE - 100);
+        assert_eq!(client.get_last_seen(), BASE);
+        let id = client.get_id();
+        let other = storage
+            .register_client("client-b", BASE - 50, None, BASE)
+            .await
+            .unwrap()
+            .get_id();
+        assert_ne!(id, other);
+        assert!(storage
+            .register_client("client-a", BASE, None, BASE)
+            .await
+            .is_err());
+
+        storage.update_last_seen(id, BASE + 10).await.unwrap();
+        storage.set_display_name(id, Some("web")).await.unwrap();
+        storage
+            .update_boot_time(other, BASE, BASE + 20)
+            .await
+            .unwrap();
+
+        let client = storage.get_client(id).await.unwrap().unwrap();
+        assert_eq!(client.get_last_seen(), BASE + 10);
+        assert_eq!(client.get_name(), "web");
+        assert_eq!(client.get_hostname().as_deref(), Some("host-a"));
+        assert_eq!(client.get_boot_time(), BASE - 100);
+        let other = storage
+            .get_client_by_uuid("client-b")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(other.get_boot_time(), BASE);
+        assert_eq!(other.get_last_seen(), BASE + 20);
+        assert_eq!(other.get_hostname(), &None);
+        storage.set_display_name(id, None).await.unwrap();
+        storage.set_retired(id, true).await.unwrap();
+        assert!(storage.get_client(id).await.unwrap().unwrap().get_retired());
+        // Retired clients are still listed
+        assert_eq!(storage.list_clients().await.unwrap().len(), 2);
+        storage.set_retired(id, false).await.unwrap();
+        storage.set_muted_until(id, Some(BASE + 60)).await.unwrap();
+        let client = storage.get_client(id).await.unwrap().unwrap();
+        assert_eq!(client.get_muted_until(), Some(BASE + 60));
+        assert!(client.is_muted(BASE) && !client.is_muted(BASE + 60));
+        storage.set_muted_until(id, None).await.unwrap();
+        assert_eq!(
+            storage
+                .get_client(id)
+                .await
+                .unwrap()
+                .unwrap()
+                .get_muted_until(),
+            None
+        );
+        let client = storage.get_client(id).await.unwrap().unwrap();
+        assert_eq!(client.get_display_name(), &None);
+        assert_eq!(client.get_name(), "host-a");
+        assert!(storage.get_client(-1).await.unwrap().is_none());
+        assert!(storage
+            .get_client_by_uuid("missing")
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(storage.list_clients().await.unwrap().len(), 2);
+
+        storage.set_timeout(id, Some(600)).await.unwrap();
+        storage.set_heartbeat_interval(id, Some(30)).await.unwrap();
+        let client = storage.get_client(id).await.unwrap().unwrap();
+        assert_eq!(client.get_timeout(), Some(600));
+        assert_eq!(client.get_heartbeat_interval(), Some(30));
+        storage.set_timeout(id, None).await.unwrap();
+        storage.set_heartbeat_interval(id, None).await.unwrap();
+        let client = storage.get_client(id).await.unwrap().unwrap();
+        assert_eq!(client.get_timeout(), None);
+        assert_eq!(client.get_heartbeat_interval(), None);
+
+        // Fresh clients have no metadata, tags keep their order
+        assert!(client.get_tags().is_empty() && client.get_labels().is_empty());
+        let tags = vec!["prod".to_string(), "db".to_string()];
+        let labels: BTreeMap<String, String> = [("site", "tokyo"), ("owner", "")]
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        storage.set_tags(id, &tags).await.unwrap();
+        storage.set_labels(id, &labels).await.unwrap();
+        let client = storage.get_client(id).await.unwrap().unwrap();
+        assert_eq!(client.get_tags(), &tags);
+        assert_eq!(client.get_labels(), &labels);
+        let other = storage.get_client(other.get_id()).await.unwrap().unwrap();
+        assert!(other.get_tags().is_empty() && other.get_labels().is_empty());
+        storage.set_tags(id, &[]).await.unwrap();
+        storage.set_labels(id, &BTreeMap::new()).await.unwrap();
+        let client = storage.get_client(id).await.unwrap().unwrap();
+        assert!(client.get_tags().is_empty() && client.get_labels().is_empty());
+        id
+    }
+
+    async fn raw_data_and_metrics(storage: &dyn Storage, id: i32) {
+        for i in 0..3 {
+            storage
+                .insert_raw_data(id, &format!("data {}", i), BASE + i)
+                .await
+                .unwrap();
+        }
+        let rows = to_value(&storage.query_raw_data(id, &latest(2)).await.unwrap());
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["data"], "data 2");
+        assert_eq!(rows[1]["data"], "data 1");
+
+        // Two samples in each of the first two 5-minute buckets
+        for (offset, cpu) in [(0, 10.0), (60, 30.0), (300, 50.0), (360, 70.0)] {
+            storage
+                .insert_metrics(id, &statistics(cpu, cpu as i64, offset * 10), BASE + offset)
+                .await
+                .unwrap();
+        }
+        let metrics = to_value(
+            &storage
+                .query_metrics(id, &HistoryQuery::default())
+                .await
+                .unwrap(),
+        );
+        assert_eq!(metrics.len(), 4);
+        assert_eq!(metrics[0]["cpu_usage"], 70.0);
+        assert_eq!(metrics[0]["load5"], 0.5);
+        assert_eq!(metrics[0]["memory_used"], 400);
+        assert_eq!(metrics[0]["uptime"], 3600);
+        let disks = to_value(
+            &storage
+                .query_disk_metrics(id, &HistoryQuery::default())
+                .await
+                .unwrap(),
+        );
+        assert_eq!(disks.len(), 4);
+        assert_eq!(disks[0]["mount"], "/");
+        assert_eq!(disks[0]["used"], 70);
+        let network = to_value(
+            &storage
+                .query_network_metrics(id, &HistoryQuery::default())
+                .await
+                .unwrap(),
+        );
+        assert_eq!(network.len(), 4);
+        assert_eq!(network[0]["interface"], "eth0");
+        assert_eq!(network[0]["rx_bytes"], 3600);
+        assert_eq!(network[0]["tx_bytes"], 1800);
+    }
+
+    async fn rollup(storage: &dyn Storage, id: i32) {
+        let query = HistoryQuery::default();
+        assert_eq!(
+            storage.rollup_metrics(ROLLUP_5M, BASE + 600).await.unwrap(),
+            6
+        );
+        assert!(storage.query_metrics(id, &query).await.unwrap().is_empty());
+        assert!(storage
+            .query_disk_metrics(id, &query)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage
+            .query_network_metrics(id, &query)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let metrics = to_value(
+            &storage
+                .query_metrics_rollup(id, ROLLUP_5M, &query)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0]["timestamp"], BASE + 300);
+        assert_eq!(metrics[0]["samples"], 2);
+        assert_eq!(metrics[0]["cpu_usage_min"], 50.0);
+        assert_eq!(metrics[0]["cpu_usage_avg"], 60.0);
+        assert_eq!(metrics[0]["cpu_usage_max"], 70.0);
+        let disks = to_value(
+            &storage
+                .query_disk_metrics_rollup(id, ROLLUP_5M, &query)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(disks.len(), 2);
+        assert_eq!(disks[1]["timestamp"], BASE);
+        assert_eq!(disks[1]["mount"], "/");
+        assert_eq!(disks[1]["total"], 100);
+        assert_eq!(disks[1]["used_min"], 10);
+        assert_eq!(disks[1]["used_avg"], 20.0);
+        assert_eq!(disks[1]["used_max"], 30);
+        let network = to_value(
+            &storage
+                .query_network_metrics_rollup(id, ROLLUP_5M, &query)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(network.len(), 2);
+        assert_eq!(network[0]["rx_bytes_min"], 3000);
+        assert_eq!(network[0]["rx_bytes_max"], 3600);
+        assert_eq!(network[0]["tx_bytes_max"], 1800);
+
+        // Hourly buckets are built from 5-minute ones, weighted by samples
+        assert_eq!(
+            storage
+                .rollup_metrics(ROLLUP_1H, BASE + 3600)
+                .await
+                .unwrap(),
+            3
+        );
+        assert!(storage
+            .query_metrics_rollup(id, ROLLUP_5M, &query)
+            .await
+            .unwrap()
+            .is_empty());
+        let metrics = to_value(
+            &storage
+                .query_metrics_rollup(id, ROLLUP_1H, &query)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0]["samples"], 4);
+        assert_eq!(metrics[0]["cpu_usage_avg"], 40.0);
+        let disks = to_value(
+            &storage
+                .query_disk_metrics_rollup(id, ROLLUP_1H, &query)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(disks[0]["used_min"], 10);
+        assert_eq!(disks[0]["used_max"], 70);
+        let network = to_value(
+            &storage
+                .query_network_metrics_rollup(id, ROLLUP_1H, &query)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(network[0]["rx_bytes_min"], 0);
+        assert_eq!(network[0]["rx_bytes_max"], 3600);
+    }
+
+    async fn retention(storage: &dyn Storage, id: i32) {
+        assert_eq!(
+            storage
+                .delete_expired(RetentionTable::RawData, BASE + 1)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            storage
+                .truncate_rows(RetentionTable::RawData, 1)
+                .await
+                .unwrap(),
+            1
+        );
+        let rows = to_value(
+            &storage
+                .query_raw_data(id, &HistoryQuery::default())
+                .await
+                .unwrap(),
+        );
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["data"], "data 2");
+
+        // One row in each rollup table
+        assert_eq!(
+            storage
+                .delete_expired(RetentionTable::Rollup(ROLLUP_1H), BASE + 1)
+                .await
+                .unwrap(),
+            3
+        );
+    }
+
+    fn incident_query(query: serde_json::Value) -> IncidentQuery {
+        serde_json::from_value(query).unwrap()
+    }
+
+    /// `(id, cause, resolved_at)` of incidents in order.
+    fn incident_keys(rows: &[IncidentRow]) -> Vec<(i64, String, Option<i64>)> {
+        to_value(rows)
+            .iter()
+            .map(|row| {
+                (
+                    row["id"].as_i64().unwrap(),
+                    row["cause"].as_str().unwrap().to_string(),
+                    row["resolved_at"].as_i64(),
+                )
+            })
+            .collect()
+    }
+
+    async fn incidents(storage: &dyn Storage, id: i32) {
+        let other = storage
+            .get_client_by_uuid("client-b")
+            .await
+            .unwrap()
+            .unwrap()
+            .get_id();
+        let first = storage
+            .open_incident(id, BASE, BASE + 60, IncidentCause::Timeout)
+            .await
+            .unwrap();
+        storage
+            .resolve_incident(first, BASE + 600, Some(IncidentCause::Reboot))
+            .await
+            .unwrap();
+        let second = storage
+            .open_incident(id, BASE + 1000, BASE + 1060, IncidentCause::Timeout)
+            .await
+            .unwrap();
+        let third = storage
+            .open_incident(other, BASE + 500, BASE + 560, IncidentCause::Timeout)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .get_open_incident(id)
+                .await
+                .unwrap()
+                .unwrap()
+                .get_id(),
+            second
+        );
+        assert_eq!(storage.list_open_incidents().await.unwrap().len(), 2);
+
+        let query = |query| async move {
+            incident_keys(
+                &storage
+                    .query_incidents(None, &incident_query(query))
+                    .await
+                    .unwrap(),
+            )
+            .iter()
+            .map(|(id, _, _)| *id)
+            .collect::<Vec<_>>()
+        };
+        // Newest first by default
+        assert_eq!(
+            query(serde_json::json!({})).await,
+            vec![second, third, first]
+        );
+        assert_eq!(
+            query(serde_json::json!({"order": "asc", "limit": 2, "offset": 1})).await,
+            vec![third, second]
+        );
+        assert_eq!(
+            query(serde_json::json!({"cause": "reboot"})).await,
+            vec![first]
+        );
+        assert_eq!(
+            query(serde_json::json!({"open": true})).await,
+            vec![second, third]
+        );
+        assert_eq!(query(serde_json::json!({"open": false})).await, vec![first]);
+        // Open incident overlaps any range after its start
+        assert_eq!(
+            query(serde_json::json!({"since": BASE + 700, "until": BASE + 900})).await,
+            vec![third]
+        );
+        assert_eq!(
+            incident_keys(
+                &storage
+                    .query_incidents(Some(id), &IncidentQuery::default())
+                    .await
+                    .unwrap()
+            ),
+            vec![
+                (second, "timeout".to_string(), None),
+                (first, "reboot".to_string(), Some(BASE + 600)),
+            ]
+        );
+
+        let between = storage
+            .list_incidents_between(id, BASE + 600, BASE + 2000)
+            .await
+            .unwrap();
+        assert_eq!(
+            between.iter().map(|row| row.get_id()).collect::<Vec<_>>(),
+            vec![first, second]
+        );
+        assert!(storage
+            .list_incidents_between(id, BASE + 700, BASE + 900)
+            .await
+            .unwrap()
+            .is_empty());
+
+        storage
+            .set_incident_cause(second, IncidentCause::Unreachable)
+            .await
+            .unwrap();
+        let open = storage.get_open_incident(id).await.unwrap().unwrap();
+        assert_eq!(
+            (open.get_id(), open.get_cause().as_str()),
+            (second, "unreachable")
+        );
+        storage
+            .set_incident_cause(second, IncidentCause::Timeout)
+            .await
+            .unwrap();
+
+        assert!(storage
+            .acknowledge_incident(second, "alice", BASE + 1000)
+            .await
+            .unwrap());
+        // Acknowledged or resolved incidents are left alone
+        assert!(!storage
+            .acknowledge_incident(second, "bob", BASE + 1100)
+            .await
+            .unwrap());
+        assert!(!storage
+            .acknowledge_incident(first, "bob", BASE + 1100)
+            .await
+            .unwrap());
+        storage.set_escalation_level(second, 2).await.unwrap();
+        let incident = storage.get_incident(second).await.unwrap().unwrap();
+        assert_eq!(
+            (
+                incident.get_acknowledged_at(),
+                incident.get_acknowledged_by().as_deref(),
+                incident.get_escalation_level()
+            ),
+            (Some(BASE + 1000), Some("alice"), 2)
+        );
+        assert!(storage.get_incident(-1).await.unwrap().is_none());
+    }
+
+    async fn alerts(storage: &dyn Storage, id: i32) {
+        assert!(storage.list_alert_rules().await.unwrap().is_empty());
+        let clients = vec!["web-*".to_string(), "db-1".to_string()];
+        storage
+            .insert_alert_rule("high-load", "load1 > 8", &clients, BASE)
+            .await
+            .unwrap();
+        let rules = storage.list_alert_rules().await.unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].get_expression(), "load1 > 8");
+        assert_eq!(rules[0].get_clients(), &clients);
+        assert!(storage.delete_alert_rule("high-load").await.unwrap());
+        assert!(!storage.delete_alert_rule("high-load").await.unwrap());
+
+        let pending = storage
+            .insert_alert("root-disk", id, r#"mount="/""#, 95.0, BASE, None)
+            .await
+            .unwrap();
+        let firing = storage
+            .insert_alert("high-load", id, "", 9.5, BASE, Some(BASE))
+            .await
+            .unwrap();
+        storage
+            .update_alert(pending, 96.5, Some(BASE + 600))
+            .await
+            .unwrap();
+        storage.update_alert(firing, 10.0, None).await.unwrap();
+        let active = storage.list_active_alerts(Some(id)).await.unwrap();
+        assert_eq!(
+            active
+                .iter()
+                .map(|alert| (alert.get_id(), alert.get_value(), alert.get_fired_at()))
+                .collect::<Vec<_>>(),
+            vec![
+                (pending, 96.5, Some(BASE + 600)),
+                (firing, 10.0, Some(BASE))
+            ]
+        );
+        assert_eq!(active[0].get_series(), r#"mount="/""#);
+        assert_eq!(active[0].get_pending_since(), BASE);
+
+        storage
+            .resolve_alert(firing, 2.0, BASE + 900)
+            .await
+            .unwrap();
+        let dropped = storage
+            .insert_alert("high-load", id, "", 9.0, BASE + 1000, None)
+            .await
+            .unwrap();
+        storage.delete_alert(dropped).await.unwrap();
+        assert_eq!(storage.list_active_alerts(None).await.unwrap().len(), 1);
+        let history = storage
+            .query_alerts(id, &HistoryQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(history
+            .iter()
+            .any(|alert| alert.get_id() == firing && alert.get_value() == 2.0));
+    }
+
+    async fn delete_client(storage: &dyn Storage, id: i32) {
+        let other = storage
+            .get_client_by_uuid("client-b")
+            .await
+            .unwrap()
+            .unwrap()
+            .get_id();
+        storage.set_parent(other, Some(id)).await.unwrap();
+        let child = storage.get_client(other).await.unwrap().unwrap();
+        assert_eq!(child.get_parent_id(), Some(id));
+        storage
+            .insert_metrics(id, &statistics(1.0, 1, 1), BASE + 4000)
+            .await
+            .unwrap();
+        storage
+            .rollup_metrics(ROLLUP_5M, BASE + 4200)
+            .await
+            .unwrap();
+        assert!(storage.delete_client(id).await.unwrap());
+        assert!(!storage.delete_client(id).await.unwrap());
+        assert!(storage.get_client(id).await.unwrap().is_none());
+        assert!(storage
+            .query_incidents(Some(id), &IncidentQuery::default())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage
+            .query_raw_data(id, &HistoryQuery::default())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage
+            .query_metrics(id, &HistoryQuery::default())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage
+            .query_disk_metrics_rollup(id, ROLLUP_5M, &HistoryQuery::default())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage.list_active_alerts(None).await.unwrap().is_empty());
+        assert_eq!(storage.list_clients().await.unwrap().len(), 1);
+        // Children no longer depend on deleted client
+        let child = storage.get_client(other).await.unwrap().unwrap();
+        assert_eq!(child.get_parent_id(), None);
+    }
+
+    /// Same assertions for every backend, so they keep behaving the same.
+    async fn notification_queue(storage: &dyn Storage) {
+        let first = storage
+            .enqueue_notification("slack-0", "1", BASE)
+            .await
+            .unwrap();
+        let second = storage
+            .enqueue_notification("slack-0", "2", BASE + 1)
+            .await
+            .unwrap();
+        storage
+            .enqueue_notification("gone", "3", BASE)
+            .await
+            .unwrap();
+        let due = |now| async move {
+            storage
+                .fetch_due_notifications("slack-0", now, 10)
+                .await
+                .unwrap()
+                .iter()
+                .map(|row| (row.get_id(), row.get_payload().clone(), row.get_attempts()))
+                .collect::<Vec<_>>()
+        };
+        // Notifications are due as soon as they are queued
+        assert_eq!(due(BASE).await, vec![(first, "1".to_string(), 0)]);
+        assert_eq!(
+            due(BASE + 1).await,
+            vec![(first, "1".to_string(), 0), (second, "2".to_string(), 0)]
+        );
+        assert_eq!(
+            storage
+                .fetch_due_notifications("slack-0", BASE + 1, 1)
+                .await
+                .unwrap()[0]
+                .get_created_at(),
+            BASE
+        );
+
+        storage
+            .reschedule_notification(first, BASE + 60, "502 Bad Gateway")
+            .await
+            .unwrap();
+        assert_eq!(due(BASE + 59).await, vec![(second, "2".to_string(), 0)]);
+        assert_eq!(due(BASE + 60).await[0], (first, "1".to_string(), 1));
+        // Failed notification is kept but never fetched again
+        storage
+            .fail_notification(second, BASE + 60, "404 Not Found")
+            .await
+            .unwrap();
+        assert_eq!(due(BASE + 3600).await, vec![(first, "1".to_string(), 1)]);
+
+        let mut notifiers = storage.list_queued_notifiers().await.unwrap();
+        notifiers.sort();
+        assert_eq!(notifiers, vec!["gone", "slack-0"]);
+        assert_eq!(storage.delete_notifications_of("gone").await.unwrap(), 1);
+        storage.delete_notification(first).await.unwrap();
+        assert!(due(BASE + 3600).await.is_empty());
+        assert_eq!(
+            storage.list_queued_notifiers().await.unwrap(),
+            vec!["slack-0"]
+        );
+    }
+
+    async fn timeout_overrides(storage: &dyn Storage) {
+        assert!(storage.list_timeout_overrides().await.unwrap().is_empty());
+        storage.set_timeout_override("web-*", 60).await.unwrap();
+        storage.set_timeout_override("db-*", 600).await.unwrap();
+        storage.set_timeout_override("web-*", 120).await.unwrap();
+        let rows = storage.list_timeout_overrides().await.unwrap();
+        let rows: Vec<_> = rows
+            .iter()
+            .map(|row| (row.get_tag().as_str(), row.get_timeout()))
+            .collect();
+        assert_eq!(rows, vec![("db-*", 600), ("web-*", 120)]);
+        assert!(storage.delete_timeout_override("db-*").await.unwrap());
+        assert!(!storage.delete_timeout_override("db-*").await.unwrap());
+        assert_eq!(storage.list_timeout_overrides().await.unwrap().len(), 1);
+    }
+
+    async fn maintenance(storage: &dyn Storage) {
+        let request = |value: serde_json::Value| -> MaintenanceRequest {
+            serde_json::from_value(value).unwrap()
+        };
+        let once = storage
+            .insert_maintenance(
+                &request(serde_json::json!({"clients": ["web-*"], "ends_at": BASE + 600})),
+                BASE,
+            )
+            .await
+            .unwrap();
+        let recurring = storage
+            .insert_maintenance(
+                &request(serde_json::json!({
+                    "description": "backup",
+                    "schedule": "0 3 * * *",
+                    "duration": 3600,
+                })),
+                BASE,
+            )
+            .await
+            .unwrap();
+        let rows = storage.list_maintenance().await.unwrap();
+        assert_eq!(
+            rows.iter().map(|row| row.get_id()).collect::<Vec<_>>(),
+            vec![once, recurring]
+        );
+        // One-off window without starts_at starts when it is inserted
+        assert_eq!(rows[0].get_clients(), &vec!["web-*"]);
+        assert_eq!(
+            (rows[0].get_starts_at(), rows[0].get_ends_at()),
+            (Some(BASE), Some(BASE + 600))
+        );
+        assert_eq!(rows[1].get_description(), "backup");
+        assert_eq!(rows[1].get_starts_at(), None);
+
+        storage
+            .set_maintenance_active(recurring, Some(BASE + 100))
+            .await
+            .unwrap();
+        storage
+            .end_maintenance(recurring, BASE + 200)
+            .await
+            .unwrap();
+        let row = storage.list_maintenance().await.unwrap().remove(1);
+        assert_eq!(row.get_schedule(), &None);
+        assert_eq!(
+            (
+                row.get_starts_at(),
+                row.get_ends_at(),
+                row.get_active_since()
+            ),
+            (Some(BASE + 100), Some(BASE + 200), Some(BASE + 100))
+        );
+
+        assert!(storage.delete_maintenance(once).await.unwrap());
+        assert!(!storage.delete_maintenance(once).await.unwrap());
+        assert!(storage.delete_maintenance(recurring).await.unwrap());
+    }
+
+    async fn run_suite(storage: &dyn Storage) {
+        let id = clients(storage).await;
+        raw_data_and_metrics(storage, id).await;
+        rollup(storage, id).await;
+        retention(storage, id).await;
+        // Migrating an up to date database is a no-op
+        storage.migrate().await.unwrap();
+        assert_eq!(storage.list_clients().await.unwrap().len(), 2);
+        incidents(storage, id).await;
+        alerts(storage, id).await;
+        delete_client(storage, id).await;
+        notification_queue(storage).await;
+        timeout_overrides(storage).await;
+        maintenance(storage).await;
+    }
+
+    #[actix_rt::test]
+    async fn sqlite_storage() {
+        let storage = connect("sqlite::memory:").await.unwrap();
+        run_suite(storage.as_ref()).await;
+    }
+
+    /// Needs a PostgreSQL server, `PROBE_TEST_POSTGRES_URL` points to a database of a role which
+    /// may create databases. Suite runs in a throwaway database which is dropped afterwards.
+    #[actix_rt::test]
+    #[ignore]
+    async fn postgres_storage() {
+        let location = std::env::var("PROBE_TEST_POSTGRES_URL")
+            .expect("PROBE_TEST_POSTGRES_URL is required by PostgreSQL storage tests");
+        let database = format!(
+            "probe_test_{}_{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        );
+        let mut admin = PgConnection::connect(&location).await.unwrap();
+        admin
+            .execute(format!(r#"CREATE DATABASE "{}""#, database).as_str())
+            .await
+            .unwrap();
+        let mut url = url::Url::parse(&location).unwrap();
+        url.set_path(&database);
+
+        let storage = connect(url.as_str()).await.unwrap();
+        run_suite(storage.as_ref()).await;
+        drop(storage);
+
+        admin
+            .execute(format!(r#"DROP DATABASE "{}" WITH (FORCE)"#, database).as_str())
+            .await
+            .unwrap();
+    }
+}
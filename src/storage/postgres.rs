@@ -0,0 +1,989 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::database::{
+    self, AlertRow, AlertRuleRow, ClientRow, DiskMetricsRollupRow, DiskMetricsRow, IncidentRow,
+    MaintenanceRow, MetricsRollupRow, MetricsRow, NetworkMetricsRollupRow, NetworkMetricsRow,
+    NotificationRow, RawDataRow, TimeoutOverrideRow,
+};
+use crate::metrics::Statistics;
+use crate::structs::{HistoryQuery, IncidentQuery, MaintenanceRequest};
+use crate::storage::{
+    IncidentCause, RetentionTable, Storage, CLIENT_TABLES, ROLLUP_5M, ROLLUP_TABLES,
+};
+use async_trait::async_trait;
+use sqlx::postgres::PgRow;
+use sqlx::types::Json;
+use sqlx::{Executor, PgPool};
+use std::collections::BTreeMap;
+
+const ROLLUP_FROM_METRICS: &str = r#"INSERT INTO "metrics_rollup" ("client_id", "resolution", "timestamp", "samples", "cpu_usage_min", "cpu_usage_avg", "cpu_usage_max", "load1_min", "load1_avg", "load1_max", "memory_used_min", "memory_used_avg", "memory_used_max", "swap_used_min", "swap_used_avg", "swap_used_max")
+    SELECT "client_id", $1, "timestamp" / $1 * $1, COUNT(*),
+        MIN("cpu_usage"), AVG("cpu_usage"), MAX("cpu_usage"),
+        MIN("load1"), AVG("load1"), MAX("load1"),
+        MIN("memory_used"), AVG("memory_used"), MAX("memory_used"),
+        MIN("swap_used"), AVG("swap_used"), MAX("swap_used")
+    FROM "metrics" WHERE "timestamp" < $2
+    GROUP BY "client_id", "timestamp" / $1 * $1"#;
+
+const ROLLUP_FROM_ROLLUP: &str = r#"INSERT INTO "metrics_rollup" ("client_id", "resolution", "timestamp", "samples", "cpu_usage_min", "cpu_usage_avg", "cpu_usage_max", "load1_min", "load1_avg", "load1_max", "memory_used_min", "memory_used_avg", "memory_used_max", "swap_used_min", "swap_used_avg", "swap_used_max")
+    SELECT "client_id", $1, "timestamp" / $1 * $1, SUM("samples"),
+        MIN("cpu_usage_min"), SUM("cpu_usage_avg" * "samples") / SUM("samples"), MAX("cpu_usage_max"),
+        MIN("load1_min"), SUM("load1_avg" * "samples") / SUM("samples"), MAX("load1_max"),
+        MIN("memory_used_min"), SUM("memory_used_avg" * "samples") / SUM("samples"), MAX("memory_used_max"),
+        MIN("swap_used_min"), SUM("swap_used_avg" * "samples") / SUM("samples"), MAX("swap_used_max")
+    FROM "metrics_rollup" WHERE "resolution" = $2 AND "timestamp" < $3
+    GROUP BY "client_id", "timestamp" / $1 * $1"#;
+
+const ROLLUP_DISK_FROM_METRICS: &str = r#"INSERT INTO "disk_metrics_rollup" ("client_id", "resolution", "timestamp", "mount", "samples", "total", "used_min", "used_avg", "used_max")
+    SELECT "client_id", $1, "timestamp" / $1 * $1, "mount", COUNT(*),
+        MAX("total"), MIN("used"), AVG("used"), MAX("used")
+    FROM "disk_metrics" WHERE "timestamp" < $2
+    GROUP BY "client_id", "timestamp" / $1 * $1, "mount""#;
+
+const ROLLUP_DISK_FROM_ROLLUP: &str = r#"INSERT INTO "disk_metrics_rollup" ("client_id", "resolution", "timestamp", "mount", "samples", "total", "used_min", "used_avg", "used_max")
+    SELECT "client_id", $1, "timestamp" / $1 * $1, "mount", SUM("samples"),
+        MAX("total"), MIN("used_min"), SUM("used_avg" * "samples") / SUM("samples"), MAX("used_max")
+    FROM "disk_metrics_rollup" WHERE "resolution" = $2 AND "timestamp" < $3
+    GROUP BY "client_id", "timestamp" / $1 * $1, "mount""#;
+
+const ROLLUP_NETWORK_FROM_METRICS: &str = r#"INSERT INTO "network_metrics_rollup" ("client_id", "resolution", "timestamp", "interface", "samples", "rx_bytes_min", "rx_bytes_max", "tx_bytes_min", "tx_bytes_max")
+    SELECT "client_id", $1, "timestamp" / $1 * $1, "interface", COUNT(*),
+        MIN("rx_bytes"), MAX("rx_bytes"), MIN("tx_bytes"), MAX("tx_bytes")
+    FROM "network_metrics" WHERE "timestamp" < $2
+    GROUP BY "client_id", "timestamp" / $1 * $1, "interface""#;
+
+const ROLLUP_NETWORK_FROM_ROLLUP: &str = r#"INSERT INTO "network_metrics_rollup" ("client_id", "resolution", "timestamp", "interface", "samples", "rx_bytes_min", "rx_bytes_max", "tx_bytes_min", "tx_bytes_max")
+    SELECT "client_id", $1, "timestamp" / $1 * $1, "interface", SUM("samples"),
+        MIN("rx_bytes_min"), MAX("rx_bytes_max"), MIN("tx_bytes_min"), MAX("tx_bytes_max")
+    FROM "network_metrics_rollup" WHERE "resolution" = $2 AND "timestamp" < $3
+    GROUP BY "client_id", "timestamp" / $1 * $1, "interface""#;
+
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+
+impl PostgresStorage {
+    pub async fn connect(location: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            pool: database::postgres::connect(location).await?,
+        })
+    }
+
+    async fn query_history<T>(
+        &self,
+        table: &str,
+        client_column: &str,
+        id: i32,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<T>>
+    where
+        T: for<'r> sqlx::FromRow<'r, PgRow> + Send + Unpin,
+    {
+        Ok(sqlx::query_as(&format!(
+            r#"SELECT * FROM "{}" WHERE "{}" = $1 AND "timestamp" >= $2 AND "timestamp" <= $3 ORDER BY "timestamp" {} LIMIT $4 OFFSET $5"#,
+            table,
+            client_column,
+            query.get_order()
+        ))
+        .bind(id)
+        .bind(query.get_since())
+        .bind(query.get_until())
+        .bind(query.get_limit())
+        .bind(query.get_offset())
+        .fetch_all(&self.pool)
+        .await?)
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        database::postgres::migrate(&mut *self.pool.acquire().await?).await
+    }
+
+    async fn get_client(&self, id: i32) -> anyhow::Result<Option<ClientRow>> {
+        Ok(sqlx::query_as(r#"SELECT * FROM "clients" WHERE "id" = $1"#)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn get_client_by_uuid(&self, uuid: &str) -> anyhow::Result<Option<ClientRow>> {
+        Ok(sqlx::query_as(r#"SELECT * FROM "clients" WHERE "uuid" = $1"#)
+            .bind(uuid)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn register_client(
+        &self,
+        uuid: &str,
+        boot_time: i64,
+        hostname: Option<&str>,
+        timestamp: i64,
+    ) -> anyhow::Result<ClientRow> {
+        Ok(sqlx::query_as(
+            r#"INSERT INTO "clients" ("uuid", "boot_time", "last_seen", "hostname") VALUES ($1, $2, $3, $4) RETURNING *"#,
+        )
+        .bind(uuid)
+        .bind(boot_time)
+        .bind(timestamp)
+        .bind(hostname)
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn update_boot_time(
+        &self,
+        id: i32,
+        boot_time: i64,
+        timestamp: i64,
+    ) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "boot_time" = $1, "last_seen" = $2 WHERE "id" = $3"#)
+            .bind(boot_time)
+            .bind(timestamp)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_last_seen(&self, id: i32, timestamp: i64) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "last_seen" = $1 WHERE "id" = $2"#)
+            .bind(timestamp)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_client(&self, id: i32) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM "raw_data" WHERE "from" = $1"#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        for table in CLIENT_TABLES {
+            sqlx::query(&format!(r#"DELETE FROM "{}" WHERE "client_id" = $1"#, table))
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+        }
+        sqlx::query(r#"UPDATE "clients" SET "parent_id" = NULL WHERE "parent_id" = $1"#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        let r = sqlx::query(r#"DELETE FROM "clients" WHERE "id" = $1"#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn set_display_name(&self, id: i32, display_name: Option<&str>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "display_name" = $1 WHERE "id" = $2"#)
+            .bind(display_name)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_retired(&self, id: i32, retired: bool) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "retired" = $1 WHERE "id" = $2"#)
+            .bind(retired)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_muted_until(&self, id: i32, until: Option<i64>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "muted_until" = $1 WHERE "id" = $2"#)
+            .bind(until)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_timeout(&self, id: i32, timeout: Option<i64>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "timeout" = $1 WHERE "id" = $2"#)
+            .bind(timeout)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_heartbeat_interval(&self, id: i32, interval: Option<i64>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "heartbeat_interval" = $1 WHERE "id" = $2"#)
+            .bind(interval)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_flapping_since(&self, id: i32, since: Option<i64>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "flapping_since" = $1 WHERE "id" = $2"#)
+            .bind(since)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_tags(&self, id: i32, tags: &[String]) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "tags" = $1 WHERE "id" = $2"#)
+            .bind(Json(tags))
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_labels(&self, id: i32, labels: &BTreeMap<String, String>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "labels" = $1 WHERE "id" = $2"#)
+            .bind(Json(labels))
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_parent(&self, id: i32, parent_id: Option<i32>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "parent_id" = $1 WHERE "id" = $2"#)
+            .bind(parent_id)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_timeout_overrides(&self) -> anyhow::Result<Vec<TimeoutOverrideRow>> {
+        Ok(
+            sqlx::query_as(r#"SELECT * FROM "timeout_overrides" ORDER BY "tag""#)
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn set_timeout_override(&self, tag: &str, timeout: i64) -> anyhow::Result<()> {
+        sqlx::query(r#"INSERT INTO "timeout_overrides" ("tag", "timeout") VALUES ($1, $2) ON CONFLICT ("tag") DO UPDATE SET "timeout" = $2"#)
+            .bind(tag)
+            .bind(timeout)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_timeout_override(&self, tag: &str) -> anyhow::Result<bool> {
+        let r = sqlx::query(r#"DELETE FROM "timeout_overrides" WHERE "tag" = $1"#)
+            .bind(tag)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn insert_raw_data(&self, id: i32, data: &str, timestamp: i64) -> anyhow::Result<()> {
+        sqlx::query(r#"INSERT INTO "raw_data" ("from", "data", "timestamp") VALUES ($1, $2, $3)"#)
+            .bind(id)
+            .bind(data)
+            .bind(timestamp)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_metrics(
+        &self,
+        id: i32,
+        statistics: &Statistics,
+        timestamp: i64,
+    ) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"INSERT INTO "metrics" ("client_id", "timestamp", "cpu_usage", "load1", "load5", "load15", "memory_total", "memory_used", "swap_total", "swap_used", "uptime") VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)"#,
+        )
+        .bind(id)
+        .bind(timestamp)
+        .bind(statistics.get_cpu())
+        .bind(statistics.get_load_average().as_ref().map(|load| load.get_one()))
+        .bind(statistics.get_load_average().as_ref().map(|load| load.get_five()))
+        .bind(statistics.get_load_average().as_ref().map(|load| load.get_fifteen()))
+        .bind(statistics.get_memory().as_ref().map(|memory| memory.get_total()))
+        .bind(statistics.get_memory().as_ref().map(|memory| memory.get_used()))
+        .bind(statistics.get_swap().as_ref().map(|swap| swap.get_total()))
+        .bind(statistics.get_swap().as_ref().map(|swap| swap.get_used()))
+        .bind(statistics.get_uptime())
+        .execute(&mut tx)
+        .await?;
+        for disk in statistics.get_disks() {
+            sqlx::query(
+                r#"INSERT INTO "disk_metrics" ("client_id", "timestamp", "mount", "total", "used") VALUES ($1, $2, $3, $4, $5)"#,
+            )
+            .bind(id)
+            .bind(timestamp)
+            .bind(disk.get_mount())
+            .bind(disk.get_total())
+            .bind(disk.get_used())
+            .execute(&mut tx)
+            .await?;
+        }
+        for counter in statistics.get_network() {
+            sqlx::query(
+                r#"INSERT INTO "network_metrics" ("client_id", "timestamp", "interface", "rx_bytes", "tx_bytes") VALUES ($1, $2, $3, $4, $5)"#,
+            )
+            .bind(id)
+            .bind(timestamp)
+            .bind(counter.get_interface())
+            .bind(counter.get_rx_bytes())
+            .bind(counter.get_tx_bytes())
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn query_raw_data(
+        &self,
+        id: i32,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<RawDataRow>> {
+        self.query_history("raw_data", "from", id, query).await
+    }
+
+    async fn query_metrics(&self, id: i32, query: &HistoryQuery) -> anyhow::Result<Vec<MetricsRow>> {
+        self.query_history("metrics", "client_id", id, query).await
+    }
+
+    async fn query_metrics_rollup(
+        &self,
+        id: i32,
+        resolution: i64,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<MetricsRollupRow>> {
+        Ok(sqlx::query_as(&format!(
+            r#"SELECT * FROM "metrics_rollup" WHERE "client_id" = $1 AND "resolution" = $2 AND "timestamp" >= $3 AND "timestamp" <= $4 ORDER BY "timestamp" {} LIMIT $5 OFFSET $6"#,
+            query.get_order()
+        ))
+        .bind(id)
+        .bind(resolution)
+        .bind(query.get_since())
+        .bind(query.get_until())
+        .bind(query.get_limit())
+        .bind(query.get_offset())
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn query_disk_metrics(
+        &self,
+        id: i32,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<DiskMetricsRow>> {
+        self.query_history("disk_metrics", "client_id", id, query).await
+    }
+
+    async fn query_network_metrics(
+        &self,
+        id: i32,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<NetworkMetricsRow>> {
+        self.query_history("network_metrics", "client_id", id, query).await
+    }
+
+    async fn query_disk_metrics_rollup(
+        &self,
+        id: i32,
+        resolution: i64,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<DiskMetricsRollupRow>> {
+        Ok(sqlx::query_as(&format!(
+            r#"SELECT * FROM "disk_metrics_rollup" WHERE "client_id" = $1 AND "resolution" = $2 AND "timestamp" >= $3 AND "timestamp" <= $4 ORDER BY "timestamp" {}, "mount" LIMIT $5 OFFSET $6"#,
+            query.get_order()
+        ))
+        .bind(id)
+        .bind(resolution)
+        .bind(query.get_since())
+        .bind(query.get_until())
+        .bind(query.get_limit())
+        .bind(query.get_offset())
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn query_network_metrics_rollup(
+        &self,
+        id: i32,
+        resolution: i64,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<NetworkMetricsRollupRow>> {
+        Ok(sqlx::query_as(&format!(
+            r#"SELECT * FROM "network_metrics_rollup" WHERE "client_id" = $1 AND "resolution" = $2 AND "timestamp" >= $3 AND "timestamp" <= $4 ORDER BY "timestamp" {}, "interface" LIMIT $5 OFFSET $6"#,
+            query.get_order()
+        ))
+        .bind(id)
+        .bind(resolution)
+        .bind(query.get_since())
+        .bind(query.get_until())
+        .bind(query.get_limit())
+        .bind(query.get_offset())
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn delete_expired(&self, table: RetentionTable, before: i64) -> anyhow::Result<u64> {
+        let r = match table {
+            RetentionTable::Rollup(resolution) => {
+                let mut rows = 0;
+                for table in ROLLUP_TABLES {
+                    rows += sqlx::query(&format!(
+                        r#"DELETE FROM "{}" WHERE "resolution" = $1 AND "timestamp" < $2"#,
+                        table
+                    ))
+                    .bind(resolution)
+                    .bind(before)
+                    .execute(&self.pool)
+                    .await?
+                    .rows_affected();
+                }
+                return Ok(rows);
+            }
+            _ => {
+                sqlx::query(&format!(
+                    r#"DELETE FROM "{}" WHERE "timestamp" < $1"#,
+                    table.get_table_name()
+                ))
+                .bind(before)
+                .execute(&self.pool)
+                .await?
+            }
+        };
+        Ok(r.rows_affected())
+    }
+
+    async fn truncate_rows(&self, table: RetentionTable, max_rows: i64) -> anyhow::Result<u64> {
+        let r = match table {
+            RetentionTable::Rollup(resolution) => {
+                let mut rows = 0;
+                for table in ROLLUP_TABLES {
+                    rows += sqlx::query(&format!(
+                        r#"DELETE FROM "{0}" WHERE "resolution" = $1 AND "timestamp" < (SELECT "timestamp" FROM "{0}" WHERE "resolution" = $1 ORDER BY "timestamp" DESC LIMIT 1 OFFSET $2)"#,
+                        table
+                    ))
+                    .bind(resolution)
+                    .bind(max_rows)
+                    .execute(&self.pool)
+                    .await?
+                    .rows_affected();
+                }
+                return Ok(rows);
+            }
+            _ => {
+                sqlx::query(&format!(
+                    r#"DELETE FROM "{0}" WHERE "id" <= (SELECT "id" FROM "{0}" ORDER BY "id" DESC LIMIT 1 OFFSET $1)"#,
+                    table.get_table_name()
+                ))
+                .bind(max_rows)
+                .execute(&self.pool)
+                .await?
+            }
+        };
+        Ok(r.rows_affected())
+    }
+
+    async fn rollup_metrics(&self, resolution: i64, before: i64) -> anyhow::Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut rows = 0;
+        if resolution == ROLLUP_5M {
+            for (rollup, source) in [
+                (ROLLUP_FROM_METRICS, "metrics"),
+                (ROLLUP_DISK_FROM_METRICS, "disk_metrics"),
+                (ROLLUP_NETWORK_FROM_METRICS, "network_metrics"),
+            ] {
+                rows += sqlx::query(rollup)
+                    .bind(resolution)
+                    .bind(before)
+                    .execute(&mut tx)
+                    .await?
+                    .rows_affected();
+                sqlx::query(&format!(r#"DELETE FROM "{}" WHERE "timestamp" < $1"#, source))
+                    .bind(before)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        } else {
+            for (rollup, source) in [
+                (ROLLUP_FROM_ROLLUP, "metrics_rollup"),
+                (ROLLUP_DISK_FROM_ROLLUP, "disk_metrics_rollup"),
+                (ROLLUP_NETWORK_FROM_ROLLUP, "network_metrics_rollup"),
+            ] {
+                rows += sqlx::query(rollup)
+                    .bind(resolution)
+                    .bind(ROLLUP_5M)
+                    .bind(before)
+                    .execute(&mut tx)
+                    .await?
+                    .rows_affected();
+                sqlx::query(&format!(
+                    r#"DELETE FROM "{}" WHERE "resolution" = $1 AND "timestamp" < $2"#,
+                    source
+                ))
+                .bind(ROLLUP_5M)
+                .bind(before)
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(rows)
+    }
+
+    async fn vacuum(&self, _incremental: bool) -> anyhow::Result<()> {
+        // VACUUM can not run inside transaction, so use simple query protocol
+        self.pool.execute("VACUUM ANALYZE").await?;
+        Ok(())
+    }
+
+    async fn enqueue_notification(
+        &self,
+        notifier: &str,
+        payload: &str,
+        timestamp: i64,
+    ) -> anyhow::Result<i64> {
+        let r: (i64,) = sqlx::query_as(
+            r#"INSERT INTO "notification_queue" ("notifier", "payload", "next_attempt", "created_at") VALUES ($1, $2, $3, $4) RETURNING "id""#,
+        )
+        .bind(notifier)
+        .bind(payload)
+        .bind(timestamp)
+        .bind(timestamp)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(r.0)
+    }
+
+    async fn fetch_due_notifications(
+        &self,
+        notifier: &str,
+        now: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<NotificationRow>> {
+        Ok(sqlx::query_as(
+            r#"SELECT * FROM "notification_queue" WHERE "notifier" = $1 AND "next_attempt" <= $2 AND "failed_at" IS NULL ORDER BY "id" LIMIT $3"#,
+        )
+        .bind(notifier)
+        .bind(now)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn list_queued_notifiers(&self) -> anyhow::Result<Vec<String>> {
+        let rows: Vec<(String,)> =
+            sqlx::query_as(r#"SELECT DISTINCT "notifier" FROM "notification_queue""#)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows.into_iter().map(|row| row.0).collect())
+    }
+
+    async fn delete_notifications_of(&self, notifier: &str) -> anyhow::Result<u64> {
+        let r = sqlx::query(r#"DELETE FROM "notification_queue" WHERE "notifier" = $1"#)
+            .bind(notifier)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected())
+    }
+
+    async fn delete_notification(&self, id: i64) -> anyhow::Result<()> {
+        sqlx::query(r#"DELETE FROM "notification_queue" WHERE "id" = $1"#)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn reschedule_notification(
+        &self,
+        id: i64,
+        next_attempt: i64,
+        error: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"UPDATE "notification_queue" SET "attempts" = "attempts" + 1, "next_attempt" = $1, "last_error" = $2 WHERE "id" = $3"#,
+        )
+        .bind(next_attempt)
+        .bind(error)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn fail_notification(&self, id: i64, timestamp: i64, error: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"UPDATE "notification_queue" SET "attempts" = "attempts" + 1, "failed_at" = $1, "last_error" = $2 WHERE "id" = $3"#,
+        )
+        .bind(timestamp)
+        .bind(error)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn open_incident(
+        &self,
+        client_id: i32,
+        started_at: i64,
+        detected_at: i64,
+        cause: IncidentCause,
+    ) -> anyhow::Result<i64> {
+        let r: (i64,) = sqlx::query_as(
+            r#"INSERT INTO "incidents" ("client_id", "started_at", "detected_at", "cause") VALUES ($1, $2, $3, $4) RETURNING "id""#,
+        )
+        .bind(client_id)
+        .bind(started_at)
+        .bind(detected_at)
+        .bind(cause.get_name())
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(r.0)
+    }
+
+    async fn get_open_incident(&self, client_id: i32) -> anyhow::Result<Option<IncidentRow>> {
+        Ok(sqlx::query_as(
+            r#"SELECT * FROM "incidents" WHERE "client_id" = $1 AND "resolved_at" IS NULL ORDER BY "id" DESC LIMIT 1"#,
+        )
+        .bind(client_id)
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn list_open_incidents(&self) -> anyhow::Result<Vec<IncidentRow>> {
+        Ok(
+            sqlx::query_as(r#"SELECT * FROM "incidents" WHERE "resolved_at" IS NULL"#)
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn resolve_incident(
+        &self,
+        id: i64,
+        resolved_at: i64,
+        cause: Option<IncidentCause>,
+    ) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "incidents" SET "resolved_at" = $1, "cause" = COALESCE($2, "cause") WHERE "id" = $3"#)
+            .bind(resolved_at)
+            .bind(cause.map(|cause| cause.get_name()))
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_incident(&self, id: i64) -> anyhow::Result<Option<IncidentRow>> {
+        Ok(sqlx::query_as(r#"SELECT * FROM "incidents" WHERE "id" = $1"#)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn acknowledge_incident(
+        &self,
+        id: i64,
+        by: &str,
+        timestamp: i64,
+    ) -> anyhow::Result<bool> {
+        let r = sqlx::query(
+            r#"UPDATE "incidents" SET "acknowledged_at" = $1, "acknowledged_by" = $2 WHERE "id" = $3 AND "resolved_at" IS NULL AND "acknowledged_at" IS NULL"#,
+        )
+        .bind(timestamp)
+        .bind(by)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn set_escalation_level(&self, id: i64, level: i32) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "incidents" SET "escalation_level" = $1 WHERE "id" = $2"#)
+            .bind(level)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_incident_cause(&self, id: i64, cause: IncidentCause) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "incidents" SET "cause" = $1 WHERE "id" = $2"#)
+            .bind(cause.get_name())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn query_incidents(
+        &self,
+        client_id: Option<i32>,
+        query: &IncidentQuery,
+    ) -> anyhow::Result<Vec<IncidentRow>> {
+        let page = query.get_page();
+        let mut index = 2;
+        let mut next_placeholder = || {
+            index += 1;
+            format!("${}", index)
+        };
+        let mut conditions = vec![
+            r#""started_at" <= $1"#.to_string(),
+            r#"("resolved_at" IS NULL OR "resolved_at" >= $2)"#.to_string(),
+        ];
+        if client_id.is_some() {
+            conditions.push(format!(r#""client_id" = {}"#, next_placeholder()));
+        }
+        if query.get_cause().is_some() {
+            conditions.push(format!(r#""cause" = {}"#, next_placeholder()));
+        }
+        match query.get_open() {
+            Some(true) => conditions.push(r#""resolved_at" IS NULL"#.to_string()),
+            Some(false) => conditions.push(r#""resolved_at" IS NOT NULL"#.to_string()),
+            None => {}
+        }
+        let sql = format!(
+            r#"SELECT * FROM "incidents" WHERE {} ORDER BY "started_at" {} LIMIT {} OFFSET {}"#,
+            conditions.join(" AND "),
+            page.get_order(),
+            next_placeholder(),
+            next_placeholder()
+        );
+        let mut q = sqlx::query_as::<sqlx::Postgres, IncidentRow>(&sql)
+            .bind(page.get_until())
+            .bind(page.get_since());
+        if let Some(client_id) = client_id {
+            q = q.bind(client_id);
+        }
+        if let Some(cause) = query.get_cause() {
+            q = q.bind(cause);
+        }
+        Ok(q
+            .bind(page.get_limit())
+            .bind(page.get_offset())
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn list_incidents_between(
+        &self,
+        client_id: i32,
+        since: i64,
+        until: i64,
+    ) -> anyhow::Result<Vec<IncidentRow>> {
+        Ok(sqlx::query_as(
+            r#"SELECT * FROM "incidents" WHERE "client_id" = $1 AND "started_at" <= $2 AND ("resolved_at" IS NULL OR "resolved_at" >= $3) ORDER BY "started_at""#,
+        )
+        .bind(client_id)
+        .bind(until)
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn insert_maintenance(
+        &self,
+        window: &MaintenanceRequest,
+        timestamp: i64,
+    ) -> anyhow::Result<i64> {
+        let starts_at = window
+            .get_ends_at()
+            .map(|_| window.get_starts_at().unwrap_or(timestamp));
+        let r: (i64,) = sqlx::query_as(
+            r#"INSERT INTO "maintenance" ("description", "clients", "starts_at", "ends_at", "schedule", "duration", "created_at") VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING "id""#,
+        )
+        .bind(window.get_description())
+        .bind(Json(window.get_clients()))
+        .bind(starts_at)
+        .bind(window.get_ends_at())
+        .bind(window.get_schedule())
+        .bind(window.get_duration())
+        .bind(timestamp)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(r.0)
+    }
+
+    async fn list_maintenance(&self) -> anyhow::Result<Vec<MaintenanceRow>> {
+        Ok(sqlx::query_as(r#"SELECT * FROM "maintenance" ORDER BY "id""#)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn delete_maintenance(&self, id: i64) -> anyhow::Result<bool> {
+        let r = sqlx::query(r#"DELETE FROM "maintenance" WHERE "id" = $1"#)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn set_maintenance_active(&self, id: i64, since: Option<i64>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "maintenance" SET "active_since" = $1 WHERE "id" = $2"#)
+            .bind(since)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn end_maintenance(&self, id: i64, timestamp: i64) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"UPDATE "maintenance" SET "starts_at" = COALESCE("active_since", "starts_at", $1), "ends_at" = $1, "schedule" = NULL WHERE "id" = $2"#,
+        )
+        .bind(timestamp)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_alert_rules(&self) -> anyhow::Result<Vec<AlertRuleRow>> {
+        Ok(sqlx::query_as(r#"SELECT * FROM "alert_rules" ORDER BY "id""#)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn insert_alert_rule(
+        &self,
+        name: &str,
+        expression: &str,
+        clients: &[String],
+        timestamp: i64,
+    ) -> anyhow::Result<i64> {
+        let r: (i64,) = sqlx::query_as(
+            r#"INSERT INTO "alert_rules" ("name", "expression", "clients", "created_at") VALUES ($1, $2, $3, $4) RETURNING "id""#,
+        )
+        .bind(name)
+        .bind(expression)
+        .bind(Json(clients))
+        .bind(timestamp)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(r.0)
+    }
+
+    async fn delete_alert_rule(&self, name: &str) -> anyhow::Result<bool> {
+        let r = sqlx::query(r#"DELETE FROM "alert_rules" WHERE "name" = $1"#)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn list_active_alerts(&self, client_id: Option<i32>) -> anyhow::Result<Vec<AlertRow>> {
+        Ok(sqlx::query_as(
+            r#"SELECT * FROM "alerts" WHERE "resolved_at" IS NULL AND ($1::INTEGER IS NULL OR "client_id" = $1) ORDER BY "id""#,
+        )
+        .bind(client_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn query_alerts(
+        &self,
+        client_id: i32,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<AlertRow>> {
+        Ok(sqlx::query_as(&format!(
+            r#"SELECT * FROM "alerts" WHERE "client_id" = $1 AND "pending_since" >= $2 AND "pending_since" <= $3 ORDER BY "pending_since" {} LIMIT $4 OFFSET $5"#,
+            query.get_order()
+        ))
+        .bind(client_id)
+        .bind(query.get_since())
+        .bind(query.get_until())
+        .bind(query.get_limit())
+        .bind(query.get_offset())
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn insert_alert(
+        &self,
+        rule: &str,
+        client_id: i32,
+        series: &str,
+        value: f64,
+        pending_since: i64,
+        fired_at: Option<i64>,
+    ) -> anyhow::Result<i64> {
+        let r: (i64,) = sqlx::query_as(
+            r#"INSERT INTO "alerts" ("rule", "client_id", "series", "value", "pending_since", "fired_at") VALUES ($1, $2, $3, $4, $5, $6) RETURNING "id""#,
+        )
+        .bind(rule)
+        .bind(client_id)
+        .bind(series)
+        .bind(value)
+        .bind(pending_since)
+        .bind(fired_at)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(r.0)
+    }
+
+    async fn update_alert(&self, id: i64, value: f64, fired_at: Option<i64>) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"UPDATE "alerts" SET "value" = $1, "fired_at" = COALESCE("fired_at", $2) WHERE "id" = $3"#,
+        )
+        .bind(value)
+        .bind(fired_at)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn resolve_alert(&self, id: i64, value: f64, resolved_at: i64) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "alerts" SET "value" = $1, "resolved_at" = $2 WHERE "id" = $3"#)
+            .bind(value)
+            .bind(resolved_at)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_alert(&self, id: i64) -> anyhow::Result<()> {
+        sqlx::query(r#"DELETE FROM "alerts" WHERE "id" = $1"#)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_clients(&self) -> anyhow::Result<Vec<ClientRow>> {
+        Ok(sqlx::query_as(r#"SELECT * FROM "clients""#)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+}
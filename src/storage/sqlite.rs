@@ -0,0 +1,1004 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-server and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::database::{
+    self, AlertRow, AlertRuleRow, ClientRow, DiskMetricsRollupRow, DiskMetricsRow, IncidentRow,
+    MaintenanceRow, MetricsRollupRow, MetricsRow, NetworkMetricsRollupRow, NetworkMetricsRow,
+    NotificationRow, RawDataRow, TimeoutOverrideRow,
+};
+use crate::metrics::Statistics;
+use crate::structs::{HistoryQuery, IncidentQuery, MaintenanceRequest};
+use crate::storage::{
+    IncidentCause, RetentionTable, Storage, CLIENT_TABLES, ROLLUP_5M, ROLLUP_TABLES,
+};
+use async_trait::async_trait;
+use sqlx::sqlite::SqliteRow;
+use sqlx::types::Json;
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+
+const ROLLUP_FROM_METRICS: &str = r#"INSERT INTO "metrics_rollup" ("client_id", "resolution", "timestamp", "samples", "cpu_usage_min", "cpu_usage_avg", "cpu_usage_max", "load1_min", "load1_avg", "load1_max", "memory_used_min", "memory_used_avg", "memory_used_max", "swap_used_min", "swap_used_avg", "swap_used_max")
+    SELECT "client_id", ?1, "timestamp" / ?1 * ?1, COUNT(*),
+        MIN("cpu_usage"), AVG("cpu_usage"), MAX("cpu_usage"),
+        MIN("load1"), AVG("load1"), MAX("load1"),
+        MIN("memory_used"), AVG("memory_used"), MAX("memory_used"),
+        MIN("swap_used"), AVG("swap_used"), MAX("swap_used")
+    FROM "metrics" WHERE "timestamp" < ?2
+    GROUP BY "client_id", "timestamp" / ?1 * ?1"#;
+
+const ROLLUP_FROM_ROLLUP: &str = r#"INSERT INTO "metrics_rollup" ("client_id", "resolution", "timestamp", "samples", "cpu_usage_min", "cpu_usage_avg", "cpu_usage_max", "load1_min", "load1_avg", "load1_max", "memory_used_min", "memory_used_avg", "memory_used_max", "swap_used_min", "swap_used_avg", "swap_used_max")
+    SELECT "client_id", ?1, "timestamp" / ?1 * ?1, SUM("samples"),
+        MIN("cpu_usage_min"), SUM("cpu_usage_avg" * "samples") / SUM("samples"), MAX("cpu_usage_max"),
+        MIN("load1_min"), SUM("load1_avg" * "samples") / SUM("samples"), MAX("load1_max"),
+        MIN("memory_used_min"), SUM("memory_used_avg" * "samples") / SUM("samples"), MAX("memory_used_max"),
+        MIN("swap_used_min"), SUM("swap_used_avg" * "samples") / SUM("samples"), MAX("swap_used_max")
+    FROM "metrics_rollup" WHERE "resolution" = ?2 AND "timestamp" < ?3
+    GROUP BY "client_id", "timestamp" / ?1 * ?1"#;
+
+const ROLLUP_DISK_FROM_METRICS: &str = r#"INSERT INTO "disk_metrics_rollup" ("client_id", "resolution", "timestamp", "mount", "samples", "total", "used_min", "used_avg", "used_max")
+    SELECT "client_id", ?1, "timestamp" / ?1 * ?1, "mount", COUNT(*),
+        MAX("total"), MIN("used"), AVG("used"), MAX("used")
+    FROM "disk_metrics" WHERE "timestamp" < ?2
+    GROUP BY "client_id", "timestamp" / ?1 * ?1, "mount""#;
+
+const ROLLUP_DISK_FROM_ROLLUP: &str = r#"INSERT INTO "disk_metrics_rollup" ("client_id", "resolution", "timestamp", "mount", "samples", "total", "used_min", "used_avg", "used_max")
+    SELECT "client_id", ?1, "timestamp" / ?1 * ?1, "mount", SUM("samples"),
+        MAX("total"), MIN("used_min"), SUM("used_avg" * "samples") / SUM("samples"), MAX("used_max")
+    FROM "disk_metrics_rollup" WHERE "resolution" = ?2 AND "timestamp" < ?3
+    GROUP BY "client_id", "timestamp" / ?1 * ?1, "mount""#;
+
+const ROLLUP_NETWORK_FROM_METRICS: &str = r#"INSERT INTO "network_metrics_rollup" ("client_id", "resolution", "timestamp", "interface", "samples", "rx_bytes_min", "rx_bytes_max", "tx_bytes_min", "tx_bytes_max")
+    SELECT "client_id", ?1, "timestamp" / ?1 * ?1, "interface", COUNT(*),
+        MIN("rx_bytes"), MAX("rx_bytes"), MIN("tx_bytes"), MAX("tx_bytes")
+    FROM "network_metrics" WHERE "timestamp" < ?2
+    GROUP BY "client_id", "timestamp" / ?1 * ?1, "interface""#;
+
+const ROLLUP_NETWORK_FROM_ROLLUP: &str = r#"INSERT INTO "network_metrics_rollup" ("client_id", "resolution", "timestamp", "interface", "samples", "rx_bytes_min", "rx_bytes_max", "tx_bytes_min", "tx_bytes_max")
+    SELECT "client_id", ?1, "timestamp" / ?1 * ?1, "interface", SUM("samples"),
+        MIN("rx_bytes_min"), MAX("rx_bytes_max"), MIN("tx_bytes_min"), MAX("tx_bytes_max")
+    FROM "network_metrics_rollup" WHERE "resolution" = ?2 AND "timestamp" < ?3
+    GROUP BY "client_id", "timestamp" / ?1 * ?1, "interface""#;
+
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    pub async fn connect(location: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            pool: database::connect(location).await?,
+        })
+    }
+
+    async fn query_history<T>(
+        &self,
+        table: &str,
+        client_column: &str,
+        id: i32,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<T>>
+    where
+        T: for<'r> sqlx::FromRow<'r, SqliteRow> + Send + Unpin,
+    {
+        Ok(sqlx::query_as(&format!(
+            r#"SELECT * FROM "{}" WHERE "{}" = ? AND "timestamp" >= ? AND "timestamp" <= ? ORDER BY "timestamp" {} LIMIT ? OFFSET ?"#,
+            table,
+            client_column,
+            query.get_order()
+        ))
+        .bind(id)
+        .bind(query.get_since())
+        .bind(query.get_until())
+        .bind(query.get_limit())
+        .bind(query.get_offset())
+        .fetch_all(&self.pool)
+        .await?)
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        database::migrate(&mut *self.pool.acquire().await?).await
+    }
+
+    async fn get_client(&self, id: i32) -> anyhow::Result<Option<ClientRow>> {
+        Ok(sqlx::query_as(r#"SELECT * FROM "clients" WHERE "id" = ?"#)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn get_client_by_uuid(&self, uuid: &str) -> anyhow::Result<Option<ClientRow>> {
+        Ok(sqlx::query_as(r#"SELECT * FROM "clients" WHERE "uuid" = ?"#)
+            .bind(uuid)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn register_client(
+        &self,
+        uuid: &str,
+        boot_time: i64,
+        hostname: Option<&str>,
+        timestamp: i64,
+    ) -> anyhow::Result<ClientRow> {
+        sqlx::query(
+            r#"INSERT INTO "clients" ("uuid", "boot_time", "last_seen", "hostname") VALUES (?, ?, ?, ?)"#,
+        )
+        .bind(uuid)
+        .bind(boot_time)
+        .bind(timestamp)
+        .bind(hostname)
+        .execute(&self.pool)
+        .await?;
+        Ok(sqlx::query_as(r#"SELECT * FROM "clients" WHERE "uuid" = ?"#)
+            .bind(uuid)
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn update_boot_time(
+        &self,
+        id: i32,
+        boot_time: i64,
+        timestamp: i64,
+    ) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "boot_time" = ?, "last_seen" = ? WHERE "id" = ?"#)
+            .bind(boot_time)
+            .bind(timestamp)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_last_seen(&self, id: i32, timestamp: i64) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "last_seen" = ? WHERE "id" = ?"#)
+            .bind(timestamp)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_client(&self, id: i32) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM "raw_data" WHERE "from" = ?"#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        for table in CLIENT_TABLES {
+            sqlx::query(&format!(r#"DELETE FROM "{}" WHERE "client_id" = ?"#, table))
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+        }
+        sqlx::query(r#"UPDATE "clients" SET "parent_id" = NULL WHERE "parent_id" = ?"#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        let r = sqlx::query(r#"DELETE FROM "clients" WHERE "id" = ?"#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn set_display_name(&self, id: i32, display_name: Option<&str>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "display_name" = ? WHERE "id" = ?"#)
+            .bind(display_name)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_retired(&self, id: i32, retired: bool) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "retired" = ? WHERE "id" = ?"#)
+            .bind(retired)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_muted_until(&self, id: i32, until: Option<i64>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "muted_until" = ? WHERE "id" = ?"#)
+            .bind(until)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_timeout(&self, id: i32, timeout: Option<i64>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "timeout" = ? WHERE "id" = ?"#)
+            .bind(timeout)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_heartbeat_interval(&self, id: i32, interval: Option<i64>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "heartbeat_interval" = ? WHERE "id" = ?"#)
+            .bind(interval)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_flapping_since(&self, id: i32, since: Option<i64>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "flapping_since" = ? WHERE "id" = ?"#)
+            .bind(since)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_tags(&self, id: i32, tags: &[String]) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "tags" = ? WHERE "id" = ?"#)
+            .bind(Json(tags))
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_labels(&self, id: i32, labels: &BTreeMap<String, String>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "labels" = ? WHERE "id" = ?"#)
+            .bind(Json(labels))
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_parent(&self, id: i32, parent_id: Option<i32>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "clients" SET "parent_id" = ? WHERE "id" = ?"#)
+            .bind(parent_id)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_timeout_overrides(&self) -> anyhow::Result<Vec<TimeoutOverrideRow>> {
+        Ok(
+            sqlx::query_as(r#"SELECT * FROM "timeout_overrides" ORDER BY "tag""#)
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn set_timeout_override(&self, tag: &str, timeout: i64) -> anyhow::Result<()> {
+        sqlx::query(r#"INSERT OR REPLACE INTO "timeout_overrides" ("tag", "timeout") VALUES (?, ?)"#)
+            .bind(tag)
+            .bind(timeout)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_timeout_override(&self, tag: &str) -> anyhow::Result<bool> {
+        let r = sqlx::query(r#"DELETE FROM "timeout_overrides" WHERE "tag" = ?"#)
+            .bind(tag)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn insert_raw_data(&self, id: i32, data: &str, timestamp: i64) -> anyhow::Result<()> {
+        sqlx::query(r#"INSERT INTO "raw_data" ("from", "data", "timestamp") VALUES (?, ?, ?)"#)
+            .bind(id)
+            .bind(data)
+            .bind(timestamp)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_metrics(
+        &self,
+        id: i32,
+        statistics: &Statistics,
+        timestamp: i64,
+    ) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"INSERT INTO "metrics" ("client_id", "timestamp", "cpu_usage", "load1", "load5", "load15", "memory_total", "memory_used", "swap_total", "swap_used", "uptime") VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(id)
+        .bind(timestamp)
+        .bind(statistics.get_cpu())
+        .bind(statistics.get_load_average().as_ref().map(|load| load.get_one()))
+        .bind(statistics.get_load_average().as_ref().map(|load| load.get_five()))
+        .bind(statistics.get_load_average().as_ref().map(|load| load.get_fifteen()))
+        .bind(statistics.get_memory().as_ref().map(|memory| memory.get_total()))
+        .bind(statistics.get_memory().as_ref().map(|memory| memory.get_used()))
+        .bind(statistics.get_swap().as_ref().map(|swap| swap.get_total()))
+        .bind(statistics.get_swap().as_ref().map(|swap| swap.get_used()))
+        .bind(statistics.get_uptime())
+        .execute(&mut tx)
+        .await?;
+        for disk in statistics.get_disks() {
+            sqlx::query(
+                r#"INSERT INTO "disk_metrics" ("client_id", "timestamp", "mount", "total", "used") VALUES (?, ?, ?, ?, ?)"#,
+            )
+            .bind(id)
+            .bind(timestamp)
+            .bind(disk.get_mount())
+            .bind(disk.get_total())
+            .bind(disk.get_used())
+            .execute(&mut tx)
+            .await?;
+        }
+        for counter in statistics.get_network() {
+            sqlx::query(
+                r#"INSERT INTO "network_metrics" ("client_id", "timestamp", "interface", "rx_bytes", "tx_bytes") VALUES (?, ?, ?, ?, ?)"#,
+            )
+            .bind(id)
+            .bind(timestamp)
+            .bind(counter.get_interface())
+            .bind(counter.get_rx_bytes())
+            .bind(counter.get_tx_bytes())
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn query_raw_data(
+        &self,
+        id: i32,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<RawDataRow>> {
+        self.query_history("raw_data", "from", id, query).await
+    }
+
+    async fn query_metrics(&self, id: i32, query: &HistoryQuery) -> anyhow::Result<Vec<MetricsRow>> {
+        self.query_history("metrics", "client_id", id, query).await
+    }
+
+    async fn query_metrics_rollup(
+        &self,
+        id: i32,
+        resolution: i64,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<MetricsRollupRow>> {
+        Ok(sqlx::query_as(&format!(
+            r#"SELECT * FROM "metrics_rollup" WHERE "client_id" = ? AND "resolution" = ? AND "timestamp" >= ? AND "timestamp" <= ? ORDER BY "timestamp" {} LIMIT ? OFFSET ?"#,
+            query.get_order()
+        ))
+        .bind(id)
+        .bind(resolution)
+        .bind(query.get_since())
+        .bind(query.get_until())
+        .bind(query.get_limit())
+        .bind(query.get_offset())
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn query_disk_metrics(
+        &self,
+        id: i32,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<DiskMetricsRow>> {
+        self.query_history("disk_metrics", "client_id", id, query).await
+    }
+
+    async fn query_network_metrics(
+        &self,
+        id: i32,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<NetworkMetricsRow>> {
+        self.query_history("network_metrics", "client_id", id, query).await
+    }
+
+    async fn query_disk_metrics_rollup(
+        &self,
+        id: i32,
+        resolution: i64,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<DiskMetricsRollupRow>> {
+        Ok(sqlx::query_as(&format!(
+            r#"SELECT * FROM "disk_metrics_rollup" WHERE "client_id" = ? AND "resolution" = ? AND "timestamp" >= ? AND "timestamp" <= ? ORDER BY "timestamp" {}, "mount" LIMIT ? OFFSET ?"#,
+            query.get_order()
+        ))
+        .bind(id)
+        .bind(resolution)
+        .bind(query.get_since())
+        .bind(query.get_until())
+        .bind(query.get_limit())
+        .bind(query.get_offset())
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn query_network_metrics_rollup(
+        &self,
+        id: i32,
+        resolution: i64,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<NetworkMetricsRollupRow>> {
+        Ok(sqlx::query_as(&format!(
+            r#"SELECT * FROM "network_metrics_rollup" WHERE "client_id" = ? AND "resolution" = ? AND "timestamp" >= ? AND "timestamp" <= ? ORDER BY "timestamp" {}, "interface" LIMIT ? OFFSET ?"#,
+            query.get_order()
+        ))
+        .bind(id)
+        .bind(resolution)
+        .bind(query.get_since())
+        .bind(query.get_until())
+        .bind(query.get_limit())
+        .bind(query.get_offset())
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn delete_expired(&self, table: RetentionTable, before: i64) -> anyhow::Result<u64> {
+        let r = match table {
+            RetentionTable::Rollup(resolution) => {
+                let mut rows = 0;
+                for table in ROLLUP_TABLES {
+                    rows += sqlx::query(&format!(
+                        r#"DELETE FROM "{}" WHERE "resolution" = ? AND "timestamp" < ?"#,
+                        table
+                    ))
+                    .bind(resolution)
+                    .bind(before)
+                    .execute(&self.pool)
+                    .await?
+                    .rows_affected();
+                }
+                return Ok(rows);
+            }
+            _ => {
+                sqlx::query(&format!(
+                    r#"DELETE FROM "{}" WHERE "timestamp" < ?"#,
+                    table.get_table_name()
+                ))
+                .bind(before)
+                .execute(&self.pool)
+                .await?
+            }
+        };
+        Ok(r.rows_affected())
+    }
+
+    async fn truncate_rows(&self, table: RetentionTable, max_rows: i64) -> anyhow::Result<u64> {
+        let r = match table {
+            RetentionTable::Rollup(resolution) => {
+                let mut rows = 0;
+                for table in ROLLUP_TABLES {
+                    rows += sqlx::query(&format!(
+                        r#"DELETE FROM "{0}" WHERE "resolution" = ?1 AND "timestamp" < (SELECT "timestamp" FROM "{0}" WHERE "resolution" = ?1 ORDER BY "timestamp" DESC LIMIT 1 OFFSET ?2)"#,
+                        table
+                    ))
+                    .bind(resolution)
+                    .bind(max_rows)
+                    .execute(&self.pool)
+                    .await?
+                    .rows_affected();
+                }
+                return Ok(rows);
+            }
+            _ => {
+                sqlx::query(&format!(
+                    r#"DELETE FROM "{0}" WHERE "id" <= (SELECT "id" FROM "{0}" ORDER BY "id" DESC LIMIT 1 OFFSET ?)"#,
+                    table.get_table_name()
+                ))
+                .bind(max_rows)
+                .execute(&self.pool)
+                .await?
+            }
+        };
+        Ok(r.rows_affected())
+    }
+
+    async fn rollup_metrics(&self, resolution: i64, before: i64) -> anyhow::Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut rows = 0;
+        if resolution == ROLLUP_5M {
+            for (rollup, source) in [
+                (ROLLUP_FROM_METRICS, "metrics"),
+                (ROLLUP_DISK_FROM_METRICS, "disk_metrics"),
+                (ROLLUP_NETWORK_FROM_METRICS, "network_metrics"),
+            ] {
+                rows += sqlx::query(rollup)
+                    .bind(resolution)
+                    .bind(before)
+                    .execute(&mut tx)
+                    .await?
+                    .rows_affected();
+                sqlx::query(&format!(r#"DELETE FROM "{}" WHERE "timestamp" < ?"#, source))
+                    .bind(before)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        } else {
+            for (rollup, source) in [
+                (ROLLUP_FROM_ROLLUP, "metrics_rollup"),
+                (ROLLUP_DISK_FROM_ROLLUP, "disk_metrics_rollup"),
+                (ROLLUP_NETWORK_FROM_ROLLUP, "network_metrics_rollup"),
+            ] {
+                rows += sqlx::query(rollup)
+                    .bind(resolution)
+                    .bind(ROLLUP_5M)
+                    .bind(before)
+                    .execute(&mut tx)
+                    .await?
+                    .rows_affected();
+                sqlx::query(&format!(
+                    r#"DELETE FROM "{}" WHERE "resolution" = ? AND "timestamp" < ?"#,
+                    source
+                ))
+                .bind(ROLLUP_5M)
+                .bind(before)
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(rows)
+    }
+
+    async fn vacuum(&self, incremental: bool) -> anyhow::Result<()> {
+        let mut conn = self.pool.acquire().await?;
+        if incremental {
+            let r: (i64,) = sqlx::query_as("PRAGMA auto_vacuum")
+                .fetch_one(&mut *conn)
+                .await?;
+            // 2 means INCREMENTAL, switching mode only takes effect after a full vacuum
+            if r.0 != 2 {
+                sqlx::query("PRAGMA auto_vacuum = INCREMENTAL")
+                    .execute(&mut *conn)
+                    .await?;
+                sqlx::query("VACUUM").execute(&mut *conn).await?;
+            }
+            sqlx::query("PRAGMA incremental_vacuum")
+                .execute(&mut *conn)
+                .await?;
+        } else {
+            sqlx::query("VACUUM").execute(&mut *conn).await?;
+        }
+        Ok(())
+    }
+
+    async fn enqueue_notification(
+        &self,
+        notifier: &str,
+        payload: &str,
+        timestamp: i64,
+    ) -> anyhow::Result<i64> {
+        let r = sqlx::query(
+            r#"INSERT INTO "notification_queue" ("notifier", "payload", "next_attempt", "created_at") VALUES (?, ?, ?, ?)"#,
+        )
+        .bind(notifier)
+        .bind(payload)
+        .bind(timestamp)
+        .bind(timestamp)
+        .execute(&self.pool)
+        .await?;
+        Ok(r.last_insert_rowid())
+    }
+
+    async fn fetch_due_notifications(
+        &self,
+        notifier: &str,
+        now: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<NotificationRow>> {
+        Ok(sqlx::query_as(
+            r#"SELECT * FROM "notification_queue" WHERE "notifier" = ? AND "next_attempt" <= ? AND "failed_at" IS NULL ORDER BY "id" LIMIT ?"#,
+        )
+        .bind(notifier)
+        .bind(now)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn list_queued_notifiers(&self) -> anyhow::Result<Vec<String>> {
+        let rows: Vec<(String,)> =
+            sqlx::query_as(r#"SELECT DISTINCT "notifier" FROM "notification_queue""#)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows.into_iter().map(|row| row.0).collect())
+    }
+
+    async fn delete_notifications_of(&self, notifier: &str) -> anyhow::Result<u64> {
+        let r = sqlx::query(r#"DELETE FROM "notification_queue" WHERE "notifier" = ?"#)
+            .bind(notifier)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected())
+    }
+
+    async fn delete_notification(&self, id: i64) -> anyhow::Result<()> {
+        sqlx::query(r#"DELETE FROM "notification_queue" WHERE "id" = ?"#)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn reschedule_notification(
+        &self,
+        id: i64,
+        next_attempt: i64,
+        error: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"UPDATE "notification_queue" SET "attempts" = "attempts" + 1, "next_attempt" = ?, "last_error" = ? WHERE "id" = ?"#,
+        )
+        .bind(next_attempt)
+        .bind(error)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn fail_notification(&self, id: i64, timestamp: i64, error: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"UPDATE "notification_queue" SET "attempts" = "attempts" + 1, "failed_at" = ?, "last_error" = ? WHERE "id" = ?"#,
+        )
+        .bind(timestamp)
+        .bind(error)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn open_incident(
+        &self,
+        client_id: i32,
+        started_at: i64,
+        detected_at: i64,
+        cause: IncidentCause,
+    ) -> anyhow::Result<i64> {
+        let r = sqlx::query(
+            r#"INSERT INTO "incidents" ("client_id", "started_at", "detected_at", "cause") VALUES (?, ?, ?, ?)"#,
+        )
+        .bind(client_id)
+        .bind(started_at)
+        .bind(detected_at)
+        .bind(cause.get_name())
+        .execute(&self.pool)
+        .await?;
+        Ok(r.last_insert_rowid())
+    }
+
+    async fn get_open_incident(&self, client_id: i32) -> anyhow::Result<Option<IncidentRow>> {
+        Ok(sqlx::query_as(
+            r#"SELECT * FROM "incidents" WHERE "client_id" = ? AND "resolved_at" IS NULL ORDER BY "id" DESC LIMIT 1"#,
+        )
+        .bind(client_id)
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn list_open_incidents(&self) -> anyhow::Result<Vec<IncidentRow>> {
+        Ok(
+            sqlx::query_as(r#"SELECT * FROM "incidents" WHERE "resolved_at" IS NULL"#)
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn resolve_incident(
+        &self,
+        id: i64,
+        resolved_at: i64,
+        cause: Option<IncidentCause>,
+    ) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "incidents" SET "resolved_at" = ?, "cause" = COALESCE(?, "cause") WHERE "id" = ?"#)
+            .bind(resolved_at)
+            .bind(cause.map(|cause| cause.get_name()))
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_incident(&self, id: i64) -> anyhow::Result<Option<IncidentRow>> {
+        Ok(sqlx::query_as(r#"SELECT * FROM "incidents" WHERE "id" = ?"#)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn acknowledge_incident(
+        &self,
+        id: i64,
+        by: &str,
+        timestamp: i64,
+    ) -> anyhow::Result<bool> {
+        let r = sqlx::query(
+            r#"UPDATE "incidents" SET "acknowledged_at" = ?, "acknowledged_by" = ? WHERE "id" = ? AND "resolved_at" IS NULL AND "acknowledged_at" IS NULL"#,
+        )
+        .bind(timestamp)
+        .bind(by)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn set_escalation_level(&self, id: i64, level: i32) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "incidents" SET "escalation_level" = ? WHERE "id" = ?"#)
+            .bind(level)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_incident_cause(&self, id: i64, cause: IncidentCause) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "incidents" SET "cause" = ? WHERE "id" = ?"#)
+            .bind(cause.get_name())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn query_incidents(
+        &self,
+        client_id: Option<i32>,
+        query: &IncidentQuery,
+    ) -> anyhow::Result<Vec<IncidentRow>> {
+        let page = query.get_page();
+        let mut conditions = vec![
+            r#""started_at" <= ?"#,
+            r#"("resolved_at" IS NULL OR "resolved_at" >= ?)"#,
+        ];
+        if client_id.is_some() {
+            conditions.push(r#""client_id" = ?"#);
+        }
+        if query.get_cause().is_some() {
+            conditions.push(r#""cause" = ?"#);
+        }
+        match query.get_open() {
+            Some(true) => conditions.push(r#""resolved_at" IS NULL"#),
+            Some(false) => conditions.push(r#""resolved_at" IS NOT NULL"#),
+            None => {}
+        }
+        let sql = format!(
+            r#"SELECT * FROM "incidents" WHERE {} ORDER BY "started_at" {} LIMIT ? OFFSET ?"#,
+            conditions.join(" AND "),
+            page.get_order()
+        );
+        let mut q = sqlx::query_as::<sqlx::Sqlite, IncidentRow>(&sql)
+            .bind(page.get_until())
+            .bind(page.get_since());
+        if let Some(client_id) = client_id {
+            q = q.bind(client_id);
+        }
+        if let Some(cause) = query.get_cause() {
+            q = q.bind(cause);
+        }
+        Ok(q
+            .bind(page.get_limit())
+            .bind(page.get_offset())
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn list_incidents_between(
+        &self,
+        client_id: i32,
+        since: i64,
+        until: i64,
+    ) -> anyhow::Result<Vec<IncidentRow>> {
+        Ok(sqlx::query_as(
+            r#"SELECT * FROM "incidents" WHERE "client_id" = ? AND "started_at" <= ? AND ("resolved_at" IS NULL OR "resolved_at" >= ?) ORDER BY "started_at""#,
+        )
+        .bind(client_id)
+        .bind(until)
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn insert_maintenance(
+        &self,
+        window: &MaintenanceRequest,
+        timestamp: i64,
+    ) -> anyhow::Result<i64> {
+        let starts_at = window
+            .get_ends_at()
+            .map(|_| window.get_starts_at().unwrap_or(timestamp));
+        let r = sqlx::query(
+            r#"INSERT INTO "maintenance" ("description", "clients", "starts_at", "ends_at", "schedule", "duration", "created_at") VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(window.get_description())
+        .bind(Json(window.get_clients()))
+        .bind(starts_at)
+        .bind(window.get_ends_at())
+        .bind(window.get_schedule())
+        .bind(window.get_duration())
+        .bind(timestamp)
+        .execute(&self.pool)
+        .await?;
+        Ok(r.last_insert_rowid())
+    }
+
+    async fn list_maintenance(&self) -> anyhow::Result<Vec<MaintenanceRow>> {
+        Ok(sqlx::query_as(r#"SELECT * FROM "maintenance" ORDER BY "id""#)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn delete_maintenance(&self, id: i64) -> anyhow::Result<bool> {
+        let r = sqlx::query(r#"DELETE FROM "maintenance" WHERE "id" = ?"#)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn set_maintenance_active(&self, id: i64, since: Option<i64>) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "maintenance" SET "active_since" = ? WHERE "id" = ?"#)
+            .bind(since)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn end_maintenance(&self, id: i64, timestamp: i64) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"UPDATE "maintenance" SET "starts_at" = COALESCE("active_since", "starts_at", ?), "ends_at" = ?, "schedule" = NULL WHERE "id" = ?"#,
+        )
+        .bind(timestamp)
+        .bind(timestamp)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_alert_rules(&self) -> anyhow::Result<Vec<AlertRuleRow>> {
+        Ok(sqlx::query_as(r#"SELECT * FROM "alert_rules" ORDER BY "id""#)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn insert_alert_rule(
+        &self,
+        name: &str,
+        expression: &str,
+        clients: &[String],
+        timestamp: i64,
+    ) -> anyhow::Result<i64> {
+        let r = sqlx::query(
+            r#"INSERT INTO "alert_rules" ("name", "expression", "clients", "created_at") VALUES (?, ?, ?, ?)"#,
+        )
+        .bind(name)
+        .bind(expression)
+        .bind(Json(clients))
+        .bind(timestamp)
+        .execute(&self.pool)
+        .await?;
+        Ok(r.last_insert_rowid())
+    }
+
+    async fn delete_alert_rule(&self, name: &str) -> anyhow::Result<bool> {
+        let r = sqlx::query(r#"DELETE FROM "alert_rules" WHERE "name" = ?"#)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(r.rows_affected() > 0)
+    }
+
+    async fn list_active_alerts(&self, client_id: Option<i32>) -> anyhow::Result<Vec<AlertRow>> {
+        Ok(sqlx::query_as(
+            r#"SELECT * FROM "alerts" WHERE "resolved_at" IS NULL AND (? IS NULL OR "client_id" = ?) ORDER BY "id""#,
+        )
+        .bind(client_id)
+        .bind(client_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn query_alerts(
+        &self,
+        client_id: i32,
+        query: &HistoryQuery,
+    ) -> anyhow::Result<Vec<AlertRow>> {
+        Ok(sqlx::query_as(&format!(
+            r#"SELECT * FROM "alerts" WHERE "client_id" = ? AND "pending_since" >= ? AND "pending_since" <= ? ORDER BY "pending_since" {} LIMIT ? OFFSET ?"#,
+            query.get_order()
+        ))
+        .bind(client_id)
+        .bind(query.get_since())
+        .bind(query.get_until())
+        .bind(query.get_limit())
+        .bind(query.get_offset())
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn insert_alert(
+        &self,
+        rule: &str,
+        client_id: i32,
+        series: &str,
+        value: f64,
+        pending_since: i64,
+        fired_at: Option<i64>,
+    ) -> anyhow::Result<i64> {
+        let r = sqlx::query(
+            r#"INSERT INTO "alerts" ("rule", "client_id", "series", "value", "pending_since", "fired_at") VALUES (?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(rule)
+        .bind(client_id)
+        .bind(series)
+        .bind(value)
+        .bind(pending_since)
+        .bind(fired_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(r.last_insert_rowid())
+    }
+
+    async fn update_alert(&self, id: i64, value: f64, fired_at: Option<i64>) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"UPDATE "alerts" SET "value" = ?, "fired_at" = COALESCE("fired_at", ?) WHERE "id" = ?"#,
+        )
+        .bind(value)
+        .bind(fired_at)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn resolve_alert(&self, id: i64, value: f64, resolved_at: i64) -> anyhow::Result<()> {
+        sqlx::query(r#"UPDATE "alerts" SET "value" = ?, "resolved_at" = ? WHERE "id" = ?"#)
+            .bind(value)
+            .bind(resolved_at)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_alert(&self, id: i64) -> anyhow::Result<()> {
+        sqlx::query(r#"DELETE FROM "alerts" WHERE "id" = ?"#)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_clients(&self) -> anyhow::Result<Vec<ClientRow>> {
+        Ok(sqlx::query_as(r#"SELECT * FROM "clients""#)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+}
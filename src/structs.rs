@@ -19,32 +19,12 @@
  */
 #![allow(dead_code)]
 use crate::configparser::Config;
-use actix_web::dev::RequestHead;
-use actix_web::guard::Guard;
+use actix_web::guard::{Guard, GuardContext};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt::Formatter;
 pub const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
 
-pub const CREATE_TABLES: &str = r#"CREATE TABLE "clients" (
-	"id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-	"uuid"	TEXT NOT NULL UNIQUE,
-	"boot_time"	INTEGER NOT NULL,
-	"last_seen"	INTEGER NOT NULL
-);
-
-CREATE TABLE "raw_data" (
-	"id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-	"from"	INTEGER NOT NULL,
-	"data"	TEXT NOT NULL,
-	"timestamp"	INTEGER NOT NULL
-);
-"#;
-
-pub const CREATE_TABLES_WATCHDOG: &str = r#"CREATE TABLE "list" (
-    "id"    INTEGER NOT NULL PRIMARY KEY
-);
-"#;
-
 #[derive(Deserialize, Serialize, Debug, Default)]
 pub struct Response {
     version: String,
@@ -100,21 +80,377 @@ impl Request {
     }
 }
 
+/// Client referenced by admin, either database id or uuid.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ClientKey {
+    Id(i32),
+    Uuid(String),
+}
+
+impl From<&str> for ClientKey {
+    fn from(s: &str) -> Self {
+        match s.parse() {
+            Ok(id) => ClientKey::Id(id),
+            Err(_) => ClientKey::Uuid(s.to_string()),
+        }
+    }
+}
+
+const DEFAULT_HISTORY_LIMIT: i64 = 100;
+const MAXIMUM_HISTORY_LIMIT: i64 = 10000;
+
+/// Time range and pagination of historical data query.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct HistoryQuery {
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    order: Option<String>,
+    resolution: Option<i64>,
+}
+
+impl HistoryQuery {
+    /// Newest `limit` rows.
+    pub fn new_latest(limit: i64) -> Self {
+        Self {
+            limit: Some(limit),
+            ..Default::default()
+        }
+    }
+
+    pub fn get_since(&self) -> i64 {
+        self.since.unwrap_or(0)
+    }
+
+    pub fn get_since_opt(&self) -> Option<i64> {
+        self.since
+    }
+
+    pub fn get_until(&self) -> i64 {
+        self.until.unwrap_or(i64::MAX)
+    }
+
+    pub fn get_limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_HISTORY_LIMIT)
+            .clamp(0, MAXIMUM_HISTORY_LIMIT)
+    }
+
+    pub fn get_offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+
+    /// SQL keyword of order, newest first by default.
+    pub fn get_order(&self) -> &'static str {
+        match self.order.as_deref() {
+            Some(order) if order.eq_ignore_ascii_case("asc") => "ASC",
+            _ => "DESC",
+        }
+    }
+
+    /// Resolution of metrics in seconds, `None` means raw samples.
+    pub fn get_resolution(&self) -> Option<i64> {
+        self.resolution.filter(|resolution| *resolution > 0)
+    }
+}
+
+/// Filter of `GET /admin/incidents`, time range selects incidents overlapping it.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct IncidentQuery {
+    client: Option<String>,
+    cause: Option<String>,
+    /// `true` for unresolved incidents only, `false` for resolved ones only
+    open: Option<bool>,
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    order: Option<String>,
+}
+
+impl IncidentQuery {
+    pub fn get_client(&self) -> Option<ClientKey> {
+        self.client.as_deref().map(ClientKey::from)
+    }
+
+    pub fn get_cause(&self) -> &Option<String> {
+        &self.cause
+    }
+
+    pub fn get_open(&self) -> Option<bool> {
+        self.open
+    }
+
+    /// Time range and pagination part of query.
+    pub fn get_page(&self) -> HistoryQuery {
+        HistoryQuery {
+            since: self.since,
+            until: self.until,
+            limit: self.limit,
+            offset: self.offset,
+            order: self.order.clone(),
+            resolution: None,
+        }
+    }
+}
+
+impl From<&HistoryQuery> for IncidentQuery {
+    fn from(query: &HistoryQuery) -> Self {
+        Self {
+            since: query.since,
+            until: query.until,
+            limit: query.limit,
+            offset: query.offset,
+            order: query.order.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Availability of client in `[since, until]`, computed from incidents.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UptimeReport {
+    client_id: i32,
+    since: i64,
+    until: i64,
+    downtime: i64,
+    incidents: usize,
+    uptime_percent: f64,
+}
+
+impl UptimeReport {
+    pub fn new(client_id: i32, since: i64, until: i64, downtime: i64, incidents: usize) -> Self {
+        let window = until - since;
+        let uptime_percent = if window > 0 {
+            (window - downtime).max(0) as f64 * 100.0 / window as f64
+        } else {
+            100.0
+        };
+        Self {
+            client_id,
+            since,
+            until,
+            downtime,
+            incidents,
+            uptime_percent,
+        }
+    }
+}
+
+/// Query of client listing, `filter` is comma separated patterns which all have to match, e.g.
+/// `tag:prod,site=tokyo`.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ClientFilter {
+    filter: Option<String>,
+}
+
+impl ClientFilter {
+    pub fn get_patterns(&self) -> Vec<&str> {
+        self.filter
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(|pattern| pattern.trim())
+            .filter(|pattern| !pattern.is_empty())
+            .collect()
+    }
+}
+
+/// Body of `PATCH /admin/clients/{client}`, absent fields are left unchanged.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ClientPatch {
+    display_name: Option<String>,
+    retired: Option<bool>,
+    /// Heartbeat timeout in seconds, `0` restores configured timeout
+    timeout: Option<i64>,
+    /// Replaces all tags
+    tags: Option<Vec<String>>,
+    /// Labels to set, `null` removes the label
+    labels: Option<BTreeMap<String, Option<String>>>,
+    /// Id or uuid of client this one depends on, empty string removes the dependency
+    parent: Option<String>,
+}
+
+impl ClientPatch {
+    pub fn new_retired(retired: bool) -> Self {
+        Self {
+            retired: Some(retired),
+            ..Default::default()
+        }
+    }
+
+    pub fn get_retired(&self) -> Option<bool> {
+        self.retired
+    }
+
+    /// Empty string means clear display name.
+    pub fn get_display_name(&self) -> &Option<String> {
+        &self.display_name
+    }
+
+    pub fn get_timeout(&self) -> Option<i64> {
+        self.timeout
+    }
+
+    pub fn get_tags(&self) -> &Option<Vec<String>> {
+        &self.tags
+    }
+
+    pub fn get_labels(&self) -> &Option<BTreeMap<String, Option<String>>> {
+        &self.labels
+    }
+
+    /// Empty string means no parent.
+    pub fn get_parent(&self) -> &Option<String> {
+        &self.parent
+    }
+}
+
+/// Body of `PUT /admin/timeouts/{pattern}`, timeout is in seconds.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TimeoutOverride {
+    timeout: i64,
+}
+
+impl TimeoutOverride {
+    pub fn get_timeout(&self) -> i64 {
+        self.timeout
+    }
+}
+
+/// Body of `POST /admin/maintenance`, either `ends_at` (one-off window, starts now unless
+/// `starts_at` is set) or cron `schedule` with `duration` (recurring window) is required.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct MaintenanceRequest {
+    description: Option<String>,
+    /// Client id, uuid or name, `*` matches any characters, empty means all clients
+    clients: Option<Vec<String>>,
+    starts_at: Option<i64>,
+    ends_at: Option<i64>,
+    schedule: Option<String>,
+    duration: Option<i64>,
+}
+
+impl MaintenanceRequest {
+    /// One-off window from `starts_at` to `ends_at`.
+    pub fn new_once(
+        description: String,
+        clients: Vec<String>,
+        starts_at: i64,
+        ends_at: i64,
+    ) -> Self {
+        Self {
+            description: Some(description),
+            clients: Some(clients),
+            starts_at: Some(starts_at),
+            ends_at: Some(ends_at),
+            ..Default::default()
+        }
+    }
+
+    pub fn get_description(&self) -> &str {
+        self.description.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_clients(&self) -> &[String] {
+        self.clients.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_starts_at(&self) -> Option<i64> {
+        self.starts_at
+    }
+
+    pub fn get_ends_at(&self) -> Option<i64> {
+        self.ends_at
+    }
+
+    pub fn get_schedule(&self) -> &Option<String> {
+        &self.schedule
+    }
+
+    pub fn get_duration(&self) -> Option<i64> {
+        self.duration
+    }
+}
+
+/// Body of `POST /admin/alerts/rules`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AlertRuleRequest {
+    name: String,
+    expression: String,
+    /// Client id, uuid or name, `*` matches any characters, empty means all clients
+    clients: Option<Vec<String>>,
+}
+
+impl AlertRuleRequest {
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn get_expression(&self) -> &String {
+        &self.expression
+    }
+
+    pub fn get_clients(&self) -> &[String] {
+        self.clients.as_deref().unwrap_or_default()
+    }
+}
+
+/// Body of `POST /admin/incidents/{id}/acknowledge`, body can be omitted.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct AcknowledgeRequest {
+    /// Who acknowledged the incident, `admin` if absent
+    by: Option<String>,
+}
+
+impl AcknowledgeRequest {
+    pub fn get_by(&self) -> &str {
+        self.by.as_deref().unwrap_or("admin")
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct AdminRequest {
     action: String,
+    client: Option<ClientKey>,
+    #[serde(flatten)]
+    query: HistoryQuery,
+    #[serde(flatten)]
+    patch: ClientPatch,
 }
 
 impl AdminRequest {
     pub fn get_action(&self) -> &String {
         &self.action
     }
+
+    pub fn get_client(&self) -> &Option<ClientKey> {
+        &self.client
+    }
+
+    pub fn get_query(&self) -> &HistoryQuery {
+        &self.query
+    }
+
+    pub fn get_patch(&self) -> &ClientPatch {
+        &self.patch
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct AdditionalInfo {
     hostname: String,
     boot_time: i64,
+    /// Heartbeat interval of client in seconds
+    #[serde(default)]
+    interval: Option<i64>,
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Key/value metadata such as site, role or owner
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
 }
 
 impl AdditionalInfo {
@@ -125,6 +461,18 @@ impl AdditionalInfo {
     pub fn get_boot_time(&self) -> i64 {
         self.boot_time
     }
+
+    pub fn get_interval(&self) -> Option<i64> {
+        self.interval
+    }
+
+    pub fn get_tags(&self) -> &Vec<String> {
+        &self.tags
+    }
+
+    pub fn get_labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -133,8 +481,8 @@ pub enum ErrorCodes {
     NotRegister,
     ClientVersionMismatch,
     UnsupportedMethod,
-    Reversed1,
-    Reversed2,
+    InvalidParameter,
+    ClientNotFound,
     Reversed3,
     Reversed4,
     Reversed5,
@@ -147,8 +495,8 @@ impl From<&ErrorCodes> for i64 {
             ErrorCodes::NotRegister => 4031,
             ErrorCodes::ClientVersionMismatch => 4000,
             ErrorCodes::UnsupportedMethod => 4001,
-            ErrorCodes::Reversed1 => 4002,
-            ErrorCodes::Reversed2 => 4003,
+            ErrorCodes::InvalidParameter => 4002,
+            ErrorCodes::ClientNotFound => 4003,
             ErrorCodes::Reversed3 => 4004,
             ErrorCodes::Reversed4 => 4005,
             ErrorCodes::Reversed5 => 4006,
@@ -167,6 +515,8 @@ impl std::fmt::Display for ErrorCodes {
                 ErrorCodes::ClientVersionMismatch =>
                     "Client version smaller than requested version",
                 ErrorCodes::UnsupportedMethod => "Request method not supported",
+                ErrorCodes::InvalidParameter => "Invalid or missing parameter",
+                ErrorCodes::ClientNotFound => "Client not found",
                 _ => {
                     unreachable!()
                 }
@@ -175,6 +525,12 @@ impl std::fmt::Display for ErrorCodes {
     }
 }
 
+impl Response {
+    pub fn from_error_with_message(err_codes: ErrorCodes, message: String) -> Self {
+        Self::new(i64::from(&err_codes), Some(message))
+    }
+}
+
 impl From<ErrorCodes> for Response {
     fn from(err_codes: ErrorCodes) -> Self {
         Self::from(&err_codes)
@@ -225,8 +581,8 @@ impl From<&Config> for AuthorizationGuard {
 }
 
 impl Guard for AuthorizationGuard {
-    fn check(&self, request: &RequestHead) -> bool {
-        if let Some(val) = request.headers.get("authorization") {
+    fn check(&self, request: &GuardContext) -> bool {
+        if let Some(val) = request.head().headers.get("authorization") {
             return self.token.len() != 6 && val == &self.token;
         }
         false
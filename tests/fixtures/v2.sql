@@ -0,0 +1,30 @@
+-- Database created by probe-server 1.0 (schema version 2, hostname kept in its own table)
+CREATE TABLE "clients" (
+	"id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	"uuid"	TEXT NOT NULL UNIQUE,
+	"boot_time"	INTEGER NOT NULL,
+	"last_seen"	INTEGER NOT NULL
+);
+CREATE TABLE "raw_data" (
+	"id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	"from"	INTEGER NOT NULL,
+	"data"	TEXT NOT NULL,
+	"timestamp"	INTEGER NOT NULL
+);
+CREATE TABLE "pbs_meta" (
+	"key"	TEXT NOT NULL,
+	"value"	TEXT NOT NULL,
+	PRIMARY KEY("key")
+);
+CREATE TABLE "hostname" (
+	"id"	INTEGER NOT NULL,
+	"name"	TEXT,
+	PRIMARY KEY("id")
+);
+INSERT INTO "pbs_meta" VALUES('version','2');
+INSERT INTO "clients" VALUES(1,'2f1c5c4e-52a6-4e3b-9f0a-0c6a2b6c1d01',1634000000,1634086400);
+INSERT INTO "clients" VALUES(2,'8b7e2f90-1c3d-4a5b-8e6f-7a8b9c0d1e02',1634050000,1634086390);
+INSERT INTO "hostname" VALUES(1,'web-1');
+INSERT INTO "raw_data" VALUES(1,1,'{"cpu_percent": 3.1}',1634086300);
+INSERT INTO "raw_data" VALUES(2,1,'{"cpu_percent": 2.7}',1634086400);
+INSERT INTO "raw_data" VALUES(3,2,'load ok',1634086390);